@@ -0,0 +1,55 @@
+/* The C API of the raytracer, see src/ffi.rs. Build the library with
+ * `cargo build --release --no-default-features --features ffi` and link against
+ * target/release/libraytracer.so. */
+#ifndef RAYTRACER_H
+#define RAYTRACER_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct RtScene RtScene;
+
+typedef enum RtMaterialKind {
+    RT_LAMBERTIAN,
+    RT_METAL,
+    RT_DIELECTRIC,
+} RtMaterialKind;
+
+/* `kind` is one of RtMaterialKind, `parameter` is the fuzz of metals and the refraction index of
+ * dielectrics. */
+typedef struct RtMaterial {
+    int kind;
+    double color[3];
+    double parameter;
+} RtMaterial;
+
+/* Return non-zero to cancel the render. */
+typedef int (*RtProgressCallback)(double progress, void *user_data);
+
+/* Functions returning int return 0 on success and -1 on failure, described by rt_last_error.
+ * Null pointers and panics of the renderer are failures rather than crashes. */
+const char *rt_last_error(void);
+
+/* Null if the scene couldn't be made. */
+RtScene *rt_scene_new(void);
+void rt_scene_free(RtScene *scene);
+
+/* Camera settings as `name = value` lines, the format of camera config files. */
+int rt_scene_set_camera(RtScene *scene, const char *config);
+int rt_scene_add_sphere(RtScene *scene, const double center[3], double radius,
+                        const RtMaterial *material);
+int rt_scene_image_size(const RtScene *scene, size_t *width, size_t *height);
+
+/* Renders rows of linear RGB triples into `buffer`, which holds `buffer_len` floats. Nothing is
+ * written anywhere else. */
+int rt_render(const RtScene *scene, float *buffer, size_t buffer_len,
+              RtProgressCallback progress, void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
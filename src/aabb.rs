@@ -1,5 +0,0 @@
-struct AABB {
-    x: Range,
-    y: Range,
-    z: Range,
-}
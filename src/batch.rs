@@ -0,0 +1,321 @@
+use std::fmt::Write;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::{info, warn};
+
+use crate::{
+    camera::{builder::CameraBuilder, events::RenderStats, RenderControl},
+    error::{Error, Result},
+    scene,
+};
+
+// Claims are stamped with the process holding them and the time, and stamped again this often
+// while the job renders. A claim that hasn't been stamped for `CLAIM_EXPIRY` was left by a render
+// that died and is taken over.
+const CLAIM_REFRESH: Duration = Duration::from_secs(60);
+const CLAIM_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+// One render of a batch, the scene by name written to `output`, see `CameraBuilder::output`.
+#[derive(Debug, Clone)]
+pub struct BatchJob {
+    pub scene: String,
+    pub output: String,
+    // A camera config written by `CameraBuilder::to_config` to render with in place of the
+    // defaults of the batch.
+    pub preset: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub enum JobOutcome {
+    Rendered(RenderStats),
+    // The output was there already or another machine had claimed the job.
+    Skipped,
+    Failed(Error),
+}
+
+// Reads a queue of jobs, one per line as the scene, the output and optionally the path of a camera
+// preset separated by spaces, like `book_cover renders/cover presets/closeup.cfg`. Lines starting
+// with `#` are comments.
+pub fn parse_queue(queue: &str) -> Result<Vec<BatchJob>> {
+    queue
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let (scene, output, preset) = match fields[..] {
+                [scene, output] => (scene, output, None),
+                [scene, output, preset] => (scene, output, Some(PathBuf::from(preset))),
+                _ => return Err(Error::InvalidQueue(line.to_string())),
+            };
+            Ok(BatchJob {
+                scene: scene.to_string(),
+                output: output.to_string(),
+                preset,
+            })
+        })
+        .collect()
+}
+
+// Renders the jobs one after another, going on past the ones that fail, with the cameras of jobs
+// without a preset made by `defaults`. A job is claimed by creating `<output>.claim` before it is
+// rendered and skipped if its image is there already or someone else holds the claim, so machines
+// running the same queue over a shared directory split the jobs between them. Claims expire when
+// they aren't kept up, see `CLAIM_EXPIRY`.
+pub fn run(jobs: &[BatchJob], defaults: impl Fn() -> CameraBuilder) -> Vec<(BatchJob, JobOutcome)> {
+    jobs.iter()
+        .enumerate()
+        .map(|(index, job)| {
+            info!(
+                "job {} of {}: {} to {}",
+                index + 1,
+                jobs.len(),
+                job.scene,
+                job.output
+            );
+            let image = format!("{}.ppm", job.output);
+            let claim = format!("{}.claim", job.output);
+            if Path::new(&image).exists() || !try_claim(&claim) {
+                info!("{} is done or taken, skipping it", job.output);
+                return (job.clone(), JobOutcome::Skipped);
+            }
+            let rendered = thread::scope(|s| {
+                let (done, stop) = channel::<()>();
+                let claim = &claim;
+                s.spawn(move || {
+                    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(CLAIM_REFRESH) {
+                        if let Err(e) = fs::write(claim, claim_stamp()) {
+                            warn!("failed to renew the claim {}: {}", claim, e);
+                        }
+                    }
+                });
+                let rendered = render(job, &defaults);
+                drop(done);
+                rendered
+            });
+            let outcome = match rendered {
+                Ok(stats) => JobOutcome::Rendered(stats),
+                Err(e) => {
+                    warn!("{} failed: {}", job.output, e);
+                    JobOutcome::Failed(e)
+                }
+            };
+            // done jobs are skipped by their image from now on, failed ones are up for grabs again
+            let _ = fs::remove_file(&claim);
+            (job.clone(), outcome)
+        })
+        .collect()
+}
+
+fn try_claim(claim: &str) -> bool {
+    if let Some(directory) = Path::new(claim).parent() {
+        let _ = fs::create_dir_all(directory);
+    }
+    match create_stamped(claim) {
+        Ok(true) => return true,
+        Ok(false) => {}
+        Err(e) => {
+            warn!("failed to claim {}: {}", claim, e);
+            return false;
+        }
+    }
+    if let Some(age) = claim_age(claim) {
+        if age < CLAIM_EXPIRY {
+            return false;
+        }
+    }
+    // Expired claims are taken over under `<claim>.takeover`, one machine at a time, so that one
+    // doesn't remove the claim another has just made in place of the expired one.
+    let lock = format!("{}.takeover", claim);
+    match create_stamped(&lock) {
+        Ok(true) => {}
+        Ok(false) => {
+            // a takeover that died halfway leaves its lock, which is cleared for the next try
+            if claim_age(&lock).is_some_and(|age| age >= CLAIM_EXPIRY) {
+                let _ = fs::remove_file(&lock);
+            }
+            return false;
+        }
+        Err(e) => {
+            warn!("failed to lock {}: {}", lock, e);
+            return false;
+        }
+    }
+    // the claim may have been taken over by the time the lock was ours
+    let claimed = match claim_age(claim) {
+        Some(age) if age < CLAIM_EXPIRY => false,
+        age => {
+            if let Some(age) = age {
+                warn!(
+                    "taking over {}, which was last renewed {} s ago",
+                    claim,
+                    age.as_secs()
+                );
+                let _ = fs::remove_file(claim);
+            }
+            matches!(create_stamped(claim), Ok(true))
+        }
+    };
+    let _ = fs::remove_file(&lock);
+    claimed
+}
+
+// Creates `path` holding a `claim_stamp`, false if it exists already.
+fn create_stamped(path: &str) -> io::Result<bool> {
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(mut file) => {
+            if let Err(e) = file.write_all(claim_stamp().as_bytes()) {
+                warn!("failed to stamp {}: {}", path, e);
+            }
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+// The process holding the claim and when it last renewed it, in seconds since the Unix epoch.
+fn claim_stamp() -> String {
+    format!("pid {} at {}\n", std::process::id(), unix_time())
+}
+
+// How long ago the claim was stamped, from its stamp or from when the file was last written if
+// it has none. `None` if the claim is gone.
+fn claim_age(claim: &str) -> Option<Duration> {
+    let stamped = fs::read_to_string(claim)
+        .ok()?
+        .split_whitespace()
+        .last()
+        .and_then(|time| time.parse::<u64>().ok());
+    let stamped = match stamped {
+        Some(stamped) => stamped,
+        None => {
+            let modified = fs::metadata(claim).ok()?.modified().ok()?;
+            modified.duration_since(UNIX_EPOCH).ok()?.as_secs()
+        }
+    };
+    Some(Duration::from_secs(unix_time().saturating_sub(stamped)))
+}
+
+fn render(job: &BatchJob, defaults: &impl Fn() -> CameraBuilder) -> Result<RenderStats> {
+    let camera = match &job.preset {
+        Some(path) => CameraBuilder::from_config(&fs::read_to_string(path)?)?,
+        None => defaults(),
+    };
+    let scene = scene::from_name(&job.scene, camera.output(job.output.clone()))?;
+    scene.render_to_outputs(&RenderControl::default())
+}
+
+// A table of the jobs and how they went, with the totals at the end.
+pub fn summary(results: &[(BatchJob, JobOutcome)]) -> String {
+    let mut summary = String::new();
+    let (mut rendered, mut failed, mut seconds) = (0, 0, 0.0);
+    for (job, outcome) in results {
+        let status = match outcome {
+            JobOutcome::Rendered(stats) => {
+                rendered += 1;
+                seconds += stats.elapsed.as_secs_f64();
+                format!("rendered in {:.1} s", stats.elapsed.as_secs_f64())
+            }
+            JobOutcome::Skipped => "skipped".to_string(),
+            JobOutcome::Failed(e) => {
+                failed += 1;
+                format!("failed: {}", e)
+            }
+        };
+        writeln!(summary, "{:<24} {:<32} {}", job.scene, job.output, status).unwrap();
+    }
+    writeln!(
+        summary,
+        "{} rendered in {:.1} s, {} skipped, {} failed",
+        rendered,
+        seconds,
+        results.len() - rendered - failed,
+        failed
+    )
+    .unwrap();
+    return summary;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queues_parse_and_skip_claimed_jobs() {
+        let jobs = parse_queue(
+            "# overnight\n\
+             two_spheres renders/spheres\n\
+             \n\
+             book_cover renders/cover presets/closeup.cfg\n",
+        )
+        .unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[1].preset, Some(PathBuf::from("presets/closeup.cfg")));
+        assert!(parse_queue("two_spheres").is_err());
+
+        let directory =
+            std::env::temp_dir().join(format!("raytracer-batch-{}", std::process::id()));
+        let output = directory.join("claimed").to_string_lossy().into_owned();
+        assert!(try_claim(&format!("{}.claim", output)));
+        let job = BatchJob {
+            scene: "no_such_scene".to_string(),
+            output,
+            preset: None,
+        };
+        let results = run(std::slice::from_ref(&job), CameraBuilder::default);
+        assert!(matches!(results[0].1, JobOutcome::Skipped));
+        assert!(summary(&results).ends_with("0 rendered in 0.0 s, 1 skipped, 0 failed\n"));
+
+        // claims say who holds them, and ones left behind long ago are taken over
+        let claim = format!("{}.claim", job.output);
+        let stamp = fs::read_to_string(&claim).unwrap();
+        assert!(stamp.starts_with(&format!("pid {} at ", std::process::id())));
+        fs::write(&claim, format!("pid 1 at {}\n", unix_time() - 3600)).unwrap();
+        let results = run(&[job], CameraBuilder::default);
+        assert!(matches!(results[0].1, JobOutcome::Failed(_)));
+        assert!(!Path::new(&claim).exists());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn expired_claims_are_taken_over_once() {
+        let directory =
+            std::env::temp_dir().join(format!("raytracer-takeover-{}", std::process::id()));
+        let claim = directory.join("job.claim").to_string_lossy().into_owned();
+        fs::create_dir_all(&directory).unwrap();
+        for _ in 0..500 {
+            fs::write(&claim, format!("pid 1 at {}\n", unix_time() - 3600)).unwrap();
+            let barrier = std::sync::Barrier::new(16);
+            let claimed = thread::scope(|s| {
+                let takers = (0..16)
+                    .map(|_| {
+                        s.spawn(|| {
+                            barrier.wait();
+                            try_claim(&claim)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                takers
+                    .into_iter()
+                    .map(|taker| taker.join().unwrap())
+                    .filter(|&claimed| claimed)
+                    .count()
+            });
+            assert_eq!(claimed, 1);
+            assert!(!Path::new(&format!("{}.takeover", claim)).exists());
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+use std::fmt::Write;
+
+use tracing::info;
+// std's `Instant` panics in the browser, this one is std's everywhere else
+use web_time::Instant;
+
+use crate::{
+    error::Result,
+    network::RenderJob,
+    telemetry::{self, Counter},
+};
+
+// The built in scenes `raytracer bench` renders, covering spheres, instances, textures, volumes
+// and many lights. The scenes seed their own randomness and every tile seeds its samples by its
+// position, so the same commit always traces the same rays.
+pub const SCENES: [&str; 5] = [
+    "two_spheres",
+    "book_cover",
+    "linked_lights",
+    "campfire",
+    "something_blocky",
+];
+
+// The timings and counts of rendering one scene. The counts are zero without the `telemetry`
+// feature.
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+    pub scene: String,
+    pub build_seconds: f64,
+    pub bvh_build_seconds: f64,
+    pub render_seconds: f64,
+    pub rays: u64,
+    pub node_visits: u64,
+    pub primitive_tests: u64,
+}
+
+impl BenchResult {
+    pub fn mrays_per_second(&self) -> f64 {
+        self.rays as f64 / self.render_seconds / 1e6
+    }
+    // The result as one line of JSON, for comparing runs between commits with other tools.
+    pub fn to_json(&self) -> String {
+        let per_ray = |count: u64| count as f64 / self.rays.max(1) as f64;
+        let mut line = String::new();
+        write!(
+            line,
+            "{{\"scene\":\"{}\",\"build_seconds\":{:.6},\"bvh_build_seconds\":{:.6},\
+             \"render_seconds\":{:.6},\"rays\":{},\"mrays_per_second\":{:.3},\
+             \"node_visits\":{},\"primitive_tests\":{},\
+             \"node_visits_per_ray\":{:.3},\"primitive_tests_per_ray\":{:.3}}}",
+            self.scene,
+            self.build_seconds,
+            self.bvh_build_seconds,
+            self.render_seconds,
+            self.rays,
+            self.mrays_per_second(),
+            self.node_visits,
+            self.primitive_tests,
+            per_ray(self.node_visits),
+            per_ray(self.primitive_tests),
+        )
+        .unwrap();
+        return line;
+    }
+}
+
+// The settings every scene is benchmarked with, small enough that the whole set renders in
+// seconds.
+pub fn job(scene: &str) -> RenderJob {
+    RenderJob {
+        scene: scene.to_string(),
+        width: 320,
+        aspect_ratio: 16.0 / 9.0,
+        samples_per_pixel: 16,
+        max_ray_depth: 16,
+        defocus_angle: 0.0,
+    }
+}
+
+// Builds and renders the scene of `job` in memory, measuring each step. Resets the telemetry.
+pub fn run_job(job: &RenderJob) -> Result<BenchResult> {
+    telemetry::reset();
+    let start_time = Instant::now();
+    let scene = job.build_scene()?;
+    let build_seconds = start_time.elapsed().as_secs_f64();
+    let start_time = Instant::now();
+    scene.render_to_float_image();
+    let render_seconds = start_time.elapsed().as_secs_f64();
+    let result = BenchResult {
+        scene: job.scene.clone(),
+        build_seconds,
+        bvh_build_seconds: telemetry::stage("BVH build")
+            .map_or(0.0, |elapsed| elapsed.as_secs_f64()),
+        render_seconds,
+        rays: telemetry::total(Counter::CameraRays) + telemetry::total(Counter::ScatteredRays),
+        node_visits: telemetry::total(Counter::NodeVisits),
+        primitive_tests: telemetry::total(Counter::PrimitiveTests),
+    };
+    info!(
+        "{}: {:.3} s, {:.3} Mrays/s",
+        result.scene,
+        render_seconds,
+        result.mrays_per_second()
+    );
+    Ok(result)
+}
+
+// Benchmarks every scene in `SCENES`.
+pub fn run() -> Result<Vec<BenchResult>> {
+    SCENES.iter().map(|scene| run_job(&job(scene))).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_are_json_lines() {
+        let mut job = job("two_spheres");
+        job.width = 32;
+        job.samples_per_pixel = 1;
+        let result = run_job(&job).unwrap();
+        assert!(result.render_seconds > 0.0);
+        let line = result.to_json();
+        assert!(line.starts_with("{\"scene\":\"two_spheres\",\"build_seconds\":"));
+        assert!(line.contains("\"mrays_per_second\":"));
+        assert!(line.ends_with('}') && !line.contains('\n'));
+    }
+}
@@ -0,0 +1,260 @@
+use ::image::{ImageBuffer, Luma, Rgb, RgbImage};
+use tracing::debug;
+
+use super::Camera;
+use crate::{
+    color::Color,
+    error::Result,
+    float::{Float, INFINITY},
+    hittable::Hittable,
+    ray::Ray,
+    vec3::{Point3, Vec3},
+};
+
+// The layers of the image the preview can show. Beauty is the rendered image, the others are
+// arbitrary output variables (AOVs) of the first hit of a ray through the center of each pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Beauty,
+    Normal,
+    Depth,
+    Albedo,
+    SampleCount,
+    ObjectId,
+    MaterialId,
+    Motion,
+}
+
+// A tile of one layer the renderer hands to the preview, as the layer, the top left corner and
+// the size of the tile and its colors row by row.
+pub type TileUpdate = (Layer, (usize, usize), (usize, usize), Vec<Color>);
+
+impl Layer {
+    pub const ALL: [Layer; 8] = [
+        Layer::Beauty,
+        Layer::Normal,
+        Layer::Depth,
+        Layer::Albedo,
+        Layer::SampleCount,
+        Layer::ObjectId,
+        Layer::MaterialId,
+        Layer::Motion,
+    ];
+    pub const AOVS: [Layer; 7] = [
+        Layer::Normal,
+        Layer::Depth,
+        Layer::Albedo,
+        Layer::SampleCount,
+        Layer::ObjectId,
+        Layer::MaterialId,
+        Layer::Motion,
+    ];
+    pub fn name(&self) -> &'static str {
+        match self {
+            Layer::Beauty => "beauty",
+            Layer::Normal => "normals",
+            Layer::Depth => "depth",
+            Layer::Albedo => "albedo",
+            Layer::SampleCount => "sample count",
+            Layer::ObjectId => "object id",
+            Layer::MaterialId => "material id",
+            Layer::Motion => "motion",
+        }
+    }
+}
+
+impl Camera {
+    // The AOV layers of a tile in the order of `Layer::AOVS`. Normals are mapped into 0..1, depth is
+    // the camera space depth of the first hit, infinite where the ray escapes, and is stored in
+    // every channel along with the sample count. So are the object and material IDs, see
+    // `stable_id`, zero where the ray escapes, for selecting objects by their ID when compositing.
+    // Motion is how many pixels the first hit moves right and down over the shutter interval, for
+    // temporal denoisers and motion blur in post.
+    pub(crate) fn render_aovs(
+        &self,
+        top_left: (usize, usize),
+        rect: (usize, usize),
+        world: &Box<dyn Hittable>,
+    ) -> [Vec<Color>; 7] {
+        let mut layers = Layer::AOVS.map(|_| Vec::with_capacity(rect.0 * rect.1));
+        let sample_count = self.sample_count();
+        for j in 0..rect.0 {
+            for i in 0..rect.1 {
+                let pixel_center = self.pixel00_loc
+                    + ((top_left.1 + i) as Float * self.pixel_delta_u)
+                    + ((top_left.0 + j) as Float * self.pixel_delta_v);
+                let ray = Ray::new(self.center, pixel_center - self.center, 0.0);
+                let (normal, depth, albedo, object_id, material_id, motion) =
+                    match world.hit(&ray, &self.ray_interval()) {
+                        Some(hit_record) => {
+                            let material = self.material(&hit_record);
+                            (
+                                Color::from(0.5 * (hit_record.normal + Vec3::new(1.0, 1.0, 1.0))),
+                                -(hit_record.point - self.center).dot(&self.w),
+                                material.albedo(&hit_record),
+                                hit_record.object_id,
+                                material.id(),
+                                self.screen_motion(hit_record.point, hit_record.motion),
+                            )
+                        }
+                        None => (
+                            Color::black(),
+                            INFINITY,
+                            self.background(&ray),
+                            0,
+                            0,
+                            Color::black(),
+                        ),
+                    };
+                layers[0].push(normal);
+                layers[1].push(Color::gray(depth));
+                layers[2].push(albedo);
+                layers[3].push(sample_count);
+                layers[4].push(Color::gray(object_id as Float));
+                layers[5].push(Color::gray(material_id as Float));
+                layers[6].push(motion);
+            }
+        }
+        layers
+    }
+    // Where `point` shows up in the image in pixels, none if it is behind the camera.
+    fn screen_position(&self, point: Point3) -> Option<(Float, Float)> {
+        let offset = point - self.center;
+        let distance = -offset.dot(&self.w);
+        if distance <= 0.0 {
+            return None;
+        }
+        let on_viewport =
+            self.center + offset * (self.focus_distance / distance) - self.pixel00_loc;
+        Some((
+            on_viewport.dot(&self.pixel_delta_u) / self.pixel_delta_u.length_squared(),
+            on_viewport.dot(&self.pixel_delta_v) / self.pixel_delta_v.length_squared(),
+        ))
+    }
+    // The motion of `point` over the shutter interval in pixels, as red right and green down.
+    fn screen_motion(&self, point: Point3, motion: Vec3) -> Color {
+        match (
+            self.screen_position(point),
+            self.screen_position(point + motion),
+        ) {
+            (Some(start), Some(end)) => Color::new(end.0 - start.0, end.1 - start.1, 0.0),
+            _ => Color::black(),
+        }
+    }
+    // Writes the AOV layers of the whole image next to the output file, as `image-<layer>.exr`
+    // files holding the raw values. With a depth range set, the depth is also written to
+    // `image-depth.png` going from black at the near depth to white at the far depth.
+    pub(crate) fn write_aovs(&self, world: &Box<dyn Hittable>) -> Result<()> {
+        let layers = self.render_aovs((0, 0), (self.image_height, self.image_width), world);
+        for (layer, colors) in Layer::AOVS.into_iter().zip(&layers) {
+            let path = self.output_path(&format!("-{}.exr", layer.name().replace(' ', "-")));
+            // only the albedo is a color, the rest are written as they are
+            let image = match layer {
+                Layer::Albedo => self.to_float_image(colors),
+                _ => self.to_data_image(colors),
+            };
+            image.save(&path)?;
+            debug!("wrote {}", path);
+        }
+        if let Some((near, far)) = self.depth_range {
+            let depths = &layers[1];
+            let image =
+                ImageBuffer::from_fn(self.image_width as u32, self.image_height as u32, |x, y| {
+                    let depth = depths[y as usize * self.image_width + x as usize].r;
+                    let normalized = ((depth - near) / (far - near)).clamp(0.0, 1.0);
+                    Luma([(normalized * u16::MAX as Float).round() as u16])
+                });
+            let path = self.output_path("-depth.png");
+            image.save(&path)?;
+            debug!("wrote {}", path);
+        }
+        Ok(())
+    }
+    // The `Layer::SampleCount` value of the pixels this camera renders. Refined regions of the
+    // preview are rendered by a camera taking more samples, see `with_more_samples`.
+    pub(crate) fn sample_count(&self) -> Color {
+        Color::gray(self.pixel_sampler.samples_per_pixel() as Float)
+    }
+    // Writes `image-sample-counts.png` from the `Layer::SampleCount` layer of the whole image, as
+    // the heatmap the preview shows for it, relative to the most samples taken for any pixel.
+    pub(crate) fn write_sample_heatmap(&self, sample_counts: &[Color]) -> Result<()> {
+        let most = sample_counts
+            .iter()
+            .map(|count| count.r)
+            .fold(1.0, Float::max);
+        let image = RgbImage::from_fn(self.image_width as u32, self.image_height as u32, |x, y| {
+            let count = sample_counts[y as usize * self.image_width + x as usize];
+            let (r, g, b) = Color::heatmap(count.r / most).into_u8();
+            Rgb([r, g, b])
+        });
+        let path = self.output_path("-sample-counts.png");
+        image.save(&path)?;
+        debug!("wrote {}", path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        camera::test_camera,
+        hittable::{containers::HittableList, geometry::Sphere, materials::Lambertian},
+    };
+
+    #[test]
+    fn points_project_onto_their_pixels() {
+        let camera = test_camera(64, 2.0)
+            .lookfrom(Point3::new(1.0, 2.0, 3.0))
+            .lookat(Point3::new(0.0, 0.0, 0.0))
+            .build()
+            .unwrap();
+        let pixel = camera.pixel00_loc + 10.0 * camera.pixel_delta_u + 5.0 * camera.pixel_delta_v;
+        let point = camera.center + 3.0 * (pixel - camera.center);
+        let (x, y) = camera.screen_position(point).unwrap();
+        assert!((x - 10.0).abs() < 1e-3 && (y - 5.0).abs() < 1e-3);
+        assert!(camera
+            .screen_position(camera.center - (pixel - camera.center))
+            .is_none());
+    }
+
+    #[test]
+    fn depth_is_written_normalized_to_16_bits() {
+        let output = std::env::temp_dir()
+            .join(format!("raytracer-aovs-{}", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let camera = test_camera(8, 1.0)
+            .depth_range((0.25, 0.75))
+            .output(output.clone())
+            .build()
+            .unwrap();
+        let mut world = HittableList::default();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -1.0),
+            0.5,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        )));
+        let world: Box<dyn Hittable> = Box::new(world);
+        camera.write_aovs(&world).unwrap();
+
+        let path = format!("{}-depth.png", output);
+        let depth = ::image::open(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        for layer in Layer::AOVS {
+            let _ =
+                std::fs::remove_file(format!("{}-{}.exr", output, layer.name().replace(' ', "-")));
+        }
+        let depth = depth
+            .as_luma16()
+            .expect("the depth image has 16 bit samples");
+        // the front of the sphere is half a unit away, halfway through the range, and the rays
+        // that miss it are clamped to the far end
+        let center = depth.get_pixel(4, 4)[0] as Float / u16::MAX as Float;
+        assert!((0.5..0.55).contains(&center), "{}", center);
+        assert_eq!(depth.get_pixel(0, 0)[0], u16::MAX);
+    }
+}
@@ -0,0 +1,103 @@
+use ::image::GrayImage;
+
+use crate::{
+    error::{Error, Result},
+    float::Float,
+};
+
+// The shape of the aperture as a grayscale image, stretched over the square around the defocus
+// disk. Camera rays leave the lens from points picked in proportion to the brightness of the mask,
+// so out of focus highlights take the shape of the image, a hexagon for a six bladed iris or a
+// heart for a cut out lens cap. A white disk on black gives the same bokeh as no mask.
+#[derive(Debug, Clone)]
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    // the summed brightness of the rows above each row, and the total at the end
+    rows: Vec<Float>,
+    // the same for the pixels left of each pixel within its row, `width + 1` numbers a row
+    columns: Vec<Float>,
+}
+
+impl ApertureMask {
+    pub fn new(image: &GrayImage) -> Result<Self> {
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let mut rows = vec![0.0];
+        let mut columns = Vec::with_capacity((width + 1) * height);
+        for row in image.rows() {
+            let mut sum = 0.0;
+            columns.push(sum);
+            for pixel in row {
+                sum += pixel[0] as Float / 255.0;
+                columns.push(sum);
+            }
+            rows.push(rows.last().unwrap() + sum);
+        }
+        let total = *rows.last().unwrap();
+        if total.is_nan() || total <= 0.0 {
+            return Err(Error::InvalidCamera(
+                "the aperture mask needs to let some light through".to_string(),
+            ));
+        }
+        Ok(Self {
+            width,
+            height,
+            rows,
+            columns,
+        })
+    }
+    // A point on the aperture, from -1 to 1 across and from -1 at the bottom to 1 at the top, for
+    // uniformly distributed `u` and `v`. `u` picks the row and `v` the pixel within it, and where
+    // each lands within its pick places the point within the pixel, so evenly spread numbers give
+    // evenly spread points.
+    pub(super) fn sample(&self, u: Float, v: Float) -> (Float, Float) {
+        let (row, y) = pick(&self.rows, u);
+        let (column, x) = pick(&self.columns[row * (self.width + 1)..][..self.width + 1], v);
+        let x = (column as Float + x) / self.width as Float;
+        let y = (row as Float + y) / self.height as Float;
+        (2.0 * x - 1.0, 1.0 - 2.0 * y)
+    }
+}
+
+// The bin of a running sum that `u` from 0 to 1 falls in and how far into the bin it is, empty
+// bins are never picked.
+fn pick(cumulative: &[Float], u: Float) -> (usize, Float) {
+    let target = u * cumulative[cumulative.len() - 1];
+    let bin = (cumulative.partition_point(|&sum| sum <= target) - 1).min(cumulative.len() - 2);
+    let fraction = (target - cumulative[bin]) / (cumulative[bin + 1] - cumulative[bin]);
+    (bin, fraction.clamp(0.0, 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use ::image::Luma;
+
+    use super::*;
+    use crate::random::{RandomSource, Rng};
+
+    #[test]
+    fn samples_follow_the_mask() {
+        // a bright square in the top right corner and a dimmer one in the bottom left
+        let image = GrayImage::from_fn(4, 4, |x, y| match (x, y) {
+            (3, 0) => Luma([255]),
+            (0, 3) => Luma([85]),
+            _ => Luma([0]),
+        });
+        let mask = ApertureMask::new(&image).unwrap();
+        let mut rng = Rng::from_seed([5, 6]);
+        let samples = (0..4000)
+            .map(|_| mask.sample(rng.next_float(), rng.next_float()))
+            .collect::<Vec<_>>();
+        let top_right = samples
+            .iter()
+            .filter(|&&(x, y)| x >= 0.5 && y >= 0.5)
+            .count();
+        let bottom_left = samples
+            .iter()
+            .filter(|&&(x, y)| x <= -0.5 && y <= -0.5)
+            .count();
+        assert_eq!(top_right + bottom_left, samples.len());
+        assert!((top_right as Float / samples.len() as Float - 0.75).abs() < 0.03);
+        assert!(ApertureMask::new(&GrayImage::new(4, 4)).is_err());
+    }
+}
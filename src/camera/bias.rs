@@ -0,0 +1,80 @@
+use super::Camera;
+use crate::{float::Float, hittable::HitRecord, interval::Interval, ray::Ray};
+
+// The settings `ray_epsilon` and `shadow_bias` take when unset.
+pub(super) const DEFAULT_RAY_EPSILON: Float = 0.000001;
+pub(super) const DEFAULT_SHADOW_BIAS: Float = 1e-3;
+
+// Rays leaving a surface find it again right where they start if the rounding of the hit point
+// puts it a hair behind the surface, which speckles surfaces with shadow acne. How far the hit
+// point is off grows with its distance from the origin, so scenes far larger than a few units need
+// rays to stay clear of their surfaces for longer, and scenes far smaller need them to stay clear
+// for less, or the small details of them peter pan and float off their shadows. These are the
+// knobs for that, with defaults for scenes a few units across.
+impl Camera {
+    // The part of a ray that hits are looked for in, past `ray_epsilon` of its direction.
+    pub(super) fn ray_interval(&self) -> Interval {
+        Interval::new(self.ray_epsilon, Float::INFINITY)
+    }
+    // The part of a shadow ray from a point to a light, as long as the way between them, that
+    // hits shadow the point in. `shadow_bias` of the way is left clear at either end, so neither
+    // the surface nor the light shadows itself.
+    pub(super) fn shadow_interval(&self) -> Interval {
+        Interval::new(self.shadow_bias, 1.0 - self.shadow_bias)
+    }
+    // `ray` leaving the surface of `hit_record`, see `HitRecord::leave_surface`, moved
+    // `normal_offset` off it along the normal to the side the ray goes. The offset grows with the
+    // largest coordinate of the hit past one, like the rounding of the hit does.
+    pub(super) fn leave_surface(&self, hit_record: &HitRecord, ray: Ray) -> Ray {
+        let ray = hit_record.leave_surface(ray);
+        if self.normal_offset <= 0.0 {
+            return ray;
+        }
+        let point = hit_record.point;
+        let scale = point.x.abs().max(point.y.abs()).max(point.z.abs()).max(1.0);
+        let side = ray.direction.dot(&hit_record.normal).signum();
+        let origin = ray.origin + hit_record.normal * (side * self.normal_offset * scale);
+        Ray::new(origin, ray.direction, ray.time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        camera::test_camera,
+        color::Color,
+        hittable::{geometry::Sphere, materials::Lambertian, Hittable},
+        vec3::{Point3, Vec3},
+    };
+
+    #[test]
+    fn rays_leave_surfaces_on_their_side() {
+        let builder = || test_camera(8, 1.0);
+        // a big ball whose top is far from the origin
+        let sphere = Sphere::new(
+            Point3::new(0.0, -1000.0, 0.0),
+            1010.0,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        );
+        let down = Ray::new(Point3::new(0.0, 20.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let hit = sphere
+            .hit(&down, &Interval::new(0.0, Float::INFINITY))
+            .unwrap();
+        let bounce = |direction: Vec3| Ray::new(hit.point, direction, 0.0);
+
+        let camera = builder().normal_offset(1e-4).build().unwrap();
+        let up = camera.leave_surface(&hit, bounce(Vec3::new(1.0, 1.0, 0.0)));
+        assert!((up.origin.y - hit.point.y - 1e-3).abs() < 1e-6);
+        let into = camera.leave_surface(&hit, bounce(Vec3::new(1.0, -1.0, 0.0)));
+        assert!((into.origin.y - hit.point.y + 1e-3).abs() < 1e-6);
+        assert_eq!(into.direction.y, -1.0);
+        assert!(builder().normal_offset(-1.0).build().is_err());
+        assert!(builder().ray_epsilon(Float::INFINITY).build().is_err());
+        assert!(builder().normal_offset(Float::NAN).build().is_err());
+        assert!(builder().shadow_bias(0.5).build().is_err());
+        assert!(builder().shadow_bias(0.25).build().is_ok());
+    }
+}
@@ -0,0 +1,105 @@
+use crate::{color::Color, float::Float};
+
+// The glow around bright parts of the image, from light scattering in the lens and the eye. The
+// parts of pixels brighter than `threshold` are blurred with a gaussian of `radius` pixels and
+// `strength` of that is added back to the image. It works on the linear image, so only light
+// brighter than white, like emitters and their highlights, blooms with a threshold of 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Bloom {
+    pub threshold: Float,
+    pub radius: Float,
+    pub strength: Float,
+}
+
+impl Bloom {
+    pub fn new(threshold: Float, radius: Float, strength: Float) -> Self {
+        Self {
+            threshold,
+            radius,
+            strength,
+        }
+    }
+    pub fn bloomed(&self, image_buffer: &[Color], width: usize, height: usize) -> Vec<Color> {
+        let bright = image_buffer
+            .iter()
+            .map(|color| {
+                let luminance = color.luminance();
+                if luminance <= self.threshold {
+                    Color::black()
+                } else {
+                    *color * ((luminance - self.threshold) / luminance)
+                }
+            })
+            .collect::<Vec<_>>();
+        let kernel = self.kernel();
+        let horizontal = blur(&bright, &kernel, width, height, 1, width);
+        let glow = blur(&horizontal, &kernel, height, width, width, 1);
+        image_buffer
+            .iter()
+            .zip(glow)
+            .map(|(color, glow)| *color + self.strength * glow)
+            .collect()
+    }
+    // A normalized gaussian reaching three standard deviations out, with `radius` as one of them.
+    fn kernel(&self) -> Vec<Float> {
+        let sigma = self.radius.max(0.5);
+        let reach = (3.0 * sigma).ceil() as isize;
+        let weights = (-reach..=reach)
+            .map(|x| (-(x * x) as Float / (2.0 * sigma * sigma)).exp())
+            .collect::<Vec<_>>();
+        let total = weights.iter().sum::<Float>();
+        weights.into_iter().map(|weight| weight / total).collect()
+    }
+}
+
+// Blurs every line of `length` pixels, `step` apart, of `lines` lines that start `stride` apart.
+// Pixels past the ends are taken to be black, so light isn't added at the edges.
+fn blur(
+    pixels: &[Color],
+    kernel: &[Float],
+    length: usize,
+    lines: usize,
+    step: usize,
+    stride: usize,
+) -> Vec<Color> {
+    let reach = (kernel.len() / 2) as isize;
+    let mut result = vec![Color::black(); pixels.len()];
+    for line in 0..lines {
+        for i in 0..length as isize {
+            let mut sum = Color::black();
+            for (k, weight) in kernel.iter().enumerate() {
+                let j = i + k as isize - reach;
+                if (0..length as isize).contains(&j) {
+                    sum += *weight * pixels[line * stride + j as usize * step];
+                }
+            }
+            result[line * stride + i as usize * step] = sum;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bright_pixels_bleed_into_their_neighbours() {
+        let (width, height) = (21, 15);
+        let mut image = vec![Color::gray(0.5); width * height];
+        let center = 7 * width + 10;
+        image[center] = Color::gray(11.0);
+        let bloomed = Bloom::new(1.0, 2.0, 1.0).bloomed(&image, width, height);
+        // what is below the threshold stays where it is, the rest spreads out evenly
+        let added = bloomed
+            .iter()
+            .zip(&image)
+            .map(|(after, before)| (*after - *before).r)
+            .sum::<Float>();
+        assert!((added - 10.0).abs() < 0.1);
+        assert!(bloomed[center - 1].r > 0.5 && bloomed[center - 1].r < bloomed[center].r);
+        assert!((bloomed[center - 1].r - bloomed[center + width].r).abs() < 1e-6);
+        assert_eq!(bloomed[0].r, 0.5);
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use ::image::{codecs::hdr::HdrEncoder, Rgb, Rgb32FImage};
+use tracing::{debug, warn};
+
+use super::Camera;
+use crate::{
+    color::Color,
+    error::{Error, Result},
+    float::{self, Float},
+};
+
+const MAGIC: &[u8; 4] = b"RBUF";
+const VERSION: u32 = 1;
+
+// The linear colors of a render ahead of any post processing, with the samples every pixel is the
+// average of. Renders of the same scene with different seeds are independent estimates of the same
+// image, so their buffers merge into one as good as a single render with all their samples. The
+// colors are stored as 64 bit floats so merging loses nothing.
+#[derive(Debug, Clone)]
+pub struct RenderBuffer {
+    pub width: usize,
+    pub height: usize,
+    pub samples: u64,
+    pub colors: Vec<Color>,
+}
+
+impl RenderBuffer {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a render buffer");
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC || read_u64(&mut reader)? != VERSION as u64 {
+            return Err(invalid().into());
+        }
+        let width = read_u64(&mut reader)? as usize;
+        let height = read_u64(&mut reader)? as usize;
+        let samples = read_u64(&mut reader)?;
+        let colors = (0..width * height)
+            .map(|_| {
+                let mut channel = || read_f64(&mut reader).map(|value| value as Float);
+                Ok(Color::new(channel()?, channel()?, channel()?))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self {
+            width,
+            height,
+            samples,
+            colors,
+        })
+    }
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        for value in [
+            VERSION as u64,
+            self.width as u64,
+            self.height as u64,
+            self.samples,
+        ] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        for color in &self.colors {
+            for channel in [color.r, color.g, color.b] {
+                writer.write_all(&float::to_f64(channel).to_le_bytes())?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+    // The average of the buffers weighted by their samples, with the samples of all of them.
+    pub fn merge(buffers: &[RenderBuffer]) -> Result<Self> {
+        let Some(first) = buffers.first() else {
+            return Err(Error::IncompatibleBuffers("nothing to merge".to_string()));
+        };
+        if let Some(other) = buffers
+            .iter()
+            .find(|other| (other.width, other.height) != (first.width, first.height))
+        {
+            return Err(Error::IncompatibleBuffers(format!(
+                "{}x{} and {}x{} images",
+                first.width, first.height, other.width, other.height
+            )));
+        }
+        let samples = buffers.iter().map(|buffer| buffer.samples).sum::<u64>();
+        let mut colors = vec![Color::black(); first.colors.len()];
+        for buffer in buffers {
+            let weight = buffer.samples as Float / samples.max(1) as Float;
+            for (sum, color) in colors.iter_mut().zip(&buffer.colors) {
+                *sum += *color * weight;
+            }
+        }
+        Ok(Self {
+            width: first.width,
+            height: first.height,
+            samples,
+            colors,
+        })
+    }
+    pub fn to_float_image(&self) -> Rgb32FImage {
+        Rgb32FImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let color = self.colors[y as usize * self.width + x as usize];
+            Rgb([color.r as f32, color.g as f32, color.b as f32])
+        })
+    }
+    pub fn write_hdr<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        write_hdr(path, self.width, self.height, &self.colors)
+    }
+}
+
+// Writes linear colors as a Radiance `.hdr` file, which stores them with a shared exponent per
+// pixel in four bytes. It keeps the whole range of the light and opens in most software, so renders
+// can light other scenes as environment maps.
+pub fn write_hdr<P: AsRef<Path>>(
+    path: P,
+    width: usize,
+    height: usize,
+    colors: &[Color],
+) -> Result<()> {
+    let pixels = colors
+        .iter()
+        .map(|color| Rgb([color.r as f32, color.g as f32, color.b as f32]))
+        .collect::<Vec<_>>();
+    let writer = BufWriter::new(File::create(path)?);
+    HdrEncoder::new(writer).encode(&pixels, width, height)?;
+    Ok(())
+}
+
+impl Camera {
+    // Writes `image.buffer`, named after the output file, for merging with other renders. Cancelled
+    // renders have black holes in them that would darken the merge, so they are left out.
+    pub(super) fn write_render_buffer(
+        &self,
+        image_buffer: &[Color],
+        cancelled: bool,
+    ) -> Result<()> {
+        if cancelled {
+            warn!("the render was cancelled, not writing a render buffer");
+            return Ok(());
+        }
+        let buffer = RenderBuffer {
+            width: self.image_width,
+            height: self.image_height,
+            samples: self.pixel_sampler.samples_per_pixel() as u64,
+            colors: image_buffer.to_vec(),
+        };
+        let path = self.output_path(".buffer");
+        buffer.write(&path)?;
+        debug!("wrote {}", path);
+        Ok(())
+    }
+    // Writes `image.hdr`, the linear image in the float primaries, ahead of the white balance and
+    // post processing.
+    pub(super) fn write_hdr_output(&self, image_buffer: &[Color]) -> Result<()> {
+        let path = self.output_path(".hdr");
+        let colors = self.in_float_primaries(image_buffer);
+        write_hdr(&path, self.image_width, self.image_height, &colors)?;
+        debug!("wrote {}", path);
+        Ok(())
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f64(reader: &mut impl Read) -> io::Result<f64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{color_space::Primaries, test_camera};
+    use ::image::codecs::hdr::HdrDecoder;
+
+    #[test]
+    fn merges_weighted_by_samples() {
+        let buffer = |samples, value| RenderBuffer {
+            width: 2,
+            height: 1,
+            samples,
+            colors: vec![Color::gray(value), Color::new(value, 0.0, 1.0)],
+        };
+        let merged = RenderBuffer::merge(&[buffer(16, 1.0), buffer(48, 0.2)]).unwrap();
+        assert_eq!(merged.samples, 64);
+        assert!((merged.colors[0].g - 0.4).abs() < 1e-6);
+        assert!((merged.colors[1].b - 1.0).abs() < 1e-6);
+
+        let path = std::env::temp_dir().join(format!("raytracer-{}.buffer", std::process::id()));
+        merged.write(&path).unwrap();
+        let read = RenderBuffer::read(&path).unwrap();
+        assert_eq!((read.width, read.height, read.samples), (2, 1, 64));
+        for (read, merged) in read.colors.iter().zip(&merged.colors) {
+            assert_eq!([read.r, read.g, read.b], [merged.r, merged.g, merged.b]);
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let mut taller = buffer(16, 1.0);
+        taller.height = 2;
+        assert!(RenderBuffer::merge(&[merged, taller]).is_err());
+    }
+
+    #[test]
+    fn writes_radiance_hdr() {
+        let colors = vec![
+            Color::new(0.5, 2.0, 40.0),
+            Color::black(),
+            Color::gray(1e-3),
+        ];
+        let buffer = RenderBuffer {
+            width: 3,
+            height: 1,
+            samples: 1,
+            colors: colors.clone(),
+        };
+        let path = std::env::temp_dir().join(format!("raytracer-{}.hdr", std::process::id()));
+        buffer.write_hdr(&path).unwrap();
+        let decoder = HdrDecoder::new(BufReader::new(File::open(&path).unwrap())).unwrap();
+        assert_eq!(
+            (decoder.metadata().width, decoder.metadata().height),
+            (3, 1)
+        );
+        let read = decoder.read_image_hdr().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // the shared exponent keeps about two digits of the brightest channel
+        for (read, color) in read.iter().zip(&colors) {
+            for (read, channel) in read.0.iter().zip([color.r, color.g, color.b]) {
+                assert!((*read as Float - channel).abs() <= 0.01 * color.r.max(color.b));
+            }
+        }
+    }
+
+    #[test]
+    fn float_outputs_are_in_the_float_primaries() {
+        let output = std::env::temp_dir()
+            .join(format!("raytracer-primaries-{}", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let camera = test_camera(4, 1.0)
+            .float_primaries(Primaries::Rec2020)
+            .output(output.clone())
+            .build()
+            .unwrap();
+        let red = Color::new(1.0, 0.0, 0.0);
+        camera.write_hdr_output(&[red; 16]).unwrap();
+        let path = format!("{}.hdr", output);
+        let decoder = HdrDecoder::new(BufReader::new(File::open(&path).unwrap())).unwrap();
+        let read = decoder.read_image_hdr().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let expected = Primaries::Rec2020.convert(red);
+        let [r, g, b] = read[0].0;
+        assert!((r as Float - expected.r).abs() < 0.01 && (g as Float - expected.g).abs() < 0.01);
+        assert!((b as Float - expected.b).abs() < 0.01 && g > 0.0);
+    }
+}
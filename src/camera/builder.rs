@@ -1,23 +1,148 @@
+use std::sync::{Arc, Mutex, OnceLock};
+
+use super::aperture::ApertureMask;
+use super::bias::{DEFAULT_RAY_EPSILON, DEFAULT_SHADOW_BIAS};
+use super::bvh_view::BvhView;
+use super::color_space::{ColorSpace, Primaries, WHITE_BALANCE_RANGE};
+use super::environment::{Environment, EnvironmentMap};
+use super::fog::Fog;
+use super::image::ImageSpec;
+use super::post::PostStage;
+use super::probe::ReflectionProbe;
+use super::render_layers::RenderLayer;
+use super::shutter::{Shutter, ShutterCurve};
+use super::sky::{Sky, SkyModel};
+use super::tiff::TiffLayout;
+use super::tiles::TileOrder;
 use super::Camera;
 use super::PixelSampler;
-use super::image::ImageSpec;
+use crate::color::Color;
+use crate::error::{Error, Result};
+use crate::float::Float;
+use crate::hittable::containers::Accelerator;
+use crate::hittable::materials::{Lambertian, Material};
+use crate::interval::Interval;
+use crate::units::Units;
 use crate::vec3::Point3;
 use crate::vec3::Vec3;
 
-#[derive(Default)]
+#[derive(Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CameraBuilder {
+    #[cfg_attr(feature = "serde", serde(with = "image_spec_serde"))]
     pub image_spec: Option<ImageSpec>,
 
     pub pixel_sampler: Option<PixelSampler>,
     pub max_ray_depth: Option<usize>,
+    pub packet_tracing: Option<bool>,
+    pub tile_order: Option<TileOrder>,
+    pub aovs: Option<bool>,
+    // The color of rays that escape the scene, the environment, the sky or else the sky gradient
+    // when unset.
+    pub background: Option<Color>,
+    // Renders every object in a neutral gray diffuse material, to judge composition and lighting
+    // without the materials.
+    pub clay: Option<bool>,
+    // The depths written as black and white to a normalized depth image next to the AOVs.
+    pub depth_range: Option<(Float, Float)>,
+    // Writes how long each part of the image took to render as a heatmap next to the output.
+    pub tile_heatmap: Option<bool>,
+    // Writes the samples taken per pixel as a heatmap next to the output, which shows where the
+    // preview refined the image. It is written after every request of the preview, a render on its
+    // own takes as many samples everywhere and writes none.
+    pub sample_heatmap: Option<bool>,
+    // Writes the boxes of the BVH next to the output, to check how well the tree fits the scene.
+    pub bvh_view: Option<BvhView>,
+    // What the scenes gather their objects into for tracing, a BVH when unset.
+    pub accelerator: Option<Accelerator>,
+    // Groups of objects rendered into buffers of their own next to the image, see `RenderLayer`.
+    pub render_layers: Option<Vec<RenderLayer>>,
+    // How camera ray times are spread over the shutter interval, evenly when unset.
+    pub shutter_curve: Option<ShutterCurve>,
+    // The part of the shutter interval the rows of a rolling shutter are read out over, from the
+    // top of the image to the bottom. Unset or zero exposes every row at once.
+    pub rolling_shutter: Option<Float>,
+    // The scene time, in seconds, the shutter opens and closes at, which animated objects are
+    // placed in. From 0 to 1 when unset, see `FrameSequence` for rendering the frames of an
+    // animation.
+    pub shutter_interval: Option<(Float, Float)>,
+    // The lateral and longitudinal chromatic aberration of the lens, as the fraction red and blue
+    // are magnified and focused differently from green by.
+    pub chromatic_aberration: Option<(Float, Float)>,
+    // Darkens the image towards the corners with the natural cos⁴ falloff of light.
+    pub vignetting: Option<bool>,
+    // The angle off the optical axis, in degrees, past which the lens barrel starts to block the
+    // aperture.
+    pub mechanical_vignetting: Option<Float>,
+    // The post processing applied to the image before it is written out and shown, see
+    // `PostStage`.
+    pub post_process: Option<Vec<PostStage>>,
+    // The color space the 8 bit output is encoded in, a 2.2 gamma when unset.
+    pub color_space: Option<ColorSpace>,
+    // The primaries the float outputs are written in, those of Rec. 709 when unset.
+    pub float_primaries: Option<Primaries>,
+    // The color temperature of the light, in kelvin, that should come out white.
+    pub white_balance: Option<Float>,
+    // Exposures, in stops, the image is also written at next to the output file, like `-2 0 2`.
+    pub exposure_bracket: Option<Vec<Float>>,
+    // The path of a grayscale image giving the shape of the aperture, see `ApertureMask`.
+    pub aperture_mask: Option<String>,
+    // Light from all around the scene for the rays that escape it, from an image or a studio, in
+    // place of the sky, see `Environment`.
+    pub environment: Option<Environment>,
+    // Daylight for a date, time and place in place of the sky gradient, see `Sky`.
+    pub sky: Option<Sky>,
+    // Fog over the whole scene that fades distant objects into its color, see `Fog`.
+    pub fog: Option<Fog>,
+    // Steers the bounces of diffuse surfaces towards where the light comes from, as learned by a
+    // few passes of paths traced before the render. Slower per sample, but much less noisy in
+    // scenes lit mostly indirectly.
+    pub path_guiding: Option<bool>,
+    // Samples the lights directly at diffuse bounces, keeping one of this many candidate points on
+    // them for a shadow ray, see `restir`. Off when unset.
+    pub light_candidates: Option<usize>,
+    // Draws half the directions of diffuse bounces towards the lights, so paths find small lights
+    // without the shadow rays of `light_candidates`, which takes its place when set. With
+    // `path_guiding` the guide steers the bounces instead. Off when unset.
+    pub light_mixture: Option<bool>,
+    // How far along its direction a ray has to go before it can hit anything, see `bias`.
+    // 0.000001 when unset.
+    pub ray_epsilon: Option<Float>,
+    // How far rays leaving a surface start off it, times the largest coordinate of the hit past
+    // one, see `bias`. Zero when unset.
+    pub normal_offset: Option<Float>,
+    // The fraction of shadow rays left clear at either end, see `bias`. 0.001 when unset.
+    pub shadow_bias: Option<Float>,
+    // The units the scene is modelled in. The focus distance, the distance between the eyes of
+    // stereo and the fog are given in meters and scaled into them, see `Units`. Meters when unset.
+    pub units: Option<Units>,
+    // Picks the random streams of the pixels, renders with different seeds are independent
+    // and can be merged, see `RenderBuffer`. Zero when unset.
+    pub seed: Option<u64>,
+    // Also writes the linear image with its sample count to `image.buffer`, see `RenderBuffer`.
+    pub save_buffer: Option<bool>,
+    // Also writes the linear image to `image.hdr` in Radiance's RGBE format, to light other scenes
+    // with.
+    pub hdr_output: Option<bool>,
+    // Also writes the linear image to `image.tif` as 32 bit floats, in strips or tiles.
+    pub tiff_layout: Option<TiffLayout>,
+    // The path the image is written to without its extension, `image` when unset. The other files
+    // of a render are named after it, like `image-depth.png`.
+    pub output: Option<String>,
+    // Renders a 360° stereo panorama for VR with the eyes this far apart, see `stereo_ray`. The
+    // image holds both eyes, so it must be as tall as it is wide.
+    pub omnidirectional_stereo: Option<Float>,
+    // Renders a reflection probe around `lookfrom` instead, see `ReflectionProbe`. The image must be
+    // as many times wider than tall as the layout of the probe has faces across.
+    pub reflection_probe: Option<ReflectionProbe>,
 
-    pub field_of_view: Option<f64>,
+    pub field_of_view: Option<Float>,
     pub lookfrom: Option<Point3>,
     pub lookat: Option<Point3>,
     pub up_vector: Option<Vec3>,
 
-    pub defocus_angle: Option<f64>,
-    pub focus_distance: Option<f64>,
+    pub defocus_angle: Option<Float>,
+    pub focus_distance: Option<Float>,
 }
 
 macro_rules! builder_field {
@@ -34,12 +159,52 @@ macro_rules! builder_field {
 impl CameraBuilder {
     builder_field! {image_spec, ImageSpec}
     builder_field! {max_ray_depth, usize}
-    builder_field! {field_of_view, f64}
+    builder_field! {packet_tracing, bool}
+    builder_field! {tile_order, TileOrder}
+    builder_field! {aovs, bool}
+    builder_field! {background, Color}
+    builder_field! {clay, bool}
+    builder_field! {depth_range, (Float, Float)}
+    builder_field! {tile_heatmap, bool}
+    builder_field! {sample_heatmap, bool}
+    builder_field! {bvh_view, BvhView}
+    builder_field! {accelerator, Accelerator}
+    builder_field! {render_layers, Vec<RenderLayer>}
+    builder_field! {shutter_curve, ShutterCurve}
+    builder_field! {rolling_shutter, Float}
+    builder_field! {shutter_interval, (Float, Float)}
+    builder_field! {chromatic_aberration, (Float, Float)}
+    builder_field! {vignetting, bool}
+    builder_field! {mechanical_vignetting, Float}
+    builder_field! {post_process, Vec<PostStage>}
+    builder_field! {color_space, ColorSpace}
+    builder_field! {float_primaries, Primaries}
+    builder_field! {white_balance, Float}
+    builder_field! {exposure_bracket, Vec<Float>}
+    builder_field! {aperture_mask, String}
+    builder_field! {environment, Environment}
+    builder_field! {sky, Sky}
+    builder_field! {fog, Fog}
+    builder_field! {path_guiding, bool}
+    builder_field! {light_candidates, usize}
+    builder_field! {light_mixture, bool}
+    builder_field! {ray_epsilon, Float}
+    builder_field! {normal_offset, Float}
+    builder_field! {shadow_bias, Float}
+    builder_field! {units, Units}
+    builder_field! {seed, u64}
+    builder_field! {save_buffer, bool}
+    builder_field! {hdr_output, bool}
+    builder_field! {tiff_layout, TiffLayout}
+    builder_field! {output, String}
+    builder_field! {omnidirectional_stereo, Float}
+    builder_field! {reflection_probe, ReflectionProbe}
+    builder_field! {field_of_view, Float}
     builder_field! {lookfrom, Point3}
     builder_field! {lookat, Point3}
     builder_field! {up_vector, Vec3}
-    builder_field! {defocus_angle, f64}
-    builder_field! {focus_distance, f64}
+    builder_field! {defocus_angle, Float}
+    builder_field! {focus_distance, Float}
     pub fn uniform_sampler(self, samples_per_pixel: usize) -> Self {
         Self {
             pixel_sampler: Some(PixelSampler::Uniform(samples_per_pixel)),
@@ -52,24 +217,158 @@ impl CameraBuilder {
             ..self
         }
     }
-    pub fn build(self) -> Camera {
+    pub fn padded_sampler(self, samples_per_pixel: usize) -> Self {
+        Self {
+            pixel_sampler: Some(PixelSampler::Padded(samples_per_pixel)),
+            ..self
+        }
+    }
+    pub fn sobol_sampler(self, samples_per_pixel: usize) -> Self {
+        Self {
+            pixel_sampler: Some(PixelSampler::Sobol(samples_per_pixel)),
+            ..self
+        }
+    }
+    pub fn build(self) -> Result<Camera> {
+        let missing = |setting: &str| Error::InvalidCamera(format!("the {} must be set", setting));
         let image_spec = self
             .image_spec
-            .expect("The image specifications must be set");
+            .ok_or_else(|| missing("image specifications"))?;
         let pixel_sampler = match self
             .pixel_sampler
-            .expect("The samples per pixel must be set")
+            .ok_or_else(|| missing("samples per pixel"))?
         {
             PixelSampler::Uniform(samples_per_pixel) => {
-                let samples_sqrt = (samples_per_pixel as f64).sqrt();
+                let samples_sqrt = (samples_per_pixel as Float).sqrt();
                 if samples_sqrt.fract() != 0.0 {
-                    panic!("samples_per_pixel in the grid sampler must be a square number, current value: {}", samples_per_pixel);
+                    return Err(Error::InvalidCamera(format!(
+                        "samples_per_pixel in the grid sampler must be a square number, current value: {}",
+                        samples_per_pixel
+                    )));
                 }
                 PixelSampler::Uniform(samples_sqrt as usize)
             }
-            PixelSampler::Random(samples_per_pixel) => PixelSampler::Random(samples_per_pixel),
+            sampler => sampler,
         };
-        let depth = self.max_ray_depth.expect("The depth must be set");
+        let depth = self.max_ray_depth.ok_or_else(|| missing("depth"))?;
+        let packet_tracing = self.packet_tracing.unwrap_or(false);
+        let tile_order = self.tile_order.unwrap_or_default();
+        let aovs = self.aovs.unwrap_or(false);
+        let background = self.background;
+        let clay = self
+            .clay
+            .unwrap_or(false)
+            .then(|| Arc::new(Lambertian::from(Color::gray(0.5))) as Arc<dyn Material>);
+        let depth_range = self.depth_range;
+        let tile_heatmap = self.tile_heatmap.unwrap_or(false);
+        let sample_heatmap = self.sample_heatmap.unwrap_or(false);
+        let bvh_view = self.bvh_view;
+        let accelerator = self.accelerator.unwrap_or_default();
+        let render_layers = self.render_layers.unwrap_or_default();
+        let shutter = Shutter::new(
+            self.shutter_curve.unwrap_or_default(),
+            self.rolling_shutter.unwrap_or(0.0),
+        )?;
+        let (open, close) = self.shutter_interval.unwrap_or((0.0, 1.0));
+        if !(open.is_finite() && close.is_finite() && open <= close) {
+            return Err(Error::InvalidCamera(format!(
+                "the shutter interval must be finite and close after it opens, current value: {} {}",
+                open, close
+            )));
+        }
+        let shutter_interval = Interval::new(open, close);
+        if let Some(angle) = self.mechanical_vignetting {
+            if !(angle > 0.0 && angle < 90.0) {
+                return Err(Error::InvalidCamera(format!(
+                    "the mechanical vignetting must start between 0 and 90 degrees off the axis, current value: {}",
+                    angle
+                )));
+            }
+        }
+        if let Some(temperature) = self.white_balance {
+            let (min, max) = WHITE_BALANCE_RANGE;
+            if !(min..=max).contains(&temperature) {
+                return Err(Error::InvalidCamera(format!(
+                    "the white balance must be from {} to {} kelvin, current value: {}",
+                    min, max, temperature
+                )));
+            }
+        }
+        if let Some(fog) = self.fog {
+            if !(fog.density > 0.0 && fog.height_falloff >= 0.0) {
+                return Err(Error::InvalidCamera(format!(
+                    "fog needs a positive density and a falloff that isn't negative, current value: {:?}",
+                    fog
+                )));
+            }
+        }
+        if self.light_candidates == Some(0) {
+            return Err(Error::InvalidCamera(
+                "direct lighting needs at least one light candidate".to_string(),
+            ));
+        }
+        let ray_epsilon = self.ray_epsilon.unwrap_or(DEFAULT_RAY_EPSILON);
+        let normal_offset = self.normal_offset.unwrap_or(0.0);
+        let shadow_bias = self.shadow_bias.unwrap_or(DEFAULT_SHADOW_BIAS);
+        let finite = |value: Float| value >= 0.0 && value.is_finite();
+        if !(finite(ray_epsilon) && finite(normal_offset) && (0.0..0.5).contains(&shadow_bias)) {
+            return Err(Error::InvalidCamera(format!(
+                "the ray epsilon and normal offset must be finite and not negative and the shadow bias must be from 0 to under 0.5, current values: {}, {} and {}",
+                ray_epsilon, normal_offset, shadow_bias
+            )));
+        }
+        let aperture_mask = match self.aperture_mask {
+            Some(path) => {
+                let mask = ApertureMask::new(&::image::open(&path)?.to_luma8())?;
+                Some((path, mask))
+            }
+            None => None,
+        };
+        for (index, layer) in render_layers.iter().enumerate() {
+            let taken = layer.name == "background"
+                || render_layers[..index]
+                    .iter()
+                    .any(|other| other.name == layer.name);
+            // the names go into file names, which path separators would lead out of the directory
+            let unsafe_name = layer
+                .name
+                .contains(|c: char| c.is_whitespace() || c == '/' || c == '\\');
+            if layer.name.is_empty() || unsafe_name || taken {
+                return Err(Error::InvalidCamera(format!(
+                    "render layers need unique names without spaces or path separators other than background, current value: {:?}",
+                    layer.name
+                )));
+            }
+        }
+        if let Some(interpupillary_distance) = self.omnidirectional_stereo {
+            if image_spec.width != image_spec.height || interpupillary_distance < 0.0 {
+                return Err(Error::InvalidCamera(format!(
+                    "omnidirectional stereo needs a square image and a distance between the eyes that isn't negative, current values: {}x{} and {}",
+                    image_spec.width, image_spec.height, interpupillary_distance
+                )));
+            }
+        }
+        if let Some(probe) = self.reflection_probe {
+            if self.omnidirectional_stereo.is_some()
+                || image_spec.width != probe.aspect_ratio() * image_spec.height
+            {
+                return Err(Error::InvalidCamera(format!(
+                    "a {} reflection probe needs an image {} times as wide as it is tall and no stereo, current size: {}x{}",
+                    probe,
+                    probe.aspect_ratio(),
+                    image_spec.width,
+                    image_spec.height
+                )));
+            }
+        }
+        if let Some((near, far)) = depth_range {
+            if near.is_nan() || far.is_nan() || near >= far {
+                return Err(Error::InvalidCamera(format!(
+                    "the near depth must be less than the far depth, current values: {} and {}",
+                    near, far
+                )));
+            }
+        }
 
         let field_of_view = self.field_of_view.unwrap_or(90.0);
         let lookfrom = self.lookfrom.unwrap_or(Point3::new(0., 0., 0.));
@@ -77,7 +376,14 @@ impl CameraBuilder {
         let up_vector = self.up_vector.unwrap_or(Vec3::new(0., 1., 0.));
 
         let defocus_angle = self.defocus_angle.unwrap_or(0.0);
-        let focus_distance = self.focus_distance.unwrap_or(lookfrom.distance(&lookat));
+        // the focus distance is in meters only when it is given, the distance to `lookat` is in
+        // scene units already, and the aperture follows it as its angle stays the same
+        let units = self.units.unwrap_or_default();
+        let focus_distance = self
+            .focus_distance
+            .map_or(lookfrom.distance(&lookat), |distance| {
+                units.from_meters(distance)
+            });
 
         // Actual initialization
 
@@ -87,7 +393,8 @@ impl CameraBuilder {
         let theta = field_of_view.to_radians();
         let h = (theta / 2.0).tan();
         let viewport_height = 2.0 * h * focus_distance;
-        let viewport_width = viewport_height * image_spec.width as f64 / image_spec.height as f64;
+        let viewport_width =
+            viewport_height * image_spec.width as Float / image_spec.height as Float;
 
         let w = (lookfrom - lookat).unit_vector();
         let u = up_vector.cross(&w).unit_vector();
@@ -96,8 +403,8 @@ impl CameraBuilder {
         let viewport_u = viewport_width * u;
         let viewport_v = viewport_height * -v;
 
-        let pixel_delta_u = viewport_u / image_spec.width as f64;
-        let pixel_delta_v = viewport_v / image_spec.height as f64;
+        let pixel_delta_u = viewport_u / image_spec.width as Float;
+        let pixel_delta_v = viewport_v / image_spec.height as Float;
 
         let viewport_upper_left = center - (focus_distance * w) - viewport_u / 2. - viewport_v / 2.;
         let pixel00_loc = viewport_upper_left + 0.5 * (pixel_delta_u + pixel_delta_v);
@@ -105,11 +412,62 @@ impl CameraBuilder {
         let defocus_radius = focus_distance * (defocus_angle / 2.0).to_radians().tan();
         let defocus_disk_u = defocus_radius * u;
         let defocus_disk_v = defocus_radius * v;
-        Camera {
-            aspect_ratio: image_spec.aspect_ratio,
+        Ok(Camera {
+            image_spec: image_spec.clone(),
+            aspect_ratio: image_spec.aspect_ratio as Float,
             image_width: image_spec.width,
             pixel_sampler,
             depth,
+            packet_tracing,
+            tile_order,
+            aovs,
+            background,
+            clay,
+            depth_range,
+            tile_heatmap,
+            sample_heatmap,
+            bvh_view,
+            accelerator,
+            render_layers,
+            shutter,
+            shutter_interval,
+            chromatic_aberration: self.chromatic_aberration,
+            vignetting: self.vignetting.unwrap_or(false),
+            mechanical_vignetting: self.mechanical_vignetting,
+            post_process: self.post_process.unwrap_or_default(),
+            color_space: self.color_space.unwrap_or_default(),
+            float_primaries: self.float_primaries.unwrap_or_default(),
+            white_balance: self.white_balance,
+            exposure_bracket: self.exposure_bracket.unwrap_or_default(),
+            aperture_mask,
+            environment: self
+                .environment
+                .map(|environment| {
+                    let map = EnvironmentMap::new(&environment)?;
+                    Ok::<_, Error>((environment, map))
+                })
+                .transpose()?,
+            sky: self.sky.map(SkyModel::new).transpose()?,
+            fog: self.fog.map(|fog| fog.scaled(units.meters())),
+            path_guiding: self.path_guiding.unwrap_or(false),
+            guide: OnceLock::new(),
+            light_candidates: self.light_candidates,
+            light_mixture: self.light_mixture.unwrap_or(false),
+            lights: OnceLock::new(),
+            pixel_reservoirs: Mutex::new(None),
+            ray_epsilon,
+            normal_offset,
+            shadow_bias,
+            units: self.units,
+            seed: self.seed.unwrap_or(0),
+            save_buffer: self.save_buffer.unwrap_or(false),
+            hdr_output: self.hdr_output.unwrap_or(false),
+            tiff_layout: self.tiff_layout,
+            output: self.output.unwrap_or_else(|| "image".to_string()),
+            omnidirectional_stereo: self
+                .omnidirectional_stereo
+                .map(|distance| units.from_meters(distance)),
+            reflection_probe: self.reflection_probe,
 
             field_of_view,
             lookfrom,
@@ -130,6 +488,116 @@ impl CameraBuilder {
 
             defocus_disk_u,
             defocus_disk_v,
+        })
+    }
+}
+
+impl Camera {
+    // A builder that reproduces this camera, to build variations of it from.
+    pub fn to_builder(&self) -> CameraBuilder {
+        let units = self.units.unwrap_or_default();
+        let pixel_sampler = match self.pixel_sampler {
+            PixelSampler::Uniform(samples_sqrt) => PixelSampler::Uniform(samples_sqrt.pow(2)),
+            sampler => sampler,
+        };
+        CameraBuilder {
+            image_spec: Some(self.image_spec.clone()),
+
+            pixel_sampler: Some(pixel_sampler),
+            max_ray_depth: Some(self.depth),
+            packet_tracing: Some(self.packet_tracing),
+            tile_order: Some(self.tile_order),
+            aovs: Some(self.aovs),
+            background: self.background,
+            clay: Some(self.clay.is_some()),
+            depth_range: self.depth_range,
+            tile_heatmap: Some(self.tile_heatmap),
+            sample_heatmap: Some(self.sample_heatmap),
+            bvh_view: self.bvh_view,
+            accelerator: Some(self.accelerator),
+            render_layers: Some(self.render_layers.clone()),
+            shutter_curve: Some(self.shutter.curve.clone()),
+            rolling_shutter: Some(self.shutter.readout),
+            shutter_interval: Some((self.shutter_interval.min, self.shutter_interval.max)),
+            chromatic_aberration: self.chromatic_aberration,
+            vignetting: Some(self.vignetting),
+            mechanical_vignetting: self.mechanical_vignetting,
+            post_process: Some(self.post_process.clone()),
+            color_space: Some(self.color_space),
+            float_primaries: Some(self.float_primaries),
+            white_balance: self.white_balance,
+            exposure_bracket: Some(self.exposure_bracket.clone()),
+            aperture_mask: self.aperture_mask.as_ref().map(|(path, _)| path.clone()),
+            environment: self
+                .environment
+                .as_ref()
+                .map(|(environment, _)| environment.clone()),
+            sky: self.sky.as_ref().map(|model| model.sky.clone()),
+            fog: self.fog.map(|fog| fog.scaled(1.0 / units.meters())),
+            path_guiding: Some(self.path_guiding),
+            light_candidates: self.light_candidates,
+            light_mixture: Some(self.light_mixture),
+            ray_epsilon: Some(self.ray_epsilon),
+            normal_offset: Some(self.normal_offset),
+            shadow_bias: Some(self.shadow_bias),
+            units: self.units,
+            seed: Some(self.seed),
+            save_buffer: Some(self.save_buffer),
+            hdr_output: Some(self.hdr_output),
+            tiff_layout: self.tiff_layout,
+            output: Some(self.output.clone()),
+            omnidirectional_stereo: self
+                .omnidirectional_stereo
+                .map(|distance| units.to_meters(distance)),
+            reflection_probe: self.reflection_probe,
+
+            field_of_view: Some(self.field_of_view),
+            lookfrom: Some(self.lookfrom),
+            lookat: Some(self.lookat),
+            up_vector: Some(self.up_vector),
+
+            defocus_angle: Some(self.defocus_angle),
+            focus_distance: Some(units.to_meters(self.focus_distance)),
         }
     }
 }
+
+// Image specs are stored as the width and aspect ratio they are built from, the height follows.
+#[cfg(feature = "serde")]
+mod image_spec_serde {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::super::image::{ImageSpec, ImageSpecBuilder};
+    use crate::float::Float;
+
+    #[derive(Serialize, Deserialize)]
+    struct ImageSize {
+        width: usize,
+        aspect_ratio: Float,
+    }
+
+    pub fn serialize<S: Serializer>(
+        image_spec: &Option<ImageSpec>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        image_spec
+            .as_ref()
+            .map(|image_spec| ImageSize {
+                width: image_spec.width,
+                aspect_ratio: image_spec.aspect_ratio,
+            })
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<ImageSpec>, D::Error> {
+        let size = Option::<ImageSize>::deserialize(deserializer)?;
+        Ok(size.map(|size| {
+            ImageSpecBuilder::default()
+                .width(size.width)
+                .aspect_ratio(size.aspect_ratio)
+                .build()
+        }))
+    }
+}
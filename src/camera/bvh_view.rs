@@ -0,0 +1,199 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use ::image::{Rgb, RgbImage};
+use tracing::debug;
+
+use super::Camera;
+use crate::{
+    color::Color,
+    error::{Error, Result},
+    float::{Float, INFINITY},
+    hittable::{aabb::AABB, containers::BvhBox, Hittable},
+    ray::Ray,
+    vec3::Point3,
+};
+
+// How the BVH of the scene is drawn, to see at a glance where the splitting of the tree goes wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BvhView {
+    // The edges of the boxes on black, colored by their depth in the tree, from blue at the root to
+    // red at the deepest boxes.
+    Boxes,
+    // The same edges drawn over the image.
+    Overlay,
+    // Every pixel colored by the leaves its ray enters, relative to the most any ray enters. Leaves
+    // that overlap a lot or are much larger than what they hold show up as hot spots.
+    Heat,
+}
+
+impl BvhView {
+    pub const ALL: [BvhView; 3] = [BvhView::Boxes, BvhView::Overlay, BvhView::Heat];
+}
+
+impl Display for BvhView {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BvhView::Boxes => "boxes",
+            BvhView::Overlay => "overlay",
+            BvhView::Heat => "heat",
+        })
+    }
+}
+
+impl FromStr for BvhView {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|view| view.to_string() == s)
+            .ok_or_else(|| Error::InvalidCamera(format!("unknown BVH view: {}", s)))
+    }
+}
+
+impl Camera {
+    // Writes `image-bvh.png`, the BVH of `world` as `view` draws it. Only boxes in front of the
+    // first hit of the ray through each pixel center are drawn.
+    pub(crate) fn write_bvh_view(
+        &self,
+        world: &Box<dyn Hittable>,
+        image_buffer: &[Color],
+        view: BvhView,
+    ) -> Result<()> {
+        let image = self.bvh_view_image(world, image_buffer, view);
+        let path = self.output_path("-bvh.png");
+        image.save(&path)?;
+        debug!("wrote {}", path);
+        Ok(())
+    }
+
+    fn bvh_view_image(
+        &self,
+        world: &Box<dyn Hittable>,
+        image_buffer: &[Color],
+        view: BvhView,
+    ) -> RgbImage {
+        let mut boxes = Vec::new();
+        world.bvh_boxes(0, &mut boxes);
+        let deepest = boxes.iter().map(|b| b.depth).max().unwrap_or(0).max(1) as Float;
+        let pixel_size = self.pixel_delta_u.length();
+        let pixels = (0..self.image_width * self.image_height).map(|index| {
+            let (x, y) = (index % self.image_width, index / self.image_width);
+            let pixel_center = self.pixel00_loc
+                + x as Float * self.pixel_delta_u
+                + y as Float * self.pixel_delta_v;
+            let ray = Ray::new(self.center, pixel_center - self.center, 0.0);
+            let t_hit = world
+                .hit(&ray, &self.ray_interval())
+                .map_or(INFINITY, |hit| hit.t);
+            trace_boxes(&boxes, &ray, t_hit, pixel_size)
+        });
+        let pixels = pixels.collect::<Vec<_>>();
+        let most_leaves = pixels.iter().map(|p| p.leaves).max().unwrap_or(0).max(1) as Float;
+        let beauty = self.post_processed(image_buffer);
+        RgbImage::from_fn(self.image_width as u32, self.image_height as u32, |x, y| {
+            let index = y as usize * self.image_width + x as usize;
+            let pixel = &pixels[index];
+            let color = match (view, pixel.edge_depth) {
+                (BvhView::Heat, _) => Color::heatmap(pixel.leaves as Float / most_leaves),
+                (_, Some(depth)) => Color::heatmap(depth as Float / deepest),
+                (BvhView::Boxes, None) => Color::black(),
+                (BvhView::Overlay, None) => self.color_space.encode(beauty[index]),
+            };
+            let (r, g, b) = color.into_u8();
+            Rgb([r, g, b])
+        })
+    }
+}
+
+// What the ray through a pixel passes on its way to the first hit.
+struct BvhPixel {
+    leaves: usize,
+    // the depth of the nearest box edge the ray passes through, if any
+    edge_depth: Option<usize>,
+}
+
+// Walks the boxes `ray` enters before `t_hit`, skipping the subtrees of those it misses. An edge is
+// where the ray enters or leaves a box within a pixel of two of its faces at once.
+fn trace_boxes(boxes: &[BvhBox], ray: &Ray, t_hit: Float, pixel_size: Float) -> BvhPixel {
+    let mut pixel = BvhPixel {
+        leaves: 0,
+        edge_depth: None,
+    };
+    let mut nearest_edge = INFINITY;
+    let mut i = 0;
+    while i < boxes.len() {
+        let bvh_box = &boxes[i];
+        let span = match bvh_box.bounding_box.hit(ray) {
+            Some(span) if span.min < t_hit && span.max > 0.0 => span,
+            _ => {
+                i = bvh_box.end;
+                continue;
+            }
+        };
+        if bvh_box.leaf {
+            pixel.leaves += 1;
+        }
+        for t in [span.min, span.max] {
+            if t <= 0.0 || t >= t_hit || t >= nearest_edge {
+                continue;
+            }
+            if on_edge(&bvh_box.bounding_box, ray.at(t), t * pixel_size) {
+                nearest_edge = t;
+                pixel.edge_depth = Some(bvh_box.depth);
+            }
+        }
+        i += 1;
+    }
+    pixel
+}
+
+fn on_edge(bounding_box: &AABB, point: Point3, width: Float) -> bool {
+    let near_faces = (0..3).filter(|&a| {
+        let axis = bounding_box.axis(a);
+        (point[a] - axis.min).abs().min((point[a] - axis.max).abs()) < width
+    });
+    near_faces.count() >= 2
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        camera::test_camera,
+        hittable::{containers::HittableList, geometry::Sphere, materials::Lambertian},
+    };
+
+    #[test]
+    fn boxes_show_as_edges_and_heat() {
+        let material = Arc::new(Lambertian::from(Color::gray(0.5)));
+        let mut world = HittableList::default();
+        for x in [-1.0, 1.0] {
+            let center = Point3::new(x, 0.0, -4.0);
+            world.add(Box::new(Sphere::new(center, 0.5, material.clone())));
+        }
+        let world: Box<dyn Hittable> = world.into_bvh();
+        let camera = test_camera(64, 2.0)
+            .lookfrom(Point3::new(0.0, 0.0, 0.0))
+            .lookat(Point3::new(0.0, 0.0, -1.0))
+            .build()
+            .unwrap();
+        let image_buffer = vec![Color::gray(0.5); 64 * 32];
+        let boxes = camera.bvh_view_image(&world, &image_buffer, BvhView::Boxes);
+        let edges = boxes.pixels().filter(|p| p.0 != [0, 0, 0]).count();
+        assert!(edges > 0 && edges < 64 * 32 / 2);
+        // the overlay keeps the image away from the edges
+        let overlay = camera.bvh_view_image(&world, &image_buffer, BvhView::Overlay);
+        assert_eq!(overlay.get_pixel(0, 0), overlay.get_pixel(1, 0));
+        assert_ne!(overlay.get_pixel(0, 0).0, [0, 0, 0]);
+        // the rays through the spheres enter their leaves, those through the corners none
+        let heat = camera.bvh_view_image(&world, &image_buffer, BvhView::Heat);
+        let (left_sphere, corner) = (heat.get_pixel(28, 16), heat.get_pixel(0, 0));
+        assert_ne!(left_sphere, corner);
+        assert_eq!(corner.0, [0, 0, 255]);
+    }
+}
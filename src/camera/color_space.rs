@@ -0,0 +1,189 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::{
+    color::Color,
+    error::{Error, Result},
+    float::Float,
+};
+
+// The transfer function the 8 bit output is encoded with. The renderer works in linear color with
+// the primaries of sRGB and Rec. 709, which share them, so the colors only need to be encoded. Wider
+// primaries are for the float outputs, see `Primaries`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ColorSpace {
+    // The plain 2.2 gamma the output has always been written with.
+    #[default]
+    Gamma22,
+    // The piecewise sRGB transfer function, what most monitors and image viewers expect.
+    Srgb,
+    // The transfer function of Rec. 709, for video.
+    Rec709,
+}
+
+impl ColorSpace {
+    const ALL: [ColorSpace; 3] = [ColorSpace::Gamma22, ColorSpace::Srgb, ColorSpace::Rec709];
+
+    // Encodes a linear color in the working space for output, negative values are clipped.
+    pub fn encode(&self, color: Color) -> Color {
+        let transfer: fn(Float) -> Float = match self {
+            ColorSpace::Gamma22 => return color.gamma_corrected(2.2),
+            ColorSpace::Srgb => |value| {
+                if value <= 0.0031308 {
+                    12.92 * value
+                } else {
+                    1.055 * value.powf(1.0 / 2.4) - 0.055
+                }
+            },
+            ColorSpace::Rec709 => |value| {
+                if value < 0.018 {
+                    4.5 * value
+                } else {
+                    1.099 * value.powf(0.45) - 0.099
+                }
+            },
+        };
+        let transfer = |value: Float| transfer(value.max(0.0));
+        Color::new(transfer(color.r), transfer(color.g), transfer(color.b))
+    }
+}
+
+impl Display for ColorSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ColorSpace::Gamma22 => "gamma_2.2",
+            ColorSpace::Srgb => "srgb",
+            ColorSpace::Rec709 => "rec709",
+        })
+    }
+}
+
+impl FromStr for ColorSpace {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|color_space| color_space.to_string() == s)
+            .ok_or_else(|| Error::InvalidCamera(format!("unknown color space: {}", s)))
+    }
+}
+
+// The primaries the linear float outputs, the EXR, Radiance HDR and float TIFF files, are written
+// in. The renderer works in those of Rec. 709, the wider Rec. 2020 ones are for compositing and
+// grading.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Primaries {
+    #[default]
+    Rec709,
+    Rec2020,
+}
+
+impl Primaries {
+    const ALL: [Primaries; 2] = [Primaries::Rec709, Primaries::Rec2020];
+
+    // Converts a linear color from the working primaries.
+    pub fn convert(&self, color: Color) -> Color {
+        match self {
+            Primaries::Rec709 => color,
+            Primaries::Rec2020 => {
+                // the conversion from the Rec. 709 primaries in ITU-R BT.2087
+                let Color { r, g, b } = color;
+                Color::new(
+                    0.6274 * r + 0.3293 * g + 0.0433 * b,
+                    0.0691 * r + 0.9195 * g + 0.0114 * b,
+                    0.0164 * r + 0.0880 * g + 0.8956 * b,
+                )
+            }
+        }
+    }
+}
+
+impl Display for Primaries {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Primaries::Rec709 => "rec709",
+            Primaries::Rec2020 => "rec2020",
+        })
+    }
+}
+
+impl FromStr for Primaries {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|primaries| primaries.to_string() == s)
+            .ok_or_else(|| Error::InvalidCamera(format!("unknown primaries: {}", s)))
+    }
+}
+
+// The range of color temperatures, in kelvin, white balance can be set to.
+pub const WHITE_BALANCE_RANGE: (Float, Float) = Color::BLACKBODY_RANGE;
+
+// The gains that make light of the given color temperature, in kelvin, white. Light from a warm
+// 3200 K bulb gets its red turned down and blue turned up, while 6504 K, the temperature of the
+// D65 white point, is left as it is. The gains leave green alone.
+pub fn white_balance(temperature: Float) -> Color {
+    let white = Color::blackbody(6504.0);
+    let light = Color::blackbody(temperature);
+    let gains = Color::new(white.r / light.r, white.g / light.g, white.b / light.b);
+    gains / gains.g
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_encodings() {
+        let close = |a: Float, b: Float| (a - b).abs() < 1e-3;
+        for color_space in ColorSpace::ALL {
+            let white = color_space.encode(Color::gray(1.0));
+            assert!(close(white.r, 1.0) && close(white.g, 1.0) && close(white.b, 1.0));
+            assert_eq!(
+                color_space.to_string().parse::<ColorSpace>().unwrap(),
+                color_space
+            );
+        }
+        assert!(close(ColorSpace::Srgb.encode(Color::gray(0.18)).r, 0.4614));
+        assert!(close(
+            ColorSpace::Rec709.encode(Color::gray(0.18)).r,
+            0.4090
+        ));
+        assert_eq!(ColorSpace::Srgb.encode(Color::gray(-1.0)).r, 0.0);
+        assert!("display_p3".parse::<ColorSpace>().is_err());
+        assert!("linear_rec2020".parse::<ColorSpace>().is_err());
+    }
+
+    #[test]
+    fn float_primaries() {
+        for primaries in Primaries::ALL {
+            let white = primaries.convert(Color::gray(1.0));
+            assert!((white - Color::gray(1.0)).length() < 1e-3);
+            assert_eq!(
+                primaries.to_string().parse::<Primaries>().unwrap(),
+                primaries
+            );
+        }
+        // pure red in Rec. 709 is inside the wider Rec. 2020 gamut
+        let red = Primaries::Rec2020.convert(Color::new(1.0, 0.0, 0.0));
+        assert!(red.r < 1.0 && red.g > 0.0 && red.b > 0.0);
+    }
+
+    #[test]
+    fn white_balance_neutralizes_the_light() {
+        let neutral = white_balance(6504.0);
+        assert!((neutral - Color::gray(1.0)).length() < 1e-6);
+        let tungsten = white_balance(3200.0);
+        assert!(tungsten.r < 1.0 && tungsten.b > 1.0);
+        let balanced = Color::blackbody(3200.0) * tungsten;
+        let white = Color::blackbody(6504.0);
+        assert!((balanced / balanced.g - white / white.g).length() < 1e-6);
+        let shade = white_balance(9000.0);
+        assert!(shade.r > 1.0 && shade.b < 1.0);
+    }
+}
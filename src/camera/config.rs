@@ -0,0 +1,447 @@
+use std::str::FromStr;
+
+use super::{
+    builder::CameraBuilder,
+    bvh_view::BvhView,
+    color_space::{ColorSpace, Primaries},
+    environment::Environment,
+    fog::Fog,
+    image::ImageSpecBuilder,
+    post::{self, PostStage},
+    probe::ReflectionProbe,
+    render_layers::RenderLayer,
+    shutter::ShutterCurve,
+    sky::Sky,
+    tiff::TiffLayout,
+    tiles::TileOrder,
+    PixelSampler,
+};
+use crate::{
+    color::Color,
+    error::{Error, Result},
+    float::Float,
+    hittable::containers::Accelerator,
+    vec3::Vec3,
+};
+
+// Camera settings as plain text, one `name = value` line per setting that is set, so they can be
+// saved, edited by hand or written by other tools and loaded back. Vectors are written as three
+// numbers separated by spaces, lines starting with `#` are comments. Every render layer takes a
+// `render_layer` line of its own, its name followed by the object IDs it holds. Fog is written as
+// its color followed by its density, height falloff and base height.
+impl CameraBuilder {
+    pub fn to_config(&self) -> String {
+        let mut lines = Vec::new();
+        let mut line = |name: &str, value: String| lines.push(format!("{} = {}", name, value));
+        if let Some(image_spec) = &self.image_spec {
+            line("width", image_spec.width.to_string());
+            line("aspect_ratio", image_spec.aspect_ratio.to_string());
+        }
+        match self.pixel_sampler {
+            Some(PixelSampler::Uniform(samples)) => line("uniform_sampler", samples.to_string()),
+            Some(PixelSampler::Random(samples)) => line("random_sampler", samples.to_string()),
+            Some(PixelSampler::Padded(samples)) => line("padded_sampler", samples.to_string()),
+            Some(PixelSampler::Sobol(samples)) => line("sobol_sampler", samples.to_string()),
+            None => {}
+        }
+        let vector = |v: Vec3| format!("{} {} {}", v.x, v.y, v.z);
+        let settings = [
+            ("max_ray_depth", self.max_ray_depth.map(|v| v.to_string())),
+            ("packet_tracing", self.packet_tracing.map(|v| v.to_string())),
+            (
+                "tile_order",
+                self.tile_order.map(|v| tile_order_name(v).to_string()),
+            ),
+            ("aovs", self.aovs.map(|v| v.to_string())),
+            (
+                "background",
+                self.background.map(|c| format!("{} {} {}", c.r, c.g, c.b)),
+            ),
+            ("clay", self.clay.map(|v| v.to_string())),
+            (
+                "depth_range",
+                self.depth_range
+                    .map(|(near, far)| format!("{} {}", near, far)),
+            ),
+            ("tile_heatmap", self.tile_heatmap.map(|v| v.to_string())),
+            ("sample_heatmap", self.sample_heatmap.map(|v| v.to_string())),
+            ("bvh_view", self.bvh_view.map(|v| v.to_string())),
+            ("accelerator", self.accelerator.map(|v| v.to_string())),
+            (
+                "shutter_curve",
+                self.shutter_curve.as_ref().map(shutter_curve_name),
+            ),
+            (
+                "rolling_shutter",
+                self.rolling_shutter.map(|v| v.to_string()),
+            ),
+            (
+                "shutter_interval",
+                self.shutter_interval
+                    .map(|(open, close)| format!("{} {}", open, close)),
+            ),
+            (
+                "chromatic_aberration",
+                self.chromatic_aberration
+                    .map(|(lateral, longitudinal)| format!("{} {}", lateral, longitudinal)),
+            ),
+            ("vignetting", self.vignetting.map(|v| v.to_string())),
+            (
+                "mechanical_vignetting",
+                self.mechanical_vignetting.map(|v| v.to_string()),
+            ),
+            (
+                "post_process",
+                self.post_process
+                    .as_ref()
+                    .filter(|stages| !stages.is_empty())
+                    .map(|stages| {
+                        let stages = stages.iter().map(PostStage::to_string);
+                        stages.collect::<Vec<_>>().join(", ")
+                    }),
+            ),
+            ("color_space", self.color_space.map(|v| v.to_string())),
+            (
+                "float_primaries",
+                self.float_primaries.map(|v| v.to_string()),
+            ),
+            ("white_balance", self.white_balance.map(|v| v.to_string())),
+            (
+                "exposure_bracket",
+                self.exposure_bracket
+                    .as_ref()
+                    .filter(|stops| !stops.is_empty())
+                    .map(|stops| {
+                        let stops = stops.iter().map(|stop| stop.to_string());
+                        stops.collect::<Vec<_>>().join(" ")
+                    }),
+            ),
+            ("aperture_mask", self.aperture_mask.clone()),
+            (
+                "environment",
+                self.environment.as_ref().map(Environment::to_string),
+            ),
+            ("sky", self.sky.as_ref().map(Sky::to_string)),
+            (
+                "fog",
+                self.fog.map(|fog| {
+                    let Color { r, g, b } = fog.color;
+                    let (density, falloff, base) = (fog.density, fog.height_falloff, fog.base);
+                    format!("{} {} {} {} {} {}", r, g, b, density, falloff, base)
+                }),
+            ),
+            ("path_guiding", self.path_guiding.map(|v| v.to_string())),
+            (
+                "light_candidates",
+                self.light_candidates.map(|v| v.to_string()),
+            ),
+            ("light_mixture", self.light_mixture.map(|v| v.to_string())),
+            ("ray_epsilon", self.ray_epsilon.map(|v| v.to_string())),
+            ("normal_offset", self.normal_offset.map(|v| v.to_string())),
+            ("shadow_bias", self.shadow_bias.map(|v| v.to_string())),
+            ("units", self.units.map(|v| v.to_string())),
+            ("seed", self.seed.map(|v| v.to_string())),
+            ("save_buffer", self.save_buffer.map(|v| v.to_string())),
+            ("hdr_output", self.hdr_output.map(|v| v.to_string())),
+            ("tiff_layout", self.tiff_layout.map(|v| v.to_string())),
+            ("output", self.output.clone()),
+            (
+                "omnidirectional_stereo",
+                self.omnidirectional_stereo.map(|v| v.to_string()),
+            ),
+            (
+                "reflection_probe",
+                self.reflection_probe.map(|v| v.to_string()),
+            ),
+            ("field_of_view", self.field_of_view.map(|v| v.to_string())),
+            ("lookfrom", self.lookfrom.map(vector)),
+            ("lookat", self.lookat.map(vector)),
+            ("up_vector", self.up_vector.map(vector)),
+            ("defocus_angle", self.defocus_angle.map(|v| v.to_string())),
+            ("focus_distance", self.focus_distance.map(|v| v.to_string())),
+        ];
+        for (name, value) in settings {
+            if let Some(value) = value {
+                line(name, value);
+            }
+        }
+        for layer in self.render_layers.iter().flatten() {
+            let objects = layer.objects.iter().map(|id| format!(" {}", id));
+            line(
+                "render_layer",
+                layer.name.clone() + &objects.collect::<String>(),
+            );
+        }
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    pub fn from_config(config: &str) -> Result<Self> {
+        let mut builder = CameraBuilder::default();
+        let (mut width, mut aspect_ratio) = (None, None);
+        for line in config.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line
+                .split_once('=')
+                .map(|(name, value)| (name.trim(), value.trim()))
+                .ok_or_else(|| invalid(line))?;
+            builder = match name {
+                "width" => {
+                    width = Some(parse(value)?);
+                    builder
+                }
+                "aspect_ratio" => {
+                    aspect_ratio = Some(parse(value)?);
+                    builder
+                }
+                "uniform_sampler" => builder.uniform_sampler(parse(value)?),
+                "random_sampler" => builder.random_sampler(parse(value)?),
+                "padded_sampler" => builder.padded_sampler(parse(value)?),
+                "sobol_sampler" => builder.sobol_sampler(parse(value)?),
+                "max_ray_depth" => builder.max_ray_depth(parse(value)?),
+                "packet_tracing" => builder.packet_tracing(parse(value)?),
+                "tile_order" => builder.tile_order(parse_tile_order(value)?),
+                "aovs" => builder.aovs(parse(value)?),
+                "background" => builder.background(Color::from(parse_vector(value)?)),
+                "clay" => builder.clay(parse(value)?),
+                "tile_heatmap" => builder.tile_heatmap(parse(value)?),
+                "sample_heatmap" => builder.sample_heatmap(parse(value)?),
+                "bvh_view" => builder.bvh_view(value.parse::<BvhView>()?),
+                "accelerator" => builder.accelerator(value.parse::<Accelerator>()?),
+                "shutter_curve" => builder.shutter_curve(parse_shutter_curve(value)?),
+                "rolling_shutter" => builder.rolling_shutter(parse(value)?),
+                "shutter_interval" => match parse_numbers(value)?[..] {
+                    [open, close] => builder.shutter_interval((open, close)),
+                    _ => return Err(invalid(value)),
+                },
+                "chromatic_aberration" => match parse_numbers(value)?[..] {
+                    [lateral, longitudinal] => {
+                        builder.chromatic_aberration((lateral, longitudinal))
+                    }
+                    _ => return Err(invalid(value)),
+                },
+                "vignetting" => builder.vignetting(parse(value)?),
+                "mechanical_vignetting" => builder.mechanical_vignetting(parse(value)?),
+                "post_process" => builder.post_process(post::parse_pipeline(value)?),
+                "color_space" => builder.color_space(value.parse::<ColorSpace>()?),
+                "float_primaries" => builder.float_primaries(value.parse::<Primaries>()?),
+                "white_balance" => builder.white_balance(parse(value)?),
+                "exposure_bracket" => builder.exposure_bracket(parse_numbers(value)?),
+                "aperture_mask" => builder.aperture_mask(value.to_string()),
+                "environment" => builder.environment(value.parse()?),
+                "sky" => builder.sky(value.parse()?),
+                "fog" => match parse_numbers(value)?[..] {
+                    [r, g, b, density, falloff, base] => builder.fog(
+                        Fog::new(Color::new(r, g, b), density).with_height_falloff(falloff, base),
+                    ),
+                    _ => return Err(invalid(value)),
+                },
+                "path_guiding" => builder.path_guiding(parse(value)?),
+                "light_candidates" => builder.light_candidates(parse(value)?),
+                "light_mixture" => builder.light_mixture(parse(value)?),
+                "ray_epsilon" => builder.ray_epsilon(parse(value)?),
+                "normal_offset" => builder.normal_offset(parse(value)?),
+                "shadow_bias" => builder.shadow_bias(parse(value)?),
+                "units" => builder.units(value.parse()?),
+                "seed" => builder.seed(parse(value)?),
+                "save_buffer" => builder.save_buffer(parse(value)?),
+                "hdr_output" => builder.hdr_output(parse(value)?),
+                "tiff_layout" => builder.tiff_layout(value.parse::<TiffLayout>()?),
+                "output" => builder.output(value.to_string()),
+                "omnidirectional_stereo" => builder.omnidirectional_stereo(parse(value)?),
+                "reflection_probe" => builder.reflection_probe(value.parse::<ReflectionProbe>()?),
+                "render_layer" => {
+                    let mut words = value.split_whitespace();
+                    let name = words.next().ok_or_else(|| invalid(line))?;
+                    let objects = words.map(parse).collect::<Result<_>>()?;
+                    let mut builder = builder;
+                    builder
+                        .render_layers
+                        .get_or_insert_with(Vec::new)
+                        .push(RenderLayer::new(name, objects));
+                    builder
+                }
+                "depth_range" => match parse_numbers(value)?[..] {
+                    [near, far] => builder.depth_range((near, far)),
+                    _ => return Err(invalid(value)),
+                },
+                "field_of_view" => builder.field_of_view(parse(value)?),
+                "lookfrom" => builder.lookfrom(parse_vector(value)?),
+                "lookat" => builder.lookat(parse_vector(value)?),
+                "up_vector" => builder.up_vector(parse_vector(value)?),
+                "defocus_angle" => builder.defocus_angle(parse(value)?),
+                "focus_distance" => builder.focus_distance(parse(value)?),
+                _ => return Err(invalid(line)),
+            };
+        }
+        match (width, aspect_ratio) {
+            (Some(width), Some(aspect_ratio)) => {
+                let image_spec = ImageSpecBuilder::default()
+                    .width(width)
+                    .aspect_ratio(aspect_ratio)
+                    .build();
+                Ok(builder.image_spec(image_spec))
+            }
+            (None, None) => Ok(builder),
+            _ => Err(Error::InvalidCamera(
+                "width and aspect_ratio must be set together".to_string(),
+            )),
+        }
+    }
+}
+
+fn invalid(line: &str) -> Error {
+    Error::InvalidCamera(format!("invalid config line: {}", line))
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T> {
+    value.parse().map_err(|_| invalid(value))
+}
+
+fn parse_numbers(value: &str) -> Result<Vec<Float>> {
+    value.split_whitespace().map(parse::<Float>).collect()
+}
+
+fn parse_vector(value: &str) -> Result<Vec3> {
+    match parse_numbers(value)?[..] {
+        [x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => Err(invalid(value)),
+    }
+}
+
+fn tile_order_name(tile_order: TileOrder) -> &'static str {
+    match tile_order {
+        TileOrder::Scanline => "scanline",
+        TileOrder::Spiral => "spiral",
+        TileOrder::Hilbert => "hilbert",
+    }
+}
+
+fn parse_tile_order(value: &str) -> Result<TileOrder> {
+    [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert]
+        .into_iter()
+        .find(|&tile_order| tile_order_name(tile_order) == value)
+        .ok_or_else(|| invalid(value))
+}
+
+// Shutter curves are `box`, `triangle` or the points of a curve.
+fn shutter_curve_name(shutter_curve: &ShutterCurve) -> String {
+    match shutter_curve {
+        ShutterCurve::Box => "box".to_string(),
+        ShutterCurve::Triangle => "triangle".to_string(),
+        ShutterCurve::Curve(points) => points
+            .iter()
+            .map(|point| point.to_string())
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+fn parse_shutter_curve(value: &str) -> Result<ShutterCurve> {
+    match value {
+        "box" => Ok(ShutterCurve::Box),
+        "triangle" => Ok(ShutterCurve::Triangle),
+        _ => Ok(ShutterCurve::Curve(parse_numbers(value)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        camera::{bloom::Bloom, post::Tonemap},
+        units::Units,
+        vec3::Point3,
+    };
+
+    #[test]
+    fn config_round_trips() {
+        let image_spec = ImageSpecBuilder::default()
+            .width(640)
+            .aspect_ratio(1.5)
+            .build();
+        let builder = CameraBuilder::default()
+            .image_spec(image_spec)
+            .random_sampler(12)
+            .max_ray_depth(8)
+            .tile_order(TileOrder::Hilbert)
+            .background(Color::gray(0.5))
+            .clay(true)
+            .depth_range((1.0, 20.0))
+            .tile_heatmap(true)
+            .sample_heatmap(true)
+            .bvh_view(BvhView::Overlay)
+            .accelerator(Accelerator::KdTree)
+            .shutter_curve(ShutterCurve::Curve(vec![0.0, 1.0, 0.25]))
+            .rolling_shutter(0.125)
+            .shutter_interval((1.0, 1.5))
+            .chromatic_aberration((0.005, 0.02))
+            .vignetting(true)
+            .mechanical_vignetting(25.0)
+            .post_process(vec![
+                PostStage::Exposure(0.5),
+                PostStage::Bloom(Bloom::new(1.0, 4.0, 0.5)),
+                PostStage::Tonemap(Tonemap::Aces),
+                PostStage::Dither,
+            ])
+            .color_space(ColorSpace::Rec709)
+            .float_primaries(Primaries::Rec2020)
+            .white_balance(3200.0)
+            .exposure_bracket(vec![-2.0, 0.0, 2.0])
+            .aperture_mask("images/hexagon.png".to_string())
+            .environment(Environment::Image("images/studio.hdr".to_string()))
+            .sky(Sky::new(64.1466, -21.9426).time(17, 30).turbidity(2.5))
+            .fog(Fog::new(Color::new(0.6, 0.65, 0.7), 0.05).with_height_falloff(0.5, -1.0))
+            .path_guiding(true)
+            .light_candidates(16)
+            .light_mixture(true)
+            .ray_epsilon(0.01)
+            .normal_offset(1e-5)
+            .shadow_bias(0.002)
+            .units(Units::Centimeters)
+            .seed(7)
+            .save_buffer(true)
+            .hdr_output(true)
+            .tiff_layout(TiffLayout::Strips { rows: 8 })
+            .output("renders/closeup".to_string())
+            .omnidirectional_stereo(0.064)
+            .reflection_probe(ReflectionProbe::Cube)
+            .render_layers(vec![
+                RenderLayer::new("subject", vec![12, 34]),
+                RenderLayer::new("props", vec![]),
+            ])
+            .field_of_view(35.0)
+            .lookfrom(Point3::new(1.0, 2.5, -3.0))
+            .focus_distance(4.25);
+        let config = builder.to_config();
+        let parsed = CameraBuilder::from_config(&config).unwrap();
+        assert_eq!(parsed.to_config(), config);
+        assert_eq!(parsed.image_spec.unwrap().width, 640);
+        assert!(parsed.lookat.is_none());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trips() {
+        let image_spec = ImageSpecBuilder::default()
+            .width(320)
+            .aspect_ratio(2.0)
+            .build();
+        let builder = CameraBuilder::default()
+            .image_spec(image_spec)
+            .uniform_sampler(16)
+            .tile_order(TileOrder::Spiral)
+            .lookat(Point3::new(0.5, 0.0, -1.0));
+        let json = serde_json::to_string(&builder).unwrap();
+        let parsed: CameraBuilder = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.to_config(), builder.to_config());
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        assert!(CameraBuilder::from_config("zoom = 2").is_err());
+        assert!(CameraBuilder::from_config("lookat = 1 2").is_err());
+    }
+}
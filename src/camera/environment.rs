@@ -0,0 +1,197 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::str::FromStr;
+
+use ::image::codecs::hdr::HdrDecoder;
+
+use super::probe::ReflectionProbe;
+use crate::{
+    color::Color,
+    error::{Error, Result},
+    float::Float,
+    hittable::geometry::Sphere,
+    vec3::Vec3,
+};
+
+// The size of the studio environment, in texels.
+const STUDIO_WIDTH: usize = 512;
+const STUDIO_HEIGHT: usize = 256;
+
+// Light coming in from all around the scene, for the rays that escape it. Images are equirect
+// panoramas laid out like `ReflectionProbe::Equirect` renders them, so probes rendered of one scene
+// light the next, written as their path. Radiance HDR images are read as they are and others as
+// sRGB.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Environment {
+    // A photo studio made up on the spot, a dim room lit by a large key softbox up to the front
+    // left, a fill to the right, a strip light overhead and a rim light behind, written `studio`.
+    // It needs no files, so renders of it look the same everywhere.
+    Studio,
+    Image(String),
+}
+
+impl Display for Environment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Environment::Studio => f.write_str("studio"),
+            Environment::Image(path) => f.write_str(path),
+        }
+    }
+}
+
+impl FromStr for Environment {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "" => Err(Error::InvalidCamera(
+                "the environment needs a path".to_string(),
+            )),
+            "studio" => Ok(Environment::Studio),
+            path => Ok(Environment::Image(path.to_string())),
+        }
+    }
+}
+
+// The texels of an `Environment`, looked up by direction.
+#[derive(Debug, Clone)]
+pub(super) struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    texels: Vec<Color>,
+}
+
+impl EnvironmentMap {
+    pub fn new(environment: &Environment) -> Result<Self> {
+        match environment {
+            Environment::Studio => Ok(Self::studio()),
+            Environment::Image(path) => Self::load(path),
+        }
+    }
+    fn load(path: &str) -> Result<Self> {
+        let hdr = Path::new(path)
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("hdr"));
+        let (width, height, texels) = if hdr {
+            let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+            let metadata = decoder.metadata();
+            let texels = decoder
+                .read_image_hdr()?
+                .into_iter()
+                .map(|texel| {
+                    let [r, g, b] = texel.0;
+                    Color::new(r as Float, g as Float, b as Float)
+                })
+                .collect();
+            (metadata.width, metadata.height, texels)
+        } else {
+            let image = ::image::open(path)?.to_rgb8();
+            let decode = |value: u8| Color::srgb_to_linear(value as Float / 255.0);
+            let texels = image
+                .pixels()
+                .map(|texel| Color::new(decode(texel[0]), decode(texel[1]), decode(texel[2])))
+                .collect();
+            (image.width(), image.height(), texels)
+        };
+        if width == 0 || height == 0 {
+            return Err(Error::InvalidCamera(format!(
+                "the environment image is empty: {}",
+                path
+            )));
+        }
+        Ok(Self {
+            width: width as usize,
+            height: height as usize,
+            texels,
+        })
+    }
+    fn studio() -> Self {
+        // the direction each softbox faces the scene from, its half width and height on the plane
+        // a unit away that way, and its radiance
+        let softboxes = [
+            (Vec3::new(-1.0, 1.2, 1.0), 0.35, 0.25, 6.0),
+            (Vec3::new(1.2, 0.5, 0.8), 0.3, 0.3, 2.0),
+            (Vec3::new(0.0, 1.0, -0.3), 0.6, 0.12, 4.0),
+            (Vec3::new(0.3, 0.6, -1.0), 0.1, 0.3, 3.0),
+        ];
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let texels = (0..STUDIO_WIDTH * STUDIO_HEIGHT)
+            .map(|index| {
+                let s = (index % STUDIO_WIDTH) as Float + 0.5;
+                let t = (index / STUDIO_WIDTH) as Float + 0.5;
+                let direction = ReflectionProbe::Equirect
+                    .direction(s / STUDIO_WIDTH as Float, t / STUDIO_HEIGHT as Float);
+                // walls that are brighter towards the ceiling
+                let mut color = Color::gray(0.05 + 0.1 * direction.y.max(0.0));
+                for (center, half_width, half_height, radiance) in softboxes {
+                    let center = center.unit_vector();
+                    let facing = direction.dot(&center);
+                    if facing <= 0.0 {
+                        continue;
+                    }
+                    let right = up.cross(&center).unit_vector();
+                    let on_plane = direction / facing - center;
+                    let x = on_plane.dot(&right);
+                    let y = on_plane.dot(&center.cross(&right));
+                    if x.abs() < half_width && y.abs() < half_height {
+                        color = Color::gray(radiance);
+                    }
+                }
+                color
+            })
+            .collect();
+        Self {
+            width: STUDIO_WIDTH,
+            height: STUDIO_HEIGHT,
+            texels,
+        }
+    }
+    // The light coming in from `direction`.
+    pub fn radiance(&self, direction: Vec3) -> Color {
+        let (u, v) = Sphere::get_sphere_uv(&direction.unit_vector());
+        let i = ((u * self.width as Float) as usize).min(self.width - 1);
+        let j = (((1.0 - v) * self.height as Float) as usize).min(self.height - 1);
+        self.texels[j * self.width + i]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::buffer::write_hdr;
+
+    #[test]
+    fn environments_are_laid_out_like_probes() {
+        // an image that is red towards positive x and blue towards negative x, read back from a
+        // Radiance HDR file
+        let (width, height) = (64, 32);
+        let colors = (0..width * height)
+            .map(|index| {
+                let s = (index % width) as Float + 0.5;
+                let t = (index / width) as Float + 0.5;
+                let direction =
+                    ReflectionProbe::Equirect.direction(s / width as Float, t / height as Float);
+                Color::new(direction.x.max(0.0), 0.0, (-direction.x).max(0.0))
+            })
+            .collect::<Vec<_>>();
+        let path = std::env::temp_dir().join(format!("raytracer-env-{}.hdr", std::process::id()));
+        write_hdr(&path, width, height, &colors).unwrap();
+        let environment = Environment::Image(path.to_str().unwrap().to_string());
+        let map = EnvironmentMap::new(&environment).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(map.radiance(Vec3::new(1.0, 0.0, 0.0)).r > 0.9);
+        assert!(map.radiance(Vec3::new(-1.0, 0.1, 0.0)).b > 0.9);
+
+        // the key light of the studio is brighter than the walls
+        let studio = EnvironmentMap::new(&"studio".parse().unwrap()).unwrap();
+        let key = studio.radiance(Vec3::new(-1.0, 1.2, 1.0));
+        assert!(key.g > 1.0 && studio.radiance(Vec3::new(0.0, 0.0, 1.0)).g < 0.1);
+        assert_eq!(
+            environment.to_string().parse::<Environment>().unwrap(),
+            environment
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+use web_time::Instant;
+
+use super::{
+    aov::{Layer, TileUpdate},
+    Camera, RenderControl,
+};
+use crate::{
+    color::Color,
+    error::Result,
+    float::Float,
+    hittable::Hittable,
+    telemetry::{self, Counter},
+};
+
+// What `Camera::render_with` reports while rendering.
+#[derive(Debug)]
+pub enum TileEvent<'a> {
+    // A finished tile of one of the layers in linear color, like the tiles sent by `render`.
+    Tile {
+        layer: Layer,
+        top_left: (usize, usize),
+        size: (usize, usize),
+        colors: &'a [Color],
+    },
+    // The fraction of the image rendered so far, reported after every tile of the image itself.
+    Progress(Float),
+    // Reported once when the render is done.
+    Finished(RenderStats),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RenderStats {
+    pub elapsed: Duration,
+    pub tiles: usize,
+    // Only counted with the `telemetry` feature, zero otherwise.
+    pub rays: u64,
+}
+
+fn rays_traced() -> u64 {
+    [
+        Counter::CameraRays,
+        Counter::ScatteredRays,
+        Counter::ShadowRays,
+    ]
+    .into_iter()
+    .map(telemetry::total)
+    .sum()
+}
+
+impl Camera {
+    // Renders the image like `render_to_buffer` but reports the tiles, the progress and the final
+    // statistics to `on_event`. The callback runs on the calling thread while the tiles are rendered
+    // in the background, `control` can cancel them from another thread. Nothing is written to the
+    // file system, that is left to the caller.
+    pub fn render_with(
+        &self,
+        world: &Box<dyn Hittable>,
+        control: &RenderControl,
+        on_event: impl FnMut(TileEvent),
+    ) -> Result<Vec<Color>> {
+        let (image_buffer, _) = self.report(on_event, |sender| {
+            Ok(self.render_tiles(world, sender, None, control))
+        })?;
+        Ok(image_buffer)
+    }
+    // Renders the image and writes it to the outputs like `render`, returning the statistics of
+    // the render.
+    pub fn render_to_outputs(
+        &self,
+        world: &Box<dyn Hittable>,
+        control: &RenderControl,
+    ) -> Result<RenderStats> {
+        let (_, stats) = self.report(|_| {}, |sender| self.render(world, sender, control))?;
+        Ok(stats)
+    }
+    // Runs `render` in the background and reports the tiles it sends to `on_event`, returning the
+    // image and the statistics `on_event` was given last.
+    fn report(
+        &self,
+        mut on_event: impl FnMut(TileEvent),
+        render: impl FnOnce(SyncSender<TileUpdate>) -> Result<Vec<Color>> + Send,
+    ) -> Result<(Vec<Color>, RenderStats)> {
+        let start_time = Instant::now();
+        let rays_before = rays_traced();
+        let pixel_count = self.image_width * self.image_height;
+        let (sender, receiver) = sync_channel(64);
+        thread::scope(|s| {
+            let render = s.spawn(move || render(sender));
+            let mut rendered = 0;
+            let mut tiles = 0;
+            for (layer, top_left, size, colors) in receiver {
+                on_event(TileEvent::Tile {
+                    layer,
+                    top_left,
+                    size,
+                    colors: &colors,
+                });
+                if layer == Layer::Beauty {
+                    rendered += size.0 * size.1;
+                    tiles += 1;
+                    on_event(TileEvent::Progress(
+                        rendered as Float / pixel_count as Float,
+                    ));
+                }
+            }
+            let image_buffer = render.join().unwrap()?;
+            let stats = RenderStats {
+                elapsed: start_time.elapsed(),
+                tiles,
+                rays: rays_traced() - rays_before,
+            };
+            on_event(TileEvent::Finished(stats));
+            Ok((image_buffer, stats))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        camera::test_camera,
+        hittable::{containers::HittableList, geometry::Sphere, materials::Lambertian},
+        vec3::Point3,
+    };
+
+    #[test]
+    fn callbacks_leave_the_file_system_alone() {
+        let output = std::env::temp_dir()
+            .join(format!("raytracer-events-{}", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let camera = test_camera(8, 1.0)
+            .hdr_output(true)
+            .output(output.clone())
+            .build()
+            .unwrap();
+        let mut world = HittableList::default();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -1.0),
+            0.5,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        )));
+        let world: Box<dyn Hittable> = Box::new(world);
+
+        let mut progress = 0.0;
+        let image_buffer = camera
+            .render_with(&world, &RenderControl::default(), |event| {
+                if let TileEvent::Progress(fraction) = event {
+                    progress = fraction;
+                }
+            })
+            .unwrap();
+        assert_eq!(image_buffer.len(), 64);
+        assert_eq!(progress, 1.0);
+        assert!(!std::path::Path::new(&format!("{}.ppm", output)).exists());
+        assert!(!std::path::Path::new(&format!("{}.hdr", output)).exists());
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+use tracing::debug;
+use web_time::Instant;
+
+use super::{aov::TileUpdate, builder::CameraBuilder, Camera, PixelSampler, RenderControl};
+use crate::{
+    error::Result,
+    float::{consts, Float},
+    hittable::Hittable,
+    vec3::Vec3,
+};
+
+// Camera movements requested by the preview window, see `Camera::explore`.
+#[derive(Debug, Clone, Copy)]
+pub enum CameraControl {
+    // Moves the camera along its right, up and forward axes, in multiples of the focus distance.
+    Move(Vec3),
+    // Turns the view by the given number of degrees to the right and upwards.
+    Turn { right: Float, up: Float },
+}
+
+// Keeps the view from flipping over when looking straight up or down.
+const MAX_PITCH: Float = consts::FRAC_PI_2 * 0.99;
+
+impl Camera {
+    // Renders `world` progressively, starting at a single sample per pixel and working up to the
+    // sample count of the camera. Moving the camera through `controls` cancels the pass in flight
+    // and starts over from the new position. Returns once the preview hangs up.
+    pub fn explore(
+        &self,
+        world: &Box<dyn Hittable>,
+        sender: SyncSender<TileUpdate>,
+        controls: Receiver<CameraControl>,
+    ) -> Result<()> {
+        let mut camera = self.to_builder().build()?;
+        let mut passes = camera.progressive_passes()?.into_iter();
+        let mut last_pass: Option<Camera> = None;
+        loop {
+            let mut moves = Vec::new();
+            match passes.next() {
+                Some(pass) => {
+                    // the light samples of the pass before carry over, from where it was
+                    if let Some(last_pass) = &last_pass {
+                        pass.follow(last_pass);
+                    }
+                    let start_time = Instant::now();
+                    let control = RenderControl::default();
+                    let hung_up = thread::scope(|s| {
+                        let render =
+                            s.spawn(|| pass.render_tiles(world, sender.clone(), None, &control));
+                        loop {
+                            match controls.recv_timeout(Duration::from_millis(10)) {
+                                Ok(movement) => {
+                                    moves.push(movement);
+                                    control.cancel();
+                                }
+                                Err(RecvTimeoutError::Timeout) if render.is_finished() => {
+                                    return false;
+                                }
+                                Err(RecvTimeoutError::Timeout) => {}
+                                Err(RecvTimeoutError::Disconnected) => {
+                                    control.cancel();
+                                    return true;
+                                }
+                            }
+                        }
+                    });
+                    if hung_up {
+                        return Ok(());
+                    }
+                    debug!(
+                        samples = ?pass.pixel_sampler,
+                        seconds = start_time.elapsed().as_secs_f64(),
+                        "pass finished"
+                    );
+                    last_pass = Some(pass);
+                }
+                // the image has converged, wait for the camera to move
+                None => match controls.recv() {
+                    Ok(movement) => moves.push(movement),
+                    Err(_) => return Ok(()),
+                },
+            }
+            if !moves.is_empty() {
+                camera = camera.controlled(&moves)?;
+                passes = camera.progressive_passes()?.into_iter();
+            }
+        }
+    }
+
+    // Copies of this camera with the sample count growing fourfold up to the full sample count.
+    pub(crate) fn progressive_passes(&self) -> Result<Vec<Camera>> {
+        let (samples, step, sampler): (_, _, fn(usize) -> PixelSampler) = match self.pixel_sampler {
+            PixelSampler::Uniform(samples_sqrt) => {
+                (samples_sqrt, 2, |count| PixelSampler::Uniform(count.pow(2)))
+            }
+            PixelSampler::Random(samples) => (samples, 4, PixelSampler::Random),
+            PixelSampler::Padded(samples) => (samples, 4, PixelSampler::Padded),
+            PixelSampler::Sobol(samples) => (samples, 4, PixelSampler::Sobol),
+        };
+        let mut counts = std::iter::successors(Some(1), |count| Some(count * step))
+            .take_while(|&count| count < samples)
+            .collect::<Vec<_>>();
+        counts.push(samples);
+        counts
+            .into_iter()
+            .map(|count| {
+                CameraBuilder {
+                    pixel_sampler: Some(sampler(count)),
+                    ..self.to_builder()
+                }
+                .build()
+            })
+            .collect()
+    }
+
+    // The camera after applying `controls`. Turning assumes the world is Y up, like all the scenes.
+    fn controlled(&self, controls: &[CameraControl]) -> Result<Camera> {
+        let direction = (self.lookat - self.lookfrom).unit_vector();
+        let mut yaw = direction.x.atan2(direction.z);
+        let mut pitch = direction.y.asin();
+        let mut lookfrom = self.lookfrom;
+        for control in controls {
+            match *control {
+                CameraControl::Move(delta) => {
+                    let offset = delta.x * self.u + delta.y * self.v - delta.z * self.w;
+                    lookfrom = lookfrom + self.focus_distance * offset;
+                }
+                CameraControl::Turn { right, up } => {
+                    yaw -= right.to_radians();
+                    pitch = (pitch + up.to_radians()).clamp(-MAX_PITCH, MAX_PITCH);
+                }
+            }
+        }
+        let direction = Vec3::new(
+            pitch.cos() * yaw.sin(),
+            pitch.sin(),
+            pitch.cos() * yaw.cos(),
+        );
+        self.to_builder()
+            .lookfrom(lookfrom)
+            .lookat(lookfrom + self.focus_distance * direction)
+            .build()
+    }
+}
@@ -0,0 +1,123 @@
+use super::{path::PathState, Camera};
+use crate::{color::Color, float::Float, ray::Ray};
+
+// Fog filling the whole scene, thinning out exponentially with height above `base` by
+// `height_falloff` per unit, or evenly everywhere with no falloff. Rather than tracing light
+// scattering in it like a volume, the fog fades everything towards its color the further the light
+// travels through it, which is cheap and gives a sense of depth to large scenes.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fog {
+    pub color: Color,
+    // The chance per unit of distance of light being lost in the fog at the base height.
+    pub density: Float,
+    pub height_falloff: Float,
+    pub base: Float,
+}
+
+impl Fog {
+    pub fn new(color: Color, density: Float) -> Self {
+        Self {
+            color,
+            density,
+            height_falloff: 0.0,
+            base: 0.0,
+        }
+    }
+    pub fn with_height_falloff(self, height_falloff: Float, base: Float) -> Self {
+        Self {
+            height_falloff,
+            base,
+            ..self
+        }
+    }
+    // The same fog measured in units `length` times as long as the ones it is given in, denser and
+    // falling off faster per unit, with its base fewer units up.
+    pub(super) fn scaled(self, length: Float) -> Self {
+        Self {
+            density: self.density * length,
+            height_falloff: self.height_falloff * length,
+            base: self.base / length,
+            ..self
+        }
+    }
+    // The fraction of light that makes it through the fog along `ray` from its origin to `t`.
+    pub(super) fn transmittance(&self, ray: &Ray, t: Float) -> Float {
+        let speed = ray.direction.length();
+        let climb = self.height_falloff * ray.direction.y;
+        let start = self.density * (-self.height_falloff * (ray.origin.y - self.base)).exp();
+        // the integral of the density along the ray, which is the length of the ray through the
+        // fog when it stays at the same height
+        let depth = if climb.abs() < 1e-9 {
+            start * speed * t
+        } else {
+            start * speed * (1.0 - (-climb * t).exp()) / climb
+        };
+        (-depth).exp()
+    }
+}
+
+impl Camera {
+    // Adds the fog in front of `t` along `ray` to `path`, and dims the light from behind it.
+    // Rays escaping the scene go through the fog all the way.
+    pub(super) fn fog_segment(&self, ray: &Ray, t: Float, path: &mut PathState) {
+        let Some(fog) = self.fog else {
+            return;
+        };
+        let transmittance = fog.transmittance(ray, t);
+        path.add(fog.color * (1.0 - transmittance));
+        path.attenuate(Color::gray(transmittance));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        camera::test_camera,
+        units::Units,
+        vec3::{Point3, Vec3},
+    };
+
+    #[test]
+    fn fog_thins_out_with_height() {
+        let even = Fog::new(Color::gray(0.5), 1.0);
+        let level = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0), 0.0);
+        assert!((even.transmittance(&level, 0.5) - (-1.0 as Float).exp()).abs() < 1e-6);
+        assert_eq!(even.transmittance(&level, Float::INFINITY), 0.0);
+
+        let height = even.with_height_falloff(1.0, 0.0);
+        let up = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.0);
+        // the density integrates to one all the way up
+        let escaping = height.transmittance(&up, Float::INFINITY);
+        assert!((escaping - (-1.0 as Float).exp()).abs() < 1e-6);
+        let down = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let up = Ray::new(Point3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.0);
+        // the same stretch of fog either way
+        assert!((height.transmittance(&down, 1.0) - height.transmittance(&up, 1.0)).abs() < 1e-6);
+        let high = Ray::new(Point3::new(0.0, 5.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        assert!(height.transmittance(&high, 10.0) > height.transmittance(&level, 10.0));
+    }
+
+    #[test]
+    fn fog_is_given_in_meters_in_scenes_with_units() {
+        let meters = Fog::new(Color::gray(0.5), 0.05).with_height_falloff(0.5, -1.0);
+        let camera = test_camera(8, 1.0)
+            .units(Units::Centimeters)
+            .fog(meters)
+            .focus_distance(2.0)
+            .build()
+            .unwrap();
+        let fog = camera.fog.unwrap();
+        assert!((fog.density - 0.0005).abs() < 1e-9 && (fog.base + 100.0).abs() < 1e-3);
+        assert!((camera.focus_distance - 200.0).abs() < 1e-3);
+        // and the fog over a meter is the same as over a hundred centimeters
+        let meter = Ray::new(Point3::zero(), Vec3::new(1.0, 0.0, 0.0), 0.0);
+        let expected = meters.transmittance(&meter, 1.0);
+        assert!((fog.transmittance(&meter, 100.0) - expected).abs() < 1e-6);
+        // cameras give their settings back in meters
+        let given = camera.to_builder();
+        assert!((given.fog.unwrap().density - 0.05).abs() < 1e-9);
+        assert!((camera.settings().focus_distance - 2.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,409 @@
+use std::sync::Arc;
+
+use rayon::prelude::*;
+
+use super::{restir::PixelReuse, Camera};
+use crate::{
+    color::Color,
+    float::{consts::PI, Float},
+    hittable::{aabb::AABB, materials::Material, pdf::Pdf, HitRecord, Hittable},
+    interval::Interval,
+    random::{RandomSource, Rng},
+    ray::Ray,
+    vec3::{Point3, Vec3},
+};
+
+// Cells along each axis of the box the guide spans.
+const GRID: usize = 8;
+// The guide spans the first hits of the camera rays but for this share of the farthest ones, so
+// the far off points of a huge ground sphere seen towards the horizon don't stretch the cells over
+// everything. The box is grown by `GUIDE_MARGIN` of its size on each
+// side, for the bounces just beyond what the camera sees.
+const FAR_SHARE: Float = 0.05;
+const GUIDE_MARGIN: Float = 0.25;
+// Bins of equal solid angle the directions from a cell are split into, rows from straight down to
+// straight up and columns around the vertical axis.
+const ROWS: usize = 8;
+const COLUMNS: usize = 16;
+const BINS: usize = ROWS * COLUMNS;
+// The share of each cell's density spread evenly over all directions, so directions the training
+// never saw light from can still be drawn.
+const UNIFORM_SHARE: Float = 0.2;
+// The guide is trained in passes of one, two and then four samples per pixel, each pass following
+// paths guided by the last one. The rows are split into bands traced in parallel and merged in
+// order, so training gives the same guide every time.
+const TRAINING_PASSES: usize = 3;
+const TRAINING_BANDS: usize = 16;
+
+// A cache of where light comes from throughout the scene, steering the bounces of diffuse surfaces
+// towards it. The box around what the camera sees is split into a grid of cells, each with a
+// histogram of the light arriving from every direction, learned from paths traced before the
+// render. Bounces outside the box and in cells no light was seen from are left to the material.
+#[derive(Debug)]
+pub(crate) struct PathGuide {
+    bounds: AABB,
+    // the running sums of the bin densities of each cell, starting with zero, all zero in cells
+    // without any light
+    cells: Vec<[Float; BINS + 1]>,
+}
+
+// The light recorded by training paths, summed per cell and bin.
+struct GuideTraining {
+    sums: Vec<[Float; BINS]>,
+}
+
+// The density of directions out of one cell of a guide.
+struct GuidePdf<'a> {
+    cumulative: &'a [Float; BINS + 1],
+}
+
+// The guide paths are steered by while tracing, and the training that learns from them if any.
+pub(super) struct Guiding<'a> {
+    guide: &'a PathGuide,
+    training: Option<&'a mut GuideTraining>,
+}
+
+impl PathGuide {
+    fn empty(bounds: AABB) -> Self {
+        GuideTraining::new().into_guide(bounds)
+    }
+    fn cell(bounds: &AABB, point: Point3) -> Option<usize> {
+        if !bounds.contains(point) {
+            return None;
+        }
+        let mut index = 0;
+        for axis in (0..3).rev() {
+            let interval = bounds.axis(axis);
+            let position = (point[axis] - interval.min) / interval.size() * GRID as Float;
+            // `as` saturates and turns NaN from flat boxes into zero
+            index = index * GRID + (position.max(0.0) as usize).min(GRID - 1);
+        }
+        Some(index)
+    }
+    fn at(&self, point: Point3) -> Option<GuidePdf<'_>> {
+        let cumulative = &self.cells[Self::cell(&self.bounds, point)?];
+        (cumulative[BINS] > 0.0).then_some(GuidePdf { cumulative })
+    }
+}
+
+impl GuideTraining {
+    fn new() -> Self {
+        Self {
+            sums: vec![[0.0; BINS]; GRID.pow(3)],
+        }
+    }
+    fn record(&mut self, bounds: &AABB, point: Point3, direction: Vec3, weight: Float) {
+        let Some(cell) = PathGuide::cell(bounds, point) else {
+            return;
+        };
+        if weight.is_finite() && weight > 0.0 {
+            self.sums[cell][bin(direction)] += weight;
+        }
+    }
+    fn merge(mut self, other: &Self) -> Self {
+        for (cell, other) in self.sums.iter_mut().zip(&other.sums) {
+            for (sum, other) in cell.iter_mut().zip(other) {
+                *sum += other;
+            }
+        }
+        self
+    }
+    fn into_guide(self, bounds: AABB) -> PathGuide {
+        let cells = self
+            .sums
+            .iter()
+            .map(|sums| {
+                let total = sums.iter().sum::<Float>();
+                let mut cumulative = [0.0; BINS + 1];
+                if total > 0.0 {
+                    for (bin, sum) in sums.iter().enumerate() {
+                        let density =
+                            (1.0 - UNIFORM_SHARE) * sum / total + UNIFORM_SHARE / BINS as Float;
+                        cumulative[bin + 1] = cumulative[bin] + density;
+                    }
+                }
+                cumulative
+            })
+            .collect();
+        PathGuide { bounds, cells }
+    }
+}
+
+// The bin a direction falls in, split evenly by height and angle around the vertical axis so every
+// bin covers the same solid angle.
+fn bin(direction: Vec3) -> usize {
+    let unit = direction.unit_vector();
+    let row = ((unit.y + 1.0) / 2.0 * ROWS as Float) as usize;
+    let angle = unit.z.atan2(unit.x) + PI;
+    let column = (angle / (2.0 * PI) * COLUMNS as Float) as usize;
+    row.min(ROWS - 1) * COLUMNS + column.min(COLUMNS - 1)
+}
+
+impl Pdf for GuidePdf<'_> {
+    fn value(&self, direction: &Vec3) -> Float {
+        let bin = bin(*direction);
+        let share = (self.cumulative[bin + 1] - self.cumulative[bin]) / self.cumulative[BINS];
+        share * BINS as Float / (4.0 * PI)
+    }
+    fn generate(&self, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        let target = rng.next_float() * self.cumulative[BINS];
+        let bin = (self.cumulative.partition_point(|&sum| sum <= target) - 1).min(BINS - 1);
+        let (row, column) = (bin / COLUMNS, bin % COLUMNS);
+        let y = (row as Float + rng.next_float()) / ROWS as Float * 2.0 - 1.0;
+        let angle = (column as Float + rng.next_float()) / COLUMNS as Float * 2.0 * PI - PI;
+        let radius = (1.0 - y * y).max(0.0).sqrt();
+        Some(Vec3::new(radius * angle.cos(), y, radius * angle.sin()))
+    }
+}
+
+impl Guiding<'_> {
+    // Draws the direction of a bounce from an even blend of the material and the guide, in place of
+    // the `scattered` ray the material drew. Only materials with a `scattering_pdf` are guided, and
+    // only where the guide has seen light. Returns the attenuation for the new direction and the
+    // density it was drawn with, `None` for materials without a density.
+    pub(super) fn redirect(
+        &self,
+        rng: &mut impl RandomSource,
+        material: &Arc<dyn Material>,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        attenuation: Color,
+        scattered: Ray,
+    ) -> (Color, Ray, Option<Float>) {
+        let material_pdf = material.scattering_pdf(ray, hit_record, &scattered);
+        if material_pdf <= 0.0 {
+            return (attenuation, scattered, None);
+        }
+        let Some(guide) = self.guide.at(hit_record.point) else {
+            return (attenuation, scattered, Some(material_pdf));
+        };
+        // the guide always has a direction to draw, it is only there where light was seen
+        let scattered = match (rng.next_float() >= 0.5)
+            .then(|| guide.generate(rng))
+            .flatten()
+        {
+            Some(direction) => Ray::new(hit_record.point, direction, ray.time),
+            None => scattered,
+        };
+        let material_pdf = material.scattering_pdf(ray, hit_record, &scattered);
+        let pdf = 0.5 * material_pdf + 0.5 * guide.value(&scattered.direction);
+        // the attenuation of materials with a density is the share of the light they reflect
+        // divided by the density, which is the same for any direction they might draw
+        (attenuation * (material_pdf / pdf), scattered, Some(pdf))
+    }
+    // Records the light that came in along a guided bounce, drawn with density `pdf`.
+    pub(super) fn record(&mut self, point: Point3, direction: Vec3, incoming: Color, pdf: Float) {
+        if let Some(training) = &mut self.training {
+            training.record(
+                &self.guide.bounds,
+                point,
+                direction,
+                incoming.luminance() / pdf,
+            );
+        }
+    }
+}
+
+impl Camera {
+    // The guide the paths of this camera follow, trained on the first call.
+    pub(super) fn guiding(&self, world: &Box<dyn Hittable>) -> Option<Guiding<'_>> {
+        if !self.path_guiding {
+            return None;
+        }
+        let guide = self.guide.get_or_init(|| self.train_guide(world));
+        Some(Guiding {
+            guide,
+            training: None,
+        })
+    }
+    // The box the guide spans, around the first hits of rays through the pixel centers, or around
+    // the world if the camera sees none of it.
+    fn guide_bounds(&self, world: &dyn Hittable) -> AABB {
+        let mut rng = Rng::from_seed([1, 1]);
+        let mut hits = Vec::new();
+        for j in 0..self.image_height {
+            for i in 0..self.image_width {
+                let (ray, _) = self.get_ray(&mut rng, i as Float, j as Float);
+                if let Some(hit_record) = world.hit(&ray, &self.ray_interval()) {
+                    hits.push((hit_record.t * ray.direction.length(), hit_record.point));
+                }
+            }
+        }
+        if hits.is_empty() {
+            return world.bounding_box().clone();
+        }
+        hits.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let kept = hits.len() - (hits.len() as Float * FAR_SHARE) as usize;
+        let mut seen = AABB::EMPTY;
+        for (_, point) in &hits[..kept] {
+            seen.grow(&AABB::from_vecs(*point, *point));
+        }
+        let grown = |interval: &Interval| interval.expand(2.0 * GUIDE_MARGIN * interval.size());
+        AABB {
+            x: grown(&seen.x),
+            y: grown(&seen.y),
+            z: grown(&seen.z),
+        }
+    }
+    fn train_guide(&self, world: &Box<dyn Hittable>) -> PathGuide {
+        let bounds = self.guide_bounds(world.as_ref());
+        let mut guide = PathGuide::empty(bounds.clone());
+        let rows = (0..self.image_height).collect::<Vec<_>>();
+        let band_size = rows.len().div_ceil(TRAINING_BANDS).max(1);
+        for pass in 0..TRAINING_PASSES {
+            let bands = rows
+                .par_chunks(band_size)
+                .enumerate()
+                .map(|(band, rows)| {
+                    let mut rng = Rng::from_seed([pass as u64 + 1, band as u64 + 1]);
+                    let mut training = GuideTraining::new();
+                    for &j in rows {
+                        for i in 0..self.image_width {
+                            for _ in 0..1 << pass {
+                                let dy = j as Float + rng.next_float_range(-0.5..0.5);
+                                let dx = i as Float + rng.next_float_range(-0.5..0.5);
+                                let (ray, _) = self.get_ray(&mut rng, dx, dy);
+                                let guiding = Guiding {
+                                    guide: &guide,
+                                    training: Some(&mut training),
+                                };
+                                self.trace(
+                                    &mut rng,
+                                    &ray,
+                                    world,
+                                    Some(guiding),
+                                    &mut PixelReuse::default(),
+                                );
+                            }
+                        }
+                    }
+                    training
+                })
+                .collect::<Vec<_>>();
+            let training = bands
+                .iter()
+                .fold(GuideTraining::new(), |training, band| training.merge(band));
+            guide = training.into_guide(bounds.clone());
+        }
+        guide
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        camera::test_camera,
+        hittable::{
+            containers::HittableList,
+            geometry::Sphere,
+            materials::{DiffuseLight, Lambertian},
+        },
+    };
+
+    #[test]
+    fn guides_towards_the_light() {
+        let bounds = AABB::from_vecs(Point3::new(-1.0, -1.0, -1.0), Point3::new(1.0, 1.0, 1.0));
+        let mut training = GuideTraining::new();
+        let mut rng = Rng::from_seed([3, 4]);
+        let light = Vec3::new(1.0, 1.0, 0.0).unit_vector();
+        for _ in 0..10000 {
+            let direction = Vec3::random_on_unit_sphere(&mut rng);
+            let incoming = if direction.dot(&light) > 0.9 {
+                10.0
+            } else {
+                0.1
+            };
+            training.record(&bounds, Point3::zero(), direction, incoming * 4.0 * PI);
+        }
+        let guide = training.into_guide(bounds);
+        let pdf = guide.at(Point3::new(0.1, 0.1, 0.1)).unwrap();
+        let n = 200_000;
+        let integral = (0..n)
+            .map(|_| pdf.value(&Vec3::random_on_unit_sphere(&mut rng)))
+            .sum::<Float>()
+            * 4.0
+            * PI
+            / n as Float;
+        assert!((integral - 1.0).abs() < 0.02);
+        let towards_light = (0..1000)
+            .filter(|_| pdf.generate(&mut rng).unwrap().dot(&light) > 0.8)
+            .count();
+        assert!(towards_light > 600);
+        // cells no light was seen from don't guide, and neither does anything outside the box
+        assert!(guide.at(Point3::new(0.9, 0.9, 0.9)).is_none());
+        assert!(guide.at(Point3::new(0.1, 5.0, 0.1)).is_none());
+    }
+
+    #[test]
+    fn guides_span_what_the_camera_sees() {
+        let mut world = HittableList::default();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, -1000.0, 0.0),
+            1000.0,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 1.0, 0.0),
+            1.0,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        )));
+        let world: Box<dyn Hittable> = Box::new(world);
+        let camera = test_camera(16, 1.0)
+            .lookfrom(Point3::new(0.0, 6.0, 8.0))
+            .lookat(Point3::new(0.0, 0.0, 0.0))
+            .build()
+            .unwrap();
+        let bounds = camera.guide_bounds(world.as_ref());
+        // the ground is two thousand wide, the part in view some tens
+        for axis in [0, 2] {
+            assert!(bounds.axis(axis).size() < 100.0, "{:?}", bounds);
+        }
+        assert!(bounds.contains(Point3::new(0.0, 1.0, 0.0)), "{:?}", bounds);
+    }
+
+    #[test]
+    fn packets_are_guided_too() {
+        let mut world = HittableList::default();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, -1000.0, 0.0),
+            1000.0,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 3.0, 0.0),
+            0.3,
+            Arc::new(DiffuseLight::from(Color::gray(20.0))),
+        )));
+        let world: Box<dyn Hittable> = Box::new(world);
+        let render = |guided: bool, seed: u64| {
+            let builder = test_camera(16, 1.0)
+                .random_sampler(256)
+                .max_ray_depth(2)
+                .background(Color::black())
+                .seed(seed)
+                .lookfrom(Point3::new(0.0, 1.0, 3.0))
+                .lookat(Point3::new(0.0, 0.0, 0.0))
+                .path_guiding(guided)
+                .packet_tracing(true);
+            builder.build().unwrap().render_to_buffer(&world)
+        };
+        // the guide steers the bounces of packets too, which changes the noise but not the light
+        let mean = |buffer: &[Color]| {
+            buffer.iter().map(Color::luminance).sum::<Float>() / buffer.len() as Float
+        };
+        let (traced, guided) = (render(false, 1), render(true, 1));
+        assert!(traced
+            .iter()
+            .zip(&guided)
+            .any(|(a, b)| (*a - *b).length() > 0.0));
+        let traced = (mean(&traced) + mean(&render(false, 2))) / 2.0;
+        let guided = (mean(&guided) + mean(&render(true, 2))) / 2.0;
+        assert!(
+            (guided - traced).abs() < 0.1 * traced,
+            "{} {}",
+            guided,
+            traced
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use super::Camera;
+use crate::{
+    color::Color,
+    float::{consts::PI, Float},
+    random::RandomSource,
+    vec3::{Point3, Vec3},
+};
+
+impl Camera {
+    // Chromatic aberration: a lens bends the colors of light by different amounts, so each color
+    // channel gets its own image. Every camera ray carries a single channel picked at random, its
+    // color is weighed by three so the channels average out to the full image. Red and blue are
+    // magnified by `1 ± lateral` around the center of the image, which fringes edges towards the
+    // corners, and focused at `1 ± longitudinal` times the focus distance, which fringes the out of
+    // focus parts of the image. Green stays as it is. Returns where the ray should aim and the weight
+    // of its color.
+    pub(super) fn aberrate(&self, rng: &mut impl RandomSource, target: Point3) -> (Point3, Color) {
+        let Some((lateral, longitudinal)) = self.chromatic_aberration else {
+            return (target, Color::white());
+        };
+        let channel = ((rng.next_float() * 3.0) as usize).min(2);
+        let shift = channel as Float - 1.0;
+        let viewport_center = self.center - self.focus_distance * self.w;
+        let target = viewport_center + (target - viewport_center) * (1.0 - shift * lateral);
+        let target = self.center + (target - self.center) * (1.0 - shift * longitudinal);
+        let weight = match channel {
+            0 => Color::new(3.0, 0.0, 0.0),
+            1 => Color::new(0.0, 3.0, 0.0),
+            _ => Color::new(0.0, 0.0, 3.0),
+        };
+        (target, weight)
+    }
+    // How much of the light along `direction` reaches the sensor. Natural vignetting is the cos⁴
+    // falloff of light arriving at an angle to the optical axis, from the foreshortening of the
+    // aperture and the sensor and the longer way to the sensor. Mechanical vignetting is the lens
+    // barrel blocking part of the aperture past some angle, modeled as the overlap of the aperture
+    // with an equally large opening that slides off it further off the axis, the cat's eye shape of
+    // out of focus highlights in the corners of photos.
+    pub(super) fn vignette(&self, direction: Vec3) -> Float {
+        let cos = -direction.unit_vector().dot(&self.w);
+        let mut weight = 1.0;
+        if self.vignetting {
+            weight *= cos.powi(4);
+        }
+        if let Some(angle) = self.mechanical_vignetting {
+            let start = angle.to_radians().tan();
+            let tan = (1.0 - cos * cos).max(0.0).sqrt() / cos;
+            let offset = (2.0 * (tan - start) / start).clamp(0.0, 2.0);
+            let overlap =
+                2.0 * (offset / 2.0).acos() - offset / 2.0 * (4.0 - offset * offset).sqrt();
+            weight *= overlap / PI;
+        }
+        weight
+    }
+}
+
+// The point of the unit disk for the point (`u`, `v`) of the unit square, by Shirley and Chiu's
+// concentric mapping, which takes squares around the center of the square to circles around the
+// center of the disk without tearing or squashing them. Evenly spread points of the square stay
+// evenly spread on the disk, unlike with rejection sampling, which draws any number of them.
+pub(super) fn concentric_disk(u: Float, v: Float) -> (Float, Float) {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (radius, angle) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    (radius * angle.cos(), radius * angle.sin())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::{
+        sampler::{SampleStream, Sequence},
+        test_camera,
+    };
+    use crate::random::Rng;
+
+    #[test]
+    fn red_is_magnified_more_than_blue() {
+        let camera = test_camera(32, 1.0)
+            .chromatic_aberration((0.01, 0.0))
+            .build()
+            .unwrap();
+        let viewport_center = camera.center - camera.focus_distance * camera.w;
+        let corner = camera.pixel00_loc;
+        let mut rng = Rng::from_seed([3, 4]);
+        let mut distances = [0.0; 3];
+        let mut total = Color::black();
+        for _ in 0..300 {
+            let (target, weight) = camera.aberrate(&mut rng, corner);
+            let channel = [weight.r, weight.g, weight.b].iter().position(|&w| w > 0.0);
+            distances[channel.unwrap()] = (target - viewport_center).length();
+            total += weight / 300.0;
+        }
+        let green = (corner - viewport_center).length();
+        assert!(distances[0] > green && distances[2] < green);
+        assert!((distances[1] - green).abs() < 1e-9);
+        assert!((total - Color::white()).length() < 0.3);
+    }
+
+    #[test]
+    fn light_falls_off_towards_the_corners() {
+        let builder = || test_camera(32, 1.0).field_of_view(90.0);
+        let camera = builder().vignetting(true).build().unwrap();
+        let axis = -camera.w;
+        assert!((camera.vignette(axis) - 1.0).abs() < 1e-6);
+        // 45 degrees off the axis, at the edge of a 90 degree field of view
+        let edge = axis + camera.u;
+        assert!((camera.vignette(edge) - 0.25).abs() < 1e-6);
+
+        let camera = builder().mechanical_vignetting(30.0).build().unwrap();
+        assert_eq!(camera.vignette(axis), 1.0);
+        let inside = axis + camera.u * 0.5;
+        assert!((camera.vignette(inside) - 1.0).abs() < 1e-6);
+        assert!(camera.vignette(edge) < 0.5);
+        assert!(camera.vignette(axis + camera.u * 3.0) < 1e-6);
+        for angle in [0.0, 90.0, Float::NAN] {
+            assert!(builder().mechanical_vignetting(angle).build().is_err());
+        }
+    }
+
+    #[test]
+    fn the_disk_keeps_the_square_evenly_spread() {
+        let points = (0..16 * 16)
+            .map(|i| {
+                concentric_disk(
+                    (i % 16) as Float / 16.0 + 1.0 / 32.0,
+                    (i / 16) as Float / 16.0 + 1.0 / 32.0,
+                )
+            })
+            .collect::<Vec<_>>();
+        let radius = |&(x, y): &(Float, Float)| (x * x + y * y).sqrt();
+        assert!(points.iter().all(|point| radius(point) < 1.0));
+        // the middle quarter of the square is the middle quarter of the disk, by area
+        assert_eq!(
+            points.iter().filter(|point| radius(point) < 0.5).count(),
+            64
+        );
+        let right = points.iter().filter(|&&(x, _)| x > 0.0).count();
+        let top = points.iter().filter(|&&(_, y)| y > 0.0).count();
+        assert_eq!((right, top), (128, 128));
+    }
+
+    #[test]
+    fn lens_and_shutter_are_spread_with_the_pixel() {
+        let camera = test_camera(32, 1.0)
+            .sobol_sampler(16)
+            .defocus_angle(2.0)
+            .build()
+            .unwrap();
+        let mut radii = Vec::new();
+        let mut times = Vec::new();
+        for sample in 0..16 {
+            let mut stream = SampleStream::new(Sequence::Sobol, 9, sample, 16);
+            let dy = stream.next_float_range(-0.5..0.5);
+            let dx = stream.next_float_range(-0.5..0.5);
+            let (ray, _) = camera.get_ray(&mut stream, dx + 16.0, dy + 16.0);
+            let offset = ray.origin - camera.center;
+            radii.push(offset.length() / camera.defocus_disk_u.length());
+            times.push((ray.time * 16.0) as usize);
+        }
+        // the points on the lens are a 4 by 4 grid of the square mapped to the disk, so exactly a
+        // quarter of them are in the middle quarter of it, and every time is in a 16th of its own
+        assert_eq!(radii.iter().filter(|&&radius| radius < 0.5).count(), 4);
+        times.sort();
+        assert_eq!(times, (0..16).collect::<Vec<_>>());
+    }
+}
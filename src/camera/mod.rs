@@ -1,44 +1,223 @@
+use std::borrow::Cow;
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{BufWriter, Write};
-use std::ops::BitXor;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc::{sync_channel, SyncSender};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
-use std::time::Instant;
+use std::time::Duration;
 
-use crate::random::Rng;
+use ::image::{Rgb, Rgb32FImage, RgbImage};
+use tracing::{debug, info, info_span, warn};
+use web_time::Instant;
+
+use self::aov::{Layer, TileUpdate};
+use self::aperture::ApertureMask;
+use self::builder::CameraBuilder;
+use self::bvh_view::BvhView;
+use self::color_space::{ColorSpace, Primaries};
+use self::environment::{Environment, EnvironmentMap};
+use self::fog::Fog;
+use self::guiding::{Guiding, PathGuide};
+use self::image::ImageSpec;
+use self::path::PathState;
+use self::post::{PostProcess, PostStage};
+use self::probe::ReflectionProbe;
+use self::render_layers::RenderLayer;
+use self::restir::{Lights, PixelReservoirs, PixelReuse};
+use self::sampler::{SampleStream, Sequence};
+use self::settings::RenderSettings;
+use self::shutter::Shutter;
+use self::sky::SkyModel;
+use self::tiff::TiffLayout;
+use self::tiles::{AdaptiveTiles, TileOrder, TileWorker};
+use crate::network::Coordinator;
+use crate::random::{RandomSource, Rng};
 use crate::{
-    color::Color,
-    hittable::Hittable,
+    color::{Color, ColorSum},
+    error::Result,
+    float::Float,
+    hittable::{containers::Accelerator, materials::Material, HitRecord, Hittable, PACKET_SIZE},
+    interval::Interval,
     ray::Ray,
+    telemetry::{self, Counter, Tile},
+    units::Units,
     vec3::{Point3, Vec3},
 };
 
+pub mod aov;
+pub mod aperture;
+mod bias;
+pub mod bloom;
+pub mod buffer;
 pub mod builder;
+pub mod bvh_view;
+pub mod color_space;
+pub mod config;
+pub mod environment;
+pub mod events;
+pub mod explore;
+pub mod fog;
+mod guiding;
 pub mod image;
+mod lens;
+mod path;
+pub mod post;
+pub mod probe;
+pub mod render_layers;
+mod restir;
+mod sampler;
+pub mod settings;
+pub mod shutter;
+pub mod sky;
+mod stereo;
+pub mod tiff;
+pub mod tiles;
 
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PixelSampler {
     Uniform(usize),
     Random(usize),
+    // Stratified in every dimension of the path rather than only the position in the pixel, see
+    // `SampleStream`.
+    Padded(usize),
+    // Owen scrambled Sobol points in every dimension of the path, at their best with a power of two
+    // samples.
+    Sobol(usize),
+}
+
+// Lets the preview, or whatever else embeds the renderer, pause, resume and cancel a render in
+// flight. The render threads check it before every tile and between the pixels of a tile, a
+// cancelled render stops handing out tiles and returns what it has so far. It also keeps track of
+// the tiles being rendered, for the preview to outline.
+#[derive(Debug, Default)]
+pub struct RenderControl {
+    paused: AtomicBool,
+    cancelled: AtomicBool,
+    in_flight: Mutex<Vec<Tile>>,
+}
+
+impl RenderControl {
+    pub fn toggle_pause(&self) -> bool {
+        !self.paused.fetch_xor(true, Ordering::Relaxed)
+    }
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+    // Clears a cancellation so the next render can go ahead.
+    pub fn restart(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+    }
+    pub fn start_tile(&self, tile: ((usize, usize), (usize, usize))) {
+        self.in_flight.lock().unwrap().push(tile);
+    }
+    pub fn finish_tile(&self, tile: ((usize, usize), (usize, usize))) {
+        self.in_flight
+            .lock()
+            .unwrap()
+            .retain(|&other| other != tile);
+    }
+    // The top left corners and sizes of the tiles being rendered right now.
+    pub fn tiles_in_flight(&self) -> Vec<((usize, usize), (usize, usize))> {
+        self.in_flight.lock().unwrap().clone()
+    }
+    // Blocks the calling thread until the render is resumed or cancelled.
+    pub fn wait_while_paused(&self) {
+        while self.is_paused() && !self.is_cancelled() {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+// What the preview asks of the render thread once the render is done.
+#[derive(Debug, Clone, Copy)]
+pub enum RenderRequest {
+    // Render the region, given by its top left corner and size, again with more samples.
+    Refine(((usize, usize), (usize, usize))),
+    // Render the whole image again with new settings.
+    Rerender(RenderSettings),
+}
+
+impl PixelSampler {
+    pub fn samples_per_pixel(&self) -> usize {
+        match self {
+            PixelSampler::Uniform(samples_sqrt) => samples_sqrt.pow(2),
+            PixelSampler::Random(samples)
+            | PixelSampler::Padded(samples)
+            | PixelSampler::Sobol(samples) => *samples,
+        }
+    }
 }
 
 pub struct Camera {
-    aspect_ratio: f64,
+    image_spec: ImageSpec,
+    #[allow(unused)]
+    aspect_ratio: Float,
     pub image_width: usize,
     pixel_sampler: PixelSampler,
     depth: usize,
+    packet_tracing: bool,
+    tile_order: TileOrder,
+    aovs: bool,
+    background: Option<Color>,
+    clay: Option<Arc<dyn Material>>,
+    depth_range: Option<(Float, Float)>,
+    tile_heatmap: bool,
+    sample_heatmap: bool,
+    bvh_view: Option<BvhView>,
+    accelerator: Accelerator,
+    render_layers: Vec<RenderLayer>,
+    shutter: Shutter,
+    shutter_interval: Interval,
+    chromatic_aberration: Option<(Float, Float)>,
+    vignetting: bool,
+    mechanical_vignetting: Option<Float>,
+    post_process: Vec<PostStage>,
+    color_space: ColorSpace,
+    float_primaries: Primaries,
+    white_balance: Option<Float>,
+    exposure_bracket: Vec<Float>,
+    aperture_mask: Option<(String, ApertureMask)>,
+    environment: Option<(Environment, EnvironmentMap)>,
+    sky: Option<SkyModel>,
+    fog: Option<Fog>,
+    path_guiding: bool,
+    guide: OnceLock<PathGuide>,
+    light_candidates: Option<usize>,
+    light_mixture: bool,
+    lights: OnceLock<Lights>,
+    // the reservoirs of the pixels of the last render, see `Camera::prepare_reservoirs`
+    pixel_reservoirs: Mutex<Option<Arc<PixelReservoirs>>>,
+    ray_epsilon: Float,
+    normal_offset: Float,
+    shadow_bias: Float,
+    units: Option<Units>,
+    seed: u64,
+    save_buffer: bool,
+    hdr_output: bool,
+    tiff_layout: Option<TiffLayout>,
+    output: String,
+    omnidirectional_stereo: Option<Float>,
+    reflection_probe: Option<ReflectionProbe>,
 
-    field_of_view: f64,
+    field_of_view: Float,
     lookfrom: Point3,
     lookat: Point3,
     up_vector: Vec3,
 
-    defocus_angle: f64,
-    focus_distance: f64,
+    defocus_angle: Float,
+    focus_distance: Float,
 
     pub image_height: usize,
-    center: Point3,
+    pub(crate) center: Point3,
     pixel00_loc: Point3,
     pixel_delta_u: Vec3,
     pixel_delta_v: Vec3,
@@ -51,97 +230,513 @@ pub struct Camera {
 }
 
 impl Camera {
+    // Renders the image and writes it to the output file. Cancelling the render through `control`
+    // returns promptly with the finished tiles, the unfinished ones are left black.
     pub fn render(
         &self,
         world: &Box<dyn Hittable>,
-        sender: SyncSender<((usize, usize), (usize, usize), Vec<Color>)>,
-    ) {
-        let start_time = Instant::now();
-        let pixel_count = self.image_width * self.image_height;
-        let mut image_buffer = vec![Color::black(); pixel_count];
-
-        let threads = usize::from(thread::available_parallelism().unwrap());
-        let rect = (32, 32);
-        let rect_count = self.image_height.div_ceil(rect.0) * self.image_width.div_ceil(rect.1);
-        thread::scope(|s| {
-            let get_parameters = |index: usize| {
-                let bx = self.image_width.div_ceil(rect.1);
-                let rect_y = index / bx;
-                let rect_x = index % bx;
-                let top_left = (rect_y * rect.0, rect_x * rect.1);
-                let rect = (
-                    rect.0.min(self.image_height - top_left.0),
-                    rect.1.min(self.image_width - top_left.1),
-                );
-
-                return (top_left, rect, world);
-            };
+        sender: SyncSender<TileUpdate>,
+        control: &RenderControl,
+    ) -> Result<Vec<Color>> {
+        self.render_distributed(world, sender, None, control)
+    }
 
-            let shared_index = Arc::new(AtomicUsize::new(0));
-
-            let (worker_sender, delegator_receiver) = sync_channel(1);
-            for _thread in 0..threads {
-                let shared_index = shared_index.clone();
-                let worker_sender = worker_sender.clone();
-                s.spawn(move || {
-                    loop {
-                        let idx = shared_index.fetch_add(1, Ordering::SeqCst);
-                        if idx >= rect_count {
-                            break;
-                        }
-                        let (top_left, rect, world) = get_parameters(idx);
-                        let result = self.render_rect(top_left, rect, world);
-                        if let Err(_) = worker_sender.send((top_left, rect, result)) {
-                            break;
-                        }
-                    }
-                    drop(worker_sender);
-                });
+    // Renders locally while also handing tiles out to the remote workers of `coordinator`.
+    pub fn render_distributed(
+        &self,
+        world: &Box<dyn Hittable>,
+        sender: SyncSender<TileUpdate>,
+        coordinator: Option<&Coordinator>,
+        control: &RenderControl,
+    ) -> Result<Vec<Color>> {
+        let _span = info_span!(
+            "render",
+            width = self.image_width,
+            height = self.image_height
+        )
+        .entered();
+        let start_time = Instant::now();
+        // only the tiles of this render go into the heatmap
+        telemetry::take_tiles();
+        let coordinator = coordinator.filter(|_| {
+            let local = !self.render_layers.is_empty();
+            if local {
+                warn!("remote workers don't render layers, rendering locally");
             }
+            !local
+        });
+        let image_buffer = vec![Color::black(); self.image_width * self.image_height];
+        let region = ((0, 0), (self.image_height, self.image_width));
+        let (image_buffer, layer_buffers) = self.render_region(
+            world,
+            sender.clone(),
+            coordinator,
+            control,
+            image_buffer,
+            region,
+        );
+        self.send_post_processed(&image_buffer, &sender);
+        telemetry::record_stage("render", start_time.elapsed());
+        if control.is_cancelled() {
+            info!("cancelled, saving the finished tiles");
+        }
+        telemetry::time_stage("write", || self.write_buffer_to_file(&image_buffer))?;
+        if self.aovs {
+            telemetry::time_stage("write AOVs", || self.write_aovs(world))?;
+        }
+        if self.tile_heatmap {
+            self.write_tile_heatmap(&telemetry::take_tiles())?;
+        }
+        if let Some(view) = self.bvh_view {
+            self.write_bvh_view(world, &image_buffer, view)?;
+        }
+        if !self.render_layers.is_empty() {
+            self.write_render_layers(&layer_buffers)?;
+        }
+        if self.save_buffer {
+            self.write_render_buffer(&image_buffer, control.is_cancelled())?;
+        }
+        if self.hdr_output {
+            self.write_hdr_output(&image_buffer)?;
+        }
+        if let Some(layout) = self.tiff_layout {
+            self.write_tiff_output(&image_buffer, layout)?;
+        }
+        Ok(image_buffer)
+    }
 
-            for i in 0..rect_count {
-                let (top_left, rect, result) = delegator_receiver.recv().unwrap();
-                for dy in 0..rect.0 {
-                    for dx in 0..rect.1 {
-                        let index = ((top_left.0 + dy) * self.image_width) + (top_left.1 + dx);
-                        image_buffer[index] = result[(dy * rect.1) + dx];
+    // Serves the requests of the preview until it hangs up. Refined regions are rendered at four
+    // times the samples per pixel and merged into `image_buffer`, new settings render the whole
+    // image again. The output file is rewritten after every request, cancelled ones included, and
+    // so is the sample heatmap if enabled.
+    pub fn serve_requests(
+        &self,
+        world: &Box<dyn Hittable>,
+        mut image_buffer: Vec<Color>,
+        sender: SyncSender<TileUpdate>,
+        requests: Receiver<RenderRequest>,
+        control: &RenderControl,
+    ) -> Result<()> {
+        let mut camera = self.to_builder().build()?;
+        let mut sample_counts = vec![camera.sample_count(); image_buffer.len()];
+        for request in requests {
+            control.restart();
+            debug!(?request, "serving a render request");
+            let start_time = Instant::now();
+            image_buffer = match request {
+                RenderRequest::Refine(region) => {
+                    let refined = camera.with_more_samples()?;
+                    // the light samples carry over from one refinement to the next
+                    refined.follow(&camera);
+                    let image_buffer = refined.render_region(
+                        world,
+                        sender.clone(),
+                        None,
+                        control,
+                        image_buffer,
+                        region,
+                    );
+                    let image_buffer = image_buffer.0;
+                    camera.follow(&refined);
+                    // a cancelled refinement leaves some of the region at the old count
+                    if !control.is_cancelled() {
+                        let ((top, left), (height, width)) = region;
+                        for y in top..top + height {
+                            sample_counts[y * camera.image_width + left..][..width]
+                                .fill(refined.sample_count());
+                        }
                     }
+                    image_buffer
                 }
-                if let Err(_) = sender.send((top_left, rect, result)) {
-                    println!("cancelled");
-                    return;
+                RenderRequest::Rerender(settings) => {
+                    camera = settings.apply(camera.to_builder()).build()?;
+                    sample_counts.fill(camera.sample_count());
+                    camera.render_tiles(world, sender.clone(), None, control)
                 }
+            };
+            debug!(
+                seconds = start_time.elapsed().as_secs_f64(),
+                "request served"
+            );
+            camera.send_post_processed(&image_buffer, &sender);
+            camera.write_buffer_to_file(&image_buffer)?;
+            if camera.sample_heatmap {
+                camera.write_sample_heatmap(&sample_counts)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn with_more_samples(&self) -> Result<Camera> {
+        let pixel_sampler = match self.pixel_sampler {
+            PixelSampler::Uniform(samples_sqrt) => PixelSampler::Uniform((samples_sqrt * 2).pow(2)),
+            PixelSampler::Random(samples) => PixelSampler::Random(samples * 4),
+            PixelSampler::Padded(samples) => PixelSampler::Padded(samples * 4),
+            PixelSampler::Sobol(samples) => PixelSampler::Sobol(samples * 4),
+        };
+        CameraBuilder {
+            pixel_sampler: Some(pixel_sampler),
+            ..self.to_builder()
+        }
+        .build()
+    }
+
+    // Renders the whole image tile by tile, sending every finished tile to `sender` in linear color,
+    // gamma correction is left to the output. The AOV layers of each tile follow it if enabled. The
+    // render is cancelled through `control` or by the preview hanging up, the tiles that weren't
+    // finished by then are left black.
+    pub(crate) fn render_tiles(
+        &self,
+        world: &Box<dyn Hittable>,
+        sender: SyncSender<TileUpdate>,
+        coordinator: Option<&Coordinator>,
+        control: &RenderControl,
+    ) -> Vec<Color> {
+        let image_buffer = vec![Color::black(); self.image_width * self.image_height];
+        let region = ((0, 0), (self.image_height, self.image_width));
+        self.render_region(world, sender, coordinator, control, image_buffer, region)
+            .0
+    }
+
+    // Like `render_tiles` but only renders the `region`, given by its top left corner and size, into
+    // `image_buffer`. Also returns the buffers of the render layers, empty unless they are set.
+    pub(crate) fn render_region(
+        &self,
+        world: &Box<dyn Hittable>,
+        sender: SyncSender<TileUpdate>,
+        coordinator: Option<&Coordinator>,
+        control: &RenderControl,
+        image_buffer: Vec<Color>,
+        region: ((usize, usize), (usize, usize)),
+    ) -> (Vec<Color>, Vec<Vec<Color>>) {
+        // the guide is trained up front rather than by whichever tile needs it first
+        self.guiding(world);
+        self.prepare_reservoirs(world);
+        let (region_top_left, size) = region;
+        let rect = (64, 64);
+        let columns = size.1.div_ceil(rect.1);
+        let rows = size.0.div_ceil(rect.0);
+        let get_parameters = |index: usize| {
+            let rect_y = index / columns;
+            let rect_x = index % columns;
+            let offset = (rect_y * rect.0, rect_x * rect.1);
+            let top_left = (region_top_left.0 + offset.0, region_top_left.1 + offset.1);
+            let rect = (rect.0.min(size.0 - offset.0), rect.1.min(size.1 - offset.1));
+
+            return (top_left, rect);
+        };
+
+        let queue = self
+            .tile_order
+            .order(columns, rows)
+            .into_iter()
+            .map(get_parameters)
+            .collect::<VecDeque<_>>();
+        let tile_count = queue.len();
+        let layer_buffers = match self.render_layers.len() {
+            0 => Vec::new(),
+            layers => vec![vec![Color::black(); image_buffer.len()]; layers + 1],
+        };
+        let worker = TileWorker {
+            camera: self,
+            world,
+            layer_buffers: Mutex::new(layer_buffers),
+            image_buffer: Mutex::new(image_buffer),
+            sender,
+            tiles: AdaptiveTiles::new(
+                (self.image_height, self.image_width),
+                8,
+                Duration::from_millis(20),
+            ),
+            queue: Mutex::new(queue),
+            control,
+        };
+        thread::scope(|s| {
+            if let Some(coordinator) = coordinator {
+                coordinator.serve(s, &worker);
             }
-            self.write_buffer_to_file(&image_buffer).unwrap();
+            // the FIFO scope hands the tiles out in order and lets idle threads steal split tiles
+            rayon::scope_fifo(|scope| {
+                for _ in 0..tile_count {
+                    worker.spawn_queued(scope);
+                }
+            });
         });
+        (
+            worker.image_buffer.into_inner().unwrap(),
+            worker.layer_buffers.into_inner().unwrap(),
+        )
     }
 
-    fn render_rect(
+    pub(crate) fn render_tile(
         &self,
         top_left: (usize, usize),
         rect: (usize, usize),
         world: &Box<dyn Hittable>,
+        control: &RenderControl,
     ) -> Vec<Color> {
-        let mut rng = Rng::from_seed([top_left.0 as u64 + 1, top_left.1 as u64 + 1]);
-        let mut rng = rng.short_jump();
+        self.render_tile_layers(top_left, rect, world, control).0
+    }
+
+    // Renders a tile along with the tiles of its render layers, if any. Packet tracing doesn't
+    // split the samples into layers, so it is only used without them.
+    pub(crate) fn render_tile_layers(
+        &self,
+        top_left: (usize, usize),
+        rect: (usize, usize),
+        world: &Box<dyn Hittable>,
+        control: &RenderControl,
+    ) -> (Vec<Color>, Vec<Vec<Color>>) {
+        if self.packet_tracing && self.render_layers.is_empty() {
+            (
+                self.render_rect_packets(top_left, rect, world, control),
+                Vec::new(),
+            )
+        } else {
+            self.render_rect(top_left, rect, world, control)
+        }
+    }
+
+    // The random source of the pixel at row `pixel.0` and column `pixel.1`, its own stream of the
+    // streams of the seed. Every pixel draws numbers no other pixel does, and the same ones however
+    // the image is split into tiles and whichever thread renders it, so renders come out the same
+    // every time. Remote workers are sent the whole camera config, seed and all, so their pixels
+    // match too, see `Coordinator`. Renders with different seeds are independent.
+    fn pixel_rng(&self, pixel: (usize, usize)) -> Rng {
+        let index = pixel.0 * self.image_width + pixel.1;
+        Rng::new().stream(self.seed, index as u32)
+    }
+
+    fn render_rect(
+        &self,
+        top_left: (usize, usize),
+        rect: (usize, usize),
+        world: &Box<dyn Hittable>,
+        control: &RenderControl,
+    ) -> (Vec<Color>, Vec<Vec<Color>>) {
         let (height, width) = rect;
         let mut result = vec![Color::black(); rect.0 * rect.1];
+        let layer_count = match self.render_layers.len() {
+            0 => 0,
+            layers => layers + 1,
+        };
+        let mut layers = vec![vec![Color::black(); rect.0 * rect.1]; layer_count];
+        let mut pixel_layers = vec![Color::black(); layer_count];
+        let pixel_reservoirs = self.pixel_reservoirs(world);
         for j in 0..height {
             for i in 0..width {
-                let mut rng = rng.short_jump().clone();
-                let color = self.sample_pixel(&mut rng, top_left.0 + j, top_left.1 + i, world);
-
-                let gamma_corrected = color.gamma_corrected(2.2);
+                if control.is_cancelled() {
+                    return (result, layers);
+                }
+                let mut rng = self.pixel_rng((top_left.0 + j, top_left.1 + i));
+                let color = self.sample_pixel(
+                    &mut rng,
+                    top_left.0 + j,
+                    top_left.1 + i,
+                    world,
+                    pixel_reservoirs.as_deref(),
+                    &mut pixel_layers,
+                );
 
                 let index = (j * width) + i;
-                result[index] = gamma_corrected;
+                result[index] = color;
+                for (layer, color) in layers.iter_mut().zip(&pixel_layers) {
+                    layer[index] = *color;
+                }
+            }
+        }
+        return (result, layers);
+    }
+
+    // Wavefront variant of `render_rect`: every pixel of the rect advances one bounce at a time and
+    // the rays of each bounce are intersected with the world in coherent packets. Cancellation is
+    // checked between the samples, as every pixel is in flight at once.
+    fn render_rect_packets(
+        &self,
+        top_left: (usize, usize),
+        rect: (usize, usize),
+        world: &Box<dyn Hittable>,
+        control: &RenderControl,
+    ) -> Vec<Color> {
+        let (height, width) = rect;
+        // every pixel draws from its own stream like in `render_rect`
+        let mut rngs = (0..height * width)
+            .map(|index| self.pixel_rng((top_left.0 + index / width, top_left.1 + index % width)))
+            .collect::<Vec<_>>();
+        let samples = self.pixel_sampler.samples_per_pixel();
+        let mut accumulators = vec![ColorSum::new(); height * width];
+        let lights = self.lights(world);
+        let mixed_lights = self.mixed_lights(world);
+        let guiding = self.guiding(world);
+        let pixel_reservoirs = self.pixel_reservoirs(world);
+        let mut reuse = (0..height * width)
+            .map(|index| {
+                let pixel = (top_left.0 + index / width, top_left.1 + index % width);
+                PixelReuse::new(pixel_reservoirs.as_deref(), pixel)
+            })
+            .collect::<Vec<_>>();
+
+        for sample in 0..samples {
+            if control.is_cancelled() {
+                break;
+            }
+            let mut pixels = Vec::with_capacity(height * width);
+            let mut rays = Vec::with_capacity(height * width);
+            let mut paths = Vec::with_capacity(height * width);
+            for j in 0..height {
+                for i in 0..width {
+                    let rng = &mut rngs[j * width + i];
+                    let (oy, ox) = self.subpixel_offset(rng, sample);
+                    let dy = (top_left.0 + j) as Float + oy;
+                    let dx = (top_left.1 + i) as Float + ox;
+                    pixels.push((j * width) + i);
+                    let (ray, weight) = self.get_ray(rng, dx, dy);
+                    rays.push(ray);
+                    paths.push(PathState::new(weight));
+                }
+            }
+
+            for _depth in 0..self.depth {
+                if rays.is_empty() {
+                    break;
+                }
+                let mut records: Vec<Option<HitRecord>> = rays.iter().map(|_| None).collect();
+                for (ray_packet, record_packet) in rays
+                    .chunks(PACKET_SIZE)
+                    .zip(records.chunks_mut(PACKET_SIZE))
+                {
+                    world.hit_packet(ray_packet, &self.ray_interval(), record_packet);
+                }
+
+                let mut next_pixels = Vec::with_capacity(rays.len());
+                let mut next_rays = Vec::with_capacity(rays.len());
+                let mut next_paths = Vec::with_capacity(rays.len());
+                for (((pixel, ray), mut path), record) in
+                    pixels.into_iter().zip(rays).zip(paths).zip(records)
+                {
+                    let rng = &mut rngs[pixel];
+                    let Some(hit_record) = record else {
+                        self.fog_segment(&ray, Float::INFINITY, &mut path);
+                        path.add(self.background(&ray));
+                        accumulators[pixel] += path.radiance;
+                        continue;
+                    };
+                    self.fog_segment(&ray, hit_record.t, &mut path);
+                    if !path.already_lit_by(lights, hit_record.object_id) {
+                        path.add(hit_record.material.emitted(&hit_record, path.lit_object));
+                    }
+                    let material = self.material(&hit_record);
+                    if let Some((attenuation, scattered)) =
+                        material.scatter_through(rng, &ray, &hit_record, &mut path.media)
+                    {
+                        telemetry::count(Counter::ScatteredRays);
+                        let lights = lights.filter(|_| {
+                            material.scattering_pdf(&ray, &hit_record, &scattered) > 0.0
+                        });
+                        if let Some(lights) = lights {
+                            let reuse = (path.bounce == 0).then_some(&mut reuse[pixel]);
+                            path.add(self.direct_light(
+                                rng,
+                                world,
+                                lights,
+                                &ray,
+                                &hit_record,
+                                reuse,
+                            ));
+                        }
+                        let redirected = match (&guiding, mixed_lights) {
+                            (Some(guiding), _) => Some(guiding.redirect(
+                                rng,
+                                material,
+                                &ray,
+                                &hit_record,
+                                attenuation,
+                                scattered,
+                            )),
+                            (None, Some(mixed_lights)) => mixed_lights.mix_in(
+                                rng,
+                                material.as_ref(),
+                                &ray,
+                                &hit_record,
+                                attenuation,
+                                scattered,
+                            ),
+                            (None, None) => Some((attenuation, scattered, None)),
+                        };
+                        // the density of guided bounces is only kept for training the guide,
+                        // which follows single paths through `trace`
+                        let Some((attenuation, scattered, _)) = redirected else {
+                            accumulators[pixel] += path.radiance;
+                            continue;
+                        };
+                        path.scatter(attenuation, hit_record.object_id);
+                        path.lights_sampled = lights.is_some();
+                        if path.survives_roulette(rng) {
+                            next_pixels.push(pixel);
+                            next_rays.push(self.leave_surface(&hit_record, scattered));
+                            next_paths.push(path);
+                            continue;
+                        }
+                    }
+                    accumulators[pixel] += path.radiance;
+                }
+                pixels = next_pixels;
+                rays = next_rays;
+                paths = next_paths;
+            }
+            // the paths still going at the deepest bounce end there
+            for (pixel, path) in pixels.into_iter().zip(paths) {
+                accumulators[pixel] += path.radiance;
+            }
+        }
+        return accumulators
+            .into_iter()
+            .map(|sum| sum.sum() / samples as Float)
+            .collect();
+    }
+
+    // The offset of the `sample`th sample from the pixel center. Packets draw everything from the
+    // plain stream of each pixel, so the padded and Sobol samplers are random here.
+    fn subpixel_offset(&self, rng: &mut Rng, sample: usize) -> (Float, Float) {
+        match self.pixel_sampler {
+            PixelSampler::Uniform(samples_sqrt) => {
+                let subpixel_interval = 1.0 / samples_sqrt as Float;
+                let subpixel_offset = subpixel_interval / 2.0 + 0.5;
+                let yi = sample / samples_sqrt;
+                let xi = sample % samples_sqrt;
+                (
+                    yi as Float * subpixel_interval - subpixel_offset,
+                    xi as Float * subpixel_interval - subpixel_offset,
+                )
             }
+            PixelSampler::Random(_) | PixelSampler::Padded(_) | PixelSampler::Sobol(_) => (
+                rng.next_float_range(-0.5..0.5),
+                rng.next_float_range(-0.5..0.5),
+            ),
         }
-        return result;
     }
 
-    fn sample_pixel(&self, rng: &mut Rng, j: usize, i: usize, world: &Box<dyn Hittable>) -> Color {
-        let mut accumulator = Color::black();
+    // Samples the pixel at row `j` and column `i`. Each sample is also added to its render layer in
+    // `layers`, which holds one color per layer or is empty.
+    fn sample_pixel(
+        &self,
+        rng: &mut Rng,
+        j: usize,
+        i: usize,
+        world: &Box<dyn Hittable>,
+        pixel_reservoirs: Option<&PixelReservoirs>,
+        layers: &mut [Color],
+    ) -> Color {
+        let mut accumulator = ColorSum::new();
+        let mut layer_accumulators = vec![ColorSum::new(); layers.len()];
+        // the samples of the pixel reuse the light samples of the one before and of the pixels around
+        let reuse = &mut PixelReuse::new(pixel_reservoirs, (j, i));
+        let mut add = |(color, object_id): (Color, u32)| {
+            accumulator += color;
+            if let Some(layer) = layer_accumulators.get_mut(self.render_layer_of(object_id)) {
+                *layer += color;
+            }
+        };
         // let mut rngx = Rng::from_seed([j as u64 + 1, i as u64 + 1]);
         // //let mut rngx = Rng::new();
         // let rng = &mut rngx;
@@ -150,79 +745,334 @@ impl Camera {
             PixelSampler::Uniform(samples_sqrt) => {
                 for yi in 0..samples_sqrt {
                     for xi in 0..samples_sqrt {
-                        let subpixel_interval = 1.0 / samples_sqrt as f64;
+                        let subpixel_interval = 1.0 / samples_sqrt as Float;
                         let subpixel_offset = subpixel_interval / 2.0 + 0.5;
 
-                        let dy = j as f64 + yi as f64 * subpixel_interval - subpixel_offset;
-                        let dx = i as f64 + xi as f64 * subpixel_interval - subpixel_offset;
+                        let dy = j as Float + yi as Float * subpixel_interval - subpixel_offset;
+                        let dx = i as Float + xi as Float * subpixel_interval - subpixel_offset;
 
-                        accumulator += self.sample_point(rng, dx, dy, world);
+                        add(self.sample_point(rng, dx, dy, world, reuse));
                     }
                 }
-                accumulator / samples_sqrt.pow(2) as f64
             }
             PixelSampler::Random(samples) => {
                 for _ in 0..samples {
-                    let dy = j as f64 + rng.next_f64_range(-0.5..0.5);
-                    let dx = i as f64 + rng.next_f64_range(-0.5..0.5);
+                    let dy = j as Float + rng.next_float_range(-0.5..0.5);
+                    let dx = i as Float + rng.next_float_range(-0.5..0.5);
+
+                    add(self.sample_point(rng, dx, dy, world, reuse));
+                }
+            }
+            PixelSampler::Padded(samples) | PixelSampler::Sobol(samples) => {
+                let sequence = match self.pixel_sampler {
+                    PixelSampler::Sobol(_) => Sequence::Sobol,
+                    _ => Sequence::Stratified,
+                };
+                let seed = rng.next_u64();
+                for sample in 0..samples {
+                    let mut stream = SampleStream::new(sequence, seed, sample, samples);
+                    let dy = j as Float + stream.next_float_range(-0.5..0.5);
+                    let dx = i as Float + stream.next_float_range(-0.5..0.5);
 
-                    accumulator += self.sample_point(rng, dx, dy, world);
+                    add(self.sample_point(&mut stream, dx, dy, world, reuse));
                 }
-                accumulator / samples as f64
             }
         }
+        let samples = self.pixel_sampler.samples_per_pixel() as Float;
+        for (layer, accumulator) in layers.iter_mut().zip(&layer_accumulators) {
+            *layer = accumulator.sum() / samples;
+        }
+        accumulator.sum() / samples
     }
 
-    fn sample_point(&self, rng: &mut Rng, dx: f64, dy: f64, world: &Box<dyn Hittable>) -> Color {
-        let pixel_center = self.pixel00_loc + (dx * self.pixel_delta_u) + (dy * self.pixel_delta_v);
+    fn sample_point(
+        &self,
+        rng: &mut impl RandomSource,
+        dx: Float,
+        dy: Float,
+        world: &Box<dyn Hittable>,
+        reuse: &mut PixelReuse,
+    ) -> (Color, u32) {
+        let (ray, weight) = self.get_ray(rng, dx, dy);
+        let (color, object_id) = self.ray_color(rng, &ray, world, reuse);
+        return (weight * color, object_id);
+    }
+    // A camera ray through the point (`dx`, `dy`) of the image in pixels, and the weight of the
+    // color it brings back.
+    fn get_ray<R: RandomSource>(&self, rng: &mut R, dx: Float, dy: Float) -> (Ray, Color) {
+        telemetry::count(Counter::CameraRays);
+        let time = |rng: &mut R| {
+            self.shutter
+                .time(rng.next_float(), dy / self.image_height as Float)
+        };
+        if let Some(interpupillary_distance) = self.omnidirectional_stereo {
+            let (origin, direction) = self.stereo_ray(interpupillary_distance, dx + 0.5, dy + 0.5);
+            return (Ray::new(origin, direction, time(rng)), Color::white());
+        }
+        if let Some(probe) = self.reflection_probe {
+            let s = (dx + 0.5) / self.image_width as Float;
+            let t = (dy + 0.5) / self.image_height as Float;
+            let direction = probe.direction(s, t);
+            return (Ray::new(self.center, direction, time(rng)), Color::white());
+        }
+        // the lens and then the shutter draw the numbers right after the offset in the pixel, two
+        // and one whatever they are, so the samplers spread them over the pixel as evenly as the
+        // offset and depth of field and motion blur clean up as fast as the edges do
         let ray_origin = if self.defocus_angle <= 0.0 {
             self.center
         } else {
-            self.defocus_disk_sample(rng)
+            self.defocus_disk_sample(rng.next_float(), rng.next_float())
         };
+        let time = time(rng);
+        let pixel_center = self.pixel00_loc + (dx * self.pixel_delta_u) + (dy * self.pixel_delta_v);
+        let (pixel_center, weight) = self.aberrate(rng, pixel_center);
         let ray_direction = pixel_center - ray_origin;
-        let ray = Ray::new(ray_origin, ray_direction, rng.next_f64());
-        return self.ray_color(rng, &ray, world);
-    }
-    fn ray_color(&self, rng: &mut Rng, ray: &Ray, world: &Box<dyn Hittable>) -> Color {
-        fn ray_color_inner(
-            rng: &mut Rng,
-            depth: usize,
-            limit: usize,
-            ray: &Ray,
-            world: &Box<dyn Hittable>,
-        ) -> Color {
-            if depth >= limit {
-                return Color::black();
-            }
-            if let Some(hit_record) = world.hit(ray, &(0.000001..f64::INFINITY)) {
-                if let Some((attenuation, scattered)) =
-                    hit_record.material.scatter(rng, ray, &hit_record)
-                {
-                    return attenuation * ray_color_inner(rng, depth + 1, limit, &scattered, world);
+        let weight = if self.vignetting || self.mechanical_vignetting.is_some() {
+            weight * self.vignette(ray_direction)
+        } else {
+            weight
+        };
+        return (Ray::new(ray_origin, ray_direction, time), weight);
+    }
+    // The color seen along `ray` and the object ID of its first hit, zero if it escapes. `reuse`
+    // holds the light samples the first hit reuses, from the last sample of the pixel and the
+    // pixels around.
+    fn ray_color(
+        &self,
+        rng: &mut impl RandomSource,
+        ray: &Ray,
+        world: &Box<dyn Hittable>,
+        reuse: &mut PixelReuse,
+    ) -> (Color, u32) {
+        return self.trace(rng, ray, world, self.guiding(world), reuse);
+    }
+    // Follows the path of `ray` like `ray_color`, with its bounces steered by `guiding` if set.
+    fn trace(
+        &self,
+        rng: &mut impl RandomSource,
+        ray: &Ray,
+        world: &Box<dyn Hittable>,
+        mut guiding: Option<Guiding>,
+        reuse: &mut PixelReuse,
+    ) -> (Color, u32) {
+        let lights = self.lights(world);
+        let mixed_lights = self.mixed_lights(world);
+        let mut path = PathState::new(Color::white());
+        let mut ray = ray.clone();
+        let mut first_object = None;
+        while path.bounce < self.depth {
+            rng.start_bounce(path.bounce);
+            let Some(hit_record) = world.hit(&ray, &self.ray_interval()) else {
+                self.fog_segment(&ray, Float::INFINITY, &mut path);
+                path.add(self.background(&ray));
+                break;
+            };
+            first_object.get_or_insert(hit_record.object_id);
+            self.fog_segment(&ray, hit_record.t, &mut path);
+            // lights keep shining in clay mode
+            if !path.already_lit_by(lights, hit_record.object_id) {
+                path.add(hit_record.material.emitted(&hit_record, path.lit_object));
+            }
+            let material = self.material(&hit_record);
+            let Some((attenuation, scattered)) =
+                material.scatter_through(rng, &ray, &hit_record, &mut path.media)
+            else {
+                break;
+            };
+            telemetry::count(Counter::ScatteredRays);
+            // diffuse bounces take the light of the lights from them directly
+            let lights =
+                lights.filter(|_| material.scattering_pdf(&ray, &hit_record, &scattered) > 0.0);
+            if let Some(lights) = lights {
+                let reuse = (path.bounce == 0).then_some(&mut *reuse);
+                path.add(self.direct_light(rng, world, lights, &ray, &hit_record, reuse));
+            }
+            let redirected = match (&guiding, mixed_lights) {
+                (Some(guiding), _) => {
+                    Some(guiding.redirect(rng, material, &ray, &hit_record, attenuation, scattered))
                 }
+                (None, Some(lights)) => lights.mix_in(
+                    rng,
+                    material.as_ref(),
+                    &ray,
+                    &hit_record,
+                    attenuation,
+                    scattered,
+                ),
+                (None, None) => Some((attenuation, scattered, None)),
+            };
+            let Some((attenuation, scattered, pdf)) = redirected else {
+                break;
+            };
+            path.scatter(attenuation, hit_record.object_id);
+            path.lights_sampled = lights.is_some();
+            if let (Some(_), Some(pdf)) = (&guiding, pdf) {
+                path.guide(hit_record.point, scattered.direction, pdf);
             }
-            let unit_direction = ray.direction.unit_vector();
-            let a = 0.5 * (unit_direction.y + 1.0);
-            return (1. - a) * Color::new(1., 1., 1.) + a * Color::new(0.5, 0.7, 1.);
+            ray = self.leave_surface(&hit_record, scattered);
+            if !path.survives_roulette(rng) {
+                break;
+            }
+        }
+        if let Some(guiding) = &mut guiding {
+            for (point, direction, incoming, pdf) in path.guided_bounces() {
+                guiding.record(point, direction, incoming, pdf);
+            }
+        }
+        return (path.radiance, first_object.unwrap_or(0));
+    }
+    // The material of a hit, or the clay material when it overrides them all.
+    fn material<'a>(&'a self, hit_record: &'a HitRecord) -> &'a Arc<dyn Material> {
+        self.clay.as_ref().unwrap_or(&hit_record.material)
+    }
+    fn background(&self, ray: &Ray) -> Color {
+        match (self.background, &self.environment, &self.sky) {
+            (Some(background), _, _) => background,
+            (None, Some((_, map)), _) => map.radiance(ray.direction),
+            (None, None, Some(sky)) => sky.radiance(ray.direction),
+            (None, None, None) => ray.color(),
+        }
+    }
+    fn defocus_disk_sample(&self, u: Float, v: Float) -> Vec3 {
+        let (x, y) = match &self.aperture_mask {
+            Some((_, mask)) => mask.sample(u, v),
+            None => lens::concentric_disk(u, v),
+        };
+        self.center + self.defocus_disk_u * x + self.defocus_disk_v * y
+    }
+    // Renders the image in memory in linear color, without writing it anywhere.
+    pub fn render_to_buffer(&self, world: &Box<dyn Hittable>) -> Vec<Color> {
+        let (sender, receiver) = sync_channel(64);
+        thread::scope(|s| {
+            // nobody is watching the tiles, but the render stops if they can't be sent
+            s.spawn(move || receiver.into_iter().for_each(drop));
+            self.render_tiles(world, sender, None, &RenderControl::default())
+        })
+    }
+    // The encoded 8 bit image of a buffer from `render_to_buffer`, like the output file.
+    pub fn to_rgb_image(&self, image_buffer: &[Color]) -> RgbImage {
+        let image_buffer = self.post_processed(image_buffer);
+        RgbImage::from_fn(self.image_width as u32, self.image_height as u32, |x, y| {
+            let color = image_buffer[y as usize * self.image_width + x as usize];
+            let (r, g, b) = self.color_space.encode(color).into_u8();
+            Rgb([r, g, b])
+        })
+    }
+    // The linear colors of a buffer from `render_to_buffer` as a float image, in the primaries of
+    // the float outputs.
+    pub fn to_float_image(&self, image_buffer: &[Color]) -> Rgb32FImage {
+        self.to_data_image(&self.in_float_primaries(image_buffer))
+    }
+    // Values that aren't colors, like the normals and depths of the AOVs, as a float image.
+    pub(crate) fn to_data_image(&self, values: &[Color]) -> Rgb32FImage {
+        Rgb32FImage::from_fn(self.image_width as u32, self.image_height as u32, |x, y| {
+            let value = values[y as usize * self.image_width + x as usize];
+            Rgb([value.r as f32, value.g as f32, value.b as f32])
+        })
+    }
+    // The linear colors converted from the working primaries to those of the float outputs.
+    pub(crate) fn in_float_primaries<'a>(&self, image_buffer: &'a [Color]) -> Cow<'a, [Color]> {
+        match self.float_primaries {
+            Primaries::Rec709 => Cow::Borrowed(image_buffer),
+            primaries => image_buffer
+                .iter()
+                .map(|color| primaries.convert(*color))
+                .collect(),
         }
-        return ray_color_inner(rng, 0, self.depth, ray, world);
     }
-    fn defocus_disk_sample(&self, rng: &mut Rng) -> Vec3 {
-        let random = Vec3::random_in_unit_circle(rng);
-        self.center + self.defocus_disk_u * random.x + self.defocus_disk_v * random.y
+    // The image as it is written out and shown, white balanced and then put through the post
+    // processing pipeline.
+    fn post_processed<'a>(&self, image_buffer: &'a [Color]) -> Cow<'a, [Color]> {
+        if self.post_process.is_empty() && self.white_balance.is_none() {
+            return Cow::Borrowed(image_buffer);
+        }
+        let mut image_buffer = image_buffer.to_vec();
+        if let Some(temperature) = self.white_balance {
+            let gains = color_space::white_balance(temperature);
+            image_buffer
+                .iter_mut()
+                .for_each(|color| *color = *color * gains);
+        }
+        self.post_process
+            .apply(&mut image_buffer, self.image_width, self.image_height);
+        Cow::Owned(image_buffer)
+    }
+    // Replaces the image in the preview with the post processed one, as the pipeline works on the
+    // whole image and can't be applied tile by tile.
+    fn send_post_processed(&self, image_buffer: &[Color], sender: &SyncSender<TileUpdate>) {
+        if self.post_process.is_empty() && self.white_balance.is_none() {
+            return;
+        }
+        let image_buffer = self.post_processed(image_buffer).into_owned();
+        let size = (self.image_height, self.image_width);
+        // the preview may be gone already, which is fine
+        let _ = sender.send((Layer::Beauty, (0, 0), size, image_buffer));
+    }
+    // The scene time the shutter is open over, for placing animated objects, see `Animated`.
+    pub fn shutter_interval(&self) -> Interval {
+        self.shutter_interval
+    }
+    // The units the scene is modelled in, for giving lights by their power, see `Units`.
+    pub fn units(&self) -> Units {
+        self.units.unwrap_or_default()
+    }
+    // What the scene's objects are gathered into for tracing, see `Scene::accelerated`.
+    pub fn accelerator(&self) -> Accelerator {
+        self.accelerator
+    }
+    // Drops the lights and the path guide found in the world rendered so far, for the next render
+    // to find them again in a world that has been edited since.
+    pub(crate) fn forget_world(&mut self) {
+        self.guide = OnceLock::new();
+        self.lights = OnceLock::new();
+        self.pixel_reservoirs = Mutex::new(None);
+    }
+    // The path of a file written by the render, the output path followed by `suffix`.
+    pub(crate) fn output_path(&self, suffix: &str) -> String {
+        format!("{}{}", self.output, suffix)
     }
     // I would prefer this not be a method of the camera class but it's own thing
-    fn write_buffer_to_file(&self, image_buffer: &Vec<Color>) -> std::io::Result<()> {
-        let file = File::create("image.ppm")?;
+    fn write_buffer_to_file(&self, image_buffer: &Vec<Color>) -> Result<()> {
+        let processed = self.post_processed(image_buffer);
+        let path = self.output_path(".ppm");
+        let file = File::create(&path)?;
         let mut file_writer = BufWriter::new(file);
         file_writer.write_all(
             format!("P3\n{} {}\n255\n", self.image_width, self.image_height).as_bytes(),
         )?;
-        for color in image_buffer.iter() {
-            color.write_to_writer(&mut file_writer)?;
+        for color in processed.iter() {
+            self.color_space
+                .encode(*color)
+                .write_to_writer(&mut file_writer)?;
         }
         file_writer.flush()?;
+        debug!("wrote {}", path);
+        self.write_exposure_bracket(image_buffer)
+    }
+    // Writes the image again at every exposure of the bracket, as `image-ev<stops>.png` files like
+    // `image-ev-2.png`. The exposure is applied to the linear image, ahead of the post processing.
+    fn write_exposure_bracket(&self, image_buffer: &[Color]) -> Result<()> {
+        for &stops in &self.exposure_bracket {
+            let mut exposed = image_buffer.to_vec();
+            PostStage::Exposure(stops).apply(&mut exposed, self.image_width, self.image_height);
+            let path = self.output_path(&format!("-ev{:+}.png", stops));
+            self.to_rgb_image(&exposed).save(&path)?;
+            debug!("wrote {}", path);
+        }
         Ok(())
     }
 }
+
+// A small camera for tests to build on, taking one sample a pixel that ends at the first hit.
+#[cfg(test)]
+pub(crate) fn test_camera(width: usize, aspect_ratio: Float) -> CameraBuilder {
+    CameraBuilder::default()
+        .image_spec(
+            image::ImageSpecBuilder::default()
+                .width(width)
+                .aspect_ratio(aspect_ratio)
+                .build(),
+        )
+        .random_sampler(1)
+        .max_ray_depth(1)
+}
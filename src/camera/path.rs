@@ -0,0 +1,156 @@
+use super::restir::Lights;
+use crate::{
+    color::Color,
+    float::Float,
+    hittable::materials::MediumStack,
+    random::RandomSource,
+    vec3::{Point3, Vec3},
+};
+
+// Paths are only cut short by Russian roulette past this many bounces, the first bounces carry most
+// of the light and are always traced.
+const MIN_ROULETTE_BOUNCES: usize = 5;
+
+// A path traced from the camera, carried from one bounce to the next. The light found along the
+// path is added to `radiance` weighed by the `throughput` of the bounces before it, so the
+// integrator is a loop over the bounces of a path rather than a recursion over them, and packets
+// can hold the paths of many pixels in flight at once.
+#[derive(Debug, Clone)]
+pub(super) struct PathState {
+    // the share of the light found from here on that reaches the camera
+    pub throughput: Color,
+    pub radiance: Color,
+    pub bounce: usize,
+    pub media: MediumStack,
+    // the object the path last bounced off, which linked lights only light
+    pub lit_object: Option<u32>,
+    // whether the last bounce took the light of the lights directly, see `already_lit_by`
+    pub lights_sampled: bool,
+    guided: Vec<GuidedBounce>,
+}
+
+// A guided bounce of a path and the light that came in along it so far, for training the guide.
+#[derive(Debug, Clone)]
+struct GuidedBounce {
+    point: Point3,
+    direction: Vec3,
+    pdf: Float,
+    // the share of the light found from here on that reaches the bounce
+    throughput: Color,
+    incoming: Color,
+}
+
+impl PathState {
+    pub fn new(throughput: Color) -> Self {
+        Self {
+            throughput,
+            radiance: Color::black(),
+            bounce: 0,
+            media: MediumStack::default(),
+            lit_object: None,
+            lights_sampled: false,
+            guided: Vec::new(),
+        }
+    }
+    // Adds light found at the current end of the path.
+    pub fn add(&mut self, light: Color) {
+        self.radiance += self.throughput * light;
+        for bounce in &mut self.guided {
+            bounce.incoming += bounce.throughput * light;
+        }
+    }
+    // Weighs the light found from here on by `attenuation`, like the fog of a segment does.
+    pub fn attenuate(&mut self, attenuation: Color) {
+        self.throughput = self.throughput * attenuation;
+        for bounce in &mut self.guided {
+            bounce.throughput = bounce.throughput * attenuation;
+        }
+    }
+    // Moves the path on past a bounce off `object` that reflects `attenuation` of the light.
+    pub fn scatter(&mut self, attenuation: Color, object: u32) {
+        self.attenuate(attenuation);
+        self.bounce += 1;
+        self.lit_object = Some(object);
+    }
+    // Whether the light of the object the path hit is already counted, as it is one of `lights`
+    // and they were sampled directly at the bounce before.
+    pub fn already_lit_by(&self, lights: Option<&Lights>, object_id: u32) -> bool {
+        self.lights_sampled && lights.is_some_and(|lights| lights.contains(object_id))
+    }
+    // Follows the light that comes in along a guided bounce out of `point`, see `guided_bounces`.
+    pub fn guide(&mut self, point: Point3, direction: Vec3, pdf: Float) {
+        self.guided.push(GuidedBounce {
+            point,
+            direction,
+            pdf,
+            throughput: Color::white(),
+            incoming: Color::black(),
+        });
+    }
+    // The point, direction, incoming light and density of the guided bounces, the last one first.
+    pub fn guided_bounces(&self) -> impl Iterator<Item = (Point3, Vec3, Color, Float)> + '_ {
+        self.guided
+            .iter()
+            .rev()
+            .map(|bounce| (bounce.point, bounce.direction, bounce.incoming, bounce.pdf))
+    }
+    // Russian roulette. Past the first few bounces, paths carrying little light end with a chance
+    // of how little, and those that go on carry that much more, which keeps the image the same on
+    // average while dark paths stop early.
+    pub fn survives_roulette(&mut self, rng: &mut impl RandomSource) -> bool {
+        if self.bounce < MIN_ROULETTE_BOUNCES {
+            return true;
+        }
+        let survival = self
+            .throughput
+            .r
+            .max(self.throughput.g)
+            .max(self.throughput.b);
+        if survival >= 1.0 {
+            return true;
+        }
+        if rng.next_float() >= survival {
+            return false;
+        }
+        self.attenuate(Color::gray(1.0 / survival));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random::Rng;
+
+    #[test]
+    fn roulette_keeps_the_light_on_average() {
+        let mut rng = Rng::from_seed([1, 2]);
+        let mut total = Color::black();
+        let paths = 100_000;
+        for _ in 0..paths {
+            let mut path = PathState::new(Color::white());
+            for bounce in 0..MIN_ROULETTE_BOUNCES + 1 {
+                path.scatter(Color::gray(0.5), bounce as u32);
+            }
+            if path.survives_roulette(&mut rng) {
+                path.add(Color::white());
+            }
+            total += path.radiance;
+        }
+        let expected = (0.5 as Float).powi(MIN_ROULETTE_BOUNCES as i32 + 1);
+        assert!((total.g / paths as Float - expected).abs() < 0.01 * expected.sqrt());
+    }
+
+    #[test]
+    fn guided_bounces_see_the_light_after_them() {
+        let mut path = PathState::new(Color::white());
+        path.add(Color::gray(0.25));
+        path.scatter(Color::gray(0.5), 1);
+        path.guide(Point3::zero(), Vec3::new(0.0, 1.0, 0.0), 1.0);
+        path.attenuate(Color::gray(0.5));
+        path.add(Color::white());
+        let (_, _, incoming, _) = path.guided_bounces().next().unwrap();
+        assert_eq!(incoming.g, 0.5);
+        assert_eq!(path.radiance.g, 0.25 + 0.25);
+    }
+}
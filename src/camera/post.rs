@@ -0,0 +1,206 @@
+use std::fmt::{Debug, Display};
+use std::str::FromStr;
+
+use super::bloom::Bloom;
+use crate::{
+    color::Color,
+    error::{Error, Result},
+    float::Float,
+    random::{RandomSource, Rng},
+};
+
+// A step in preparing the rendered image for display, applied to the whole linear image once the
+// render is done. The image is in linear color before and after every step, the gamma correction
+// of the output comes last.
+pub trait PostProcess: Debug + Send + Sync {
+    fn apply(&self, image_buffer: &mut Vec<Color>, width: usize, height: usize);
+}
+
+// The steps of the post processing pipeline, applied in the order they are given in. The usual
+// order is exposure, bloom, tone mapping, grain and then dithering.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PostStage {
+    // Scales the image by two to the power of the given stops.
+    Exposure(Float),
+    Bloom(Bloom),
+    Tonemap(Tonemap),
+    // Film grain, noise of the given strength relative to the brightness of each pixel.
+    Grain(Float),
+    // Noise of one step of the 8 bit output, which breaks up banding in smooth gradients.
+    Dither,
+}
+
+// Maps the unbounded brightness of the linear image into the displayable range, rolling off the
+// highlights instead of clipping them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Tonemap {
+    // Divides the color by one more than its luminance.
+    Reinhard,
+    // The fit of the ACES filmic curve by Krzysztof Narkowicz, with more contrast than Reinhard.
+    Aces,
+}
+
+impl PostProcess for PostStage {
+    fn apply(&self, image_buffer: &mut Vec<Color>, width: usize, height: usize) {
+        match self {
+            PostStage::Exposure(stops) => {
+                let scale = (2.0 as Float).powf(*stops);
+                image_buffer
+                    .iter_mut()
+                    .for_each(|color| *color = *color * scale);
+            }
+            PostStage::Bloom(bloom) => bloom.apply(image_buffer, width, height),
+            PostStage::Tonemap(tonemap) => tonemap.apply(image_buffer, width, height),
+            PostStage::Grain(strength) => {
+                for (index, color) in image_buffer.iter_mut().enumerate() {
+                    *color = *color * (1.0 + strength * triangle_noise(index, 1));
+                }
+            }
+            PostStage::Dither => {
+                for (index, color) in image_buffer.iter_mut().enumerate() {
+                    let noise = triangle_noise(index, 2) / 255.0;
+                    let dither =
+                        |value: Float| (value.max(0.0).powf(1.0 / 2.2) + noise).max(0.0).powf(2.2);
+                    *color = Color::new(dither(color.r), dither(color.g), dither(color.b));
+                }
+            }
+        }
+    }
+}
+
+impl PostProcess for Tonemap {
+    fn apply(&self, image_buffer: &mut Vec<Color>, _width: usize, _height: usize) {
+        for color in image_buffer.iter_mut() {
+            *color = match self {
+                Tonemap::Reinhard => *color / (1.0 + color.luminance()),
+                Tonemap::Aces => {
+                    let curve = |x: Float| {
+                        let x = x.max(0.0);
+                        ((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)).clamp(0.0, 1.0)
+                    };
+                    Color::new(curve(color.r), curve(color.g), curve(color.b))
+                }
+            };
+        }
+    }
+}
+
+impl PostProcess for Bloom {
+    fn apply(&self, image_buffer: &mut Vec<Color>, width: usize, height: usize) {
+        *image_buffer = self.bloomed(image_buffer, width, height);
+    }
+}
+
+impl PostProcess for [PostStage] {
+    fn apply(&self, image_buffer: &mut Vec<Color>, width: usize, height: usize) {
+        for stage in self {
+            stage.apply(image_buffer, width, height);
+        }
+    }
+}
+
+// Stages are written as their name followed by their parameters, like `exposure 1.5`, `bloom 1 4
+// 0.5` for the threshold, radius and strength of the bloom, `tonemap aces`, `grain 0.05` and
+// `dither`.
+impl Display for PostStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PostStage::Exposure(stops) => write!(f, "exposure {}", stops),
+            PostStage::Bloom(b) => write!(f, "bloom {} {} {}", b.threshold, b.radius, b.strength),
+            PostStage::Tonemap(Tonemap::Reinhard) => write!(f, "tonemap reinhard"),
+            PostStage::Tonemap(Tonemap::Aces) => write!(f, "tonemap aces"),
+            PostStage::Grain(strength) => write!(f, "grain {}", strength),
+            PostStage::Dither => write!(f, "dither"),
+        }
+    }
+}
+
+impl FromStr for PostStage {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidCamera(format!("invalid post processing stage: {}", s));
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        let number = |word: &str| word.parse::<Float>().map_err(|_| invalid());
+        Ok(match words[..] {
+            ["exposure", stops] => PostStage::Exposure(number(stops)?),
+            ["bloom", threshold, radius, strength] => PostStage::Bloom(Bloom::new(
+                number(threshold)?,
+                number(radius)?,
+                number(strength)?,
+            )),
+            ["tonemap", "reinhard"] => PostStage::Tonemap(Tonemap::Reinhard),
+            ["tonemap", "aces"] => PostStage::Tonemap(Tonemap::Aces),
+            ["grain", strength] => PostStage::Grain(number(strength)?),
+            ["dither"] => PostStage::Dither,
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+// Parses a pipeline of stages separated by commas, like `exposure 1, tonemap aces, dither`.
+pub fn parse_pipeline(pipeline: &str) -> Result<Vec<PostStage>> {
+    pipeline
+        .split(',')
+        .map(str::trim)
+        .filter(|stage| !stage.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+// Noise from -1 to 1 that is more likely near zero, the same for a pixel every time so the grain
+// and dither don't crawl between frames and refinements.
+fn triangle_noise(index: usize, stream: u64) -> Float {
+    let mut rng = Rng::from_seed([index as u64 + 1, stream]);
+    rng.next_float() - rng.next_float()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stages_apply_in_order() {
+        let image = vec![Color::gray(1.0), Color::gray(3.0)];
+        let apply = |stages: &[PostStage]| {
+            let mut image = image.clone();
+            stages.apply(&mut image, 2, 1);
+            image
+        };
+        let exposed = apply(&[
+            PostStage::Exposure(1.0),
+            PostStage::Tonemap(Tonemap::Reinhard),
+        ]);
+        assert_eq!(exposed[0].r, 2.0 / 3.0);
+        let tonemapped = apply(&[
+            PostStage::Tonemap(Tonemap::Reinhard),
+            PostStage::Exposure(1.0),
+        ]);
+        assert_eq!(tonemapped[0].r, 1.0);
+        let aces = apply(&[PostStage::Tonemap(Tonemap::Aces)]);
+        assert!(aces[0].r < aces[1].r && aces[1].r <= 1.0);
+        let pipeline = "exposure -1, bloom 1 4 0.5, tonemap reinhard, grain 0.05, dither";
+        let stages = parse_pipeline(pipeline).unwrap();
+        let written = stages
+            .iter()
+            .map(|stage| stage.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(written.join(", "), pipeline);
+        assert!(parse_pipeline("tonemap filmic").is_err());
+    }
+
+    #[test]
+    fn dither_stays_within_a_step() {
+        let mut image = vec![Color::gray(0.2); 1000];
+        PostStage::Dither.apply(&mut image, 1000, 1);
+        let steps = image
+            .iter()
+            .map(|color| (color.r.powf(1.0 / 2.2) - (0.2 as Float).powf(1.0 / 2.2)) * 255.0)
+            .collect::<Vec<_>>();
+        assert!(steps.iter().all(|step| step.abs() <= 1.0 + 1e-3));
+        let mean = steps.iter().sum::<Float>() / steps.len() as Float;
+        assert!(mean.abs() < 0.05);
+    }
+}
@@ -0,0 +1,121 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::{
+    error::{Error, Result},
+    float::{consts::PI, Float},
+    vec3::Vec3,
+};
+
+// A reflection probe, everything around `lookfrom` rendered into one image along the axes of the
+// world rather than of the camera, to light and be reflected in later renders as an environment.
+// The orientation, field of view and focus of the camera don't apply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ReflectionProbe {
+    // An equirectangular panorama twice as wide as it is tall. It is laid out like the texture
+    // coordinates of a sphere, so an `ImageTexture` of it on a sphere around a scene shows the
+    // probe as it was captured, with positive y straight up.
+    Equirect,
+    // The six faces of a cube map side by side in a strip six times as wide as it is tall, in the
+    // order +x, -x, +y, -y, +z and -z, each facing the way OpenGL cube maps do.
+    Cube,
+}
+
+impl ReflectionProbe {
+    pub const ALL: [ReflectionProbe; 2] = [ReflectionProbe::Equirect, ReflectionProbe::Cube];
+
+    // How many times wider than tall the image of the probe is.
+    pub fn aspect_ratio(self) -> usize {
+        match self {
+            ReflectionProbe::Equirect => 2,
+            ReflectionProbe::Cube => 6,
+        }
+    }
+
+    // The direction seen through the point of the image a fraction `s` across and `t` down it.
+    pub fn direction(self, s: Float, t: Float) -> Vec3 {
+        match self {
+            ReflectionProbe::Equirect => {
+                // the inverse of `Sphere::get_sphere_uv`, with v from the bottom of the image
+                let phi = 2.0 * PI * s;
+                let theta = PI * (1.0 - t);
+                let (sin_phi, cos_phi) = phi.sin_cos();
+                let (sin_theta, cos_theta) = theta.sin_cos();
+                Vec3::new(-cos_phi * sin_theta, -cos_theta, sin_phi * sin_theta)
+            }
+            ReflectionProbe::Cube => {
+                let face = ((s * 6.0) as usize).min(5);
+                let a = 2.0 * (s * 6.0 - face as Float) - 1.0;
+                let b = 2.0 * t - 1.0;
+                let direction = match face {
+                    0 => Vec3::new(1.0, -b, -a),
+                    1 => Vec3::new(-1.0, -b, a),
+                    2 => Vec3::new(a, 1.0, b),
+                    3 => Vec3::new(a, -1.0, -b),
+                    4 => Vec3::new(a, -b, 1.0),
+                    _ => Vec3::new(-a, -b, -1.0),
+                };
+                direction.unit_vector()
+            }
+        }
+    }
+}
+
+impl Display for ReflectionProbe {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ReflectionProbe::Equirect => "equirect",
+            ReflectionProbe::Cube => "cube",
+        })
+    }
+}
+
+impl FromStr for ReflectionProbe {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|probe| probe.to_string() == s)
+            .ok_or_else(|| Error::InvalidCamera(format!("unknown reflection probe: {}", s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable::geometry::Sphere;
+
+    #[test]
+    fn probes_look_along_the_world_axes() {
+        // the equirect is laid out like the texture coordinates of a sphere
+        for (s, t) in [(0.1, 0.2), (0.5, 0.5), (0.8, 0.9), (0.3, 0.6)] {
+            let direction = ReflectionProbe::Equirect.direction(s, t);
+            assert!((direction.length() - 1.0).abs() < 1e-6);
+            let (u, v) = Sphere::get_sphere_uv(&direction);
+            assert!((u - s).abs() < 1e-5 && (v - (1.0 - t)).abs() < 1e-5);
+        }
+        let up = ReflectionProbe::Equirect.direction(0.7, 0.0);
+        assert!((up - Vec3::new(0.0, 1.0, 0.0)).length() < 1e-6);
+
+        // the middles of the faces of the cube look along the axes and their edges meet
+        let axes = [
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(-1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            Vec3::new(0.0, 0.0, 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+        ];
+        for (face, axis) in axes.into_iter().enumerate() {
+            let s = (face as Float + 0.5) / 6.0;
+            let direction = ReflectionProbe::Cube.direction(s, 0.5);
+            assert!((direction - axis).length() < 1e-6);
+        }
+        // the top of the +z face meets the bottom of the +y face
+        let edge = ReflectionProbe::Cube.direction(4.5 / 6.0, 0.0);
+        let other = ReflectionProbe::Cube.direction(2.5 / 6.0, 1.0);
+        assert!((edge - other).length() < 1e-6);
+    }
+}
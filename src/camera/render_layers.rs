@@ -0,0 +1,114 @@
+use tracing::debug;
+
+use super::Camera;
+use crate::{color::Color, error::Result};
+
+// A group of objects, given by their object IDs, whose share of the image is rendered into a
+// buffer of its own. Every sample goes to the first render layer holding the object its camera ray
+// hits first and the rest, escaping rays included, make up the background layer. The layers add up
+// to the image, so composites can adjust the elements separately and put them back together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RenderLayer {
+    pub name: String,
+    pub objects: Vec<u32>,
+}
+
+impl RenderLayer {
+    pub fn new(name: impl Into<String>, objects: Vec<u32>) -> Self {
+        Self {
+            name: name.into(),
+            objects,
+        }
+    }
+}
+
+impl Camera {
+    // The index of the buffer a sample whose camera ray first hits `object_id` goes to, the
+    // background is the last one.
+    pub(crate) fn render_layer_of(&self, object_id: u32) -> usize {
+        self.render_layers
+            .iter()
+            .position(|layer| layer.objects.contains(&object_id))
+            .unwrap_or(self.render_layers.len())
+    }
+    // Writes the buffers of the render layers next to the output file, as `image-layer-<name>.exr`
+    // files in linear color followed by `image-layer-background.exr`.
+    pub(crate) fn write_render_layers(&self, buffers: &[Vec<Color>]) -> Result<()> {
+        let names = self
+            .render_layers
+            .iter()
+            .map(|layer| layer.name.as_str())
+            .chain(["background"]);
+        for (name, colors) in names.zip(buffers) {
+            let path = self.output_path(&format!("-layer-{}.exr", name));
+            self.to_float_image(colors).save(&path)?;
+            debug!("wrote {}", path);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        camera::{test_camera, RenderControl},
+        hittable::{containers::HittableList, geometry::Sphere, materials::Lambertian, Hittable},
+        vec3::Point3,
+    };
+
+    #[test]
+    fn layers_add_up_to_the_image() {
+        let material = Arc::new(Lambertian::from(Color::gray(0.5)));
+        let subject = Sphere::new(Point3::new(0.0, 0.0, -1.0), 0.5, material.clone());
+        let layer = RenderLayer::new("subject", vec![subject.id()]);
+        let mut world = HittableList::default();
+        world.add(Box::new(subject));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, -100.5, -1.0),
+            100.0,
+            material,
+        )));
+        let world: Box<dyn Hittable> = world.into_bvh();
+        let camera = test_camera(16, 1.0)
+            .random_sampler(4)
+            .max_ray_depth(4)
+            .render_layers(vec![layer])
+            .build()
+            .unwrap();
+        let (image, layers) =
+            camera.render_tile_layers((0, 0), (16, 16), &world, &RenderControl::default());
+        assert_eq!(layers.len(), 2);
+        for (index, color) in image.iter().enumerate() {
+            let sum = layers[0][index] + layers[1][index];
+            assert!((sum - *color).length() < 1e-6);
+        }
+        // the sphere fills the middle of the image, the ground and sky are around it
+        let center = 8 * 16 + 8;
+        assert!(layers[0][center].length() > 0.0 && layers[1][center].length() == 0.0);
+        assert!(layers[0][0].length() == 0.0 && layers[1][0].length() > 0.0);
+    }
+
+    #[test]
+    fn layer_names_stay_in_the_output_directory() {
+        let build = |name: &str| {
+            test_camera(8, 1.0)
+                .render_layers(vec![RenderLayer::new(name, vec![1])])
+                .build()
+        };
+        assert!(build("subject").is_ok());
+        for name in [
+            "../subject",
+            "..\\subject",
+            "a/b",
+            "two words",
+            "background",
+            "",
+        ] {
+            assert!(build(name).is_err(), "{:?}", name);
+        }
+    }
+}
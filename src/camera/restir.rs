@@ -0,0 +1,906 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use rayon::prelude::*;
+
+use super::Camera;
+use crate::{
+    color::Color,
+    float::{consts, Float},
+    hittable::{
+        containers::HittableList,
+        materials::Material,
+        pdf::{ConePdf, CosinePdf, HittablePdf, MixturePdf, Pdf},
+        HitRecord, Hittable,
+    },
+    interval::Interval,
+    random::{RandomSource, Rng},
+    ray::Ray,
+    telemetry::{self, Counter},
+    vec3::{Point3, Vec3},
+};
+
+// How many times the candidates of one point the history of a reused reservoir may count, so old
+// samples fade out rather than outweighing the new ones forever.
+const HISTORY_CAP: usize = 20;
+// Reservoirs are only reused between points whose normals are this close, as cosines, and whose
+// distances from the camera are within this fraction of each other, where the lighting is alike.
+const MIN_NORMAL_COSINE: Float = 0.9;
+const MAX_DISTANCE_RATIO: Float = 0.1;
+// Rays towards a point drawn on a light find the light within this fraction of the way to it.
+const SAMPLE_TOLERANCE: Float = 1e-3;
+// How many reservoirs of the pixels around are merged into the reservoir of each first hit, and
+// how far away they are drawn from, in pixels.
+const NEIGHBOURS: usize = 3;
+const NEIGHBOUR_RADIUS: Float = 8.0;
+
+// The lights of a scene, for sampling them directly. The diffuse bounces of paths draw a number of
+// candidate points on the lights and keep one of them by weighted reservoir sampling, with a chance
+// of how much light it would bring without shadows, and only that one is tested with a shadow ray.
+// This is the resampled importance sampling of ReSTIR (Bitterli et al. 2020), which also reuses
+// reservoirs between the first hits of the samples, so scenes with many lights clean up with one
+// shadow ray per bounce. Each sample of a pixel reuses the reservoir of the sample before and those
+// of a few pixels around it, which come from the rays through the pixel centers of `PixelReservoirs`
+// made before the render, so renders come out the same however they are split into tiles. Those in
+// turn reuse the reservoirs of the frame before, the last render of the camera or the one it
+// follows. It is the biased kind of reuse, which darkens the edges of objects and shadows slightly
+// at low sample counts.
+pub(super) struct Lights {
+    lights: Vec<Arc<dyn Hittable>>,
+    // the same lights, for drawing directions towards them in a `MixturePdf`
+    list: HittableList,
+    ids: HashSet<u32>,
+    // the direction towards the sun of the sky and the cosine of its radius while it is up, which
+    // bounces are drawn towards with the lights, see `SkyModel::sun_light`
+    sun: Option<(Vec3, Float)>,
+}
+
+impl Lights {
+    pub fn new(world: &dyn Hittable, sun: Option<(Vec3, Float)>) -> Self {
+        let mut found = Vec::new();
+        world.lights(&mut found);
+        let mut list = HittableList::default();
+        for (_, light) in &found {
+            list.add(Box::new(light.clone()));
+        }
+        Self {
+            ids: found.iter().map(|(id, _)| *id).collect(),
+            lights: found.into_iter().map(|(_, light)| light).collect(),
+            list,
+            sun,
+        }
+    }
+    // Whether hits of the object are on a light sampled directly, whose light the path has
+    // already counted at the bounce before.
+    pub fn contains(&self, object_id: u32) -> bool {
+        self.ids.contains(&object_id)
+    }
+    // Draws the direction of a bounce from an even blend of cosine weighted directions and the
+    // directions towards the lights and the sun, in place of the `scattered` ray the material drew, like
+    // guided bounces do. Only materials with a `scattering_pdf` are redirected. Returns the
+    // attenuation for the new direction and the density it was drawn with, `None` for materials
+    // without a density, or `None` in place of it all when no direction could be drawn.
+    pub fn mix_in(
+        &self,
+        rng: &mut dyn RandomSource,
+        material: &dyn Material,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        attenuation: Color,
+        scattered: Ray,
+    ) -> Option<(Color, Ray, Option<Float>)> {
+        if material.scattering_pdf(ray, hit_record, &scattered) <= 0.0 {
+            return Some((attenuation, scattered, None));
+        }
+        let cosine = CosinePdf::new(&hit_record.normal);
+        let towards_lights = HittablePdf::new(&self.list, hit_record.point);
+        let towards_sun = self
+            .sun
+            .map(|(direction, cos_radius)| ConePdf::new(&direction, cos_radius));
+        let both = towards_sun
+            .as_ref()
+            .map(|towards_sun| MixturePdf::new(&towards_lights, towards_sun));
+        let towards: &dyn Pdf = match (&towards_sun, &both) {
+            (Some(towards_sun), _) if self.lights.is_empty() => towards_sun,
+            (_, Some(both)) => both,
+            _ => &towards_lights,
+        };
+        let mixture = MixturePdf::new(&cosine, towards);
+        let scattered = Ray::new(hit_record.point, mixture.generate(rng)?, ray.time);
+        let material_pdf = material.scattering_pdf(ray, hit_record, &scattered);
+        let pdf = mixture.value(&scattered.direction);
+        // the attenuation of materials with a density is the share of the light they reflect
+        // divided by the density, see `Guiding::redirect`
+        Some((attenuation * (material_pdf / pdf), scattered, Some(pdf)))
+    }
+}
+
+// A point drawn on a light.
+#[derive(Debug, Clone, Copy)]
+struct LightSample {
+    light: usize,
+    point: Point3,
+    normal: Vec3,
+}
+
+// One of a stream of candidate samples, kept with a chance of its weight.
+#[derive(Debug, Clone)]
+pub(super) struct Reservoir {
+    sample: Option<LightSample>,
+    // the light the kept sample brings to the point, without shadows
+    contribution: Color,
+    weight_sum: Float,
+    // how many candidates the reservoir has seen
+    count: usize,
+}
+
+impl Default for Reservoir {
+    fn default() -> Self {
+        Self {
+            sample: None,
+            contribution: Color::black(),
+            weight_sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Reservoir {
+    fn update(
+        &mut self,
+        rng: &mut dyn RandomSource,
+        sample: LightSample,
+        contribution: Color,
+        weight: Float,
+        count: usize,
+    ) {
+        self.weight_sum += weight;
+        self.count += count;
+        if weight > 0.0 && rng.next_float() * self.weight_sum < weight {
+            self.sample = Some(sample);
+            self.contribution = contribution;
+        }
+    }
+    // The weight of the kept sample in place of its density, the average weight of the candidates
+    // over the target density of the sample.
+    fn contribution_weight(&self) -> Float {
+        let target = self.contribution.luminance();
+        if self.sample.is_none() || target <= 0.0 {
+            return 0.0;
+        }
+        self.weight_sum / (self.count as Float * target)
+    }
+}
+
+// A reservoir of a first hit, along with where it was made.
+#[derive(Debug, Clone)]
+struct ReusedReservoir {
+    point: Point3,
+    normal: Vec3,
+    distance: Float,
+    reservoir: Reservoir,
+}
+
+impl ReusedReservoir {
+    fn new(shading: &Shading, reservoir: Reservoir) -> Self {
+        Self {
+            point: shading.hit_record.point,
+            normal: shading.hit_record.normal,
+            distance: shading.distance(),
+            reservoir,
+        }
+    }
+    // Whether the reservoir was made close enough to the shading point for the lighting to be
+    // alike.
+    fn close_to(&self, shading: &Shading) -> bool {
+        let hit_record = shading.hit_record;
+        let distance = shading.distance();
+        self.normal.dot(&hit_record.normal) >= MIN_NORMAL_COSINE
+            && (self.distance - distance).abs() <= MAX_DISTANCE_RATIO * distance
+            && (self.point - hit_record.point).length() <= MAX_DISTANCE_RATIO * distance
+    }
+}
+
+// The reservoirs of the first hits of the rays through the centers of the pixels of a render, made
+// before it from fresh candidates and the reservoirs of the frame before.
+pub(super) struct PixelReservoirs {
+    width: usize,
+    height: usize,
+    // how many frames came before, which each draw from streams of their own
+    frame: u64,
+    reservoirs: Vec<Option<ReusedReservoir>>,
+}
+
+impl PixelReservoirs {
+    // The reservoir of a pixel drawn evenly from the disk of `NEIGHBOUR_RADIUS` around `pixel`, if
+    // it is in the image and its ray hit anything.
+    fn around(
+        &self,
+        rng: &mut dyn RandomSource,
+        pixel: (usize, usize),
+    ) -> Option<&ReusedReservoir> {
+        let radius = NEIGHBOUR_RADIUS * rng.next_float().sqrt();
+        let angle = 2.0 * consts::PI * rng.next_float();
+        let j = (pixel.0 as Float + radius * angle.sin()).round();
+        let i = (pixel.1 as Float + radius * angle.cos()).round();
+        if !((0.0..self.height as Float).contains(&j) && (0.0..self.width as Float).contains(&i)) {
+            return None;
+        }
+        self.reservoirs[j as usize * self.width + i as usize].as_ref()
+    }
+}
+
+// The reservoirs the first hits of the samples of a pixel reuse.
+#[derive(Default)]
+pub(super) struct PixelReuse<'a> {
+    // the reservoir of the last sample of the pixel, replaced by that of each sample
+    last: Option<ReusedReservoir>,
+    pixels: Option<&'a PixelReservoirs>,
+    // the row and column of the pixel
+    pixel: (usize, usize),
+}
+
+impl<'a> PixelReuse<'a> {
+    pub fn new(pixels: Option<&'a PixelReservoirs>, pixel: (usize, usize)) -> Self {
+        Self {
+            last: None,
+            pixels,
+            pixel,
+        }
+    }
+}
+
+// The point the lights are sampled from.
+struct Shading<'a> {
+    ray: &'a Ray,
+    hit_record: &'a HitRecord,
+    material: &'a dyn Material,
+}
+
+impl Shading<'_> {
+    // How far along the ray the point is, from the camera for first hits.
+    fn distance(&self) -> Float {
+        self.hit_record.t * self.ray.direction.length()
+    }
+}
+
+impl Lights {
+    // The light `sample` brings to the point without shadows, zero from its back or if the light
+    // doesn't light the object there. The material weighs it by the density it would scatter
+    // towards it with, the cosine at the point included, and the cosine at the light over the
+    // squared distance turns a density over the area of the light into one over directions.
+    fn contribution(&self, shading: &Shading, sample: &LightSample) -> Color {
+        let hit_record = shading.hit_record;
+        let towards = Ray::new(
+            hit_record.point,
+            sample.point - hit_record.point,
+            shading.ray.time,
+        );
+        let scattering = shading
+            .material
+            .scattering_pdf(shading.ray, hit_record, &towards);
+        let distance_squared = towards.direction.length_squared();
+        let cosine = -sample.normal.dot(&towards.direction) / distance_squared.sqrt();
+        if scattering <= 0.0 || cosine <= 0.0 {
+            return Color::black();
+        }
+        // the light alone, for the light it gives off towards the point
+        let interval = Interval::new(1.0 - SAMPLE_TOLERANCE, 1.0 + SAMPLE_TOLERANCE);
+        let Some(light_hit) = self.lights[sample.light].hit(&towards, &interval) else {
+            return Color::black();
+        };
+        let emitted = light_hit
+            .material
+            .emitted(&light_hit, Some(hit_record.object_id));
+        shading.material.albedo(hit_record) * emitted * (scattering * cosine / distance_squared)
+    }
+    // A reservoir of `count` candidate points drawn on lights picked evenly.
+    fn candidates(&self, rng: &mut dyn RandomSource, shading: &Shading, count: usize) -> Reservoir {
+        let mut reservoir = Reservoir::default();
+        for _ in 0..count {
+            let light = ((rng.next_float() * self.lights.len() as Float) as usize)
+                .min(self.lights.len() - 1);
+            let Some((point, normal, pdf)) = self.lights[light].sample_point(rng) else {
+                reservoir.count += 1;
+                continue;
+            };
+            let sample = LightSample {
+                light,
+                point,
+                normal,
+            };
+            let contribution = self.contribution(shading, &sample);
+            let pdf = pdf / self.lights.len() as Float;
+            let weight = contribution.luminance() / pdf;
+            reservoir.update(rng, sample, contribution, weight, 1);
+        }
+        reservoir
+    }
+    // Merges `reused` into `reservoir` if it was made close to the shading point, with its sample
+    // weighed by the light it brings there and at most `cap` candidates of its history. The
+    // samples are points on the lights, so their densities are over the same area wherever they
+    // are reused and don't need converting.
+    fn merge(
+        &self,
+        rng: &mut dyn RandomSource,
+        shading: &Shading,
+        reservoir: &mut Reservoir,
+        reused: &ReusedReservoir,
+        cap: usize,
+    ) {
+        if !reused.close_to(shading) {
+            return;
+        }
+        let history = reused.reservoir.count.min(cap);
+        let Some(sample) = reused.reservoir.sample else {
+            // the candidates that brought no light count all the same, or the others would be
+            // weighed as if they were all there was
+            reservoir.count += history;
+            return;
+        };
+        let contribution = self.contribution(shading, &sample);
+        let weight =
+            contribution.luminance() * reused.reservoir.contribution_weight() * history as Float;
+        reservoir.update(rng, sample, contribution, weight, history);
+    }
+}
+
+impl Camera {
+    // The lights of `world` if lights are sampled directly and it has any.
+    pub(super) fn lights(&self, world: &Box<dyn Hittable>) -> Option<&Lights> {
+        self.light_candidates?;
+        let lights = self.scene_lights(world.as_ref());
+        (!lights.lights.is_empty()).then_some(lights)
+    }
+    // The lights of `world` if bounces are drawn towards them with `Lights::mix_in` and it has
+    // any, or the sky has its sun up. Lights sampled directly take the place of the mixture.
+    pub(super) fn mixed_lights(&self, world: &Box<dyn Hittable>) -> Option<&Lights> {
+        if !self.light_mixture || self.light_candidates.is_some() {
+            return None;
+        }
+        let lights = self.scene_lights(world.as_ref());
+        (!lights.lights.is_empty() || lights.sun.is_some()).then_some(lights)
+    }
+    fn scene_lights(&self, world: &dyn Hittable) -> &Lights {
+        self.lights.get_or_init(|| {
+            // the sun only lights the scene where nothing else takes the place of the sky
+            let sun = match (self.background, &self.environment, &self.sky) {
+                (None, None, Some(sky)) => sky.sun_light(),
+                _ => None,
+            };
+            Lights::new(world, sun)
+        })
+    }
+
+    // Makes the reservoirs of the pixels for a render from those of the last render, if lights are
+    // sampled directly. Called before the tiles are handed out.
+    pub(super) fn prepare_reservoirs(&self, world: &Box<dyn Hittable>) {
+        let Some(lights) = self.lights(world) else {
+            return;
+        };
+        let earlier = self.pixel_reservoirs.lock().unwrap().clone();
+        let reservoirs = self.make_reservoirs(world, lights, earlier.as_deref());
+        *self.pixel_reservoirs.lock().unwrap() = Some(Arc::new(reservoirs));
+    }
+    // The reservoirs of the pixels of the render if lights are sampled directly. Renders that didn't
+    // start with `prepare_reservoirs`, like the tiles of remote workers, make them without a frame
+    // before on the first tile. The lock isn't held while making them, as the thread may pick up
+    // another tile in the meantime.
+    pub(super) fn pixel_reservoirs(
+        &self,
+        world: &Box<dyn Hittable>,
+    ) -> Option<Arc<PixelReservoirs>> {
+        let lights = self.lights(world)?;
+        if let Some(reservoirs) = self.pixel_reservoirs.lock().unwrap().as_ref() {
+            return Some(reservoirs.clone());
+        }
+        let reservoirs = Arc::new(self.make_reservoirs(world, lights, None));
+        let mut made = self.pixel_reservoirs.lock().unwrap();
+        Some(made.get_or_insert(reservoirs).clone())
+    }
+    // Has the next render of this camera reuse the reservoirs of the last render of `earlier` as
+    // its frame before, like the passes of the preview do.
+    pub(crate) fn follow(&self, earlier: &Camera) {
+        let reservoirs = earlier.pixel_reservoirs.lock().unwrap().clone();
+        *self.pixel_reservoirs.lock().unwrap() = reservoirs;
+    }
+
+    // The reservoirs of the first hits of the rays through the pixel centers, each of fresh
+    // candidates merged with the reservoir of the frame before that is now seen through the pixel.
+    // The pixels draw from streams of their own, apart from those of the render and those of the
+    // frames before, so the frames don't draw the same candidates over again.
+    fn make_reservoirs(
+        &self,
+        world: &Box<dyn Hittable>,
+        lights: &Lights,
+        earlier: Option<&PixelReservoirs>,
+    ) -> PixelReservoirs {
+        let (width, height) = (self.image_width, self.image_height);
+        let mut reprojected = vec![None; width * height];
+        if let Some(earlier) = earlier {
+            for (index, reused) in earlier.reservoirs.iter().enumerate() {
+                let Some(reused) = reused else {
+                    continue;
+                };
+                let Some(pixel) = self.pixel_seeing(reused.point, index, earlier) else {
+                    continue;
+                };
+                // the nearest of those seen through the same pixel, which hides the others
+                let distance = (reused.point - self.center).length_squared();
+                let nearer = |(_, nearest): &(&ReusedReservoir, Float)| distance < *nearest;
+                if reprojected[pixel].as_ref().is_none_or(nearer) {
+                    reprojected[pixel] = Some((reused, distance));
+                }
+            }
+        }
+        let candidates = self.light_candidates.unwrap_or(1);
+        let frame = earlier.map_or(0, |earlier| earlier.frame + 1);
+        let streams = Rng::from_seed([self.seed, frame]);
+        let reservoirs = (0..width * height)
+            .into_par_iter()
+            .map(|index| {
+                let mut rng = streams.stream(0, index as u32);
+                let (j, i) = (index / width, index % width);
+                let (ray, _) = self.get_ray(&mut rng, i as Float, j as Float);
+                let hit_record = world.hit(&ray, &self.ray_interval())?;
+                let shading = Shading {
+                    ray: &ray,
+                    hit_record: &hit_record,
+                    material: self.material(&hit_record).as_ref(),
+                };
+                let mut reservoir = lights.candidates(&mut rng, &shading, candidates);
+                if let Some((reused, _)) = reprojected[index] {
+                    let cap = HISTORY_CAP * candidates;
+                    lights.merge(&mut rng, &shading, &mut reservoir, reused, cap);
+                }
+                if self.light_visible(world, &shading, &reservoir).is_none() {
+                    reservoir.weight_sum = 0.0;
+                }
+                Some(ReusedReservoir::new(&shading, reservoir))
+            })
+            .collect();
+        PixelReservoirs {
+            width,
+            height,
+            frame,
+            reservoirs,
+        }
+    }
+    // The pixel `point` is seen through, if it is in front of the camera and inside the image. The
+    // panoramas and probes don't see through a plane, so they take the reservoir of the pixel at
+    // `index` of `earlier` when it is of the same size.
+    fn pixel_seeing(
+        &self,
+        point: Point3,
+        index: usize,
+        earlier: &PixelReservoirs,
+    ) -> Option<usize> {
+        if self.omnidirectional_stereo.is_some() || self.reflection_probe.is_some() {
+            let same_size =
+                (earlier.width, earlier.height) == (self.image_width, self.image_height);
+            return same_size.then_some(index);
+        }
+        let normal = self.pixel_delta_u.cross(&self.pixel_delta_v);
+        let towards = point - self.center;
+        let scale = (self.pixel00_loc - self.center).dot(&normal) / towards.dot(&normal);
+        if scale.is_nan() || scale <= 0.0 {
+            return None;
+        }
+        let offset = self.center + scale * towards - self.pixel00_loc;
+        let i = (offset.dot(&self.pixel_delta_u) / self.pixel_delta_u.length_squared()).round();
+        let j = (offset.dot(&self.pixel_delta_v) / self.pixel_delta_v.length_squared()).round();
+        let inside = (0.0..self.image_width as Float).contains(&i)
+            && (0.0..self.image_height as Float).contains(&j);
+        inside.then(|| j as usize * self.image_width + i as usize)
+    }
+    // The shadow ray towards the kept sample of `reservoir` if nothing is in the way.
+    fn light_visible(
+        &self,
+        world: &Box<dyn Hittable>,
+        shading: &Shading,
+        reservoir: &Reservoir,
+    ) -> Option<Ray> {
+        let sample = reservoir.sample?;
+        let (hit_record, time) = (shading.hit_record, shading.ray.time);
+        let towards = Ray::new(hit_record.point, sample.point - hit_record.point, time);
+        let towards = self.leave_surface(hit_record, towards);
+        let towards = Ray::new(towards.origin, sample.point - towards.origin, time);
+        telemetry::count(Counter::ShadowRays);
+        let blocked = world.hit(&towards, &self.shadow_interval()).is_some();
+        (!blocked).then_some(towards)
+    }
+
+    // The light reaching the hit from the lights, through a shadow ray towards the one sample kept
+    // of `light_candidates` drawn. `reuse` is given for first hits, whose sample may come from the
+    // reservoir of the sample of the pixel before or of the pixels around it instead if they are
+    // close by, and is passed on to the next sample of the pixel.
+    pub(super) fn direct_light(
+        &self,
+        rng: &mut dyn RandomSource,
+        world: &Box<dyn Hittable>,
+        lights: &Lights,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        reuse: Option<&mut PixelReuse>,
+    ) -> Color {
+        let shading = Shading {
+            ray,
+            hit_record,
+            material: self.material(hit_record).as_ref(),
+        };
+        let candidates = self.light_candidates.unwrap_or(1);
+        let mut reservoir = lights.candidates(rng, &shading, candidates);
+        let cap = HISTORY_CAP * candidates;
+        if let Some(reuse) = reuse.as_deref() {
+            if let Some(last) = &reuse.last {
+                lights.merge(rng, &shading, &mut reservoir, last, cap);
+            }
+            if let Some(pixels) = reuse.pixels {
+                for _ in 0..NEIGHBOURS {
+                    if let Some(neighbour) = pixels.around(rng, reuse.pixel) {
+                        lights.merge(rng, &shading, &mut reservoir, neighbour, cap);
+                    }
+                }
+            }
+        }
+
+        let light = match self.light_visible(world, &shading, &reservoir) {
+            Some(towards) => {
+                let transmittance = self.fog.map_or(1.0, |fog| fog.transmittance(&towards, 1.0));
+                reservoir.contribution * (reservoir.contribution_weight() * transmittance)
+            }
+            None => {
+                // shadowed samples aren't passed on
+                reservoir.weight_sum = 0.0;
+                Color::black()
+            }
+        };
+        if let Some(reuse) = reuse {
+            reuse.last = Some(ReusedReservoir::new(&shading, reservoir));
+        }
+        light
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        camera::{builder::CameraBuilder, image::ImageSpecBuilder, sky::Sky, test_camera},
+        hittable::{
+            containers::HittableList,
+            geometry::Sphere,
+            instance::{Instance, TopLevelBVH},
+            materials::{DiffuseLight, Lambertian},
+            mesh::{Mesh, MeshData},
+        },
+        random::Rng,
+    };
+
+    #[test]
+    fn reservoirs_keep_candidates_by_weight() {
+        let mut rng = Rng::from_seed([3, 4]);
+        let sample = |light| LightSample {
+            light,
+            point: Point3::zero(),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+        };
+        let trials = 20_000;
+        let mut kept = [0; 3];
+        for _ in 0..trials {
+            let mut reservoir = Reservoir::default();
+            for (light, weight) in [1.0, 0.0, 3.0].into_iter().enumerate() {
+                reservoir.update(&mut rng, sample(light), Color::gray(weight), weight, 1);
+            }
+            kept[reservoir.sample.unwrap().light] += 1;
+            assert_eq!(reservoir.count, 3);
+        }
+        assert_eq!(kept[1], 0);
+        let share = kept[2] as Float / trials as Float;
+        assert!((share - 0.75).abs() < 0.02, "{}", share);
+    }
+
+    // Checks that sampling the lights of `world` directly and drawing bounces towards them come out
+    // like path tracing, with less noise. The lights are out of view, so the image is all light off
+    // the ground.
+    fn assert_sampling_matches_path_tracing(world: &Box<dyn Hittable>) {
+        let render = |(candidates, mixture): (Option<usize>, bool), seed: u64| {
+            let mut builder = test_camera(24, 1.5)
+                .random_sampler(256)
+                .max_ray_depth(3)
+                .background(Color::black())
+                .seed(seed)
+                .lookfrom(Point3::new(0.0, 3.0, 4.0))
+                .lookat(Point3::new(0.0, 0.0, -1.0));
+            builder.light_candidates = candidates;
+            builder.light_mixture = Some(mixture);
+            let camera = builder.build().unwrap();
+            camera.render_to_buffer(world)
+        };
+        let mean = |buffer: &[Color]| {
+            buffer.iter().map(Color::luminance).sum::<Float>() / buffer.len() as Float
+        };
+        // the noise is how far apart renders with different seeds are
+        let noise = |sampling| {
+            let (first, second) = (render(sampling, 1), render(sampling, 2));
+            let squared = first
+                .iter()
+                .zip(&second)
+                .map(|(a, b)| (a.luminance() - b.luminance()).powi(2))
+                .sum::<Float>();
+            let mean = (mean(&first) + mean(&second)) / 2.0;
+            (mean, (squared / first.len() as Float).sqrt())
+        };
+        let (traced, traced_noise) = noise((None, false));
+        let (direct, direct_noise) = noise((Some(8), false));
+        let (mixed, mixed_noise) = noise((None, true));
+        // within the noise of the path traced render and the bias of the reuse
+        assert!(
+            (direct - traced).abs() < 0.1 * traced,
+            "{} {}",
+            direct,
+            traced
+        );
+        assert!(
+            direct_noise < 0.5 * traced_noise,
+            "{} {}",
+            direct_noise,
+            traced_noise
+        );
+        // drawing bounces towards the lights is unbiased, and finds them more often
+        assert!(
+            (mixed - traced).abs() < 0.05 * traced,
+            "{} {}",
+            mixed,
+            traced
+        );
+        assert!(
+            mixed_noise < 0.5 * traced_noise,
+            "{} {}",
+            mixed_noise,
+            traced_noise
+        );
+    }
+
+    #[test]
+    fn direct_lighting_matches_path_tracing() {
+        let mut world = HittableList::default();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, -10.0, 0.0),
+            10.0,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        )));
+        for x in [-2.0, 0.0, 2.0] {
+            world.add(Box::new(Sphere::new(
+                Point3::new(x, 4.0, 3.0),
+                0.4,
+                Arc::new(DiffuseLight::from(Color::gray(4.0))),
+            )));
+        }
+        assert_sampling_matches_path_tracing(&world.into_bvh());
+    }
+
+    #[test]
+    fn instanced_and_mesh_lights_are_sampled() {
+        let light = || Arc::new(DiffuseLight::from(Color::gray(4.0)));
+        let ground: Arc<dyn Hittable> = Arc::new(Sphere::new(
+            Point3::new(0.0, -10.0, 0.0),
+            10.0,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        ));
+        let sphere: Arc<dyn Hittable> = Arc::new(Sphere::new(Point3::zero(), 0.4, light()));
+        // a square facing down
+        let square = "v -0.4 0 -0.4\nv 0.4 0 -0.4\nv 0.4 0 0.4\nv -0.4 0 0.4\nf 1 2 3 4";
+        let square: Arc<dyn Hittable> =
+            Arc::new(Mesh::new(MeshData::parse_obj(square).unwrap(), vec![light()]).unwrap());
+        let world: Box<dyn Hittable> = Box::new(TopLevelBVH::new(vec![
+            Instance::new(ground, Vec3::zero()),
+            Instance::new(sphere.clone(), Vec3::new(-2.0, 4.0, 3.0)),
+            Instance::new(sphere, Vec3::new(2.0, 4.0, 3.0)),
+            Instance::new(square, Vec3::new(0.0, 4.0, 3.0)),
+        ]));
+        let lights = Lights::new(world.as_ref(), None);
+        assert_eq!(lights.lights.len(), 3);
+        // the lights are where the instances put them, give or take the rounding of single precision
+        let mut rng = Rng::from_seed([5, 6]);
+        let within = |value: Float, center: Float| (value - center).abs() <= 0.4 + 1e-4;
+        for light in &lights.lights {
+            let (point, _, _) = light.sample_point(&mut rng).unwrap();
+            assert!(within(point.y, 4.0) && within(point.z, 3.0));
+        }
+        assert_sampling_matches_path_tracing(&world);
+    }
+
+    // Many small lights out of view over the ground, where one candidate a sample seldom finds
+    // much of the light and the reuse does the work.
+    fn many_lights() -> Box<dyn Hittable> {
+        let mut world = HittableList::default();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, -10.0, 0.0),
+            10.0,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        )));
+        let mut rng = Rng::from_seed([3, 4]);
+        for _ in 0..24 {
+            let x = rng.next_float() * 8.0 - 4.0;
+            let y = rng.next_float() + 4.0;
+            let z = rng.next_float() * 3.0 + 2.0;
+            world.add(Box::new(Sphere::new(
+                Point3::new(x, y, z),
+                0.1,
+                Arc::new(DiffuseLight::from(Color::gray(20.0))),
+            )));
+        }
+        world.into_bvh()
+    }
+
+    fn many_lights_camera(samples: usize, candidates: usize, seed: u64) -> Camera {
+        test_camera(48, 1.5)
+            .random_sampler(samples)
+            .max_ray_depth(2)
+            .background(Color::black())
+            .seed(seed)
+            .light_candidates(candidates)
+            .lookfrom(Point3::new(0.0, 3.0, 4.0))
+            .lookat(Point3::new(0.0, 0.0, -1.0))
+            .build()
+            .unwrap()
+    }
+
+    fn mean(buffer: &[Color]) -> Float {
+        buffer.iter().map(Color::luminance).sum::<Float>() / buffer.len() as Float
+    }
+
+    #[test]
+    fn reuse_with_few_candidates_matches_path_tracing() {
+        let world = many_lights();
+        let traced = test_camera(48, 1.5)
+            .random_sampler(1024)
+            .max_ray_depth(2)
+            .background(Color::black())
+            .lookfrom(Point3::new(0.0, 3.0, 4.0))
+            .lookat(Point3::new(0.0, 0.0, -1.0))
+            .build()
+            .unwrap()
+            .render_to_buffer(&world);
+        // most candidates are on the backs of the lights, whose reservoirs keep nothing but still
+        // count, or the reuse brightens the image by a fifth
+        let reused = (0..16)
+            .map(|seed| mean(&many_lights_camera(4, 1, seed).render_to_buffer(&world)))
+            .sum::<Float>()
+            / 16.0;
+        let traced = mean(&traced);
+        assert!(
+            (reused - traced).abs() < 0.05 * traced,
+            "{} {}",
+            reused,
+            traced
+        );
+    }
+
+    #[test]
+    fn later_frames_reuse_the_reservoirs_of_earlier_ones() {
+        let world = many_lights();
+        let reference = many_lights_camera(1024, 8, 9).render_to_buffer(&world);
+        let error = |buffer: &[Color]| {
+            let squared = buffer
+                .iter()
+                .zip(&reference)
+                .map(|(a, b)| (a.luminance() - b.luminance()).powi(2))
+                .sum::<Float>();
+            (squared / buffer.len() as Float).sqrt()
+        };
+        let camera = many_lights_camera(4, 1, 1);
+        let first = error(&camera.render_to_buffer(&world));
+        for _ in 0..2 {
+            camera.render_to_buffer(&world);
+        }
+        let fourth = error(&camera.render_to_buffer(&world));
+        let reservoirs = camera.pixel_reservoirs(&world).unwrap();
+        assert_eq!(reservoirs.frame, 3);
+        assert!(fourth < 0.97 * first, "{} {}", fourth, first);
+        // a camera following another picks up where it left off
+        let following = many_lights_camera(4, 1, 1);
+        following.follow(&camera);
+        following.render_to_buffer(&world);
+        assert_eq!(following.pixel_reservoirs(&world).unwrap().frame, 4);
+    }
+
+    #[test]
+    fn reservoirs_are_reprojected_to_where_their_points_are_seen() {
+        let world = many_lights();
+        let camera = many_lights_camera(1, 1, 1);
+        let reservoirs = camera.pixel_reservoirs(&world).unwrap();
+        let made = reservoirs.reservoirs.iter().enumerate();
+        let made = made
+            .filter_map(|(index, reused)| Some((index, reused.as_ref()?)))
+            .collect::<Vec<_>>();
+        // the ground fills about the lower third of the image
+        assert!(made.len() > reservoirs.reservoirs.len() / 4);
+        for &(index, reused) in &made {
+            assert_eq!(
+                camera.pixel_seeing(reused.point, index, &reservoirs),
+                Some(index)
+            );
+        }
+        // from a step to the side, the points are seen through the pixels whose rays pass by them
+        let moved = test_camera(48, 1.5)
+            .lookfrom(Point3::new(0.3, 3.0, 4.0))
+            .lookat(Point3::new(0.3, 0.0, -1.0))
+            .build()
+            .unwrap();
+        let mut seen = 0;
+        for &(index, reused) in &made {
+            let Some(pixel) = moved.pixel_seeing(reused.point, index, &reservoirs) else {
+                continue;
+            };
+            seen += 1;
+            let (j, i) = (pixel / moved.image_width, pixel % moved.image_width);
+            let pixel_center = moved.pixel00_loc
+                + i as Float * moved.pixel_delta_u
+                + j as Float * moved.pixel_delta_v;
+            let through = (pixel_center - moved.center).unit_vector();
+            let towards = (reused.point - moved.center).unit_vector();
+            let pixel_angle = moved.pixel_delta_u.length() / (pixel_center - moved.center).length();
+            assert!(through.cross(&towards).length() < pixel_angle);
+        }
+        assert!(seen > made.len() / 2);
+    }
+
+    #[test]
+    fn bounces_are_drawn_towards_the_sun() {
+        let ground: Box<dyn Hittable> = Box::new(Sphere::new(
+            Point3::new(0.0, -100.0, 0.0),
+            100.0,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        ));
+        let render = |mixture: bool, samples: usize, seed: u64| {
+            let mut builder = CameraBuilder::default()
+                .image_spec(
+                    ImageSpecBuilder::default()
+                        .width(16)
+                        .aspect_ratio(1.0)
+                        .build(),
+                )
+                .random_sampler(samples)
+                .max_ray_depth(2)
+                .sky(Sky::new(45.0, 0.0).date(2024, 6, 21).time(15, 0))
+                .seed(seed)
+                .lookfrom(Point3::new(0.0, 2.0, 2.0))
+                .lookat(Point3::zero());
+            builder.light_mixture = Some(mixture);
+            builder.build().unwrap().render_to_buffer(&ground)
+        };
+        let spread = |mixture, samples| {
+            let (first, second) = (render(mixture, samples, 1), render(mixture, samples, 2));
+            let mean = first
+                .iter()
+                .chain(&second)
+                .map(Color::luminance)
+                .sum::<Float>()
+                / (2 * first.len()) as Float;
+            let squared = first
+                .iter()
+                .zip(&second)
+                .map(|(a, b)| (a.luminance() - b.luminance()).powi(2))
+                .sum::<Float>();
+            (mean, (squared / first.len() as Float).sqrt())
+        };
+        // so few paths find the sun by chance that tracing them takes many more samples to even
+        // come close, which the mixture beats at a sixteenth of them
+        let (traced, traced_noise) = spread(false, 1024);
+        let (mixed, mixed_noise) = spread(true, 64);
+        assert!(
+            (mixed - traced).abs() < 0.1 * traced,
+            "{} {}",
+            mixed,
+            traced
+        );
+        assert!(
+            mixed_noise < 0.5 * traced_noise,
+            "{} {}",
+            mixed_noise,
+            traced_noise
+        );
+    }
+}
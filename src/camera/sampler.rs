@@ -0,0 +1,216 @@
+use crate::random::RandomSource;
+
+// The dimensions of the samples every part of a path draws from. The camera ray gets the first
+// ones, its offset in the pixel and the point on the lens as the first two pairs and the time in
+// the shutter after them, and every bounce a block of its own after that. Numbers drawn past the
+// end of a block are plain random numbers, so a material drawing more than usual doesn't take the
+// dimensions of the next bounce.
+const CAMERA_DIMENSIONS: u64 = 8;
+const BOUNCE_DIMENSIONS: u64 = 8;
+
+// How the samples of a pixel spread over each dimension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Sequence {
+    // Every sample lands in its own of `samples` equal intervals of the dimension.
+    Stratified,
+    // The first two dimensions of the Sobol sequence, used in pairs, with Owen scrambling. The
+    // samples are evenly spread over the square of each pair, and over every dimension, at every
+    // power of two samples, and the scrambling randomizes them with none of the structured patterns
+    // of the plain sequence showing through at high sample counts.
+    Sobol,
+}
+
+// The numbers one sample of a pixel draws, dimension by dimension rather than from one shared
+// stream. The samples of a pixel are spread evenly over every dimension as their `Sequence` does,
+// and shuffled differently for every dimension, or pair of dimensions, so the dimensions don't
+// line up with each other. That is padding: the dimensions of later bounces are as well spread as
+// those of the camera ray, where a single stream only spreads the first few numbers of a sample
+// evenly.
+pub(super) struct SampleStream {
+    sequence: Sequence,
+    seed: u64,
+    sample: u64,
+    samples: u64,
+    dimension: u64,
+    end: u64,
+}
+
+impl SampleStream {
+    // The `sample`th of `samples` samples of the pixel identified by `seed`.
+    pub(super) fn new(sequence: Sequence, seed: u64, sample: usize, samples: usize) -> Self {
+        Self {
+            sequence,
+            seed,
+            sample: sample as u64,
+            samples: samples.max(1) as u64,
+            dimension: 0,
+            end: CAMERA_DIMENSIONS,
+        }
+    }
+}
+
+impl RandomSource for SampleStream {
+    fn next_u64(&mut self) -> u64 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let key = mix(self.seed ^ mix(dimension));
+        let past_end = dimension >= self.end;
+        if past_end {
+            return mix(key ^ 0x5851f42d4c957f2d ^ mix(self.sample));
+        }
+        match self.sequence {
+            Sequence::Stratified => {
+                let stratum = permute(self.sample as u32, self.samples as u32, key as u32) as u64;
+                let width = u64::MAX / self.samples;
+                stratum * width + mix(key ^ mix(self.sample)) % width
+            }
+            Sequence::Sobol => {
+                // both dimensions of a pair shuffle the samples the same way
+                let pair_key = mix(self.seed ^ mix(dimension & !1) ^ 0x2545f4914f6cdd1d);
+                let index = owen_scramble(self.sample as u32, pair_key as u32);
+                let value = owen_scramble(sobol(index, dimension & 1 == 1), key as u32);
+                // the 32 bits of the sequence, jittered within the gap below the next value
+                (value as u64) << 32 | mix(key ^ mix(self.sample)) >> 32
+            }
+        }
+    }
+    fn start_bounce(&mut self, depth: usize) {
+        self.dimension = CAMERA_DIMENSIONS + depth as u64 * BOUNCE_DIMENSIONS;
+        self.end = self.dimension + BOUNCE_DIMENSIONS;
+    }
+}
+
+// The splitmix64 finalizer, which spreads every bit of its input over the whole output.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+// The `index`th point of the first or the second dimension of the Sobol sequence, as the bits of
+// a fraction. The first is the van der Corput sequence, the bits of the index reversed, and the
+// second is built from the columns of the Pascal matrix mod 2.
+fn sobol(index: u32, second: bool) -> u32 {
+    if !second {
+        return index.reverse_bits();
+    }
+    let mut result = 0;
+    let mut column = 1 << 31;
+    let mut index = index;
+    while index != 0 {
+        if index & 1 == 1 {
+            result ^= column;
+        }
+        column ^= column >> 1;
+        index >>= 1;
+    }
+    result
+}
+
+// Owen scrambling of the bits of a fraction, which flips every bit depending on a hash of the bits
+// above it. That shuffles the halves of the unit interval, then the halves of each half and so on,
+// which keeps points that were evenly spread evenly spread. The hash is Burley's version of the
+// Laine-Karras permutation from "Practical Hash-based Owen Scrambling", which scrambles towards
+// the higher bits and so works on the reversed bits.
+fn owen_scramble(value: u32, seed: u32) -> u32 {
+    let mut x = value.reverse_bits();
+    x ^= x.wrapping_mul(0x3d20adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x05526c56);
+    x ^= x.wrapping_mul(0x53a22864);
+    x.reverse_bits()
+}
+
+// Where `index` goes in a random shuffle of `0..n` picked by `seed`, computed on the spot without
+// the shuffle. Kensler's hashed permutation from "Correlated Multi-Jittered Sampling", which
+// shuffles the next power of two and skips the indices past `n`.
+fn permute(mut index: u32, n: u32, seed: u32) -> u32 {
+    let mut mask = n - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    loop {
+        index ^= seed;
+        index = index.wrapping_mul(0xe170893d);
+        index ^= seed >> 16;
+        index ^= (index & mask) >> 4;
+        index ^= seed >> 8;
+        index = index.wrapping_mul(0x0929eb3f);
+        index ^= seed >> 23;
+        index ^= (index & mask) >> 1;
+        index = index.wrapping_mul(1 | seed >> 27);
+        index = index.wrapping_mul(0x6935fa69);
+        index ^= (index & mask) >> 11;
+        index = index.wrapping_mul(0x74dcb303);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0x9e501cc3);
+        index ^= (index & mask) >> 2;
+        index = index.wrapping_mul(0xc860a3df);
+        index &= mask;
+        index ^= index >> 5;
+        if index < n {
+            return index.wrapping_add(seed) % n;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The strata of `strata` the first `samples` samples of a pixel land in, in the first four
+    // dimensions of the camera ray or of a bounce.
+    fn strata(
+        sequence: Sequence,
+        samples: usize,
+        strata: usize,
+        bounce: Option<usize>,
+    ) -> Vec<Vec<usize>> {
+        let mut dimensions = vec![Vec::new(); 4];
+        for sample in 0..samples {
+            let mut stream = SampleStream::new(sequence, 42, sample, samples);
+            if let Some(depth) = bounce {
+                stream.start_bounce(depth);
+            }
+            for dimension in dimensions.iter_mut() {
+                dimension.push((stream.next_f64() * strata as f64) as usize);
+            }
+        }
+        dimensions
+    }
+
+    fn sorted(mut strata: Vec<usize>) -> Vec<usize> {
+        strata.sort();
+        strata
+    }
+
+    #[test]
+    fn every_dimension_is_stratified() {
+        for sequence in [Sequence::Stratified, Sequence::Sobol] {
+            let camera = strata(sequence, 16, 16, None);
+            let bounce = strata(sequence, 16, 16, Some(3));
+            for dimension in camera.iter().chain(&bounce) {
+                assert_eq!(sorted(dimension.clone()), (0..16).collect::<Vec<_>>());
+            }
+            // the dimensions are shuffled apart
+            assert_ne!(camera[0], camera[1]);
+            assert_ne!(camera[0], bounce[0]);
+        }
+    }
+
+    #[test]
+    fn sobol_pairs_cover_the_square() {
+        // every cell of an 8 by 8 grid gets one of 64 samples, in every pair of dimensions
+        let dimensions = strata(Sequence::Sobol, 64, 8, Some(1));
+        for pair in dimensions.chunks(2) {
+            let cells = pair[0].iter().zip(&pair[1]).map(|(x, y)| y * 8 + x);
+            assert_eq!(sorted(cells.collect()), (0..64).collect::<Vec<_>>());
+        }
+        // and the first samples of them are spread evenly as well
+        let first = strata(Sequence::Sobol, 64, 4, None);
+        assert_eq!(sorted(first[0][..4].to_vec()), vec![0, 1, 2, 3]);
+    }
+}
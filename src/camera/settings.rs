@@ -0,0 +1,44 @@
+use super::{builder::CameraBuilder, Camera, PixelSampler};
+use crate::float::Float;
+
+// The settings of a camera that can be tuned from the preview before rendering again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RenderSettings {
+    pub field_of_view: Float,
+    pub defocus_angle: Float,
+    pub focus_distance: Float,
+    pub samples_per_pixel: usize,
+    pub max_ray_depth: usize,
+}
+
+impl RenderSettings {
+    pub fn apply(&self, builder: CameraBuilder) -> CameraBuilder {
+        let builder = builder
+            .field_of_view(self.field_of_view)
+            .defocus_angle(self.defocus_angle)
+            .focus_distance(self.focus_distance)
+            .max_ray_depth(self.max_ray_depth);
+        match builder.pixel_sampler {
+            Some(PixelSampler::Random(_)) => builder.random_sampler(self.samples_per_pixel),
+            Some(PixelSampler::Padded(_)) => builder.padded_sampler(self.samples_per_pixel),
+            Some(PixelSampler::Sobol(_)) => builder.sobol_sampler(self.samples_per_pixel),
+            _ => builder.uniform_sampler(self.samples_per_pixel),
+        }
+    }
+}
+
+impl Camera {
+    pub fn settings(&self) -> RenderSettings {
+        RenderSettings {
+            field_of_view: self.field_of_view,
+            defocus_angle: self.defocus_angle,
+            focus_distance: self
+                .units
+                .unwrap_or_default()
+                .to_meters(self.focus_distance),
+            samples_per_pixel: self.pixel_sampler.samples_per_pixel(),
+            max_ray_depth: self.depth,
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{
+    error::{Error, Result},
+    float::Float,
+};
+
+// How far open the shutter is over the shutter interval, which decides how the times of camera
+// rays are spread over it. A real shutter takes a while to open and close, so the ends of motion
+// blur streaks fade out rather than stopping sharply like they do with an instant box shutter.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ShutterCurve {
+    // Fully open for the whole interval.
+    #[default]
+    Box,
+    // Opens until the middle of the interval and closes again.
+    Triangle,
+    // How far open the shutter is at evenly spaced points from the start of the interval to its
+    // end, linearly interpolated between them.
+    Curve(Vec<Float>),
+}
+
+// A shutter curve prepared for sampling, with the area under every segment of the curve summed up.
+// With a rolling shutter the rows of the image are exposed one after another, `readout` is the
+// part of the shutter interval between the exposure of the top row starting and the bottom row
+// starting, and each row is exposed for the rest of it. Fast moving objects then lean and wobble
+// like they do in footage from CMOS sensors.
+#[derive(Debug, Clone)]
+pub(crate) struct Shutter {
+    pub(crate) curve: ShutterCurve,
+    pub(crate) readout: Float,
+    points: Vec<Float>,
+    cumulative: Vec<Float>,
+}
+
+impl Shutter {
+    pub(crate) fn new(curve: ShutterCurve, readout: Float) -> Result<Self> {
+        if !(0.0..1.0).contains(&readout) {
+            return Err(Error::InvalidCamera(format!(
+                "the rolling shutter readout must be at least 0 and less than 1, current value: {}",
+                readout
+            )));
+        }
+        let points = match &curve {
+            ShutterCurve::Box => Vec::new(),
+            ShutterCurve::Triangle => vec![0.0, 1.0, 0.0],
+            ShutterCurve::Curve(points) => points.clone(),
+        };
+        if curve != ShutterCurve::Box
+            && (points.len() < 2
+                || points.iter().any(|&point| point.is_nan() || point < 0.0)
+                || points.iter().all(|&point| point == 0.0))
+        {
+            return Err(Error::InvalidCamera(format!(
+                "the shutter curve needs at least two points that are not negative and not all zero, current value: {:?}",
+                points
+            )));
+        }
+        let mut cumulative = vec![0.0];
+        for pair in points.windows(2) {
+            let area = (pair[0] + pair[1]) / 2.0;
+            cumulative.push(cumulative.last().unwrap() + area);
+        }
+        Ok(Self {
+            curve,
+            readout,
+            points,
+            cumulative,
+        })
+    }
+    // The time of a ray through `row`, from 0 at the top of the image to 1 at the bottom, for a
+    // uniformly distributed `u`.
+    pub(crate) fn time(&self, u: Float, row: Float) -> Float {
+        let start = self.readout * row.clamp(0.0, 1.0);
+        start + (1.0 - self.readout) * self.sample(u)
+    }
+    // The time in the shutter interval, from 0 to 1, for a uniformly distributed `u`, by inverting
+    // the integral of the curve.
+    fn sample(&self, u: Float) -> Float {
+        if self.points.is_empty() {
+            return u;
+        }
+        let segments = self.points.len() - 1;
+        let target = u * self.cumulative[segments];
+        let segment =
+            (self.cumulative.partition_point(|&area| area <= target) - 1).min(segments - 1);
+        let (a, b) = (self.points[segment], self.points[segment + 1]);
+        let area = target - self.cumulative[segment];
+        // the area from the start of the segment to x is a x + (b - a) x² / 2
+        let x = if (b - a).abs() < 1e-9 {
+            area / a.max(1e-9)
+        } else {
+            ((a * a + 2.0 * (b - a) * area).max(0.0).sqrt() - a) / (b - a)
+        };
+        (segment as Float + x.clamp(0.0, 1.0)) / segments as Float
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(curve: ShutterCurve) -> Vec<Float> {
+        let shutter = Shutter::new(curve, 0.0).unwrap();
+        (0..1000)
+            .map(|i| shutter.time((i as Float + 0.5) / 1000.0, 0.0))
+            .collect()
+    }
+
+    #[test]
+    fn times_follow_the_shutter_curve() {
+        let mean = |times: &[Float]| times.iter().sum::<Float>() / times.len() as Float;
+        let triangle = samples(ShutterCurve::Triangle);
+        let middle = triangle
+            .iter()
+            .filter(|&&t| (0.25..0.75).contains(&t))
+            .count();
+        assert!((middle as Float / 1000.0 - 0.75).abs() < 0.01);
+        assert!((mean(&triangle) - 0.5).abs() < 0.01);
+        // a shutter that closes over the interval puts a third of the way through on average
+        let closing = samples(ShutterCurve::Curve(vec![1.0, 0.5, 0.0]));
+        assert!((mean(&closing) - 1.0 / 3.0).abs() < 0.01);
+        assert!(closing.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(Shutter::new(ShutterCurve::Curve(vec![0.0, 0.0]), 0.0).is_err());
+    }
+
+    #[test]
+    fn rolling_shutters_expose_rows_in_turn() {
+        let shutter = Shutter::new(ShutterCurve::Box, 0.25).unwrap();
+        assert_eq!(shutter.time(0.0, 0.0), 0.0);
+        assert_eq!(shutter.time(1.0, 0.0), 0.75);
+        assert_eq!(shutter.time(0.0, 1.0), 0.25);
+        assert_eq!(shutter.time(1.0, 1.0), 1.0);
+        assert!(Shutter::new(ShutterCurve::Box, 1.0).is_err());
+    }
+}
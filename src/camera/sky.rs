@@ -0,0 +1,367 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::{
+    color::Color,
+    error::{Error, Result},
+    float::{consts::PI, Float},
+    vec3::Vec3,
+};
+
+// Daylight for outdoor scenes, set by where and when rather than by a sun direction. The position
+// of the sun is worked out from the date, the local time and the place, and the sky around it is
+// the model of Preetham et al. for the given turbidity, the haziness of the air from 2 for a very
+// clear day to 10 for a hazy one. North is towards negative z and east towards positive x.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Sky {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    // The hours the local time is ahead of UTC.
+    pub utc_offset: Float,
+    // In degrees, north and east are positive.
+    pub latitude: Float,
+    pub longitude: Float,
+    pub turbidity: Float,
+}
+
+impl Sky {
+    // Noon at the summer solstice of 2024, in UTC, on a clear day at the given place.
+    pub fn new(latitude: Float, longitude: Float) -> Self {
+        Self {
+            year: 2024,
+            month: 6,
+            day: 21,
+            hour: 12,
+            minute: 0,
+            utc_offset: 0.0,
+            latitude,
+            longitude,
+            turbidity: 3.0,
+        }
+    }
+    pub fn date(self, year: i32, month: u32, day: u32) -> Self {
+        Self {
+            year,
+            month,
+            day,
+            ..self
+        }
+    }
+    pub fn time(self, hour: u32, minute: u32) -> Self {
+        Self {
+            hour,
+            minute,
+            ..self
+        }
+    }
+    pub fn utc_offset(self, utc_offset: Float) -> Self {
+        Self { utc_offset, ..self }
+    }
+    pub fn turbidity(self, turbidity: Float) -> Self {
+        Self { turbidity, ..self }
+    }
+    // The direction towards the sun, from the approximation of its position by the NOAA, good to
+    // a fraction of a degree. Fails for a sky that doesn't pass `validate`.
+    pub fn sun_direction(&self) -> Result<Vec3> {
+        self.validate()?;
+        let leap = is_leap_year(self.year);
+        let days_before_month = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+        let leap_day = (leap && self.month > 2) as u32;
+        let day_of_year = days_before_month[self.month as usize - 1] + leap_day + self.day;
+        let utc_hours = self.hour as Float + self.minute as Float / 60.0 - self.utc_offset;
+        let year_days = if leap { 366.0 } else { 365.0 };
+        // the fraction of the year gone, in radians
+        let g = 2.0 * PI / year_days * (day_of_year as Float - 1.0 + (utc_hours - 12.0) / 24.0);
+        let equation_of_time = 229.18
+            * (0.000075 + 0.001868 * g.cos()
+                - 0.032077 * g.sin()
+                - 0.014615 * (2.0 * g).cos()
+                - 0.040849 * (2.0 * g).sin());
+        let declination = 0.006918 - 0.399912 * g.cos() + 0.070257 * g.sin()
+            - 0.006758 * (2.0 * g).cos()
+            + 0.000907 * (2.0 * g).sin()
+            - 0.002697 * (3.0 * g).cos()
+            + 0.00148 * (3.0 * g).sin();
+        let solar_minutes = utc_hours * 60.0 + equation_of_time + 4.0 * self.longitude;
+        let hour_angle = (solar_minutes / 4.0 - 180.0).to_radians();
+        let latitude = self.latitude.to_radians();
+        let east = -declination.cos() * hour_angle.sin();
+        let north = latitude.cos() * declination.sin()
+            - latitude.sin() * declination.cos() * hour_angle.cos();
+        let up = latitude.sin() * declination.sin()
+            + latitude.cos() * declination.cos() * hour_angle.cos();
+        Ok(Vec3::new(east, up, -north))
+    }
+    // Checks that the sky is at a date and time that exists, at a latitude on the globe and with a
+    // turbidity the sky model holds for.
+    pub fn validate(&self) -> Result<()> {
+        let days_in_month = match self.month {
+            2 if is_leap_year(self.year) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+        if !(1..=12).contains(&self.month)
+            || !(1..=days_in_month).contains(&self.day)
+            || self.hour >= 24
+            || self.minute >= 60
+            || !self.utc_offset.is_finite()
+            || !(-90.0..=90.0).contains(&self.latitude)
+            || !self.longitude.is_finite()
+            || !(2.0..=10.0).contains(&self.turbidity)
+        {
+            return Err(Error::InvalidCamera(format!(
+                "the sky needs a valid date and time, a latitude from -90 to 90 and a turbidity from 2 to 10, current value: {}",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Written as the date, local time, UTC offset, latitude, longitude and turbidity, like
+// `2024-06-21 17:30 +0 64.1466 -21.9426 3` for a summer evening in Reykjavík.
+impl Display for Sky {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{:02}-{:02} {:02}:{:02} {:+} {} {} {}",
+            self.year,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.utc_offset,
+            self.latitude,
+            self.longitude,
+            self.turbidity
+        )
+    }
+}
+
+impl FromStr for Sky {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidCamera(format!("invalid sky: {}", s));
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        let [date, time, utc_offset, latitude, longitude, turbidity] = words[..] else {
+            return Err(invalid());
+        };
+        let date = date
+            .splitn(3, '-')
+            .map(str::parse::<i32>)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        let (hour, minute) = time.split_once(':').ok_or_else(invalid)?;
+        let number = |word: &str| word.parse::<Float>().map_err(|_| invalid());
+        let whole = |word: &str| word.parse::<u32>().map_err(|_| invalid());
+        let [year, month, day] = date[..] else {
+            return Err(invalid());
+        };
+        let sky = Self {
+            year,
+            month: month.try_into().map_err(|_| invalid())?,
+            day: day.try_into().map_err(|_| invalid())?,
+            hour: whole(hour)?,
+            minute: whole(minute)?,
+            utc_offset: number(utc_offset)?,
+            latitude: number(latitude)?,
+            longitude: number(longitude)?,
+            turbidity: number(turbidity)?,
+        };
+        sky.validate()?;
+        Ok(sky)
+    }
+}
+
+// A sky prepared for looking up its color, with the sun placed and the coefficients of the
+// distribution of light over the sky worked out.
+#[derive(Debug, Clone)]
+pub(crate) struct SkyModel {
+    pub(crate) sky: Sky,
+    sun: Vec3,
+    sun_color: Color,
+    // the coefficients of the Perez distribution of the luminance and the x and y chromaticity
+    perez: [[Float; 5]; 3],
+    zenith: [Float; 3],
+    // the Perez distribution towards the zenith, which the zenith values are relative to
+    zenith_perez: [Float; 3],
+    // the sky fades out as the sun sets, as the model only holds for a sun above the horizon
+    twilight: Float,
+}
+
+impl SkyModel {
+    // The sky is scaled down from its luminance in kilocandelas per square meter to about the
+    // brightness of the default background.
+    const SCALE: Float = 0.05;
+    // The light of the sun straight overhead and outside the atmosphere, relative to the sky.
+    const SUN_IRRADIANCE: Float = 25.0;
+    // The radius of the sun in degrees. The real sun is half a degree across, which so few
+    // paths find by chance that it lights the scene with fireflies unless lights are sampled, see
+    // `sun_light`. A larger sun of the same power gives the same light with softer shadows and far
+    // less noise either way.
+    const SUN_RADIUS: Float = 2.5;
+
+    pub(crate) fn new(sky: Sky) -> Result<Self> {
+        let sun = sky.sun_direction()?.unit_vector();
+        // the angle of the sun from the zenith, held just above the horizon for the model
+        let theta = sun.y.clamp(-1.0, 1.0).acos().min(PI / 2.0 - 0.01);
+        let t = sky.turbidity;
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+        let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (theta2, theta3) = (theta * theta, theta * theta * theta);
+        let x = t * t * (0.00166 * theta3 - 0.00375 * theta2 + 0.00209 * theta)
+            + t * (-0.02903 * theta3 + 0.06377 * theta2 - 0.03202 * theta + 0.00394)
+            + (0.11693 * theta3 - 0.21196 * theta2 + 0.06052 * theta + 0.25886);
+        let y = t * t * (0.00275 * theta3 - 0.00610 * theta2 + 0.00317 * theta)
+            + t * (-0.04214 * theta3 + 0.08970 * theta2 - 0.04153 * theta + 0.00516)
+            + (0.15346 * theta3 - 0.26756 * theta2 + 0.06670 * theta + 0.26688);
+        let zenith_perez = perez.map(|coefficients| perez_distribution(&coefficients, 1.0, theta));
+        // civil twilight ends with the sun six degrees under the horizon
+        let elevation = 90.0 - sun.y.clamp(-1.0, 1.0).acos().to_degrees();
+        let twilight = (1.0 + elevation / 6.0).clamp(0.0, 1.0);
+        Ok(Self {
+            sky,
+            sun,
+            sun_color: sun_color(sun, t),
+            perez,
+            zenith: [luminance, x, y],
+            zenith_perez,
+            twilight,
+        })
+    }
+    // The light coming from the sky in `direction`, the sun included. Below the horizon the sky
+    // carries on as it is at the horizon.
+    pub(crate) fn radiance(&self, direction: Vec3) -> Color {
+        let direction = direction.unit_vector();
+        let cos_sun = direction.dot(&self.sun);
+        let sun_cos_radius = Self::SUN_RADIUS.to_radians().cos();
+        if cos_sun >= sun_cos_radius && self.sun.y > 0.0 {
+            let solid_angle = 2.0 * PI * (1.0 - sun_cos_radius);
+            return self.sun_color * (Self::SUN_IRRADIANCE / solid_angle);
+        }
+        let cos_theta = direction.y.max(0.01);
+        let gamma = cos_sun.clamp(-1.0, 1.0).acos();
+        let [luminance, x, y] = [0, 1, 2].map(|index| {
+            self.zenith[index] * perez_distribution(&self.perez[index], cos_theta, gamma)
+                / self.zenith_perez[index]
+        });
+        Color::from_xy(x, y, luminance.max(0.0) * Self::SCALE * self.twilight)
+    }
+    // The direction towards the sun and the cosine of its radius, for drawing bounces towards it
+    // like towards the lights of the scene, or `None` once it has set.
+    pub(crate) fn sun_light(&self) -> Option<(Vec3, Float)> {
+        (self.sun.y > 0.0).then(|| (self.sun, Self::SUN_RADIUS.to_radians().cos()))
+    }
+}
+
+fn is_leap_year(year: i32) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+// How the light of the sky is spread by the angle from the zenith and from the sun.
+fn perez_distribution(coefficients: &[Float; 5], cos_theta: Float, gamma: Float) -> Float {
+    let [a, b, c, d, e] = *coefficients;
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * gamma.cos().powi(2))
+}
+
+// The color of sunlight after the way through the atmosphere, at the wavelengths of red, green and
+// blue. Air scatters blue away the most, more so the lower the sun and the longer its way.
+fn sun_color(sun: Vec3, turbidity: Float) -> Color {
+    let zenith_angle = sun.y.clamp(0.0, 1.0).acos().to_degrees();
+    let air_mass =
+        1.0 / (zenith_angle.to_radians().cos() + 0.15 * (93.885 - zenith_angle).powf(-1.253));
+    let haze = 0.04608 * turbidity - 0.04586;
+    let transmittance = |micrometers: Float| {
+        let rayleigh = 0.008735 * micrometers.powf(-4.08);
+        let aerosol = haze * micrometers.powf(-1.3);
+        (-(rayleigh + aerosol) * air_mass).exp()
+    };
+    Color::new(
+        transmittance(0.68),
+        transmittance(0.55),
+        transmittance(0.44),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn elevation(sky: &Sky) -> Float {
+        90.0 - sky
+            .sun_direction()
+            .unwrap()
+            .unit_vector()
+            .y
+            .acos()
+            .to_degrees()
+    }
+
+    #[test]
+    fn the_sun_follows_the_clock() {
+        let reykjavik = Sky::new(64.1466, -21.9426).time(17, 30);
+        assert!((elevation(&reykjavik) - 33.9).abs() < 0.5);
+        // in the afternoon the sun is in the west, towards negative x
+        assert!(reykjavik.sun_direction().unwrap().x < 0.0);
+        // overhead at noon on the equator at the equinox
+        let equator = Sky::new(0.0, 0.0).date(2024, 3, 20).time(12, 7);
+        assert!(elevation(&equator) > 88.0);
+        let midnight = Sky::new(0.0, 0.0).time(0, 0);
+        assert!(elevation(&midnight) < -60.0);
+        let reykjavik = reykjavik.utc_offset(2.0).time(19, 30);
+        assert!((elevation(&reykjavik) - 33.9).abs() < 0.5);
+        let written = reykjavik.turbidity(4.5).to_string();
+        assert_eq!(written, "2024-06-21 19:30 +2 64.1466 -21.9426 4.5");
+        assert_eq!(written.parse::<Sky>().unwrap().to_string(), written);
+        assert!(SkyModel::new(Sky::new(0.0, 0.0).date(2023, 2, 29)).is_err());
+        for month in [0, 13] {
+            assert!(Sky::new(0.0, 0.0)
+                .date(2024, month, 1)
+                .sun_direction()
+                .is_err());
+        }
+        assert!("2024-00-10 12:00 +0 0 0 3".parse::<Sky>().is_err());
+    }
+
+    #[test]
+    fn sunsets_are_red_and_skies_are_blue() {
+        let noon = SkyModel::new(Sky::new(45.0, 0.0)).unwrap();
+        let zenith = noon.radiance(Vec3::new(0.0, 1.0, 0.0));
+        assert!(zenith.b > zenith.r && zenith.luminance() > 0.1);
+        let evening = SkyModel::new(Sky::new(45.0, 0.0).time(19, 30)).unwrap();
+        let ratio = |color: Color| color.r / color.b;
+        assert!(ratio(evening.sun_color) > ratio(noon.sun_color));
+        assert!(noon.radiance(noon.sun).luminance() > 100.0);
+        let night = SkyModel::new(Sky::new(45.0, 0.0).time(0, 0)).unwrap();
+        assert_eq!(night.radiance(Vec3::new(0.0, 1.0, 0.0)).luminance(), 0.0);
+    }
+}
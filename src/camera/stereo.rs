@@ -0,0 +1,67 @@
+use super::Camera;
+use crate::{
+    float::{consts::PI, Float},
+    vec3::{Point3, Vec3},
+};
+
+impl Camera {
+    // Omni-directional stereo: the image is two equirectangular panoramas of everything around the
+    // camera, the left eye's on top of the right eye's, which VR headsets show as a 360° scene in
+    // depth. Every column looks out in its own direction from the eyes of a head turned to face
+    // it, so the eyes sit `interpupillary_distance` apart on a circle around the camera, offset
+    // sideways from the direction of the ray. The eyes move in towards the center towards straight
+    // up and down, where there is no sideways to offset them in, so the poles don't pinch. Returns
+    // the origin and direction of the ray through a point of the image, in pixels from its top
+    // left corner.
+    pub(super) fn stereo_ray(
+        &self,
+        interpupillary_distance: Float,
+        dx: Float,
+        dy: Float,
+    ) -> (Point3, Vec3) {
+        let eye_height = self.image_height as Float / 2.0;
+        let (eye, row) = if dy < eye_height {
+            (-1.0, dy)
+        } else {
+            (1.0, dy - eye_height)
+        };
+        // the middle of the image looks at `lookat`
+        let azimuth = (dx / self.image_width as Float - 0.5) * 2.0 * PI;
+        let elevation = (0.5 - row / eye_height) * PI;
+        let (sin_azimuth, cos_azimuth) = azimuth.sin_cos();
+        let (sin_elevation, cos_elevation) = elevation.sin_cos();
+        let direction =
+            cos_elevation * (sin_azimuth * self.u - cos_azimuth * self.w) + sin_elevation * self.v;
+        let sideways = cos_azimuth * self.u + sin_azimuth * self.w;
+        let offset = eye * interpupillary_distance / 2.0 * cos_elevation;
+        (self.center + offset * sideways, direction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::camera::test_camera;
+
+    #[test]
+    fn eyes_look_around_from_either_side() {
+        let camera = test_camera(64, 1.0)
+            .omnidirectional_stereo(0.064)
+            .build()
+            .unwrap();
+        let forward = -camera.w;
+        // the middle of the top half is the left eye looking at `lookat`
+        let (left, direction) = camera.stereo_ray(0.064, 32.0, 16.0);
+        assert!((direction - forward).length() < 1e-6);
+        assert!((left - (camera.center - 0.032 * camera.u)).length() < 1e-6);
+        let (right, _) = camera.stereo_ray(0.064, 32.0, 48.0);
+        assert!((right - (camera.center + 0.032 * camera.u)).length() < 1e-6);
+        // a quarter turn to the right, the eyes are then in front of and behind the center
+        let (left, direction) = camera.stereo_ray(0.064, 48.0, 16.0);
+        assert!((direction - camera.u).length() < 1e-6);
+        assert!((left - (camera.center + 0.032 * forward)).length() < 1e-6);
+        // the eyes meet looking straight up
+        let (left, direction) = camera.stereo_ray(0.064, 10.0, 0.0);
+        assert!((direction - camera.v).length() < 1e-6);
+        assert!((left - camera.center).length() < 1e-6);
+    }
+}
@@ -0,0 +1,287 @@
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use tracing::debug;
+
+use super::Camera;
+use crate::{
+    color::Color,
+    error::{Error, Result},
+};
+
+// Tiles have to be a multiple of this many pixels wide and tall.
+const TILE_MULTIPLE: usize = 16;
+
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+
+// How the pixels of a float TIFF are laid out in the file. Readers load strips or tiles one at a
+// time, so large images read piece by piece do best with tiles and small ones with a single strip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TiffLayout {
+    // Bands of this many rows across the image, written `strips:16`.
+    Strips { rows: usize },
+    // Rectangles of pixels, written `tiles:64x64`. Tiles on the right and bottom edges are padded
+    // with black.
+    Tiles { width: usize, height: usize },
+}
+
+impl Default for TiffLayout {
+    fn default() -> Self {
+        TiffLayout::Tiles {
+            width: 64,
+            height: 64,
+        }
+    }
+}
+
+impl Display for TiffLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TiffLayout::Strips { rows } => write!(f, "strips:{}", rows),
+            TiffLayout::Tiles { width, height } => write!(f, "tiles:{}x{}", width, height),
+        }
+    }
+}
+
+impl FromStr for TiffLayout {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidCamera(format!("invalid TIFF layout: {}", s));
+        let layout = match s.split_once(':').ok_or_else(invalid)? {
+            ("strips", rows) => TiffLayout::Strips {
+                rows: rows.parse().map_err(|_| invalid())?,
+            },
+            ("tiles", size) => {
+                let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+                TiffLayout::Tiles {
+                    width: width.parse().map_err(|_| invalid())?,
+                    height: height.parse().map_err(|_| invalid())?,
+                }
+            }
+            _ => return Err(invalid()),
+        };
+        match layout {
+            TiffLayout::Strips { rows } if rows > 0 => Ok(layout),
+            TiffLayout::Tiles { width, height }
+                if width > 0
+                    && height > 0
+                    && width % TILE_MULTIPLE == 0
+                    && height % TILE_MULTIPLE == 0 =>
+            {
+                Ok(layout)
+            }
+            _ => Err(Error::InvalidCamera(format!(
+                "TIFF strips need rows and tiles a multiple of {} pixels: {}",
+                TILE_MULTIPLE, s
+            ))),
+        }
+    }
+}
+
+// A field of the image file directory, numbers of `kind` under a `tag`.
+struct Field {
+    tag: u16,
+    kind: u16,
+    values: Vec<u32>,
+}
+
+impl Field {
+    fn new(tag: u16, kind: u16, values: Vec<u32>) -> Self {
+        Self { tag, kind, values }
+    }
+    fn bytes(&self) -> Vec<u8> {
+        self.values
+            .iter()
+            .flat_map(|&value| match self.kind {
+                SHORT => (value as u16).to_le_bytes().to_vec(),
+                _ => value.to_le_bytes().to_vec(),
+            })
+            .collect()
+    }
+}
+
+// Writes linear colors as an uncompressed TIFF of 32 bit floats, for pipelines that read TIFF but
+// not EXR.
+pub fn write_float_tiff<P: AsRef<Path>>(
+    path: P,
+    width: usize,
+    height: usize,
+    colors: &[Color],
+    layout: TiffLayout,
+) -> Result<()> {
+    let pixel = |x: usize, y: usize| {
+        let color = if x < width && y < height {
+            colors[y * width + x]
+        } else {
+            Color::black()
+        };
+        [color.r as f32, color.g as f32, color.b as f32]
+    };
+    let (chunk_width, chunk_height) = match layout {
+        TiffLayout::Strips { rows } => (width, rows.min(height)),
+        TiffLayout::Tiles { width, height } => (width, height),
+    };
+    let mut chunks = Vec::new();
+    for top in (0..height).step_by(chunk_height) {
+        for left in (0..width).step_by(chunk_width) {
+            // strips end at the bottom of the image, tiles are always whole
+            let bottom = match layout {
+                TiffLayout::Strips { .. } => (top + chunk_height).min(height),
+                TiffLayout::Tiles { .. } => top + chunk_height,
+            };
+            let chunk = (top..bottom)
+                .flat_map(|y| (left..left + chunk_width).map(move |x| (x, y)))
+                .flat_map(|(x, y)| pixel(x, y))
+                .flat_map(f32::to_le_bytes)
+                .collect::<Vec<u8>>();
+            chunks.push(chunk);
+        }
+    }
+
+    // the header, then the pixels, then the directory and the values too long to fit in it
+    let mut offsets = Vec::new();
+    let mut offset = 8;
+    for chunk in &chunks {
+        offsets.push(file_offset(offset)?);
+        offset += chunk.len();
+    }
+    let counts = chunks.iter().map(|chunk| chunk.len() as u32).collect();
+    let mut fields = vec![
+        Field::new(256, LONG, vec![width as u32]),
+        Field::new(257, LONG, vec![height as u32]),
+        // bits per sample
+        Field::new(258, SHORT, vec![32; 3]),
+        // no compression
+        Field::new(259, SHORT, vec![1]),
+        // RGB
+        Field::new(262, SHORT, vec![2]),
+        // samples per pixel
+        Field::new(277, SHORT, vec![3]),
+        // the channels of a pixel are together
+        Field::new(284, SHORT, vec![1]),
+        // floating point samples
+        Field::new(339, SHORT, vec![3; 3]),
+    ];
+    match layout {
+        TiffLayout::Strips { .. } => {
+            fields.push(Field::new(273, LONG, offsets));
+            fields.push(Field::new(278, LONG, vec![chunk_height as u32]));
+            fields.push(Field::new(279, LONG, counts));
+        }
+        TiffLayout::Tiles { .. } => {
+            fields.push(Field::new(322, LONG, vec![chunk_width as u32]));
+            fields.push(Field::new(323, LONG, vec![chunk_height as u32]));
+            fields.push(Field::new(324, LONG, offsets));
+            fields.push(Field::new(325, LONG, counts));
+        }
+    }
+    fields.sort_by_key(|field| field.tag);
+
+    let directory_offset = offset;
+    let mut extra_offset = directory_offset + 2 + 12 * fields.len() + 4;
+    let mut directory = (fields.len() as u16).to_le_bytes().to_vec();
+    let mut extra = Vec::new();
+    for field in &fields {
+        let bytes = field.bytes();
+        directory.extend(field.tag.to_le_bytes());
+        directory.extend(field.kind.to_le_bytes());
+        directory.extend((field.values.len() as u32).to_le_bytes());
+        if bytes.len() <= 4 {
+            directory.extend(&bytes);
+            directory.extend(vec![0; 4 - bytes.len()]);
+        } else {
+            directory.extend(file_offset(extra_offset)?.to_le_bytes());
+            extra_offset += bytes.len();
+            extra.extend(bytes);
+        }
+    }
+    // no directory follows
+    directory.extend(0u32.to_le_bytes());
+    // the values at the end have to be reachable too
+    file_offset(extra_offset)?;
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(b"II*\0")?;
+    writer.write_all(&file_offset(directory_offset)?.to_le_bytes())?;
+    for chunk in &chunks {
+        writer.write_all(chunk)?;
+    }
+    writer.write_all(&directory)?;
+    writer.write_all(&extra)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Offsets into a TIFF are 32 bits, which no part of a file past 4 GiB can be reached with.
+fn file_offset(offset: usize) -> Result<u32> {
+    u32::try_from(offset).map_err(|_| {
+        Error::InvalidCamera(format!(
+            "the TIFF is too large for 32 bit offsets, current offset: {}",
+            offset
+        ))
+    })
+}
+
+impl Camera {
+    // Writes `image.tif`, the linear image in the float primaries, ahead of the white balance and
+    // post processing.
+    pub(super) fn write_tiff_output(
+        &self,
+        image_buffer: &[Color],
+        layout: TiffLayout,
+    ) -> Result<()> {
+        let path = self.output_path(".tif");
+        write_float_tiff(
+            &path,
+            self.image_width,
+            self.image_height,
+            &self.in_float_primaries(image_buffer),
+            layout,
+        )?;
+        debug!("wrote {}", path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::float::Float;
+    use ::tiff::decoder::{Decoder, DecodingResult};
+    use std::io::BufReader;
+
+    #[test]
+    fn writes_strips_and_tiles() {
+        let (width, height) = (21, 18);
+        let colors = (0..width * height)
+            .map(|i| Color::new(i as Float, 0.5, -1e6))
+            .collect::<Vec<_>>();
+        let path = std::env::temp_dir().join(format!("raytracer-{}.tif", std::process::id()));
+        for layout in ["strips:4", "strips:100", "tiles:16x16", "tiles:32x16"] {
+            let layout = layout.parse::<TiffLayout>().unwrap();
+            assert_eq!(layout.to_string().parse::<TiffLayout>().unwrap(), layout);
+            write_float_tiff(&path, width, height, &colors, layout).unwrap();
+            let mut decoder = Decoder::new(BufReader::new(File::open(&path).unwrap())).unwrap();
+            assert_eq!(decoder.dimensions().unwrap(), (width as u32, height as u32));
+            let DecodingResult::F32(read) = decoder.read_image().unwrap() else {
+                panic!("{} didn't read as floats", layout);
+            };
+            let expected = colors
+                .iter()
+                .flat_map(|color| [color.r as f32, color.g as f32, color.b as f32])
+                .collect::<Vec<_>>();
+            assert_eq!(read, expected, "{}", layout);
+        }
+        std::fs::remove_file(&path).unwrap();
+        assert!("tiles:20x16".parse::<TiffLayout>().is_err());
+        assert!("strips:0".parse::<TiffLayout>().is_err());
+        assert_eq!(file_offset(u32::MAX as usize).unwrap(), u32::MAX);
+        assert!(file_offset(u32::MAX as usize + 1).is_err());
+    }
+}
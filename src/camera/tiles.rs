@@ -0,0 +1,432 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::SyncSender;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use ::image::{Rgb, RgbImage};
+use rayon::ScopeFifo;
+use tracing::{debug, warn};
+use web_time::Instant;
+
+use super::{
+    aov::{Layer, TileUpdate},
+    Camera, RenderControl,
+};
+use crate::{
+    color::Color,
+    error::Result,
+    float::Float,
+    hittable::Hittable,
+    telemetry::{self, Tile},
+};
+
+// The order in which the tiles of an image are handed out to the render threads.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TileOrder {
+    // Row by row from the top left.
+    #[default]
+    Scanline,
+    // Rings around the center of the image, so the region of interest shows up first.
+    Spiral,
+    // Along a Hilbert curve, consecutive tiles are neighbours and share cache warm BVH nodes.
+    Hilbert,
+}
+
+impl TileOrder {
+    // Returns the row major indices of a `columns` by `rows` grid of tiles in render order.
+    pub fn order(&self, columns: usize, rows: usize) -> Vec<usize> {
+        match self {
+            TileOrder::Scanline => (0..columns * rows).collect(),
+            TileOrder::Spiral => spiral_order(columns, rows),
+            TileOrder::Hilbert => hilbert_order(columns, rows),
+        }
+    }
+}
+
+fn spiral_order(columns: usize, rows: usize) -> Vec<usize> {
+    let center_x = (columns as Float - 1.0) / 2.0;
+    let center_y = (rows as Float - 1.0) / 2.0;
+    let key = |index: usize| {
+        let dx = (index % columns) as Float - center_x;
+        let dy = (index / columns) as Float - center_y;
+        let ring = dx.abs().max(dy.abs());
+        (ring, dy.atan2(dx))
+    };
+    let mut order = (0..columns * rows).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| {
+        let (ring_a, angle_a) = key(a);
+        let (ring_b, angle_b) = key(b);
+        ring_a.total_cmp(&ring_b).then(angle_a.total_cmp(&angle_b))
+    });
+    return order;
+}
+
+fn hilbert_order(columns: usize, rows: usize) -> Vec<usize> {
+    let side = columns.max(rows).next_power_of_two();
+    let mut order = Vec::with_capacity(columns * rows);
+    for distance in 0..side * side {
+        let (x, y) = hilbert_point(side, distance);
+        if x < columns && y < rows {
+            order.push(y * columns + x);
+        }
+    }
+    return order;
+}
+
+// Maps a distance along the Hilbert curve filling a `side` by `side` square to a point in it.
+fn hilbert_point(side: usize, distance: usize) -> (usize, usize) {
+    let (mut x, mut y) = (0, 0);
+    let mut t = distance;
+    let mut s = 1;
+    while s < side {
+        let rx = 1 & (t / 2);
+        let ry = 1 & (t ^ rx);
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - x;
+                y = s - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x += s * rx;
+        y += s * ry;
+        t /= 4;
+        s *= 2;
+    }
+    return (x, y);
+}
+
+// The image is divided into this many regions on a side for estimating the cost of tiles.
+const REGIONS: usize = 8;
+
+// Decides when a tile is too expensive to render in one piece. The cost of a tile is estimated
+// from the time per pixel of the tiles rendered so far in the regions of the image it covers, so
+// tiles of the sky stay whole while tiles of a glass sculpture next to it are split, and from the
+// time per pixel of the whole image in regions nothing has been rendered in yet. Tiles are split
+// into quadrants more eagerly once the queue runs low so the last few tiles don't leave most
+// threads idle.
+#[derive(Debug)]
+pub struct AdaptiveTiles {
+    min_size: usize,
+    target_nanos: u64,
+    image: (usize, usize),
+    nanos_per_pixel: AtomicU64,
+    region_nanos_per_pixel: Vec<AtomicU64>,
+    pending: AtomicUsize,
+    threads: usize,
+}
+
+impl AdaptiveTiles {
+    // For an image of `image` rows and columns.
+    pub fn new(image: (usize, usize), min_size: usize, target: Duration) -> Self {
+        Self {
+            min_size,
+            target_nanos: target.as_nanos() as u64,
+            image,
+            nanos_per_pixel: AtomicU64::new(0),
+            region_nanos_per_pixel: (0..REGIONS * REGIONS).map(|_| AtomicU64::new(0)).collect(),
+            pending: AtomicUsize::new(0),
+            threads: rayon::current_num_threads(),
+        }
+    }
+    pub fn should_split(&self, top_left: (usize, usize), rect: (usize, usize)) -> bool {
+        if rect.0 < 2 * self.min_size || rect.1 < 2 * self.min_size {
+            return false;
+        }
+        let overall = self.nanos_per_pixel.load(Ordering::Relaxed);
+        let estimate = self
+            .regions(top_left, rect)
+            .map(|(region, pixels)| {
+                match self.region_nanos_per_pixel[region].load(Ordering::Relaxed) {
+                    0 => pixels * overall,
+                    nanos_per_pixel => pixels * nanos_per_pixel,
+                }
+            })
+            .sum::<u64>();
+        let target = if self.pending.load(Ordering::Relaxed) < 2 * self.threads {
+            self.target_nanos / 4
+        } else {
+            self.target_nanos
+        };
+        return estimate > target;
+    }
+    pub fn record(&self, top_left: (usize, usize), rect: (usize, usize), elapsed: Duration) {
+        let sample = elapsed.as_nanos() as u64 / (rect.0 * rect.1).max(1) as u64;
+        let update = |nanos_per_pixel: &AtomicU64| {
+            let previous = nanos_per_pixel.load(Ordering::Relaxed);
+            let average = if previous == 0 {
+                sample
+            } else {
+                (previous * 7 + sample) / 8
+            };
+            nanos_per_pixel.store(average, Ordering::Relaxed);
+        };
+        update(&self.nanos_per_pixel);
+        for (region, _) in self.regions(top_left, rect) {
+            update(&self.region_nanos_per_pixel[region]);
+        }
+    }
+    // The regions the tile overlaps, with the number of its pixels in each.
+    fn regions(
+        &self,
+        top_left: (usize, usize),
+        rect: (usize, usize),
+    ) -> impl Iterator<Item = (usize, u64)> + '_ {
+        // the pixels of the region `index` along an axis of the image `size` pixels long, and how
+        // many of them the tile covers
+        let bounds =
+            |index: usize, size: usize| (index * size / REGIONS, (index + 1) * size / REGIONS);
+        let overlap = move |index: usize, size: usize, start: usize, length: usize| {
+            let (region_start, region_end) = bounds(index, size);
+            (start + length)
+                .min(region_end)
+                .saturating_sub(start.max(region_start))
+        };
+        let region =
+            |position: usize, size: usize| (position * REGIONS / size.max(1)).min(REGIONS - 1);
+        let (rows, columns) = self.image;
+        let first = (region(top_left.0, rows), region(top_left.1, columns));
+        let last = (
+            region(top_left.0 + rect.0.max(1) - 1, rows),
+            region(top_left.1 + rect.1.max(1) - 1, columns),
+        );
+        (first.0..=last.0).flat_map(move |y| {
+            (first.1..=last.1).map(move |x| {
+                let pixels =
+                    overlap(y, rows, top_left.0, rect.0) * overlap(x, columns, top_left.1, rect.1);
+                (y * REGIONS + x, pixels as u64)
+            })
+        })
+    }
+}
+
+pub(crate) fn quadrants(
+    top_left: (usize, usize),
+    rect: (usize, usize),
+) -> [((usize, usize), (usize, usize)); 4] {
+    let (half_y, half_x) = (rect.0 / 2, rect.1 / 2);
+    let (rest_y, rest_x) = (rect.0 - half_y, rect.1 - half_x);
+    [
+        (top_left, (half_y, half_x)),
+        ((top_left.0, top_left.1 + half_x), (half_y, rest_x)),
+        ((top_left.0 + half_y, top_left.1), (rest_y, half_x)),
+        ((top_left.0 + half_y, top_left.1 + half_x), (rest_y, rest_x)),
+    ]
+}
+
+// The state shared by the render tasks of one `Camera::render` call.
+pub(crate) struct TileWorker<'a> {
+    pub(crate) camera: &'a Camera,
+    pub(crate) world: &'a Box<dyn Hittable>,
+    pub(crate) image_buffer: Mutex<Vec<Color>>,
+    pub(crate) layer_buffers: Mutex<Vec<Vec<Color>>>,
+    pub(crate) sender: SyncSender<TileUpdate>,
+    pub(crate) tiles: AdaptiveTiles,
+    pub(crate) queue: Mutex<VecDeque<Tile>>,
+    pub(crate) control: &'a RenderControl,
+}
+
+impl<'a> TileWorker<'a> {
+    pub(crate) fn spawn<'s>(
+        &'s self,
+        scope: &ScopeFifo<'s>,
+        top_left: (usize, usize),
+        rect: (usize, usize),
+    ) {
+        self.tiles.pending.fetch_add(1, Ordering::Relaxed);
+        scope.spawn_fifo(move |scope| {
+            self.tiles.pending.fetch_sub(1, Ordering::Relaxed);
+            self.process(scope, top_left, rect);
+        });
+    }
+
+    // Spawns a task that renders the next tile in the queue, unless a remote worker claimed it.
+    pub(crate) fn spawn_queued<'s>(&'s self, scope: &ScopeFifo<'s>) {
+        self.tiles.pending.fetch_add(1, Ordering::Relaxed);
+        scope.spawn_fifo(move |scope| {
+            self.tiles.pending.fetch_sub(1, Ordering::Relaxed);
+            if let Some((top_left, rect)) = self.claim() {
+                self.process(scope, top_left, rect);
+            }
+        });
+    }
+
+    pub(crate) fn claim(&self) -> Option<((usize, usize), (usize, usize))> {
+        self.control.wait_while_paused();
+        if self.control.is_cancelled() {
+            return None;
+        }
+        self.queue.lock().unwrap().pop_front()
+    }
+
+    fn process<'s>(
+        &'s self,
+        scope: &ScopeFifo<'s>,
+        top_left: (usize, usize),
+        rect: (usize, usize),
+    ) {
+        self.control.wait_while_paused();
+        if self.control.is_cancelled() {
+            return;
+        }
+        if self.tiles.should_split(top_left, rect) {
+            for (top_left, rect) in quadrants(top_left, rect) {
+                self.spawn(scope, top_left, rect);
+            }
+            return;
+        }
+
+        self.control.start_tile((top_left, rect));
+        let start_time = Instant::now();
+        let (result, layers) =
+            self.camera
+                .render_tile_layers(top_left, rect, self.world, self.control);
+        self.tiles.record(top_left, rect, start_time.elapsed());
+        telemetry::record_tile((top_left, rect), start_time.elapsed());
+        telemetry::flush();
+        if self.control.is_cancelled() {
+            // the tile may have been cut short, leave the image as it was
+            self.control.finish_tile((top_left, rect));
+            return;
+        }
+        self.complete(top_left, rect, result, layers);
+    }
+
+    // Stores a rendered tile in the image and forwards it to the preview, along with its AOVs. The
+    // tiles of the render layers, if any, are stored in their buffers. The tile must have been
+    // started on `control` first.
+    pub(crate) fn complete(
+        &self,
+        top_left: (usize, usize),
+        rect: (usize, usize),
+        result: Vec<Color>,
+        layers: Vec<Vec<Color>>,
+    ) {
+        let image_width = self.camera.image_width;
+        let store = |buffer: &mut Vec<Color>, tile: &[Color]| {
+            for dy in 0..rect.0 {
+                for dx in 0..rect.1 {
+                    let index = ((top_left.0 + dy) * image_width) + (top_left.1 + dx);
+                    buffer[index] = tile[(dy * rect.1) + dx];
+                }
+            }
+        };
+        store(&mut self.image_buffer.lock().unwrap(), &result);
+        if !layers.is_empty() {
+            let mut layer_buffers = self.layer_buffers.lock().unwrap();
+            for (buffer, tile) in layer_buffers.iter_mut().zip(&layers) {
+                store(buffer, tile);
+            }
+        }
+        let aovs = self
+            .camera
+            .aovs
+            .then(|| self.camera.render_aovs(top_left, rect, self.world));
+        if self
+            .sender
+            .send((Layer::Beauty, top_left, rect, result))
+            .is_err()
+        {
+            self.control.cancel();
+        }
+        for (layer, colors) in Layer::AOVS.into_iter().zip(aovs.into_iter().flatten()) {
+            if self.sender.send((layer, top_left, rect, colors)).is_err() {
+                self.control.cancel();
+            }
+        }
+        self.control.finish_tile((top_left, rect));
+    }
+}
+
+impl Camera {
+    // Writes `image-tile-times.png`, every tile colored by its render time per pixel relative to
+    // the slowest one. Tiles without a recorded time, like those of remote workers, are black.
+    pub(crate) fn write_tile_heatmap(&self, tiles: &[(Tile, Duration)]) -> Result<()> {
+        if tiles.is_empty() {
+            warn!("no tile times were recorded, the tile heatmap needs the telemetry feature");
+            return Ok(());
+        }
+        let per_pixel = |((_, rect), elapsed): &(Tile, Duration)| {
+            elapsed.as_secs_f64() / (rect.0 * rect.1).max(1) as f64
+        };
+        let slowest = tiles
+            .iter()
+            .map(per_pixel)
+            .fold(f64::MIN_POSITIVE, f64::max);
+        let mut image = RgbImage::new(self.image_width as u32, self.image_height as u32);
+        for tile in tiles {
+            let ((top, left), (height, width)) = tile.0;
+            let (r, g, b) = Color::heatmap((per_pixel(tile) / slowest) as Float).into_u8();
+            for y in top..top + height {
+                for x in left..left + width {
+                    image.put_pixel(x as u32, y as u32, Rgb([r, g, b]));
+                }
+            }
+        }
+        let path = self.output_path("-tile-times.png");
+        image.save(&path)?;
+        debug!(slowest_seconds_per_pixel = slowest, "wrote {}", path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        camera::{test_camera, RenderControl},
+        hittable::{geometry::Sphere, materials::Lambertian, Hittable},
+        vec3::Point3,
+    };
+
+    #[test]
+    fn tiles_split_where_the_image_is_expensive() {
+        let tiles = AdaptiveTiles::new((256, 256), 8, Duration::from_millis(20));
+        tiles.pending.store(usize::MAX, Ordering::Relaxed);
+        // a cheap tile of sky at the top and an expensive one at the bottom, each filling a region,
+        // so the next tiles there are split on their own times
+        tiles.record((0, 0), (32, 32), Duration::from_micros(100));
+        tiles.record((224, 224), (32, 32), Duration::from_millis(50));
+        assert!(!tiles.should_split((0, 0), (32, 32)));
+        assert!(tiles.should_split((224, 224), (32, 32)));
+        // nothing was rendered in the middle yet, it takes the average of the image
+        let overall = tiles.nanos_per_pixel.load(Ordering::Relaxed);
+        assert_eq!(
+            tiles
+                .regions((128, 128), (32, 32))
+                .map(|(_, pixels)| pixels)
+                .sum::<u64>(),
+            32 * 32
+        );
+        assert_eq!(
+            tiles.should_split((128, 128), (32, 32)),
+            32 * 32 * overall > tiles.target_nanos
+        );
+    }
+
+    #[test]
+    fn packets_render_the_same_however_they_are_split() {
+        let world: Box<dyn Hittable> = Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -1.0),
+            0.5,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        ));
+        let camera = test_camera(8, 1.0)
+            .random_sampler(4)
+            .max_ray_depth(4)
+            .packet_tracing(true)
+            .build()
+            .unwrap();
+        let control = RenderControl::default();
+        let whole = camera.render_tile((0, 0), (8, 8), &world, &control);
+        let top = camera.render_tile((0, 0), (4, 8), &world, &control);
+        let bottom = camera.render_tile((4, 0), (4, 8), &world, &control);
+        for (whole, split) in whole.iter().zip(top.iter().chain(&bottom)) {
+            assert_eq!((*whole - *split).length(), 0.0);
+        }
+    }
+}
@@ -2,12 +2,14 @@ use std::fs::File;
 use std::io::{BufWriter, Result, Write};
 use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub};
 
-use crate::random::Rng;
+use crate::float::Float;
+use crate::random::RandomSource;
 use crate::vec3::Vec3;
 
-type Value = f64;
+type Value = Float;
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Color {
     pub r: Value,
     pub g: Value,
@@ -56,14 +58,63 @@ impl Color {
         *self / self.length()
     }
     pub fn gamma_corrected(&self, gamma: Value) -> Self {
-        let correct = |val: f64| val.powf(1.0 / gamma);
+        let correct = |val: Float| val.powf(1.0 / gamma);
         Self::new(correct(self.r), correct(self.g), correct(self.b))
     }
+    // Decodes a channel of an sRGB encoded color, like the colors in image files, to linear.
+    pub fn srgb_to_linear(value: Value) -> Value {
+        if value <= 0.04045 {
+            value / 12.92
+        } else {
+            ((value + 0.055) / 1.055).powf(2.4)
+        }
+    }
+    // The linear color of the given CIE xy chromaticity and luminance.
+    pub fn from_xy(x: Value, y: Value, luminance: Value) -> Self {
+        let (cx, cy, cz) = (x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+        Self::new(
+            3.2406 * cx - 1.5372 * cy - 0.4986 * cz,
+            -0.9689 * cx + 1.8758 * cy + 0.0415 * cz,
+            0.0557 * cx - 0.2040 * cy + 1.0570 * cz,
+        )
+    }
+    // The range of temperatures, in kelvin, `blackbody` covers.
+    pub const BLACKBODY_RANGE: (Value, Value) = (1667.0, 25000.0);
+    // The color of a black body at the given temperature with a luminance of one, from the cubic
+    // fit of the Planckian locus by Kim et al. Temperatures outside of the range are held at its
+    // ends.
+    pub fn blackbody(temperature: Value) -> Self {
+        let t = temperature.clamp(Self::BLACKBODY_RANGE.0, Self::BLACKBODY_RANGE.1);
+        let (t2, t3) = (t * t, t * t * t);
+        let x = if t <= 4000.0 {
+            -0.2661239e9 / t3 - 0.2343589e6 / t2 + 0.8776956e3 / t + 0.179910
+        } else {
+            -3.0258469e9 / t3 + 2.1070379e6 / t2 + 0.2226347e3 / t + 0.240390
+        };
+        let (x2, x3) = (x * x, x * x * x);
+        let y = if t <= 2222.0 {
+            -1.1063814 * x3 - 1.34811020 * x2 + 2.18555832 * x - 0.20219683
+        } else if t <= 4000.0 {
+            -0.9549476 * x3 - 1.37418593 * x2 + 2.09137015 * x - 0.16748867
+        } else {
+            3.0817580 * x3 - 5.87338670 * x2 + 3.75112997 * x - 0.37001483
+        };
+        Self::from_xy(x, y, 1.0)
+    }
+    // The brightness of a linear color as the eye sees it, with the Rec. 709 weights.
+    pub fn luminance(&self) -> Value {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+    // Blue through green to red for values from 0 to 1.
+    pub fn heatmap(value: Value) -> Self {
+        let value = value.clamp(0.0, 1.0);
+        Self::new(value, 1.0 - (2.0 * value - 1.0).abs(), 1.0 - value)
+    }
     pub fn blend(&self, rhs: &Self, t: Value) -> Self {
         (1.0 - t) * *self + t * *rhs
     }
-    pub fn random(rng: &mut Rng) -> Self {
-        Self::new(rng.next_f64(), rng.next_f64(), rng.next_f64())
+    pub fn random(rng: &mut (impl RandomSource + ?Sized)) -> Self {
+        Self::new(rng.next_float(), rng.next_float(), rng.next_float())
     }
     pub fn into_u8(&self) -> (u8, u8, u8) {
         let ir = (256.0 * self.r) as u8;
@@ -128,3 +179,59 @@ impl AddAssign for Color {
         *self = self.add(rhs);
     }
 }
+
+// A running sum of colors with Kahan compensation. Adding thousands of samples to a sum that has
+// grown large rounds away the low bits of each one, which biases bright pixels, so the rounding
+// error of every addition is carried over to the next.
+#[derive(Debug, Clone, Copy)]
+pub struct ColorSum {
+    sum: Color,
+    compensation: Color,
+}
+
+impl ColorSum {
+    pub fn new() -> Self {
+        Self {
+            sum: Color::black(),
+            compensation: Color::black(),
+        }
+    }
+    pub fn sum(&self) -> Color {
+        self.sum
+    }
+}
+
+impl Default for ColorSum {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AddAssign<Color> for ColorSum {
+    fn add_assign(&mut self, rhs: Color) {
+        let corrected = rhs - self.compensation;
+        let sum = self.sum + corrected;
+        self.compensation = (sum - self.sum) - corrected;
+        self.sum = sum;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compensated_sum_keeps_small_samples() {
+        let small = Float::EPSILON / 4.0;
+        let mut naive = Color::white();
+        let mut compensated = ColorSum::new();
+        compensated += Color::white();
+        for _ in 0..1000 {
+            naive += Color::gray(small);
+            compensated += Color::gray(small);
+        }
+        assert_eq!(naive.r, 1.0);
+        let expected = 1.0 + 1000.0 * small;
+        assert!((compensated.sum().r - expected).abs() <= Float::EPSILON);
+    }
+}
@@ -0,0 +1,86 @@
+use std::fmt;
+use std::io;
+
+// Everything that can go wrong while setting up, rendering or saving an image.
+#[derive(Debug)]
+pub enum Error {
+    // Reading or writing a file or a network connection failed.
+    Io(io::Error),
+    // An image used as a texture couldn't be loaded, or an output image couldn't be saved.
+    Image(image::ImageError),
+    // There is no scene by this name.
+    UnknownScene(String),
+    // The preview window couldn't be set up or drawn to.
+    Sdl(String),
+    // The camera builder was missing settings or had invalid ones.
+    InvalidCamera(String),
+    // Render buffers couldn't be merged, as they are of different images.
+    IncompatibleBuffers(String),
+    // A line of a batch queue isn't a job.
+    InvalidQueue(String),
+    // A material graph has a node that can't be read or is missing an output.
+    InvalidMaterial(String),
+    // A null pointer or a value out of range was passed through the C API, or the command line
+    // asks for something that can't be done.
+    InvalidArgument(String),
+    // A call through the C API panicked, which is caught rather than unwound into C.
+    Panicked(String),
+    // A mesh file has a line that can't be read, or a face refers to vertices the mesh doesn't have.
+    InvalidMesh(String),
+    // A scene description can't be read or written.
+    InvalidScene(String),
+    // A voxel grid has too few points along an axis, or more or fewer values than points.
+    InvalidVolume(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Image(e) => write!(f, "image error: {}", e),
+            Error::UnknownScene(name) => write!(f, "unknown scene: {}", name),
+            Error::Sdl(message) => write!(f, "preview failed: {}", message),
+            Error::InvalidCamera(message) => write!(f, "invalid camera: {}", message),
+            Error::IncompatibleBuffers(message) => {
+                write!(f, "can't merge render buffers: {}", message)
+            }
+            Error::InvalidQueue(line) => write!(f, "invalid batch job: {}", line),
+            Error::InvalidMaterial(message) => write!(f, "invalid material: {}", message),
+            Error::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+            Error::Panicked(message) => write!(f, "panicked: {}", message),
+            Error::InvalidMesh(message) => write!(f, "invalid mesh: {}", message),
+            Error::InvalidScene(message) => write!(f, "invalid scene: {}", message),
+            Error::InvalidVolume(message) => write!(f, "invalid volume: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<image::ImageError> for Error {
+    fn from(e: image::ImageError) -> Self {
+        Error::Image(e)
+    }
+}
+
+// SDL reports most of its errors as strings or types that only implement `Display`.
+#[cfg(feature = "preview")]
+pub(crate) fn sdl_error(e: impl fmt::Display) -> Error {
+    Error::Sdl(e.to_string())
+}
@@ -0,0 +1,356 @@
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+    sync::Arc,
+};
+
+use crate::{
+    camera::{builder::CameraBuilder, events::TileEvent, RenderControl},
+    color::Color,
+    error::{Error, Result},
+    float::{self, Float},
+    hittable::{
+        containers::HittableList,
+        geometry::Sphere,
+        materials::{Dielectric, Lambertian, Material, Metal},
+    },
+    scene::Scene,
+    vec3::Point3,
+};
+
+// A C API for embedding the renderer in programs written in other languages, declared in
+// `include/raytracer.h`. Scenes are opaque handles made by `rt_scene_new` and freed by
+// `rt_scene_free`. Functions that can fail return 0 on success and -1 on failure, in which case
+// `rt_last_error` describes what went wrong. Panics count as failures, as they must not unwind
+// into C. The API uses doubles whether or not the renderer is built with the `f32` feature.
+pub struct RtScene {
+    camera: Option<String>,
+    spheres: Vec<(Point3, Float, Arc<dyn Material>)>,
+}
+
+// The values of `RtMaterial::kind`.
+pub enum RtMaterialKind {
+    Lambertian,
+    Metal,
+    Dielectric,
+}
+
+impl TryFrom<c_int> for RtMaterialKind {
+    type Error = Error;
+
+    fn try_from(kind: c_int) -> Result<Self> {
+        match kind {
+            0 => Ok(RtMaterialKind::Lambertian),
+            1 => Ok(RtMaterialKind::Metal),
+            2 => Ok(RtMaterialKind::Dielectric),
+            _ => Err(Error::InvalidArgument(format!(
+                "unknown material kind {}",
+                kind
+            ))),
+        }
+    }
+}
+
+// `kind` is one of `RtMaterialKind`, and `parameter` is the fuzz of metals and the refraction
+// index of dielectrics. The kind is an integer rather than the enum so kinds C made up are caught.
+#[repr(C)]
+pub struct RtMaterial {
+    kind: c_int,
+    color: [f64; 3],
+    parameter: f64,
+}
+
+pub type RtProgressCallback = extern "C" fn(progress: f64, user_data: *mut c_void) -> c_int;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+// Runs the body of an entry point, which returns `failed` if it fails or panics.
+fn catching<T>(failed: T, body: impl FnOnce() -> Result<T>) -> T {
+    let error = match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(e)) => e,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Error::Panicked(message)
+        }
+    };
+    let message = CString::new(error.to_string()).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+    failed
+}
+
+fn report(body: impl FnOnce() -> Result<()>) -> c_int {
+    catching(-1, || body().map(|()| 0))
+}
+
+// Fails for a null `pointer`, named `name` in the error.
+fn not_null<T>(pointer: *const T, name: &str) -> Result<()> {
+    if pointer.is_null() {
+        return Err(Error::InvalidArgument(format!("{} is null", name)));
+    }
+    Ok(())
+}
+
+// The message of the last error on this thread, or null. Valid until the next failing call.
+#[no_mangle]
+pub extern "C" fn rt_last_error() -> *const c_char {
+    catching(ptr::null(), || {
+        Ok(LAST_ERROR.with(|last| last.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr())))
+    })
+}
+
+// A new empty scene, or null if it couldn't be made.
+#[no_mangle]
+pub extern "C" fn rt_scene_new() -> *mut RtScene {
+    catching(ptr::null_mut(), || {
+        Ok(Box::into_raw(Box::new(RtScene {
+            camera: None,
+            spheres: Vec::new(),
+        })))
+    })
+}
+
+/// Frees a scene made by `rt_scene_new`.
+///
+/// # Safety
+///
+/// `scene` must be null or a scene from `rt_scene_new` that hasn't been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_free(scene: *mut RtScene) {
+    catching((), || {
+        if !scene.is_null() {
+            drop(Box::from_raw(scene));
+        }
+        Ok(())
+    })
+}
+
+/// Sets the camera from settings in the camera config format, `name = value` lines.
+///
+/// # Safety
+///
+/// `scene` must be null or a live scene from `rt_scene_new`, and `config` null or a NUL
+/// terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_set_camera(scene: *mut RtScene, config: *const c_char) -> c_int {
+    report(|| {
+        not_null(scene, "the scene")?;
+        not_null(config, "the config")?;
+        let config = CStr::from_ptr(config)
+            .to_str()
+            .map_err(|_| Error::InvalidArgument("the config is not UTF-8".to_string()))?;
+        // built once here so mistakes are reported now rather than when rendering
+        CameraBuilder::from_config(config)?.build()?;
+        (*scene).camera = Some(config.to_string());
+        Ok(())
+    })
+}
+
+/// Adds a sphere of `material` around `center`, which points to its three coordinates.
+///
+/// # Safety
+///
+/// `scene` must be null or a live scene from `rt_scene_new`, `center` null or three doubles and
+/// `material` null or a material.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_add_sphere(
+    scene: *mut RtScene,
+    center: *const f64,
+    radius: f64,
+    material: *const RtMaterial,
+) -> c_int {
+    report(|| {
+        not_null(scene, "the scene")?;
+        not_null(center, "the center")?;
+        not_null(material, "the material")?;
+        let [x, y, z] = *center.cast::<[f64; 3]>();
+        let material = &*material;
+        let [r, g, b] = material.color.map(|c| c as Float);
+        let color = Color::new(r, g, b);
+        let material: Arc<dyn Material> = match RtMaterialKind::try_from(material.kind)? {
+            RtMaterialKind::Lambertian => Arc::new(Lambertian::from(color)),
+            RtMaterialKind::Metal => Arc::new(Metal::new(color, material.parameter as Float)),
+            RtMaterialKind::Dielectric => Arc::new(Dielectric::new(material.parameter as Float)),
+        };
+        let center = Point3::new(x as Float, y as Float, z as Float);
+        (*scene).spheres.push((center, radius as Float, material));
+        Ok(())
+    })
+}
+
+/// The size of the images the camera renders, for allocating the buffer given to `rt_render`.
+///
+/// # Safety
+///
+/// `scene` must be null or a live scene from `rt_scene_new`, and `width` and `height` null or
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn rt_scene_image_size(
+    scene: *const RtScene,
+    width: *mut usize,
+    height: *mut usize,
+) -> c_int {
+    report(|| {
+        not_null(scene, "the scene")?;
+        not_null(width, "the width")?;
+        not_null(height, "the height")?;
+        let camera = camera(&*scene)?.build()?;
+        *width = camera.image_width;
+        *height = camera.image_height;
+        Ok(())
+    })
+}
+
+/// Renders the scene into `buffer`, which holds `buffer_len` floats, as rows of linear RGB
+/// triples. `progress` may be null, otherwise it is called on the calling thread with the
+/// fraction rendered so far and `user_data`, returning non-zero from it cancels the render.
+/// Nothing is written anywhere but the buffer.
+///
+/// # Safety
+///
+/// `scene` must be null or a live scene from `rt_scene_new`, and `buffer` null or writable for
+/// `buffer_len` floats.
+#[no_mangle]
+pub unsafe extern "C" fn rt_render(
+    scene: *const RtScene,
+    buffer: *mut f32,
+    buffer_len: usize,
+    progress: Option<RtProgressCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    report(|| {
+        not_null(scene, "the scene")?;
+        not_null(buffer, "the buffer")?;
+        let scene = &*scene;
+        let camera = camera(scene)?.build()?;
+        let needed = camera.image_width * camera.image_height * 3;
+        if buffer_len < needed {
+            return Err(Error::InvalidArgument(format!(
+                "the buffer holds {} floats but the image needs {}",
+                buffer_len, needed
+            )));
+        }
+        let mut world = HittableList::default();
+        for (center, radius, material) in &scene.spheres {
+            world.add(Box::new(Sphere::new(*center, *radius, material.clone())));
+        }
+        let scene = Scene::new(camera, world.into_bvh());
+        let control = RenderControl::default();
+        let image_buffer = scene.render_with(&control, |event| {
+            if let (TileEvent::Progress(fraction), Some(progress)) = (event, progress) {
+                if progress(float::to_f64(fraction), user_data) != 0 {
+                    control.cancel();
+                }
+            }
+        })?;
+        let buffer = slice::from_raw_parts_mut(buffer, needed);
+        for (pixel, color) in buffer.chunks_exact_mut(3).zip(image_buffer) {
+            pixel.copy_from_slice(&[color.r as f32, color.g as f32, color.b as f32]);
+        }
+        Ok(())
+    })
+}
+
+fn camera(scene: &RtScene) -> Result<CameraBuilder> {
+    let config = scene
+        .camera
+        .as_deref()
+        .ok_or_else(|| Error::InvalidCamera("the camera must be set".to_string()))?;
+    CameraBuilder::from_config(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn cancel_when_half_done(progress: f64, calls: *mut c_void) -> c_int {
+        unsafe { *calls.cast::<usize>() += 1 };
+        (progress >= 0.5) as c_int
+    }
+
+    fn last_error() -> String {
+        unsafe { CStr::from_ptr(rt_last_error()) }
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn scenes_render_through_the_c_api() {
+        let config =
+            CString::new("width = 8\naspect_ratio = 1\nrandom_sampler = 1\nmax_ray_depth = 2")
+                .unwrap();
+        let material = RtMaterial {
+            kind: 1,
+            color: [0.8, 0.6, 0.2],
+            parameter: 0.1,
+        };
+        unsafe {
+            let scene = rt_scene_new();
+            assert_eq!(rt_scene_set_camera(scene, config.as_ptr()), 0);
+            let center = [0.0, 0.0, -1.0];
+            assert_eq!(
+                rt_scene_add_sphere(scene, center.as_ptr(), 0.5, &material),
+                0
+            );
+            let (mut width, mut height) = (0, 0);
+            assert_eq!(rt_scene_image_size(scene, &mut width, &mut height), 0);
+            assert_eq!((width, height), (8, 8));
+
+            let mut buffer = vec![-1.0; width * height * 3];
+            let mut calls = 0usize;
+            let result = rt_render(
+                scene,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                Some(cancel_when_half_done),
+                (&mut calls as *mut usize).cast(),
+            );
+            assert_eq!(result, 0);
+            assert!(calls > 0);
+            assert!(buffer.iter().all(|value| *value >= 0.0));
+
+            // made up kinds, null pointers and short buffers are errors rather than crashes
+            let unknown = RtMaterial {
+                kind: 7,
+                ..material
+            };
+            assert_eq!(
+                rt_scene_add_sphere(scene, center.as_ptr(), 0.5, &unknown),
+                -1
+            );
+            assert!(last_error().contains("unknown material kind 7"));
+            assert_eq!(rt_scene_add_sphere(scene, ptr::null(), 0.5, &material), -1);
+            assert_eq!(rt_scene_set_camera(ptr::null_mut(), config.as_ptr()), -1);
+            assert!(last_error().contains("the scene is null"));
+            assert_eq!(
+                rt_render(scene, ptr::null_mut(), 0, None, ptr::null_mut()),
+                -1
+            );
+            assert_eq!(
+                rt_render(scene, buffer.as_mut_ptr(), 3, None, ptr::null_mut()),
+                -1
+            );
+            assert!(last_error().contains("the buffer holds 3 floats"));
+            rt_scene_free(scene);
+        }
+    }
+
+    #[test]
+    fn panics_are_reported_as_errors() {
+        let panicking = || -> Result<c_int> { panic!("the renderer broke") };
+        assert_eq!(catching(-1, panicking), -1);
+        assert_eq!(last_error(), "panicked: the renderer broke");
+        let formatted = || -> Result<c_int> { panic!("tile {} broke", 3) };
+        assert_eq!(catching(-1, formatted), -1);
+        assert_eq!(last_error(), "panicked: tile 3 broke");
+    }
+}
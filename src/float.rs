@@ -0,0 +1,29 @@
+// The scalar type used throughout the renderer. It is `f64` unless the `f32` feature is enabled,
+// which halves the memory used by vectors, colors and bounding boxes.
+
+#[cfg(not(feature = "f32"))]
+mod precision {
+    pub use std::f64::{consts, INFINITY, NEG_INFINITY};
+    pub type Float = f64;
+    pub type Floatx4 = std::simd::f64x4;
+    pub type Maskx4 = std::simd::mask64x4;
+
+    // For interfaces that take doubles whatever the precision.
+    pub fn to_f64(value: Float) -> f64 {
+        value
+    }
+}
+
+#[cfg(feature = "f32")]
+mod precision {
+    pub use std::f32::{consts, INFINITY, NEG_INFINITY};
+    pub type Float = f32;
+    pub type Floatx4 = std::simd::f32x4;
+    pub type Maskx4 = std::simd::mask32x4;
+
+    pub fn to_f64(value: Float) -> f64 {
+        f64::from(value)
+    }
+}
+
+pub use precision::*;
@@ -1,32 +1,43 @@
-use std::{
-    f64::{INFINITY, NEG_INFINITY},
-    ops::Range,
+use std::simd::prelude::*;
+
+use crate::{
+    float::{Float, Floatx4, Maskx4, INFINITY, NEG_INFINITY},
+    interval::Interval,
+    ray::Ray,
+    vec3::{Point3, Vec3},
 };
 
-use crate::{ray::Ray, vec3::Vec3, range::Expandable};
+const MINIMUM_EXTENT: Float = 0.0001;
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone)]
 pub struct AABB {
-    pub x: Range<f64>,
-    pub y: Range<f64>,
-    pub z: Range<f64>,
+    pub x: Interval,
+    pub y: Interval,
+    pub z: Interval,
 }
 
 impl AABB {
+    // The box around nothing, which takes the shape of the first box it is grown by.
+    pub const EMPTY: Self = Self {
+        x: Interval::EMPTY,
+        y: Interval::EMPTY,
+        z: Interval::EMPTY,
+    };
+
     pub fn new() -> Self {
         Self {
-            x: 0.0..0.0,
-            y: 0.0..0.0,
-            z: 0.0..0.0,
+            x: Interval::new(0.0, 0.0),
+            y: Interval::new(0.0, 0.0),
+            z: Interval::new(0.0, 0.0),
         }
     }
     pub fn from_vecs(start: Vec3, end: Vec3) -> Self {
         Self {
-            x: start.x.min(end.x)..start.x.max(end.x),
-            y: start.y.min(end.y)..start.y.max(end.y),
-            z: start.z.min(end.z)..start.z.max(end.z),
+            x: Interval::new(start.x.min(end.x), start.x.max(end.x)),
+            y: Interval::new(start.y.min(end.y), start.y.max(end.y)),
+            z: Interval::new(start.z.min(end.z), start.z.max(end.z)),
         }
-
+        .padded()
     }
     pub fn from_boxes(a: &AABB, b: &AABB) -> Self {
         Self {
@@ -34,9 +45,59 @@ impl AABB {
             y: a.y.union(&b.y),
             z: a.z.union(&b.z),
         }
-
+        .padded()
+    }
+    // Flat primitives like quads and triangles have no extent along some axis, and a box without
+    // volume is missed by rays that hit it exactly, so every axis is grown to a minimum size.
+    fn padded(self) -> Self {
+        let pad = |interval: Interval| {
+            if interval.size() < MINIMUM_EXTENT {
+                interval.expand(MINIMUM_EXTENT)
+            } else {
+                interval
+            }
+        };
+        Self {
+            x: pad(self.x),
+            y: pad(self.y),
+            z: pad(self.z),
+        }
+    }
+    // Grows the box to take in `other` as well.
+    pub fn grow(&mut self, other: &AABB) {
+        *self = Self::from_boxes(self, other);
+    }
+    // The area of the faces of the box. A ray that hits a box hits a box inside it with a chance
+    // of the ratio of their surface areas, which is what the surface area heuristic is built on.
+    pub fn surface_area(&self) -> Float {
+        let (x, y, z) = (self.x.size(), self.y.size(), self.z.size());
+        2.0 * (x * y + y * z + z * x)
+    }
+    pub fn centroid(&self) -> Point3 {
+        Point3::new(self.x.middle(), self.y.middle(), self.z.middle())
     }
-    pub fn axis(&self, n: usize) -> &Range<f64> {
+    // The axis the box is the longest along, the first of them when it is as long along several.
+    pub fn longest_axis(&self) -> usize {
+        (0..3).fold(0, |longest, axis| {
+            if self.axis(axis).size() > self.axis(longest).size() {
+                axis
+            } else {
+                longest
+            }
+        })
+    }
+    // Whether `point` is in the box, its faces included.
+    pub fn contains(&self, point: Point3) -> bool {
+        (0..3).all(|axis| self.axis(axis).contains(point[axis]))
+    }
+    pub fn translated(&self, offset: Vec3) -> Self {
+        Self {
+            x: self.x + offset.x,
+            y: self.y + offset.y,
+            z: self.z + offset.z,
+        }
+    }
+    pub fn axis(&self, n: usize) -> &Interval {
         if n == 1 {
             &self.y
         } else if n == 2 {
@@ -45,16 +106,24 @@ impl AABB {
             &self.x
         }
     }
-    pub fn hit(&self, ray: &Ray) -> Option<Range<f64>> {
+    pub fn hit(&self, ray: &Ray) -> Option<Interval> {
         let mut raymin = NEG_INFINITY;
         let mut raymax = INFINITY;
         for a in 0..3 {
-            let inverse_direction = 1. / ray.direction[a];
             let orig = ray.origin[a];
             let ax = self.axis(a);
+            // a ray parallel to the slab is inside it everywhere or nowhere, the distances to it
+            // would be infinities or NaN when the origin is on its edge
+            if ray.direction[a] == 0. {
+                if !ax.contains(orig) {
+                    return None;
+                }
+                continue;
+            }
+            let inverse_direction = 1. / ray.direction[a];
 
-            let mut t0 = (ax.start - orig) * inverse_direction;
-            let mut t1 = (ax.end - orig) * inverse_direction;
+            let mut t0 = (ax.min - orig) * inverse_direction;
+            let mut t1 = (ax.max - orig) * inverse_direction;
 
             if inverse_direction < 0. {
                 std::mem::swap(&mut t0, &mut t1)
@@ -67,6 +136,101 @@ impl AABB {
                 return None;
             }
         }
-        return Some(raymin..raymax);
+        return Some(Interval::new(raymin, raymax));
+    }
+}
+
+// Four bounding boxes laid out per axis so a ray can be tested against all of them at once.
+#[derive(Debug, Clone)]
+pub struct AABB4 {
+    min: [Floatx4; 3],
+    max: [Floatx4; 3],
+    occupied: Maskx4,
+}
+
+impl AABB4 {
+    pub fn new(boxes: &[&AABB]) -> Self {
+        assert!(boxes.len() <= 4, "AABB4 holds at most four boxes");
+        let mut min = [[0.0; 4]; 3];
+        let mut max = [[0.0; 4]; 3];
+        let mut occupied = [false; 4];
+        for (lane, bounding_box) in boxes.iter().enumerate() {
+            for a in 0..3 {
+                min[a][lane] = bounding_box.axis(a).min;
+                max[a][lane] = bounding_box.axis(a).max;
+            }
+            occupied[lane] = true;
+        }
+        Self {
+            min: min.map(Floatx4::from_array),
+            max: max.map(Floatx4::from_array),
+            occupied: Maskx4::from_array(occupied),
+        }
+    }
+    // Returns the entry distance for every lane, lanes that are missed or unused are INFINITY.
+    pub fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Floatx4 {
+        let mut raymin = Floatx4::splat(ray_trange.min);
+        let mut raymax = Floatx4::splat(ray_trange.max);
+        for a in 0..3 {
+            let orig = Floatx4::splat(ray.origin[a]);
+            // parallel to the slabs, see `AABB::hit`
+            if ray.direction[a] == 0. {
+                let inside = self.min[a].simd_le(orig) & orig.simd_le(self.max[a]);
+                raymax = inside.select(raymax, Floatx4::splat(NEG_INFINITY));
+                continue;
+            }
+            let inverse_direction = Floatx4::splat(1. / ray.direction[a]);
+
+            let t0 = (self.min[a] - orig) * inverse_direction;
+            let t1 = (self.max[a] - orig) * inverse_direction;
+
+            raymin = raymin.simd_max(t0.simd_min(t1));
+            raymax = raymax.simd_min(t0.simd_max(t1));
+        }
+        let hits = raymin.simd_lt(raymax) & self.occupied;
+        return hits.select(raymin, Floatx4::splat(INFINITY));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_boxes_are_hit() {
+        // a square in the y = 1 plane
+        let flat = AABB::from_vecs(Vec3::new(-1.0, 1.0, -1.0), Vec3::new(1.0, 1.0, 1.0));
+        assert!(flat.y.size() > 0.0);
+        let down = Ray::new(Vec3::new(0.5, 3.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let beside = Ray::new(Vec3::new(2.0, 3.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        // parallel to the square and starting on the edge of its box
+        let along = Ray::new(Vec3::new(-1.0, 1.0, -2.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        for (ray, hit) in [(down, true), (beside, false), (along, true)] {
+            assert_eq!(flat.hit(&ray).is_some(), hit);
+            let lanes = AABB4::new(&[&flat]).hit(&ray, &Interval::UNIVERSE);
+            assert_eq!(lanes[0] < INFINITY, hit);
+        }
+    }
+
+    #[test]
+    fn boxes_measure_themselves() {
+        let mut bounding_box = AABB::EMPTY;
+        bounding_box.grow(&AABB::from_vecs(
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 2.0, 1.0),
+        ));
+        bounding_box.grow(&AABB::from_vecs(
+            Vec3::new(2.0, 1.0, 0.0),
+            Vec3::new(3.0, 2.0, 4.0),
+        ));
+        assert_eq!((bounding_box.x.min, bounding_box.z.max), (0.0, 4.0));
+        assert_eq!(
+            bounding_box.surface_area(),
+            2.0 * (3.0 * 2.0 + 2.0 * 4.0 + 4.0 * 3.0)
+        );
+        assert_eq!(bounding_box.centroid().x, 1.5);
+        assert_eq!(bounding_box.longest_axis(), 2);
+        assert!(bounding_box.contains(Point3::new(3.0, 1.0, 2.0)));
+        assert!(!bounding_box.contains(Point3::new(3.5, 1.0, 2.0)));
     }
 }
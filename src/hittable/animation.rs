@@ -0,0 +1,375 @@
+use std::sync::Arc;
+
+use crate::{
+    float::Float,
+    interval::Interval,
+    random::RandomSource,
+    ray::Ray,
+    vec3::{Point3, Vec3},
+};
+
+use super::{aabb::AABB, geometry::Sphere, report::SceneReport, HitRecord, Hittable};
+
+// The placement of an animated object at some point in time. Rotation is about the vertical axis
+// in degrees and scale is uniform, so normals only need to be rotated.
+#[derive(Debug, Clone, Copy)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Float,
+    pub scale: Float,
+}
+
+impl Transform {
+    pub fn new(translation: Vec3, rotation: Float, scale: Float) -> Self {
+        Self {
+            translation,
+            rotation,
+            scale,
+        }
+    }
+    fn lerp(&self, other: &Self, t: Float) -> Self {
+        Self {
+            translation: self.translation + (other.translation - self.translation) * t,
+            rotation: self.rotation + (other.rotation - self.rotation) * t,
+            scale: self.scale + (other.scale - self.scale) * t,
+        }
+    }
+    fn rotate(&self, v: Vec3, sign: Float) -> Vec3 {
+        let (sin, cos) = (sign * self.rotation.to_radians()).sin_cos();
+        Vec3::new(cos * v.x + sin * v.z, v.y, -sin * v.x + cos * v.z)
+    }
+    fn apply(&self, point: Point3) -> Point3 {
+        self.rotate(point, 1.0) * self.scale + self.translation
+    }
+    fn invert(&self, point: Point3) -> Point3 {
+        self.rotate((point - self.translation) / self.scale, -1.0)
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::new(Vec3::zero(), 0.0, 1.0)
+    }
+}
+
+// Transforms at points in time, played back by interpolating linearly between them and holding
+// the first and last ones before and after.
+#[derive(Debug, Clone, Default)]
+pub struct Animation {
+    keyframes: Vec<(Float, Transform)>,
+}
+
+impl Animation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn keyframe(mut self, time: Float, transform: Transform) -> Self {
+        let index = self.keyframes.partition_point(|(t, _)| *t <= time);
+        self.keyframes.insert(index, (time, transform));
+        self
+    }
+    pub fn at(&self, time: Float) -> Transform {
+        let index = self.keyframes.partition_point(|(t, _)| *t <= time);
+        match (
+            self.keyframes.get(index.wrapping_sub(1)),
+            self.keyframes.get(index),
+        ) {
+            (Some((t0, a)), Some((t1, b))) => a.lerp(b, (time - t0) / (t1 - t0)),
+            (Some((_, a)), None) | (None, Some((_, a))) => *a,
+            (None, None) => Transform::default(),
+        }
+    }
+}
+
+// Plays an animation back on an object. The shutter of the frame being rendered opens at the start
+// of `shutter` and closes at its end, rays are placed in it by their time so fast moving objects
+// are motion blurred. Scenes wrap their objects with the shutter interval of the camera, see
+// `Camera::shutter_interval`, so rendering a sequence is a matter of building the scene again for
+// every frame like `FrameSequence` does.
+#[derive(Debug)]
+pub struct Animated {
+    object: Arc<dyn Hittable>,
+    animation: Animation,
+    shutter: Interval,
+    bounding_box: AABB,
+}
+
+impl Animated {
+    pub fn new(object: Arc<dyn Hittable>, animation: Animation, shutter: Interval) -> Self {
+        // the transform goes linearly from one keyframe to the next, so the object is bounded
+        // over every piece of the shutter interval between them on its own
+        let mut times = vec![shutter.min, shutter.max];
+        times.extend(
+            animation
+                .keyframes
+                .iter()
+                .map(|(time, _)| *time)
+                .filter(|time| shutter.min < *time && *time < shutter.max),
+        );
+        times.sort_by(Float::total_cmp);
+        let local = object.bounding_box();
+        let bounding_box = times
+            .windows(2)
+            .map(|pair| swept_bounds(local, &animation.at(pair[0]), &animation.at(pair[1])))
+            .reduce(|a, b| AABB::from_boxes(&a, &b))
+            .unwrap();
+        Self {
+            object,
+            animation,
+            shutter,
+            bounding_box,
+        }
+    }
+    // Moves an object from `open`, where it is as the shutter opens, to `close`, where it is as it
+    // closes, which is all the motion blur of most objects needs.
+    pub fn between(object: Arc<dyn Hittable>, open: Transform, close: Transform) -> Self {
+        let animation = Animation::new().keyframe(0.0, open).keyframe(1.0, close);
+        Self::new(object, animation, Interval::new(0.0, 1.0))
+    }
+    fn transform_at(&self, ray_time: Float) -> Transform {
+        self.animation
+            .at(self.shutter.min + self.shutter.size() * ray_time)
+    }
+    // Whether the object stays where it is while the shutter is open.
+    fn holds_still(&self) -> bool {
+        let keyframe_while_open = self
+            .animation
+            .keyframes
+            .iter()
+            .any(|(time, _)| self.shutter.min < *time && *time < self.shutter.max);
+        let (open, close) = (self.transform_at(0.0), self.transform_at(1.0));
+        !keyframe_while_open
+            && (open.translation - close.translation).near_zero()
+            && open.rotation == close.rotation
+            && open.scale == close.scale
+    }
+}
+
+impl Hittable for Animated {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        let transform = self.transform_at(ray.time);
+        let origin = transform.invert(ray.origin);
+        let direction = transform.rotate(ray.direction / transform.scale, -1.0);
+        let local_ray = Ray::new(origin, direction, ray.time);
+        let mut hit_record = self.object.hit(&local_ray, ray_trange)?;
+        let local_point = hit_record.point;
+        hit_record.point = transform.apply(local_point);
+        hit_record.normal = transform.rotate(hit_record.normal, 1.0);
+        let start = self.transform_at(0.0).apply(local_point);
+        let end = self.transform_at(1.0).apply(local_point);
+        hit_record.motion =
+            transform.rotate(hit_record.motion, 1.0) * transform.scale + end - start;
+        hit_record.shading_offset =
+            transform.rotate(hit_record.shading_offset, 1.0) * transform.scale;
+        return Some(hit_record);
+    }
+    fn bounding_box(&self) -> &AABB {
+        &self.bounding_box
+    }
+    // Directions and points are drawn where the object is as the shutter opens, which is where it
+    // stays for the lights that are listed, see `lights`. Turning and uniform scaling keep the
+    // angles between directions, so densities over them don't change, while areas grow with the
+    // square of the scale.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        let transform = self.transform_at(0.0);
+        let local_direction = transform.rotate(*direction, -1.0);
+        self.object
+            .pdf_value(&transform.invert(*origin), &local_direction)
+    }
+    fn random(&self, origin: &Point3, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        let transform = self.transform_at(0.0);
+        let local_direction = self.object.random(&transform.invert(*origin), rng)?;
+        Some(transform.rotate(local_direction, 1.0))
+    }
+    fn sample_point(&self, rng: &mut dyn RandomSource) -> Option<(Point3, Vec3, Float)> {
+        let transform = self.transform_at(0.0);
+        let (point, normal, pdf) = self.object.sample_point(rng)?;
+        Some((
+            transform.apply(point),
+            transform.rotate(normal, 1.0),
+            pdf / transform.scale.powi(2),
+        ))
+    }
+    // The lights of the object placed by the animation, if it holds still. Points on lights have
+    // no time to place them by, so moving lights are left for paths to find.
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        if !self.holds_still() {
+            return;
+        }
+        let mut placed = Vec::new();
+        self.object.lights(&mut placed);
+        for (id, light) in placed {
+            let animated = Animated::new(light, self.animation.clone(), self.shutter);
+            lights.push((id, Arc::new(animated)));
+        }
+    }
+    // Spheres moving in a straight line from where they are over the shutter interval, without
+    // turning or scaling, are batched into `SphereList`s like static ones.
+    fn as_sphere(&self) -> Option<(&Sphere, Vec3)> {
+        let (sphere, motion) = self.object.as_sphere()?;
+        // no keyframes while the shutter is open leaves the object moving in a straight line
+        let linear = !self
+            .animation
+            .keyframes
+            .iter()
+            .any(|(time, _)| self.shutter.min < *time && *time < self.shutter.max);
+        let (open, close) = (self.transform_at(0.0), self.transform_at(1.0));
+        let translated = |t: &Transform| t.rotation == 0.0 && t.scale == 1.0;
+        let moves = linear && translated(&open) && translated(&close);
+        (moves && open.translation.near_zero()).then_some((sphere, motion + close.translation))
+    }
+    fn report(&self, report: &mut SceneReport) {
+        let keyframes = self.animation.keyframes.len() * std::mem::size_of::<(Float, Transform)>();
+        report.objects("animated", 1, std::mem::size_of_val(self) + keyframes);
+        let broken = self.animation.keyframes.iter().any(|(time, transform)| {
+            let Transform {
+                translation,
+                rotation,
+                scale,
+            } = transform;
+            !(time.is_finite() && translation.is_finite() && rotation.is_finite())
+                || !scale.is_finite()
+                || *scale == 0.0
+        });
+        if broken {
+            report.warn(
+                "an animation has a keyframe that isn't finite or scales to zero".to_string(),
+            );
+        }
+        report.shared(&self.object);
+    }
+}
+
+// Bounds `local` over a piece of an animation where the transform goes linearly from `start` to
+// `end`. Without turning, every corner of the box moves in a straight line between where the ends
+// put it. Turning moves them along arcs instead, which stay within the distance of the farthest
+// corner from the vertical axis, scaled, around the translation.
+fn swept_bounds(local: &AABB, start: &Transform, end: &Transform) -> AABB {
+    let corners = (0..8).map(|corner| {
+        Point3::new(
+            [local.x.min, local.x.max][corner & 1],
+            [local.y.min, local.y.max][corner >> 1 & 1],
+            [local.z.min, local.z.max][corner >> 2],
+        )
+    });
+    let bounds = |points: &mut dyn Iterator<Item = Point3>| {
+        points
+            .map(|point| AABB::from_vecs(point, point))
+            .reduce(|a, b| AABB::from_boxes(&a, &b))
+            .unwrap()
+    };
+    if start.rotation == end.rotation {
+        return bounds(&mut corners.flat_map(|corner| [start.apply(corner), end.apply(corner)]));
+    }
+    let radius = corners
+        .map(|corner| corner.x.hypot(corner.z))
+        .fold(0.0, Float::max);
+    let reach = radius * start.scale.abs().max(end.scale.abs());
+    // scaled heights go linearly from one end to the other like the translation
+    let mut ends = [start, end].into_iter().flat_map(|transform| {
+        [local.y.min, local.y.max]
+            .into_iter()
+            .flat_map(move |height| {
+                let height = height * transform.scale;
+                [-reach, reach].map(|side| transform.translation + Vec3::new(side, height, side))
+            })
+    });
+    bounds(&mut ends)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color::Color,
+        float::consts::FRAC_1_SQRT_2,
+        hittable::{geometry::Sphere, materials::Lambertian},
+    };
+
+    #[test]
+    fn animated_objects_follow_their_keyframes() {
+        let animation = Animation::new()
+            .keyframe(10.0, Transform::new(Vec3::new(4.0, 0.0, 0.0), 90.0, 2.0))
+            .keyframe(0.0, Transform::default());
+        let halfway = animation.at(5.0);
+        assert_eq!(halfway.translation.x, 2.0);
+        assert_eq!(halfway.rotation, 45.0);
+        assert_eq!(animation.at(20.0).scale, 2.0);
+
+        let material = Arc::new(Lambertian::from(Color::gray(0.5)));
+        let sphere = Arc::new(Sphere::new(Point3::new(1.0, 0.0, 0.0), 0.5, material));
+        // the second half of the animation, as the shutter of a frame from 5 to 10
+        let animated = Animated::new(sphere, animation, Interval::new(5.0, 10.0));
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let at = |x: Float, z: Float, time: Float| {
+            animated.hit(
+                &Ray::new(Point3::new(x, 5.0, z), down, time),
+                &Interval::new(0.001, Float::INFINITY),
+            )
+        };
+        // rotated a quarter turn, the sphere ends up on the negative z side of the pivot, scaled up
+        let end = at(4.0, -2.0, 1.0).unwrap();
+        assert!((end.point - Point3::new(4.0, 1.0, -2.0)).length() < 1e-6);
+        assert!(at(4.0, -2.9, 1.0).is_some() && at(5.0, 0.0, 1.0).is_none());
+        let diagonal = 1.5 * FRAC_1_SQRT_2;
+        assert!(at(2.0 + diagonal, -diagonal, 0.0).is_some());
+        let bounds = animated.bounding_box();
+        assert!(bounds.z.min <= -3.0 && bounds.x.max >= 3.0);
+        assert!(animated.as_sphere().is_none());
+    }
+
+    #[test]
+    fn bounds_hold_the_object_all_the_way() {
+        let material = Arc::new(Lambertian::from(Color::gray(0.5)));
+        let sphere = Arc::new(Sphere::new(Point3::new(3.0, 1.0, 0.0), 0.5, material));
+        let local = sphere.bounding_box().clone();
+        let animation = Animation::new()
+            .keyframe(0.0, Transform::default())
+            .keyframe(1.0, Transform::new(Vec3::new(0.0, 0.0, 2.0), 170.0, 2.0))
+            .keyframe(2.0, Transform::new(Vec3::new(-1.0, 0.0, 2.0), 170.0, 1.0));
+        let animated = Animated::new(sphere, animation.clone(), Interval::new(0.5, 2.0));
+        let bounds = animated.bounding_box();
+        for step in 0..=150 {
+            let transform = animation.at(0.5 + step as Float / 100.0);
+            for corner in 0..8 {
+                let point = Point3::new(
+                    [local.x.min, local.x.max][corner & 1],
+                    [local.y.min, local.y.max][corner >> 1 & 1],
+                    [local.z.min, local.z.max][corner >> 2],
+                );
+                assert!(bounds.contains(transform.apply(point)), "{}", step);
+            }
+        }
+        // without turning the bounds are those of the ends
+        let still = Animated::between(
+            Arc::new(Sphere::new(
+                Point3::zero(),
+                1.0,
+                Arc::new(Lambertian::from(Color::white())),
+            )),
+            Transform::new(Vec3::zero(), 90.0, 1.0),
+            Transform::new(Vec3::new(0.0, 2.0, 0.0), 90.0, 1.0),
+        );
+        let bounds = still.bounding_box();
+        assert!(bounds.x.max < 1.01 && bounds.y.max < 3.01 && bounds.y.min > -1.01);
+    }
+
+    #[test]
+    fn moving_spheres_batch() {
+        let material = Arc::new(Lambertian::from(Color::gray(0.5)));
+        let sphere = Arc::new(Sphere::new(Point3::new(1.0, 0.0, 0.0), 0.5, material));
+        let close = Transform::new(Vec3::new(0.0, 2.0, 0.0), 0.0, 1.0);
+        let moving = Animated::between(sphere.clone(), Transform::default(), close);
+        let (_, motion) = moving.as_sphere().unwrap();
+        assert_eq!(motion.y, 2.0);
+        let ray = Ray::new(Point3::new(1.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.5);
+        let halfway = Ray::new(Point3::new(1.0, 1.0, 5.0), Vec3::new(0.0, 0.0, -1.0), 0.5);
+        let range = Interval::new(0.001, Float::INFINITY);
+        assert!(moving.hit(&ray, &range).is_none() && moving.hit(&halfway, &range).is_some());
+        // turning ones don't
+        let turning = Transform::new(Vec3::zero(), 45.0, 1.0);
+        assert!(Animated::between(sphere, Transform::default(), turning)
+            .as_sphere()
+            .is_none());
+    }
+}
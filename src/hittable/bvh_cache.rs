@@ -0,0 +1,247 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use tracing::{debug, warn};
+
+use crate::float::{self, Float};
+
+use super::{
+    containers::{QBVHChild, QBVH},
+    Hittable,
+};
+
+const MAGIC: &[u8; 4] = b"QBVH";
+const VERSION: u32 = 1;
+
+static GLOBAL_CACHE: OnceLock<BVHCache> = OnceLock::new();
+
+// Stores the layout of built QBVHs on disk so later runs over the same geometry can skip the
+// build. The build only looks at the bounding boxes of the objects so those, in order, are
+// the key. Only the tree topology is stored, the objects themselves are still built by the scene.
+#[derive(Debug)]
+pub struct BVHCache {
+    directory: PathBuf,
+}
+
+impl BVHCache {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+        }
+    }
+    // The cache used by `HittableList::into_bvh`, if one has been installed.
+    pub fn global() -> Option<&'static BVHCache> {
+        GLOBAL_CACHE.get()
+    }
+    pub fn install(self) {
+        if GLOBAL_CACHE.set(self).is_err() {
+            warn!("a BVH cache is already installed");
+        }
+    }
+
+    pub fn build(&self, mut objects: Vec<Box<dyn Hittable>>) -> Box<dyn Hittable> {
+        if objects.len() == 1 {
+            return objects.pop().unwrap();
+        }
+        let path = self.directory.join(format!("{:016x}.qbvh", key(&objects)));
+        // a stale or damaged entry just means building from scratch
+        let objects = match read_layout(&path) {
+            Ok((order, layout)) => match QBVH::from_layout(objects, &order, layout) {
+                Ok(qbvh) => {
+                    debug!("loaded the BVH from {}", path.display());
+                    return Box::new(qbvh);
+                }
+                Err(objects) => objects,
+            },
+            Err(e) => {
+                debug!("no usable BVH cache at {}: {}", path.display(), e);
+                objects
+            }
+        };
+        let (qbvh, order) = QBVH::new(objects);
+        if let Err(e) = write_layout(&self.directory, &path, &order, &qbvh.layout()) {
+            warn!("failed to write BVH cache {}: {}", path.display(), e);
+        }
+        return Box::new(qbvh);
+    }
+}
+
+// FNV-1a over the bounding boxes, stable between runs unlike the std hasher.
+fn key(objects: &[Box<dyn Hittable>]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let mut feed = |bytes: &[u8]| {
+        for byte in bytes {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    };
+    feed(&VERSION.to_le_bytes());
+    feed(&(std::mem::size_of::<Float>() as u32).to_le_bytes());
+    feed(&(objects.len() as u64).to_le_bytes());
+    for object in objects {
+        let bounding_box = object.bounding_box();
+        for axis in 0..3 {
+            let interval = bounding_box.axis(axis);
+            feed(&float::to_f64(interval.min).to_bits().to_le_bytes());
+            feed(&float::to_f64(interval.max).to_bits().to_le_bytes());
+        }
+    }
+    return hash;
+}
+
+fn write_layout(
+    directory: &Path,
+    path: &Path,
+    order: &[u32],
+    layout: &[[QBVHChild; 4]],
+) -> io::Result<()> {
+    fs::create_dir_all(directory)?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&(order.len() as u64).to_le_bytes())?;
+    writer.write_all(&(layout.len() as u64).to_le_bytes())?;
+    for index in order {
+        writer.write_all(&index.to_le_bytes())?;
+    }
+    for child in layout.iter().flatten() {
+        let (tag, index) = match *child {
+            QBVHChild::Empty => (0u8, 0),
+            QBVHChild::Node(index) => (1, index),
+            QBVHChild::Primitive(index) => (2, index),
+        };
+        writer.write_all(&[tag])?;
+        writer.write_all(&index.to_le_bytes())?;
+    }
+    writer.flush()
+}
+
+fn read_layout(path: &Path) -> io::Result<(Vec<u32>, Vec<[QBVHChild; 4]>)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a QBVH cache entry");
+    let file = File::open(path)?;
+    let length = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u32(&mut reader)? != VERSION {
+        return Err(invalid());
+    }
+    let primitive_count = read_u64(&mut reader)?;
+    let node_count = read_u64(&mut reader)?;
+    // the counts of a damaged entry can be anything, so they have to fit in the file before
+    // anything is allocated for them
+    let expected_length = primitive_count
+        .checked_mul(4)
+        .zip(node_count.checked_mul(4 * 5))
+        .and_then(|(primitives, nodes)| primitives.checked_add(nodes))
+        .and_then(|body| body.checked_add(24));
+    if expected_length != Some(length) {
+        return Err(invalid());
+    }
+    let (primitive_count, node_count) = (primitive_count as usize, node_count as usize);
+    let order = (0..primitive_count)
+        .map(|_| read_u32(&mut reader))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut layout = Vec::with_capacity(node_count);
+    for _ in 0..node_count {
+        let mut children = [QBVHChild::Empty; 4];
+        for child in children.iter_mut() {
+            let mut tag = [0; 1];
+            reader.read_exact(&mut tag)?;
+            let index = read_u32(&mut reader)?;
+            *child = match tag[0] {
+                0 => QBVHChild::Empty,
+                1 => QBVHChild::Node(index),
+                2 => QBVHChild::Primitive(index),
+                _ => return Err(invalid()),
+            };
+        }
+        layout.push(children);
+    }
+    return Ok((order, layout));
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        color::Color,
+        float::INFINITY,
+        hittable::{geometry::Sphere, materials::Lambertian},
+        interval::Interval,
+        random::{RandomSource, Rng},
+        ray::Ray,
+        vec3::Vec3,
+    };
+
+    fn spheres() -> Vec<Box<dyn Hittable>> {
+        let mut rng = Rng::from_seed([5, 7]);
+        let material = Arc::new(Lambertian::from(Color::gray(0.5)));
+        (0..50)
+            .map(|_| {
+                let center = 4.0 * Vec3::random_in_unit_sphere(&mut rng);
+                let radius = rng.next_float_range(0.1..0.5);
+                Box::new(Sphere::new(center, radius, material.clone())) as Box<dyn Hittable>
+            })
+            .collect()
+    }
+
+    fn assert_hits_match(expected: &dyn Hittable, actual: &dyn Hittable) {
+        let mut rng = Rng::from_seed([11, 3]);
+        let range = Interval::new(0.001, INFINITY);
+        for _ in 0..500 {
+            let origin = 10.0 * Vec3::random_in_unit_sphere(&mut rng);
+            let ray = Ray::new(origin, Vec3::random_on_unit_sphere(&mut rng), 0.0);
+            let expected = expected.hit(&ray, &range).map(|record| record.t);
+            let actual = actual.hit(&ray, &range).map(|record| record.t);
+            assert_eq!(expected, actual);
+        }
+    }
+
+    #[test]
+    fn damaged_entries_are_rebuilt() {
+        let directory =
+            std::env::temp_dir().join(format!("raytracer-bvh-cache-{}", std::process::id()));
+        let cache = BVHCache::new(&directory);
+        let expected = QBVH::from_vec(spheres());
+        let path = directory.join(format!("{:016x}.qbvh", key(&spheres())));
+
+        assert_hits_match(expected.as_ref(), cache.build(spheres()).as_ref());
+        let entry = fs::read(&path).unwrap();
+        assert_hits_match(expected.as_ref(), cache.build(spheres()).as_ref());
+
+        // cut short, with counts far past what the file holds, and plain garbage
+        let mut huge_counts = entry.clone();
+        huge_counts[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        let damaged = [
+            entry[..entry.len() - 3].to_vec(),
+            huge_counts,
+            vec![0xab; 64],
+        ];
+        for bytes in damaged {
+            fs::write(&path, bytes).unwrap();
+            assert_hits_match(expected.as_ref(), cache.build(spheres()).as_ref());
+            // and the rebuilt tree replaces the damaged entry
+            assert_eq!(fs::read(&path).unwrap(), entry);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
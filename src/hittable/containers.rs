@@ -1,13 +1,58 @@
-use super::aabb::AABB;
+use super::aabb::{AABB, AABB4};
+use super::bvh_cache::BVHCache;
+use super::grid::Grid;
+use super::kd_tree::KdTree;
+use super::sphere_list::SphereList;
 use super::HitRecord;
 use std::cmp::Ordering;
-use std::f64::INFINITY;
-use std::f64::NEG_INFINITY;
-use std::ops::Range;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
 
-use crate::range::RangeExtensions;
+use super::{report::SceneReport, Hittable, PACKET_SIZE};
+use crate::error::{self, Error};
+use crate::float::{Float, INFINITY};
+use crate::interval::Interval;
+use crate::random::RandomSource;
 use crate::ray::Ray;
-use super::Hittable;
+use crate::telemetry::{self, Counter};
+use crate::vec3::{Point3, Vec3};
+
+// The structure a scene's objects are gathered into for tracing. The BVH is the fastest on most
+// scenes, the others are there to compare against it and to cross-check it.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Accelerator {
+    #[default]
+    Bvh,
+    Grid,
+    KdTree,
+}
+
+impl Accelerator {
+    pub const ALL: [Accelerator; 3] = [Accelerator::Bvh, Accelerator::Grid, Accelerator::KdTree];
+}
+
+impl Display for Accelerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Accelerator::Bvh => "bvh",
+            Accelerator::Grid => "grid",
+            Accelerator::KdTree => "kd-tree",
+        })
+    }
+}
+
+impl FromStr for Accelerator {
+    type Err = Error;
+
+    fn from_str(s: &str) -> error::Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|accelerator| accelerator.to_string() == s)
+            .ok_or_else(|| Error::InvalidArgument(format!("unknown accelerator: {}", s)))
+    }
+}
 
 #[derive(Default, Debug)]
 pub struct HittableList {
@@ -17,31 +62,135 @@ pub struct HittableList {
 
 impl HittableList {
     pub fn add(&mut self, object: Box<dyn Hittable>) {
-        self.bounding_box = AABB::from_boxes(&self.bounding_box, object.bounding_box());
+        self.bounding_box.grow(object.bounding_box());
         self.objects.push(object);
     }
-    pub fn into_bvh(mut self) -> Box<dyn Hittable> {
+    // Builds a QBVH over the objects, batching the spheres into `SphereList`s first when there
+    // are many of them.
+    pub fn into_bvh(self) -> Box<dyn Hittable> {
+        telemetry::time_stage("BVH build", || {
+            let objects = SphereList::batch(self.objects);
+            match BVHCache::global() {
+                Some(cache) => cache.build(objects),
+                None => QBVH::from_vec(objects),
+            }
+        })
+    }
+    // Builds a uniform grid over the objects in place of a BVH, see `Grid`.
+    pub fn into_grid(self) -> Box<dyn Hittable> {
+        telemetry::time_stage("grid build", || Box::new(Grid::new(self.objects)))
+    }
+    // Builds a kd-tree over the objects in place of a BVH, see `KdTree`.
+    pub fn into_kd_tree(self) -> Box<dyn Hittable> {
+        telemetry::time_stage("kd-tree build", || Box::new(KdTree::new(self.objects)))
+    }
+    pub fn into_accelerator(self, accelerator: Accelerator) -> Box<dyn Hittable> {
+        match accelerator {
+            Accelerator::Bvh => self.into_bvh(),
+            Accelerator::Grid => self.into_grid(),
+            Accelerator::KdTree => self.into_kd_tree(),
+        }
+    }
+    // Builds a bottom level structure that can be shared between instances.
+    pub fn into_blas(self) -> Arc<dyn Hittable> {
+        return Arc::from(self.into_bvh());
+    }
+    pub fn into_binary_bvh(mut self) -> Box<dyn Hittable> {
         return BVHNode::from_vec(&mut self.objects);
     }
 }
 
 impl Hittable for HittableList {
-    fn hit(&self, ray: &Ray, ray_trange: &Range<f64>) -> Option<HitRecord> {
-        let mut closest_so_far = ray_trange.end;
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        let mut closest_so_far = ray_trange.max;
         let mut result = None;
 
         for object in self.objects.iter() {
-            if let Some(hit_record) = object.hit(ray, &(ray_trange.start..closest_so_far)) {
+            telemetry::count(Counter::PrimitiveTests);
+            if let Some(hit_record) = object.hit(ray, &ray_trange.with_max(closest_so_far)) {
                 closest_so_far = hit_record.t;
                 result = Some(hit_record);
             }
         }
         return result;
     }
+    // Picks one of the objects evenly, a list of lights samples each of them in turn.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        if self.objects.is_empty() {
+            return 0.0;
+        }
+        let sum = self
+            .objects
+            .iter()
+            .map(|object| object.pdf_value(origin, direction))
+            .sum::<Float>();
+        sum / self.objects.len() as Float
+    }
+    fn random(&self, origin: &Point3, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        if self.objects.is_empty() {
+            return None;
+        }
+        let index = (rng.next_float() * self.objects.len() as Float) as usize;
+        self.objects[index.min(self.objects.len() - 1)].random(origin, rng)
+    }
 
     fn bounding_box(&self) -> &AABB {
         &self.bounding_box
     }
+    fn bvh_boxes(&self, depth: usize, boxes: &mut Vec<BvhBox>) {
+        BvhBox::node(depth, boxes, |boxes| {
+            for object in &self.objects {
+                object.bvh_boxes(depth + 1, boxes);
+            }
+        });
+    }
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        for object in &self.objects {
+            object.lights(lights);
+        }
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.memory += std::mem::size_of_val(self)
+            + self.objects.capacity() * std::mem::size_of::<Box<dyn Hittable>>();
+        for object in &self.objects {
+            object.report(report);
+        }
+    }
+}
+
+// A box of a BVH as seen by `Hittable::bvh_boxes`. The boxes of a tree are listed depth first, so
+// the boxes under one run from right after it up to `end`, and a ray that misses a box can skip
+// straight to `end`.
+#[derive(Debug, Clone)]
+pub struct BvhBox {
+    pub bounding_box: AABB,
+    pub depth: usize,
+    pub leaf: bool,
+    pub end: usize,
+}
+
+impl BvhBox {
+    // Appends a node with the boxes `children` appends under it, bounding them all.
+    pub(crate) fn node(
+        depth: usize,
+        boxes: &mut Vec<BvhBox>,
+        children: impl FnOnce(&mut Vec<BvhBox>),
+    ) {
+        let start = boxes.len();
+        boxes.push(BvhBox {
+            bounding_box: AABB::default(),
+            depth,
+            leaf: false,
+            end: start + 1,
+        });
+        children(boxes);
+        let mut bounding_box = AABB::EMPTY;
+        for child in &boxes[start + 1..] {
+            bounding_box.grow(&child.bounding_box);
+        }
+        boxes[start].bounding_box = bounding_box;
+        boxes[start].end = boxes.len();
+    }
 }
 
 #[derive(Debug)]
@@ -52,15 +201,16 @@ pub struct BVHNode {
 }
 
 impl BVHNode {
-    pub(crate) fn from_vec(mut objects: &mut Vec<Box<dyn Hittable>>) -> Box<dyn Hittable> {
+    pub(crate) fn from_vec(objects: &mut Vec<Box<dyn Hittable>>) -> Box<dyn Hittable> {
         return BVHNode::inner_from_vec(objects, 0, 0);
     }
     pub(crate) fn inner_from_vec(
-        mut objects: &mut Vec<Box<dyn Hittable>>,
+        objects: &mut Vec<Box<dyn Hittable>>,
         start: usize,
         depth: usize,
     ) -> Box<dyn Hittable> {
         let length = objects.len() - start;
+        #[allow(unused)]
         let axis = depth % 3;
 
         return if length == 1 {
@@ -87,16 +237,16 @@ impl BVHNode {
             //             .1
             //             .iter()
             //             .map(|x| x.bounding_box().axis(i).middle().powi(2))
-            //             .sum::<f64>()
+            //             .sum::<Float>()
             //             - (objects
             //                 .split_at(start)
             //                 .1
             //                 .iter()
             //                 .map(|x| x.bounding_box().axis(i).middle())
-            //                 .sum::<f64>()
+            //                 .sum::<Float>()
             //                 .powi(2)
-            //                 / length as f64))
-            //             / length as f64;
+            //                 / length as Float))
+            //             / length as Float;
             //         if variance > max_variance {
             //             result = i;
             //             max_variance = variance;
@@ -104,27 +254,15 @@ impl BVHNode {
             //     }
             //     result
             // };
-            let (axis, _) = (0..3).fold((0, NEG_INFINITY), |(prev_axid, highest_diff), axid| {
-                let (min, max) = objects
-                    .split_at(start)
-                    .1
-                    .iter()
-                    .map(|o| o.bounding_box().axis(axid).middle())
-                    .fold((INFINITY, NEG_INFINITY), |(min, max), next| {
-                        (min.min(next), max.max(next))
-                    });
-                ((max - min) > highest_diff)
-                    .then(|| (axid, max - min))
-                    .unwrap_or_else(|| (prev_axid, highest_diff))
-            });
+            let axis = centroid_bounds(objects.split_at(start).1.iter()).longest_axis();
             let comparator = |a: &_, b: &_| BVHNode::box_compare(a, b, axis);
             let mean = objects
                 .split_at(start)
                 .1
                 .iter()
                 .map(|o| o.bounding_box().axis(axis).middle())
-                .sum::<f64>()
-                / length as f64;
+                .sum::<Float>()
+                / length as Float;
 
             // sort the end of the vec from `start` to the end
             objects.split_at_mut(start).1.sort_by(comparator);
@@ -162,25 +300,29 @@ impl BVHNode {
         a_bound.middle().total_cmp(&b_bound.middle())
         // a_bound.start.total_cmp(&b_bound.start)
     }
+    #[allow(unused)]
     pub(crate) fn box_x_compare(a: &Box<dyn Hittable>, b: &Box<dyn Hittable>) -> Ordering {
         Self::box_compare(a, b, 0)
     }
+    #[allow(unused)]
     pub(crate) fn box_y_compare(a: &Box<dyn Hittable>, b: &Box<dyn Hittable>) -> Ordering {
         Self::box_compare(a, b, 1)
     }
+    #[allow(unused)]
     pub(crate) fn box_z_compare(a: &Box<dyn Hittable>, b: &Box<dyn Hittable>) -> Ordering {
         Self::box_compare(a, b, 2)
     }
 }
 
 impl Hittable for BVHNode {
-    fn hit(&self, ray: &Ray, ray_trange: &Range<f64>) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        telemetry::count(Counter::NodeVisits);
         if self.bounding_box.hit(ray).is_none() {
             return None;
         }
 
         if let Some(record) = self.left.hit(ray, ray_trange) {
-            if let Some(record) = self.right.hit(ray, &(ray_trange.start..record.t)) {
+            if let Some(record) = self.right.hit(ray, &ray_trange.with_max(record.t)) {
                 Some(record)
             } else {
                 Some(record)
@@ -193,4 +335,450 @@ impl Hittable for BVHNode {
     fn bounding_box(&self) -> &AABB {
         &self.bounding_box
     }
+
+    fn bvh_boxes(&self, depth: usize, boxes: &mut Vec<BvhBox>) {
+        BvhBox::node(depth, boxes, |boxes| {
+            self.left.bvh_boxes(depth + 1, boxes);
+            self.right.bvh_boxes(depth + 1, boxes);
+        });
+    }
+
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        self.left.lights(lights);
+        self.right.lights(lights);
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.memory += std::mem::size_of_val(self);
+        self.left.report(report);
+        self.right.report(report);
+    }
+}
+
+// A BVH with up to four children per node whose boxes are tested together with SIMD. The nodes
+// live contiguously in one arena and refer to their children by index, and the primitives are
+// stored in the order the tree visits them. The primitives are boxed objects unless the tree is
+// over one kind of object, like the instances of a `TopLevelBVH`.
+#[derive(Debug, Clone)]
+pub struct QBVH<P = Box<dyn Hittable>> {
+    pub(crate) nodes: Vec<QBVHNode>,
+    pub(crate) primitives: Vec<P>,
+    pub(crate) bounding_box: AABB,
+}
+
+#[derive(Debug, Clone)]
+pub struct QBVHNode {
+    pub(crate) child_boxes: AABB4,
+    pub(crate) children: [QBVHChild; 4],
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum QBVHChild {
+    Empty,
+    Node(u32),
+    Primitive(u32),
+}
+
+impl QBVH {
+    pub(crate) fn from_vec(mut objects: Vec<Box<dyn Hittable>>) -> Box<dyn Hittable> {
+        if objects.len() == 1 {
+            return objects.pop().unwrap();
+        }
+        return Box::new(QBVH::new(objects).0);
+    }
+
+    // Rebuilds a tree from a previously built layout, see `QBVH::layout`. Only the bounding boxes
+    // are recomputed so this skips all of the sorting and partitioning of a build. The objects
+    // are handed back if the layout doesn't fit them.
+    pub(crate) fn from_layout(
+        objects: Vec<Box<dyn Hittable>>,
+        order: &[u32],
+        layout: Vec<[QBVHChild; 4]>,
+    ) -> Result<Self, Vec<Box<dyn Hittable>>> {
+        let count = objects.len();
+        let mut seen = vec![false; count];
+        for &index in order {
+            match seen.get_mut(index as usize) {
+                Some(seen) if !*seen => *seen = true,
+                _ => return Err(objects),
+            }
+        }
+        // nodes are laid out depth first so children always come after their parent
+        let valid_node = |(parent, children): (usize, &[QBVHChild; 4])| {
+            children.iter().all(|child| match *child {
+                QBVHChild::Empty => true,
+                QBVHChild::Node(index) => {
+                    parent < index as usize && (index as usize) < layout.len()
+                }
+                QBVHChild::Primitive(index) => (index as usize) < count,
+            })
+        };
+        if order.len() != count || layout.is_empty() || !layout.iter().enumerate().all(valid_node) {
+            return Err(objects);
+        }
+
+        let mut slots = objects.into_iter().map(Some).collect::<Vec<_>>();
+        let primitives = order
+            .iter()
+            .map(|&index| slots[index as usize].take().unwrap())
+            .collect();
+        let mut qbvh = QBVH {
+            nodes: layout
+                .into_iter()
+                .map(|children| QBVHNode {
+                    child_boxes: AABB4::new(&[]),
+                    children,
+                })
+                .collect(),
+            primitives,
+            bounding_box: AABB::default(),
+        };
+        qbvh.bounding_box = qbvh.refit(0);
+        return Ok(qbvh);
+    }
+}
+
+impl<P: Hittable> QBVH<P> {
+    // Builds the tree and also returns the input index of every primitive in tree order.
+    pub(crate) fn new(objects: Vec<P>) -> (Self, Vec<u32>) {
+        let mut qbvh = QBVH {
+            nodes: Vec::with_capacity(objects.len() / 2 + 1),
+            primitives: Vec::with_capacity(objects.len()),
+            bounding_box: AABB::default(),
+        };
+        let mut order = Vec::with_capacity(objects.len());
+        let objects = objects
+            .into_iter()
+            .enumerate()
+            .map(|(index, object)| (index as u32, object))
+            .collect();
+        let (_, bounding_box) = qbvh.build(objects, &mut order);
+        qbvh.bounding_box = bounding_box;
+        return (qbvh, order);
+    }
+
+    // The children of every node, which together with the primitive order describes the tree.
+    pub(crate) fn layout(&self) -> Vec<[QBVHChild; 4]> {
+        self.nodes.iter().map(|node| node.children).collect()
+    }
+
+    // Recomputes the child boxes of a node and the nodes below it.
+    pub(crate) fn refit(&mut self, index: u32) -> AABB {
+        let mut boxes = Vec::with_capacity(4);
+        for child in self.nodes[index as usize].children {
+            match child {
+                QBVHChild::Empty => {}
+                QBVHChild::Node(child) => boxes.push(self.refit(child)),
+                QBVHChild::Primitive(child) => {
+                    boxes.push(self.primitives[child as usize].bounding_box().clone())
+                }
+            }
+        }
+        self.nodes[index as usize].child_boxes = AABB4::new(&boxes.iter().collect::<Vec<_>>());
+        return boxes
+            .iter()
+            .skip(1)
+            .fold(boxes.first().cloned().unwrap_or_default(), |acc, b| {
+                AABB::from_boxes(&acc, b)
+            });
+    }
+
+    // Appends the subtree for `objects` to the arena in depth first order.
+    fn build(&mut self, mut objects: Vec<(u32, P)>, order: &mut Vec<u32>) -> (QBVHChild, AABB) {
+        if objects.len() == 1 {
+            let (source, object) = objects.pop().unwrap();
+            let bounding_box = object.bounding_box().clone();
+            self.primitives.push(object);
+            order.push(source);
+            return (
+                QBVHChild::Primitive(self.primitives.len() as u32 - 1),
+                bounding_box,
+            );
+        }
+        let groups = if objects.len() <= 4 {
+            objects.into_iter().map(|o| vec![o]).collect()
+        } else {
+            // split twice with the binary heuristic to get four groups
+            QBVH::partition(objects)
+                .into_iter()
+                .flat_map(QBVH::partition)
+                .collect::<Vec<_>>()
+        };
+
+        let index = self.nodes.len();
+        self.nodes.push(QBVHNode {
+            child_boxes: AABB4::new(&[]),
+            children: [QBVHChild::Empty; 4],
+        });
+        let mut children = [QBVHChild::Empty; 4];
+        let mut boxes = Vec::with_capacity(4);
+        for (lane, group) in groups.into_iter().enumerate() {
+            let (child, bounding_box) = self.build(group, order);
+            children[lane] = child;
+            boxes.push(bounding_box);
+        }
+        self.nodes[index] = QBVHNode {
+            child_boxes: AABB4::new(&boxes.iter().collect::<Vec<_>>()),
+            children,
+        };
+        let bounding_box = boxes
+            .iter()
+            .skip(1)
+            .fold(boxes.first().cloned().unwrap_or_default(), |acc, b| {
+                AABB::from_boxes(&acc, b)
+            });
+        return (QBVHChild::Node(index as u32), bounding_box);
+    }
+
+    // Splits the objects in two along the axis where their centers are most spread out.
+    pub(crate) fn partition(mut objects: Vec<(u32, P)>) -> Vec<Vec<(u32, P)>> {
+        let length = objects.len();
+        if length < 2 {
+            return vec![objects];
+        }
+        let axis = centroid_bounds(objects.iter().map(|(_, o)| o)).longest_axis();
+        let mean = objects
+            .iter()
+            .map(|(_, o)| o.bounding_box().axis(axis).middle())
+            .sum::<Float>()
+            / length as Float;
+
+        objects.sort_by(|(_, a), (_, b)| {
+            let (a, b) = (a.bounding_box().axis(axis), b.bounding_box().axis(axis));
+            a.middle().total_cmp(&b.middle())
+        });
+
+        let split = objects
+            .iter()
+            .map(|(_, o)| o.bounding_box().axis(axis).middle())
+            .position(|x| x >= mean)
+            .unwrap_or(length / 2)
+            .max(1);
+        let right = objects.split_off(split);
+        vec![objects, right]
+    }
+
+    fn child_bvh_boxes(&self, child: QBVHChild, depth: usize, boxes: &mut Vec<BvhBox>) {
+        match child {
+            QBVHChild::Empty => {}
+            QBVHChild::Node(index) => BvhBox::node(depth, boxes, |boxes| {
+                for child in self.nodes[index as usize].children {
+                    self.child_bvh_boxes(child, depth + 1, boxes);
+                }
+            }),
+            QBVHChild::Primitive(index) => self.primitives[index as usize].bvh_boxes(depth, boxes),
+        }
+    }
+
+    // Calls `visit` with every primitive whose box the ray crosses, for finding all of the hits
+    // along it rather than the closest one.
+    pub(crate) fn visit_crossed(
+        &self,
+        ray: &Ray,
+        ray_trange: &Interval,
+        visit: &mut impl FnMut(&P),
+    ) {
+        if self.nodes.is_empty() {
+            self.primitives.iter().for_each(visit);
+            return;
+        }
+        self.visit_crossed_node(0, ray, ray_trange, visit);
+    }
+
+    fn visit_crossed_node(
+        &self,
+        index: u32,
+        ray: &Ray,
+        ray_trange: &Interval,
+        visit: &mut impl FnMut(&P),
+    ) {
+        let node = &self.nodes[index as usize];
+        let entries = node.child_boxes.hit(ray, ray_trange).to_array();
+        for (child, entry) in node.children.into_iter().zip(entries) {
+            match child {
+                _ if entry == INFINITY => {}
+                QBVHChild::Empty => {}
+                QBVHChild::Node(index) => self.visit_crossed_node(index, ray, ray_trange, visit),
+                QBVHChild::Primitive(index) => visit(&self.primitives[index as usize]),
+            }
+        }
+    }
+
+    fn hit_child(&self, child: QBVHChild, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        match child {
+            QBVHChild::Empty => None,
+            QBVHChild::Node(index) => self.hit_node(index, ray, ray_trange),
+            QBVHChild::Primitive(index) => {
+                telemetry::count(Counter::PrimitiveTests);
+                self.primitives[index as usize].hit(ray, ray_trange)
+            }
+        }
+    }
+
+    fn hit_node(&self, index: u32, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        telemetry::count(Counter::NodeVisits);
+        let node = &self.nodes[index as usize];
+        let entries = node.child_boxes.hit(ray, ray_trange).to_array();
+        // visit the nearest children first so the far ones can be culled by the closest hit
+        let mut order = [0, 1, 2, 3];
+        order.sort_unstable_by(|&a, &b| entries[a].total_cmp(&entries[b]));
+
+        let mut closest_so_far = ray_trange.max;
+        let mut result = None;
+        for lane in order {
+            if entries[lane] >= closest_so_far {
+                break;
+            }
+            let child = node.children[lane];
+            if let Some(hit_record) =
+                self.hit_child(child, ray, &ray_trange.with_max(closest_so_far))
+            {
+                closest_so_far = hit_record.t;
+                result = Some(hit_record);
+            }
+        }
+        return result;
+    }
+
+    fn hit_node_packet(
+        &self,
+        index: u32,
+        rays: &[Ray],
+        ray_trange: &Interval,
+        records: &mut [Option<HitRecord>],
+    ) {
+        telemetry::count(Counter::NodeVisits);
+        let node = &self.nodes[index as usize];
+        let mut entries = [[INFINITY; 4]; PACKET_SIZE];
+        let mut nearest = [INFINITY; 4];
+        for (ray, entry) in rays.iter().zip(entries.iter_mut()) {
+            *entry = node.child_boxes.hit(ray, ray_trange).to_array();
+            for lane in 0..4 {
+                nearest[lane] = nearest[lane].min(entry[lane]);
+            }
+        }
+        let mut order = [0, 1, 2, 3];
+        order.sort_unstable_by(|&a, &b| nearest[a].total_cmp(&nearest[b]));
+
+        for lane in order {
+            if nearest[lane] == INFINITY {
+                break;
+            }
+            // descend if any ray in the packet can still find a closer hit in this child
+            let visit = entries.iter().zip(records.iter()).any(|(entry, record)| {
+                entry[lane] < record.as_ref().map_or(ray_trange.max, |r| r.t)
+            });
+            if !visit {
+                continue;
+            }
+            match node.children[lane] {
+                QBVHChild::Empty => {}
+                QBVHChild::Node(index) => self.hit_node_packet(index, rays, ray_trange, records),
+                QBVHChild::Primitive(index) => {
+                    telemetry::count(Counter::PrimitiveTests);
+                    self.primitives[index as usize].hit_packet(rays, ray_trange, records)
+                }
+            }
+        }
+    }
+}
+
+impl<P: Hittable> Hittable for QBVH<P> {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        if self.nodes.is_empty() {
+            // a tree of one primitive has no nodes
+            return self.primitives.first()?.hit(ray, ray_trange);
+        }
+        return self.hit_node(0, ray, ray_trange);
+    }
+
+    fn bounding_box(&self) -> &AABB {
+        &self.bounding_box
+    }
+
+    fn hit_packet(&self, rays: &[Ray], ray_trange: &Interval, records: &mut [Option<HitRecord>]) {
+        assert!(
+            rays.len() <= PACKET_SIZE,
+            "ray packets are at most {PACKET_SIZE} rays"
+        );
+        if self.nodes.is_empty() {
+            if let Some(primitive) = self.primitives.first() {
+                primitive.hit_packet(rays, ray_trange, records);
+            }
+            return;
+        }
+        self.hit_node_packet(0, rays, ray_trange, records);
+    }
+
+    fn bvh_boxes(&self, depth: usize, boxes: &mut Vec<BvhBox>) {
+        if self.nodes.is_empty() {
+            // a tree of one primitive has no nodes
+            for primitive in &self.primitives {
+                primitive.bvh_boxes(depth, boxes);
+            }
+            return;
+        }
+        self.child_bvh_boxes(QBVHChild::Node(0), depth, boxes);
+    }
+
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        for primitive in &self.primitives {
+            primitive.lights(lights);
+        }
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.memory += std::mem::size_of_val(self)
+            + self.nodes.capacity() * std::mem::size_of::<QBVHNode>()
+            + self.primitives.capacity() * std::mem::size_of::<P>();
+        for primitive in &self.primitives {
+            primitive.report(report);
+        }
+    }
+}
+
+// The box around the centers of the boxes of `objects`. Objects are split along its longest axis,
+// where their centers are the most spread out.
+fn centroid_bounds<'a, H: Hittable + 'a>(objects: impl Iterator<Item = &'a H>) -> AABB {
+    let mut bounds = AABB::EMPTY;
+    for object in objects {
+        let centroid = object.bounding_box().centroid();
+        bounds.grow(&AABB::from_vecs(centroid, centroid));
+    }
+    bounds
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{float::INFINITY, random::Rng};
+
+    // Checks that `accelerator` hits the same objects at the same distances as the BVH, both over
+    // the objects `world` builds, for rays from up to 10 away from the origin, half of them
+    // starting within 2 of it.
+    pub(crate) fn assert_hits_match_the_bvh(
+        world: impl Fn() -> HittableList,
+        accelerator: Accelerator,
+    ) {
+        let mut rng = Rng::from_seed([13, 17]);
+        let bvh = world().into_bvh();
+        let actual = world().into_accelerator(accelerator);
+        let range = Interval::new(0.001, INFINITY);
+        for i in 0..2000 {
+            let origin = (10.0 - (i % 2) as Float * 8.0) * Vec3::random_in_unit_sphere(&mut rng);
+            let ray = Ray::new(origin, Vec3::random_on_unit_sphere(&mut rng), 0.0);
+            match (bvh.hit(&ray, &range), actual.hit(&ray, &range)) {
+                (None, None) => {}
+                // the spheres the BVH batches are intersected with SIMD, which rounds differently
+                (Some(expected), Some(actual)) => {
+                    assert!((expected.t - actual.t).abs() < 1e-3);
+                    assert_eq!(expected.object_id, actual.object_id);
+                }
+                (expected, actual) => panic!(
+                    "the BVH hit {:?} and the {} {:?}",
+                    expected.map(|r| r.t),
+                    accelerator,
+                    actual.map(|r| r.t)
+                ),
+            }
+        }
+    }
 }
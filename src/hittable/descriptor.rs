@@ -0,0 +1,239 @@
+use std::sync::Arc;
+
+use super::{
+    geometry::Sphere,
+    materials::{Cutout, Dielectric, DiffuseLight, Lambertian, Material, Metal},
+    mesh::Mesh,
+    nodes::NodeMaterial,
+    streaming::open_texture,
+    texture::{CheckerTexture, NoiseTexture, SolidColor, Texture},
+    Hittable,
+};
+use crate::{color::Color, error::Result, float::Float, vec3::Point3};
+
+// A texture described by its settings rather than its texels, for saving and loading with serde.
+// Images are named by their path and loaded when the texture is built, see `open_texture`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum TextureDescriptor {
+    Solid {
+        color: Color,
+    },
+    Checker {
+        scale: Float,
+        odd: Box<TextureDescriptor>,
+        even: Box<TextureDescriptor>,
+    },
+    // An sRGB image, or one holding data like a roughness map with `linear` set, see
+    // `ImageTexture::linear`.
+    Image {
+        path: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        linear: bool,
+    },
+    Noise {
+        scale: Float,
+    },
+}
+
+impl TextureDescriptor {
+    pub fn build(&self) -> Result<Arc<dyn Texture>> {
+        Ok(Arc::from(self.build_boxed()?))
+    }
+    fn build_boxed(&self) -> Result<Box<dyn Texture>> {
+        Ok(match self {
+            TextureDescriptor::Solid { color } => Box::new(SolidColor::from(*color)),
+            TextureDescriptor::Checker { scale, odd, even } => Box::new(CheckerTexture::new(
+                *scale,
+                odd.build_boxed()?,
+                even.build_boxed()?,
+            )),
+            TextureDescriptor::Image { path, linear } => open_texture(path, *linear)?,
+            TextureDescriptor::Noise { scale } => Box::new(NoiseTexture::new(*scale)),
+        })
+    }
+}
+
+impl From<Color> for TextureDescriptor {
+    fn from(color: Color) -> Self {
+        TextureDescriptor::Solid { color }
+    }
+}
+
+// A material described by its settings, for saving and loading with serde like
+// `TextureDescriptor`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum MaterialDescriptor {
+    Lambertian {
+        albedo: TextureDescriptor,
+    },
+    Metal {
+        albedo: Color,
+        fuzz: Float,
+    },
+    Dielectric {
+        index_of_refraction: Float,
+        #[cfg_attr(feature = "serde", serde(default))]
+        priority: u32,
+    },
+    DiffuseLight {
+        emit: TextureDescriptor,
+    },
+    Cutout {
+        material: Box<MaterialDescriptor>,
+        opacity: TextureDescriptor,
+    },
+    // A graph of nodes written as text, see `NodeMaterial::from_graph`.
+    Node {
+        graph: String,
+    },
+}
+
+impl MaterialDescriptor {
+    pub fn build(&self) -> Result<Arc<dyn Material>> {
+        Ok(match self {
+            MaterialDescriptor::Lambertian { albedo } => {
+                Arc::new(Lambertian::from(albedo.build()?))
+            }
+            MaterialDescriptor::Metal { albedo, fuzz } => Arc::new(Metal::new(*albedo, *fuzz)),
+            MaterialDescriptor::Dielectric {
+                index_of_refraction,
+                priority,
+            } => Arc::new(Dielectric::new(*index_of_refraction).with_priority(*priority)),
+            MaterialDescriptor::DiffuseLight { emit } => Arc::new(DiffuseLight::new(emit.build()?)),
+            MaterialDescriptor::Cutout { material, opacity } => {
+                Arc::new(Cutout::new(material.build()?, opacity.build()?))
+            }
+            MaterialDescriptor::Node { graph } => Arc::new(NodeMaterial::from_graph(graph)?),
+        })
+    }
+}
+
+// An object of a scene described by its settings, see `SceneDescriptor`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", rename_all = "snake_case"))]
+pub enum ObjectDescriptor {
+    Sphere {
+        center: Point3,
+        radius: Float,
+        material: MaterialDescriptor,
+    },
+    // An OBJ file with one material for every slot. Once `embed` has read the file its text is in
+    // `obj`, so the mesh can be built where `path` doesn't exist, like on a remote worker.
+    Mesh {
+        path: String,
+        #[cfg_attr(feature = "serde", serde(default))]
+        obj: Option<String>,
+        material: MaterialDescriptor,
+    },
+}
+
+impl ObjectDescriptor {
+    pub fn build(&self) -> Result<Box<dyn Hittable>> {
+        Ok(match self {
+            ObjectDescriptor::Sphere {
+                center,
+                radius,
+                material,
+            } => Box::new(Sphere::new(*center, *radius, material.build()?)),
+            ObjectDescriptor::Mesh {
+                path,
+                obj,
+                material,
+            } => {
+                let material = material.build()?;
+                let materials = |_: &str| material.clone();
+                match obj {
+                    Some(text) => Box::new(Mesh::parse_obj(text, materials)?),
+                    None => Box::new(Mesh::load_obj(path, materials)?),
+                }
+            }
+        })
+    }
+    // Reads the files the object is built from into the descriptor.
+    pub fn embed(&mut self) -> Result<()> {
+        if let ObjectDescriptor::Mesh {
+            path,
+            obj: obj @ None,
+            ..
+        } = self
+        {
+            *obj = Some(std::fs::read_to_string(path)?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::Point3;
+
+    fn checkered_glass() -> MaterialDescriptor {
+        MaterialDescriptor::Cutout {
+            material: Box::new(MaterialDescriptor::Dielectric {
+                index_of_refraction: 1.5,
+                priority: 1,
+            }),
+            opacity: TextureDescriptor::Checker {
+                scale: 0.5,
+                odd: Box::new(Color::black().into()),
+                even: Box::new(Color::white().into()),
+            },
+        }
+    }
+
+    #[test]
+    fn descriptors_build_what_they_describe() {
+        let checker = TextureDescriptor::Checker {
+            scale: 1.0,
+            odd: Box::new(Color::black().into()),
+            even: Box::new(Color::white().into()),
+        }
+        .build()
+        .unwrap();
+        let odd = checker.value(0.0, 0.0, &Point3::new(0.5, 0.5, 0.5)).r;
+        let even = checker.value(0.0, 0.0, &Point3::new(1.5, 0.5, 0.5)).r;
+        assert_eq!(odd + even, 1.0);
+        assert!(checkered_glass().build().is_ok());
+        let missing = TextureDescriptor::Image {
+            path: "images/missing.png".to_string(),
+            linear: false,
+        };
+        assert!(MaterialDescriptor::Lambertian { albedo: missing }
+            .build()
+            .is_err());
+        let graph = "base_color = rgb 0.2 0.4 0.6\nspecular = fresnel 1.5";
+        let coat = MaterialDescriptor::Node {
+            graph: graph.to_string(),
+        };
+        let expected = NodeMaterial::from_graph(graph).unwrap();
+        assert_eq!(coat.build().unwrap().id(), expected.id());
+        assert!(MaterialDescriptor::Node {
+            graph: "specular = fresnel 1.5".to_string()
+        }
+        .build()
+        .is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn descriptors_round_trip_through_serde() {
+        let json = serde_json::to_string(&checkered_glass()).unwrap();
+        assert!(json.contains(r#""type":"cutout""#));
+        let parsed: MaterialDescriptor = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        let metal: MaterialDescriptor = serde_json::from_str(
+            r#"{"type": "metal", "albedo": {"r": 0.9, "g": 0.6, "b": 0.3}, "fuzz": 0.2}"#,
+        )
+        .unwrap();
+        assert!(matches!(metal, MaterialDescriptor::Metal { fuzz, .. } if fuzz == 0.2));
+        let node: MaterialDescriptor =
+            serde_json::from_str(r#"{"type": "node", "graph": "base_color = value 0.5"}"#).unwrap();
+        assert!(node.build().is_ok());
+    }
+}
@@ -0,0 +1,340 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    color::Color,
+    error::{Error, Result},
+    float::Float,
+};
+
+use super::{
+    nodes::{MathOp, Node},
+    streaming::open_texture,
+    texture::NoiseTexture,
+};
+
+// Texture expressions like `mix(checker(2.0), noise(5.0), fresnel())`, compiled into the nodes of a
+// `NodeMaterial` when they are loaded. Expressions are calls of the functions below, numbers,
+// names of nodes defined earlier and the operators `+`, `-`, `*` and `/` between them, with the
+// usual precedence.
+//
+//     rgb(r, g, b)                 a color
+//     checker(scale, odd, even)    a 3D checkerboard, of white and black without the colors
+//     noise(scale)                 smooth noise
+//     image("path")                an image mapped onto the surface
+//     fresnel(ior)                 the reflectance of the surface, of glass without the ior
+//     mix(a, b, factor)            a blend from `a` where the factor is zero to `b` where it's one
+//     min(a, b), max(a, b), pow(a, b)
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(Float),
+    Name(String),
+    Text(String),
+    Symbol(char),
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>> {
+    let invalid = || Error::InvalidMaterial(format!("invalid expression: {}", expression));
+    let mut tokens = Vec::new();
+    let mut chars = expression.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            _ if c.is_whitespace() => {}
+            '(' | ')' | ',' | '+' | '-' | '*' | '/' => tokens.push(Token::Symbol(c)),
+            '"' => {
+                let text = chars
+                    .by_ref()
+                    .map(|(_, c)| c)
+                    .take_while(|&c| c != '"')
+                    .collect();
+                tokens.push(Token::Text(text));
+            }
+            _ if c.is_ascii_digit() || c == '.' => {
+                let mut end = start + 1;
+                while let Some(&(index, c)) = chars.peek() {
+                    if !(c.is_ascii_digit() || c == '.') {
+                        break;
+                    }
+                    end = index + 1;
+                    chars.next();
+                }
+                let number = expression[start..end].parse().map_err(|_| invalid())?;
+                tokens.push(Token::Number(number));
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(index, c)) = chars.peek() {
+                    if !(c.is_alphanumeric() || c == '_') {
+                        break;
+                    }
+                    end = index + c.len_utf8();
+                    chars.next();
+                }
+                tokens.push(Token::Name(expression[start..end].to_string()));
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(tokens)
+}
+
+// How deep parentheses, calls and negations may nest, well short of running out of stack.
+const MAX_DEPTH: usize = 64;
+
+struct Parser<'a> {
+    expression: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+    // the factors being parsed, one inside the other
+    depth: usize,
+    nodes: &'a mut Vec<Node>,
+    names: &'a HashMap<String, usize>,
+}
+
+impl Parser<'_> {
+    fn invalid(&self) -> Error {
+        Error::InvalidMaterial(format!("invalid expression: {}", self.expression))
+    }
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+    fn expect(&mut self, symbol: char) -> Result<()> {
+        match self.next() {
+            Some(Token::Symbol(c)) if c == symbol => Ok(()),
+            _ => Err(self.invalid()),
+        }
+    }
+    fn push(&mut self, node: Node) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+    fn constant(&mut self, color: Color) -> usize {
+        self.push(Node::Constant(color))
+    }
+    // Sums and differences of terms.
+    fn expression(&mut self) -> Result<usize> {
+        let mut left = self.term()?;
+        while let Some(&Token::Symbol(symbol @ ('+' | '-'))) = self.peek() {
+            self.position += 1;
+            let right = self.term()?;
+            let op = if symbol == '+' {
+                MathOp::Add
+            } else {
+                MathOp::Subtract
+            };
+            left = self.push(Node::Math(op, left, right));
+        }
+        Ok(left)
+    }
+    // Products and quotients of factors.
+    fn term(&mut self) -> Result<usize> {
+        let mut left = self.factor()?;
+        while let Some(&Token::Symbol(symbol @ ('*' | '/'))) = self.peek() {
+            self.position += 1;
+            let right = self.factor()?;
+            let op = if symbol == '*' {
+                MathOp::Multiply
+            } else {
+                MathOp::Divide
+            };
+            left = self.push(Node::Math(op, left, right));
+        }
+        Ok(left)
+    }
+    // Every nested expression is inside a factor, so this is where the nesting is counted.
+    fn factor(&mut self) -> Result<usize> {
+        if self.depth == MAX_DEPTH {
+            return Err(Error::InvalidMaterial(format!(
+                "expression nested too deep: {}",
+                self.expression
+            )));
+        }
+        self.depth += 1;
+        let value = self.nested_factor();
+        self.depth -= 1;
+        value
+    }
+    fn nested_factor(&mut self) -> Result<usize> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(self.constant(Color::gray(number))),
+            Some(Token::Symbol('-')) => {
+                let zero = self.constant(Color::black());
+                let value = self.factor()?;
+                Ok(self.push(Node::Math(MathOp::Subtract, zero, value)))
+            }
+            Some(Token::Symbol('(')) => {
+                let value = self.expression()?;
+                self.expect(')')?;
+                Ok(value)
+            }
+            Some(Token::Name(name)) if self.peek() == Some(&Token::Symbol('(')) => {
+                self.position += 1;
+                self.call(&name)
+            }
+            Some(Token::Name(name)) => self.names.get(&name).copied().ok_or_else(|| {
+                Error::InvalidMaterial(format!("no node named {} before {}", name, self.expression))
+            }),
+            _ => Err(self.invalid()),
+        }
+    }
+    // The arguments of a call up to its closing parenthesis, the path of `image` as text.
+    fn arguments(&mut self) -> Result<Vec<usize>> {
+        let mut arguments = Vec::new();
+        if self.peek() == Some(&Token::Symbol(')')) {
+            self.position += 1;
+            return Ok(arguments);
+        }
+        loop {
+            arguments.push(self.expression()?);
+            match self.next() {
+                Some(Token::Symbol(',')) => {}
+                Some(Token::Symbol(')')) => return Ok(arguments),
+                _ => return Err(self.invalid()),
+            }
+        }
+    }
+    fn call(&mut self, function: &str) -> Result<usize> {
+        if function == "image" {
+            let Some(Token::Text(path)) = self.next() else {
+                return Err(self.invalid());
+            };
+            self.expect(')')?;
+            let texture = open_texture(path, false)?;
+            return Ok(self.push(Node::Texture(Arc::from(texture))));
+        }
+        // functions that take plain numbers read them from the constant nodes of their arguments
+        let start = self.nodes.len();
+        let arguments = self.arguments()?;
+        let number = |parser: &Self, index: usize| match parser.nodes[index] {
+            Node::Constant(color) if index >= start => Ok(color.r),
+            _ => Err(Error::InvalidMaterial(format!(
+                "{} takes numbers in {}",
+                function, parser.expression
+            ))),
+        };
+        let node = match (function, &arguments[..]) {
+            ("rgb", &[r, g, b]) => Node::Constant(Color::new(
+                number(self, r)?,
+                number(self, g)?,
+                number(self, b)?,
+            )),
+            ("checker", &[scale]) => {
+                let scale = number(self, scale)?;
+                let odd = self.constant(Color::white());
+                let even = self.constant(Color::black());
+                Node::Checker { scale, odd, even }
+            }
+            ("checker", &[scale, odd, even]) => Node::Checker {
+                scale: number(self, scale)?,
+                odd,
+                even,
+            },
+            ("noise", &[scale]) => Node::Texture(Arc::new(NoiseTexture::new(number(self, scale)?))),
+            ("fresnel", &[]) => Node::Fresnel(1.5),
+            ("fresnel", &[index_of_refraction]) => {
+                Node::Fresnel(number(self, index_of_refraction)?)
+            }
+            ("mix", &[a, b, factor]) => Node::Mix { a, b, factor },
+            ("min", &[a, b]) => Node::Math(MathOp::Minimum, a, b),
+            ("max", &[a, b]) => Node::Math(MathOp::Maximum, a, b),
+            ("pow", &[a, b]) => Node::Math(MathOp::Power, a, b),
+            _ => {
+                return Err(Error::InvalidMaterial(format!(
+                    "unknown function {} with {} arguments in {}",
+                    function,
+                    arguments.len(),
+                    self.expression
+                )))
+            }
+        };
+        Ok(self.push(node))
+    }
+}
+
+// Compiles an expression onto the end of `nodes`, which the names refer into, and returns the node
+// with its value.
+pub(crate) fn compile(
+    expression: &str,
+    nodes: &mut Vec<Node>,
+    names: &HashMap<String, usize>,
+) -> Result<usize> {
+    let mut parser = Parser {
+        expression,
+        tokens: tokenize(expression)?,
+        position: 0,
+        depth: 0,
+        nodes,
+        names,
+    };
+    let value = parser.expression()?;
+    if parser.position != parser.tokens.len() {
+        return Err(parser.invalid());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expressions_compile_to_nodes() {
+        let mut nodes = Vec::new();
+        let names = HashMap::new();
+        let value = compile(
+            "mix(checker(2.0), noise(5.0), fresnel()) * 0.5",
+            &mut nodes,
+            &names,
+        )
+        .unwrap();
+        assert_eq!(value, nodes.len() - 1);
+        assert!(matches!(nodes[value], Node::Math(MathOp::Multiply, _, _)));
+        let Node::Mix { a, b, factor } = nodes[value - 2] else {
+            panic!("expected a mix, got {:?}", nodes[value - 2]);
+        };
+        assert!(matches!(nodes[a], Node::Checker { scale: 2.0, .. }));
+        assert!(matches!(nodes[b], Node::Texture(_)));
+        assert!(matches!(nodes[factor], Node::Fresnel(1.5)));
+
+        // precedence and names of earlier nodes
+        let names = HashMap::from([("base".to_string(), value)]);
+        let sum = compile("base + 2 * -3", &mut nodes, &names).unwrap();
+        let Node::Math(MathOp::Add, left, right) = nodes[sum] else {
+            panic!("expected a sum, got {:?}", nodes[sum]);
+        };
+        assert_eq!(left, value);
+        assert!(matches!(nodes[right], Node::Math(MathOp::Multiply, _, _)));
+
+        for invalid in [
+            "mix(1, 2)",
+            "checker(base)",
+            "missing * 2",
+            "rgb(1, 2, 3",
+            "1 2",
+        ] {
+            assert!(compile(invalid, &mut nodes, &names).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn deep_nesting_is_refused() {
+        let mut nodes = Vec::new();
+        let names = HashMap::new();
+        let nested = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(compile(&nested(MAX_DEPTH - 1), &mut nodes, &names).is_ok());
+        for deep in [
+            nested(100_000),
+            format!("{}1", "-".repeat(100_000)),
+            format!("{}1{}", "min(1, ".repeat(100_000), ")".repeat(100_000)),
+        ] {
+            assert!(matches!(
+                compile(&deep, &mut nodes, &names),
+                Err(Error::InvalidMaterial(_))
+            ));
+        }
+    }
+}
@@ -1,33 +1,61 @@
-use std::{f64::consts::PI, ops::Range, sync::Arc};
+use std::sync::Arc;
 
 use crate::{
-    range::Membership,
+    float::{consts::PI, Float, INFINITY},
+    interval::Interval,
+    random::RandomSource,
     ray::Ray,
-    vec3::{Point3, Vec3},
+    vec3::{Onb, Point3, Vec3},
 };
 
-use super::{aabb::AABB, materials::Material, HitRecord, Hittable};
+use super::{aabb::AABB, materials::Material, report::SceneReport, stable_id, HitRecord, Hittable};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Sphere {
     pub(crate) center: Point3,
-    pub(crate) radius: f64,
+    pub(crate) radius: Float,
     pub(crate) material: Arc<dyn Material>,
     pub(crate) bounding_box: AABB,
+    pub(crate) id: u32,
 }
 
 impl Sphere {
-    pub fn new(center: Point3, radius: f64, material: Arc<dyn Material>) -> Self {
+    pub fn new(center: Point3, radius: Float, material: Arc<dyn Material>) -> Self {
         let radius_vec = Vec3::new(radius, radius, radius);
+        // single precision so f32 builds give the same IDs
+        let id = stable_id(
+            &[center.x, center.y, center.z, radius]
+                .map(|value| (value as f32).to_le_bytes())
+                .concat(),
+        );
         Self {
             center,
             radius,
             material,
             bounding_box: AABB::from_vecs(center - radius_vec, center + radius_vec),
+            id,
+        }
+    }
+
+    // The object ID of the sphere in hit records, for linking lights to it.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    // Warns about spheres too small or too broken to ever be hit.
+    fn report_problems(&self, report: &mut SceneReport) {
+        report.material(&self.material);
+        if self.radius == 0.0 {
+            report.warn(format!("sphere {} has a radius of zero", self.id));
+        } else if !self.radius.is_finite() || !self.center.is_finite() {
+            report.warn(format!(
+                "sphere {} has a center or radius that isn't finite",
+                self.id
+            ));
         }
     }
 
-    fn get_sphere_uv(p: &Point3) -> (f64, f64) {
+    pub(crate) fn get_sphere_uv(p: &Point3) -> (Float, Float) {
         let theta = (-p.y).acos();
         let phi = (-p.z).atan2(p.x) + PI;
         (phi / (2. * PI), theta / PI)
@@ -36,8 +64,9 @@ impl Sphere {
     pub(crate) fn calculate_hit(
         &self,
         ray: &Ray,
-        ray_trange: &Range<f64>,
+        ray_trange: &Interval,
         center: Point3,
+        motion: Vec3,
     ) -> Option<HitRecord> {
         let sphere_to_ray = ray.origin - center;
         let squared_raydir_magnitude = ray.direction.length_squared();
@@ -49,9 +78,9 @@ impl Sphere {
         }
         let sqrtd = discriminant.sqrt();
         let mut root = (-alignment - sqrtd) / squared_raydir_magnitude;
-        if !ray_trange.exclusive(root) {
+        if !ray_trange.surrounds(root) {
             root = (-alignment + sqrtd) / squared_raydir_magnitude;
-            if !ray_trange.exclusive(root) {
+            if !ray_trange.surrounds(root) {
                 return None;
             }
         }
@@ -67,48 +96,59 @@ impl Sphere {
             u,
             v,
             front_face,
+            object_id: self.id,
+            motion,
+            shading_offset: Vec3::zero(),
+            temperature: 0.0,
         });
     }
 }
 
 impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, ray_trange: &Range<f64>) -> Option<HitRecord> {
-        return self.calculate_hit(ray, ray_trange, self.center);
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        return self.calculate_hit(ray, ray_trange, self.center, Vec3::zero());
     }
-    fn bounding_box(&self) -> &AABB {
-        return &self.bounding_box;
-    }
-}
-
-#[derive(Debug)]
-pub struct MovingSphere {
-    pub(crate) sphere: Sphere,
-    pub(crate) destination: Point3,
-}
-
-impl MovingSphere {
-    pub fn new(sphere: Sphere, destination: Point3) -> Self {
-        let radius_vec = Vec3::new(sphere.radius, sphere.radius, sphere.radius);
-        let dest_bb = AABB::from_vecs(destination - radius_vec, destination + radius_vec);
-        Self {
-            sphere: Sphere {
-                bounding_box: AABB::from_boxes(&sphere.bounding_box, &dest_bb),
-                ..sphere
-            },
-            destination,
+    // Directions are drawn uniformly from the cone the sphere fills as seen from `origin`.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        let ray = Ray::new(*origin, *direction, 0.0);
+        if self.hit(&ray, &Interval::new(0.001, INFINITY)).is_none() {
+            return 0.0;
         }
+        let distance_squared = (self.center - *origin).length_squared();
+        let cos_theta_max = (1.0 - self.radius.powi(2) / distance_squared).sqrt();
+        let solid_angle = 2.0 * PI * (1.0 - cos_theta_max);
+        return 1.0 / solid_angle;
     }
-}
-
-impl Hittable for MovingSphere {
-    fn hit(&self, ray: &Ray, ray_trange: &Range<f64>) -> Option<HitRecord> {
-        return self.sphere.calculate_hit(
-            ray,
-            ray_trange,
-            self.sphere.center * (1. - ray.time) + self.destination * ray.time,
-        );
+    fn random(&self, origin: &Point3, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        let direction = self.center - *origin;
+        let distance_squared = direction.length_squared();
+        let cos_theta_max = (1.0 - self.radius.powi(2) / distance_squared).sqrt();
+        let z = 1.0 + rng.next_float() * (cos_theta_max - 1.0);
+        let phi = 2.0 * PI * rng.next_float();
+        let sin_theta = (1.0 - z * z).sqrt();
+        let local = Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z);
+        return Some(Onb::new(&direction).transform(&local));
+    }
+    // Points are drawn uniformly over the whole sphere, the half facing away from where it is lit
+    // included. The normals point inwards for negative radii, as those of hits do.
+    fn sample_point(&self, rng: &mut dyn RandomSource) -> Option<(Point3, Vec3, Float)> {
+        let normal = Vec3::random_on_unit_sphere(rng);
+        let area = 4.0 * PI * self.radius.powi(2);
+        Some((self.center + self.radius * normal, normal, 1.0 / area))
+    }
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        if self.material.is_emissive() {
+            lights.push((self.id, Arc::new(self.clone())));
+        }
     }
     fn bounding_box(&self) -> &AABB {
-        &self.sphere.bounding_box
+        return &self.bounding_box;
+    }
+    fn as_sphere(&self) -> Option<(&Sphere, Vec3)> {
+        Some((self, Vec3::zero()))
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.objects("sphere", 1, std::mem::size_of_val(self));
+        self.report_problems(report);
     }
 }
@@ -0,0 +1,327 @@
+use std::sync::Arc;
+
+use crate::{
+    float::{Float, INFINITY},
+    interval::Interval,
+    ray::Ray,
+    telemetry::{self, Counter},
+    vec3::Point3,
+};
+
+use super::{aabb::AABB, containers::BvhBox, report::SceneReport, HitRecord, Hittable};
+
+// How many cells the grid aims for per object, so each cell holds a few objects at most.
+const CELLS_PER_OBJECT: Float = 8.0;
+// The most cells along any axis of the grid.
+const MAX_RESOLUTION: usize = 128;
+// Objects with boxes this many times larger than the median, like ground spheres, are kept out of
+// the grid and tested by every ray, so they don't stretch the grid over empty space or fill every
+// cell with themselves.
+const LARGE_RATIO: Float = 8.0;
+// How many of the objects a ray tested last it remembers, to skip testing them again in the next
+// cells they overlap.
+const MAILBOX_SIZE: usize = 8;
+
+// A uniform grid of cells over the objects, each cell listing the objects whose boxes overlap it.
+// Rays walk the cells they pass through in order and stop at the first cell that ends behind the
+// closest hit so far. For objects spread evenly through the scene, like a field of spheres, it
+// finds hits with less work than a tree does, and being built differently it makes a good check
+// of the BVH.
+#[derive(Debug)]
+pub struct Grid {
+    objects: Vec<Box<dyn Hittable>>,
+    large: Vec<Box<dyn Hittable>>,
+    // the box the cells divide, around the objects in the grid
+    bounds: AABB,
+    resolution: [usize; 3],
+    cell_size: [Float; 3],
+    // the objects of cell `i` are `cell_objects[cell_starts[i]..cell_starts[i + 1]]`
+    cell_starts: Vec<u32>,
+    cell_objects: Vec<u32>,
+    bounding_box: AABB,
+}
+
+impl Grid {
+    pub fn new(objects: Vec<Box<dyn Hittable>>) -> Self {
+        let mut bounding_box = AABB::EMPTY;
+        for object in &objects {
+            bounding_box.grow(object.bounding_box());
+        }
+        let mut diagonals = objects
+            .iter()
+            .map(|o| diagonal(o.bounding_box()))
+            .collect::<Vec<_>>();
+        diagonals.sort_by(Float::total_cmp);
+        let max_diagonal = diagonals
+            .get(diagonals.len() / 2)
+            .map_or(INFINITY, |median| median * LARGE_RATIO);
+        let (objects, large): (Vec<_>, Vec<_>) = objects
+            .into_iter()
+            .partition(|o| diagonal(o.bounding_box()) <= max_diagonal);
+
+        let mut bounds = AABB::EMPTY;
+        for object in &objects {
+            bounds.grow(object.bounding_box());
+        }
+        let extent = [0, 1, 2].map(|a| bounds.axis(a).size().max(0.0));
+        let volume = extent.iter().product::<Float>();
+        let cells_per_unit = if volume > 0.0 {
+            (CELLS_PER_OBJECT * objects.len() as Float / volume).cbrt()
+        } else {
+            0.0
+        };
+        let resolution =
+            extent.map(|e| ((e * cells_per_unit).round() as usize).clamp(1, MAX_RESOLUTION));
+        let cell_size = [0, 1, 2].map(|a| extent[a] / resolution[a] as Float);
+
+        let mut grid = Self {
+            objects: Vec::new(),
+            large,
+            bounds,
+            resolution,
+            cell_size,
+            cell_starts: Vec::new(),
+            cell_objects: Vec::new(),
+            bounding_box,
+        };
+        // count the objects of every cell, then fill them in
+        let cell_count = resolution.iter().product::<usize>();
+        let mut counts = vec![0u32; cell_count + 1];
+        let ranges = objects
+            .iter()
+            .map(|o| grid.cell_range(o.bounding_box()))
+            .collect::<Vec<_>>();
+        for range in &ranges {
+            grid.for_each_cell(range, |cell| counts[cell] += 1);
+        }
+        let mut start = 0;
+        for count in counts.iter_mut() {
+            (start, *count) = (start + *count, start);
+        }
+        let mut cell_objects = vec![0; start as usize];
+        let mut next = counts.clone();
+        for (index, range) in ranges.iter().enumerate() {
+            grid.for_each_cell(range, |cell| {
+                cell_objects[next[cell] as usize] = index as u32;
+                next[cell] += 1;
+            });
+        }
+        grid.cell_starts = counts;
+        grid.cell_objects = cell_objects;
+        grid.objects = objects;
+        grid
+    }
+
+    fn cell_of(&self, a: usize, x: Float) -> usize {
+        let cell = ((x - self.bounds.axis(a).min) / self.cell_size[a]).floor();
+        // NaN from empty axes lands in the first cell
+        (cell.max(0.0) as usize).min(self.resolution[a] - 1)
+    }
+
+    fn cell_index(&self, cell: [usize; 3]) -> usize {
+        (cell[2] * self.resolution[1] + cell[1]) * self.resolution[0] + cell[0]
+    }
+
+    // The first and last cell along each axis that `bounding_box` overlaps.
+    fn cell_range(&self, bounding_box: &AABB) -> [(usize, usize); 3] {
+        [0, 1, 2].map(|a| {
+            let axis = bounding_box.axis(a);
+            (self.cell_of(a, axis.min), self.cell_of(a, axis.max))
+        })
+    }
+
+    fn for_each_cell(&self, range: &[(usize, usize); 3], mut f: impl FnMut(usize)) {
+        for z in range[2].0..=range[2].1 {
+            for y in range[1].0..=range[1].1 {
+                for x in range[0].0..=range[0].1 {
+                    f(self.cell_index([x, y, z]));
+                }
+            }
+        }
+    }
+
+    // Walks the cells the ray passes through within `ray_trange` with a 3D DDA, testing the
+    // objects of each. Objects overlapping several cells can be hit outside of the cell they are
+    // tested in, so the walk only stops when the next cell starts behind the closest hit.
+    fn hit_cells(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        let span = self.bounds.hit(ray)?;
+        let entry = span.min.max(ray_trange.min);
+        if entry > span.max.min(ray_trange.max) {
+            return None;
+        }
+        let start = ray.at(entry);
+        let mut cell = [0, 1, 2].map(|a| self.cell_of(a, start[a]));
+        let mut step = [0isize; 3];
+        let mut next_crossing = [INFINITY; 3];
+        let mut crossing_step = [INFINITY; 3];
+        for a in 0..3 {
+            let direction = ray.direction[a];
+            if direction == 0.0 {
+                continue;
+            }
+            let min = self.bounds.axis(a).min;
+            let boundary = if direction > 0.0 {
+                step[a] = 1;
+                min + (cell[a] + 1) as Float * self.cell_size[a]
+            } else {
+                step[a] = -1;
+                min + cell[a] as Float * self.cell_size[a]
+            };
+            next_crossing[a] = (boundary - ray.origin[a]) / direction;
+            crossing_step[a] = self.cell_size[a] / direction.abs();
+        }
+
+        let mut closest_so_far = ray_trange.max;
+        let mut result = None;
+        // the objects tested last, which are likely to overlap the next cell too
+        let mut tested = [u32::MAX; MAILBOX_SIZE];
+        let mut next_slot = 0;
+        loop {
+            telemetry::count(Counter::NodeVisits);
+            let index = self.cell_index(cell);
+            let objects = self.cell_starts[index] as usize..self.cell_starts[index + 1] as usize;
+            for &object in &self.cell_objects[objects] {
+                if tested.contains(&object) {
+                    continue;
+                }
+                tested[next_slot] = object;
+                next_slot = (next_slot + 1) % MAILBOX_SIZE;
+                telemetry::count(Counter::PrimitiveTests);
+                let object = &self.objects[object as usize];
+                if let Some(hit_record) = object.hit(ray, &ray_trange.with_max(closest_so_far)) {
+                    closest_so_far = hit_record.t;
+                    result = Some(hit_record);
+                }
+            }
+            let axis = (0..3)
+                .min_by(|&a, &b| next_crossing[a].total_cmp(&next_crossing[b]))
+                .unwrap();
+            if next_crossing[axis] >= closest_so_far {
+                return result;
+            }
+            let next = cell[axis] as isize + step[axis];
+            if next < 0 || next >= self.resolution[axis] as isize {
+                return result;
+            }
+            cell[axis] = next as usize;
+            next_crossing[axis] += crossing_step[axis];
+        }
+    }
+}
+
+impl Hittable for Grid {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        // the large objects first, as they are likely hit and cut the walk through the cells short
+        let mut closest_so_far = ray_trange.max;
+        let mut result = None;
+        for object in &self.large {
+            telemetry::count(Counter::PrimitiveTests);
+            if let Some(hit_record) = object.hit(ray, &ray_trange.with_max(closest_so_far)) {
+                closest_so_far = hit_record.t;
+                result = Some(hit_record);
+            }
+        }
+        if self.objects.is_empty() {
+            return result;
+        }
+        self.hit_cells(ray, &ray_trange.with_max(closest_so_far))
+            .or(result)
+    }
+
+    fn bounding_box(&self) -> &AABB {
+        &self.bounding_box
+    }
+
+    // The occupied cells are the leaves, next to the large objects.
+    fn bvh_boxes(&self, depth: usize, boxes: &mut Vec<BvhBox>) {
+        BvhBox::node(depth, boxes, |boxes| {
+            for object in &self.large {
+                object.bvh_boxes(depth + 1, boxes);
+            }
+            let min = [0, 1, 2].map(|a| self.bounds.axis(a).min);
+            for z in 0..self.resolution[2] {
+                for y in 0..self.resolution[1] {
+                    for x in 0..self.resolution[0] {
+                        let index = self.cell_index([x, y, z]);
+                        if self.cell_starts[index] == self.cell_starts[index + 1] {
+                            continue;
+                        }
+                        let corner = |cell: [usize; 3]| {
+                            let [x, y, z] =
+                                [0, 1, 2].map(|a| min[a] + cell[a] as Float * self.cell_size[a]);
+                            Point3::new(x, y, z)
+                        };
+                        boxes.push(BvhBox {
+                            bounding_box: AABB::from_vecs(
+                                corner([x, y, z]),
+                                corner([x + 1, y + 1, z + 1]),
+                            ),
+                            depth: depth + 1,
+                            leaf: true,
+                            end: boxes.len() + 1,
+                        });
+                    }
+                }
+            }
+        });
+    }
+
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        for object in self.large.iter().chain(&self.objects) {
+            object.lights(lights);
+        }
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.memory += std::mem::size_of_val(self)
+            + (self.objects.capacity() + self.large.capacity())
+                * std::mem::size_of::<Box<dyn Hittable>>()
+            + (self.cell_starts.capacity() + self.cell_objects.capacity())
+                * std::mem::size_of::<u32>();
+        for object in self.objects.iter().chain(&self.large) {
+            object.report(report);
+        }
+    }
+}
+
+fn diagonal(bounding_box: &AABB) -> Float {
+    let size = [0, 1, 2].map(|a| bounding_box.axis(a).size());
+    size.iter().map(|s| s * s).sum::<Float>().sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        color::Color,
+        hittable::{
+            containers::{tests::assert_hits_match_the_bvh, Accelerator, HittableList},
+            geometry::Sphere,
+            materials::Lambertian,
+        },
+        random::{RandomSource, Rng},
+        vec3::Vec3,
+    };
+
+    // a field of small spheres on a ground sphere too large for the grid
+    fn field() -> HittableList {
+        let mut rng = Rng::from_seed([3, 5]);
+        let material = Arc::new(Lambertian::from(Color::gray(0.5)));
+        let mut world = HittableList::default();
+        let ground = Sphere::new(Point3::new(0.0, -100.0, 0.0), 100.0, material.clone());
+        world.add(Box::new(ground));
+        for _ in 0..200 {
+            let center = 4.0 * Vec3::random_in_unit_sphere(&mut rng);
+            let radius = rng.next_float_range(0.05..0.4);
+            world.add(Box::new(Sphere::new(center, radius, material.clone())));
+        }
+        world
+    }
+
+    #[test]
+    fn hits_match_the_bvh() {
+        assert_eq!(Grid::new(field().objects).large.len(), 1);
+        assert_hits_match_the_bvh(field, Accelerator::Grid);
+    }
+}
@@ -0,0 +1,434 @@
+use std::sync::Arc;
+
+use crate::{
+    float::Float,
+    interval::Interval,
+    random::RandomSource,
+    ray::Ray,
+    vec3::{Point3, Vec3},
+};
+
+use super::{
+    aabb::{AABB, AABB4},
+    containers::{BvhBox, QBVHChild, QBVHNode, QBVH},
+    report::SceneReport,
+    HitRecord, Hittable,
+};
+
+// A placement of a shared bottom level structure (BLAS) in the scene. Moving an instance only
+// changes its offset, the structure it refers to is left untouched.
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub(crate) blas: Arc<dyn Hittable>,
+    pub(crate) offset: Vec3,
+    pub(crate) bounding_box: AABB,
+}
+
+impl Instance {
+    pub fn new(blas: Arc<dyn Hittable>, offset: Vec3) -> Self {
+        let bounding_box = blas.bounding_box().translated(offset);
+        Self {
+            blas,
+            offset,
+            bounding_box,
+        }
+    }
+    pub fn offset(&self) -> Vec3 {
+        self.offset
+    }
+    pub fn set_offset(&mut self, offset: Vec3) {
+        *self = Self::new(self.blas.clone(), offset);
+    }
+}
+
+impl Hittable for Instance {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        let local_ray = Ray::new(ray.origin - self.offset, ray.direction, ray.time);
+        let mut hit_record = self.blas.hit(&local_ray, ray_trange)?;
+        hit_record.point = hit_record.point + self.offset;
+        return Some(hit_record);
+    }
+    fn bounding_box(&self) -> &AABB {
+        &self.bounding_box
+    }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        self.blas.pdf_value(&(*origin - self.offset), direction)
+    }
+    fn random(&self, origin: &Point3, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        self.blas.random(&(*origin - self.offset), rng)
+    }
+    fn sample_point(&self, rng: &mut dyn RandomSource) -> Option<(Point3, Vec3, Float)> {
+        let (point, normal, pdf) = self.blas.sample_point(rng)?;
+        Some((point + self.offset, normal, pdf))
+    }
+    // The lights of the structure, placed like the instance. Every instance of a light hits with
+    // the same ID, and all of them are listed.
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        let mut placed = Vec::new();
+        self.blas.lights(&mut placed);
+        for (id, light) in placed {
+            lights.push((id, Arc::new(Instance::new(light, self.offset))));
+        }
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.objects("instance", 1, std::mem::size_of_val(self));
+        if !self.offset.is_finite() {
+            report.warn("an instance has an offset that isn't finite".to_string());
+        }
+        report.shared(&self.blas);
+    }
+}
+
+// The top level structure (TLAS) of a scene, a BVH over instances. Instances are edited in place:
+// the boxes above an edit are refit, and a subtree whose box has grown to more than
+// `REBUILD_GROWTH` times the surface it had when it was built is rebuilt on its own. Instances keep
+// the key they were added with, the position in the list for those it was made with.
+#[derive(Debug, Clone)]
+pub struct TopLevelBVH {
+    // the instances are the primitives of the tree in the order of their keys, removed ones are
+    // left in place until their key is reused
+    tree: QBVH<Instance>,
+    // the box of every node and its surface area when it was built
+    boxes: Vec<AABB>,
+    built_areas: Vec<Float>,
+    // the node and lane every node and instance hangs from, the root and removed instances from
+    // nothing
+    node_parents: Vec<Option<(u32, usize)>>,
+    instance_parents: Vec<Option<(u32, usize)>>,
+    free_keys: Vec<usize>,
+    // nodes left out of the tree by removals and local rebuilds
+    unused_nodes: usize,
+}
+
+const REBUILD_GROWTH: Float = 2.0;
+
+impl TopLevelBVH {
+    pub fn new(instances: Vec<Instance>) -> Self {
+        let mut tlas = Self {
+            tree: QBVH {
+                nodes: Vec::new(),
+                primitives: instances,
+                bounding_box: AABB::default(),
+            },
+            boxes: Vec::new(),
+            built_areas: Vec::new(),
+            node_parents: Vec::new(),
+            instance_parents: Vec::new(),
+            free_keys: Vec::new(),
+            unused_nodes: 0,
+        };
+        tlas.rebuild();
+        tlas
+    }
+    // The instances in the scene with their keys.
+    pub fn instances(&self) -> impl Iterator<Item = (usize, &Instance)> {
+        let instances = self.tree.primitives.iter().enumerate();
+        instances.filter(|(key, _)| self.instance_parents[*key].is_some())
+    }
+    pub fn add(&mut self, instance: Instance) -> usize {
+        let key = match self.free_keys.pop() {
+            Some(key) => {
+                self.tree.primitives[key] = instance;
+                key
+            }
+            None => {
+                self.tree.primitives.push(instance);
+                self.instance_parents.push(None);
+                self.tree.primitives.len() - 1
+            }
+        };
+        // go down to the node whose box grows the least, making room by pairing the new instance
+        // with another one when it is full
+        let bounding_box = self.tree.primitives[key].bounding_box().clone();
+        let mut node = 0;
+        loop {
+            let children = self.tree.nodes[node as usize].children;
+            if let Some(lane) = children
+                .iter()
+                .position(|child| matches!(child, QBVHChild::Empty))
+            {
+                self.attach(node, lane, QBVHChild::Primitive(key as u32));
+                break;
+            }
+            let growth = |lane: &usize| {
+                let child_box = self.child_box(children[*lane]);
+                AABB::from_boxes(child_box, &bounding_box).surface_area() - child_box.surface_area()
+            };
+            let lane = (0..4)
+                .min_by(|a, b| growth(a).total_cmp(&growth(b)))
+                .unwrap();
+            match children[lane] {
+                QBVHChild::Node(child) => node = child,
+                sibling => {
+                    let pair = self.push_node(Some((node, lane)));
+                    self.attach(node, lane, QBVHChild::Node(pair));
+                    self.attach(pair, 0, sibling);
+                    self.attach(pair, 1, QBVHChild::Primitive(key as u32));
+                    node = pair;
+                    break;
+                }
+            }
+        }
+        self.refit_from(node);
+        return key;
+    }
+    // Takes the instance out of the scene, `None` if the key isn't of an instance in it.
+    pub fn remove(&mut self, key: usize) -> Option<Instance> {
+        let (mut node, mut lane) = self.instance_parents.get_mut(key)?.take()?;
+        self.free_keys.push(key);
+        // take the child out of its node and the nodes it leaves empty out of theirs
+        loop {
+            let children = &mut self.tree.nodes[node as usize].children;
+            children[lane..].rotate_left(1);
+            children[3] = QBVHChild::Empty;
+            let children = *children;
+            for (lane, child) in children.iter().enumerate().skip(lane) {
+                self.set_parent(*child, Some((node, lane)));
+            }
+            match self.node_parents[node as usize] {
+                Some(parent) if matches!(children[0], QBVHChild::Empty) => {
+                    self.unused_nodes += 1;
+                    (node, lane) = parent;
+                }
+                _ => break,
+            }
+        }
+        self.refit_from(node);
+        return Some(self.tree.primitives[key].clone());
+    }
+    // Moves the instance to `offset` and returns the offset it had, `None` if the key isn't of an
+    // instance in the scene.
+    pub fn move_instance(&mut self, key: usize, offset: Vec3) -> Option<Vec3> {
+        let (node, _) = (*self.instance_parents.get(key)?)?;
+        let instance = &mut self.tree.primitives[key];
+        let previous = instance.offset();
+        instance.set_offset(offset);
+        self.refit_from(node);
+        Some(previous)
+    }
+    // Builds the whole tree again, which also drops the unused nodes.
+    fn rebuild(&mut self) {
+        self.tree.nodes.clear();
+        self.boxes.clear();
+        self.built_areas.clear();
+        self.node_parents.clear();
+        self.unused_nodes = 0;
+        let live = (0..self.tree.primitives.len())
+            .filter(|key| !self.free_keys.contains(key))
+            .collect::<Vec<_>>();
+        self.instance_parents = vec![None; self.tree.primitives.len()];
+        self.push_node(None);
+        self.build(0, live);
+    }
+    // Rebuilds the subtree under `node` in place, or the whole tree once there are as many unused
+    // nodes as nodes in use.
+    fn rebuild_subtree(&mut self, node: u32) {
+        let mut keys = Vec::new();
+        let mut stack = vec![node];
+        while let Some(index) = stack.pop() {
+            for child in self.tree.nodes[index as usize].children {
+                match child {
+                    QBVHChild::Empty => {}
+                    QBVHChild::Node(child) => {
+                        stack.push(child);
+                        self.unused_nodes += 1;
+                    }
+                    QBVHChild::Primitive(key) => keys.push(key as usize),
+                }
+            }
+        }
+        if 2 * self.unused_nodes > self.tree.nodes.len() {
+            self.rebuild();
+        } else {
+            self.build(node, keys);
+        }
+    }
+    // Fills `node` with a tree over the instances of `keys`, with the same splits as `QBVH`.
+    fn build(&mut self, node: u32, keys: Vec<usize>) {
+        let groups = if keys.len() <= 4 {
+            keys.into_iter().map(|key| vec![key]).collect()
+        } else {
+            let instances = keys
+                .into_iter()
+                .map(|key| (key as u32, self.tree.primitives[key].clone()))
+                .collect();
+            QBVH::partition(instances)
+                .into_iter()
+                .flat_map(QBVH::partition)
+                .map(|group| group.into_iter().map(|(key, _)| key as usize).collect())
+                .collect::<Vec<Vec<_>>>()
+        };
+        self.tree.nodes[node as usize].children = [QBVHChild::Empty; 4];
+        for (lane, group) in groups.into_iter().enumerate() {
+            if let [key] = group[..] {
+                self.attach(node, lane, QBVHChild::Primitive(key as u32));
+            } else {
+                let child = self.push_node(Some((node, lane)));
+                self.attach(node, lane, QBVHChild::Node(child));
+                self.build(child, group);
+            }
+        }
+        self.refit_node(node);
+        self.built_areas[node as usize] = self.boxes[node as usize].surface_area();
+        if node == 0 {
+            self.tree.bounding_box = self.boxes[0].clone();
+        }
+    }
+    // Refits the boxes from `node` up to the root, then rebuilds the highest subtree on the way
+    // that has outgrown the box it was built with.
+    fn refit_from(&mut self, node: u32) {
+        let mut outgrown = None;
+        let mut next = Some(node);
+        while let Some(node) = next {
+            self.refit_node(node);
+            if self.boxes[node as usize].surface_area()
+                > REBUILD_GROWTH * self.built_areas[node as usize]
+            {
+                outgrown = Some(node);
+            }
+            next = self.node_parents[node as usize].map(|(parent, _)| parent);
+        }
+        if let Some(node) = outgrown {
+            self.rebuild_subtree(node);
+            let mut next = self.node_parents.get(node as usize).copied().flatten();
+            while let Some((parent, _)) = next {
+                self.refit_node(parent);
+                next = self.node_parents[parent as usize];
+            }
+        }
+        self.tree.bounding_box = self.boxes[0].clone();
+    }
+    fn refit_node(&mut self, node: u32) {
+        let children = self.tree.nodes[node as usize].children;
+        let boxes = children
+            .iter()
+            .filter(|child| !matches!(child, QBVHChild::Empty))
+            .map(|child| self.child_box(*child).clone())
+            .collect::<Vec<_>>();
+        self.tree.nodes[node as usize].child_boxes = AABB4::new(&boxes.iter().collect::<Vec<_>>());
+        self.boxes[node as usize] = boxes
+            .iter()
+            .skip(1)
+            .fold(boxes.first().cloned().unwrap_or_default(), |acc, b| {
+                AABB::from_boxes(&acc, b)
+            });
+    }
+    fn child_box(&self, child: QBVHChild) -> &AABB {
+        match child {
+            QBVHChild::Node(node) => &self.boxes[node as usize],
+            QBVHChild::Primitive(key) => self.tree.primitives[key as usize].bounding_box(),
+            QBVHChild::Empty => unreachable!("empty lanes have no box"),
+        }
+    }
+    fn push_node(&mut self, parent: Option<(u32, usize)>) -> u32 {
+        self.tree.nodes.push(QBVHNode {
+            child_boxes: AABB4::new(&[]),
+            children: [QBVHChild::Empty; 4],
+        });
+        self.boxes.push(AABB::default());
+        self.built_areas.push(0.0);
+        self.node_parents.push(parent);
+        return self.tree.nodes.len() as u32 - 1;
+    }
+    fn attach(&mut self, node: u32, lane: usize, child: QBVHChild) {
+        self.tree.nodes[node as usize].children[lane] = child;
+        self.set_parent(child, Some((node, lane)));
+    }
+    fn set_parent(&mut self, child: QBVHChild, parent: Option<(u32, usize)>) {
+        match child {
+            QBVHChild::Empty => {}
+            QBVHChild::Node(node) => self.node_parents[node as usize] = parent,
+            QBVHChild::Primitive(key) => self.instance_parents[key as usize] = parent,
+        }
+    }
+}
+
+impl Hittable for TopLevelBVH {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        self.tree.hit(ray, ray_trange)
+    }
+    fn bounding_box(&self) -> &AABB {
+        self.tree.bounding_box()
+    }
+    fn hit_packet(&self, rays: &[Ray], ray_trange: &Interval, records: &mut [Option<HitRecord>]) {
+        self.tree.hit_packet(rays, ray_trange, records)
+    }
+    fn bvh_boxes(&self, depth: usize, boxes: &mut Vec<BvhBox>) {
+        self.tree.bvh_boxes(depth, boxes)
+    }
+    // Only the instances in the scene, not the removed ones waiting for their key to be reused.
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        for (_, instance) in self.instances() {
+            instance.lights(lights);
+        }
+    }
+    fn report(&self, report: &mut SceneReport) {
+        // the bookkeeping for edits, for every node and instance
+        let parent = std::mem::size_of::<Option<(u32, usize)>>();
+        let node = std::mem::size_of::<AABB>() + std::mem::size_of::<Float>() + parent;
+        report.memory += self.boxes.len() * node + self.instance_parents.len() * parent;
+        self.tree.report(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color::Color,
+        hittable::{geometry::Sphere, materials::Lambertian},
+        random::{RandomSource, Rng},
+        vec3::Point3,
+    };
+
+    #[test]
+    fn edits_keep_the_tree_in_step() {
+        let blas: Arc<dyn Hittable> = Arc::new(Sphere::new(
+            Point3::zero(),
+            0.5,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        ));
+        let mut rng = Rng::from_seed([7, 8]);
+        let random_offset =
+            |rng: &mut Rng| Vec3::new(rng.next_float(), rng.next_float(), 0.0) * 20.0;
+        let mut tlas = TopLevelBVH::new(
+            (0..10)
+                .map(|_| Instance::new(blas.clone(), random_offset(&mut rng)))
+                .collect(),
+        );
+        let mut live = (0..10).collect::<Vec<usize>>();
+        for step in 0..300 {
+            match step % 3 {
+                0 => live.push(tlas.add(Instance::new(blas.clone(), random_offset(&mut rng)))),
+                1 if live.len() > 1 => {
+                    let key = live.swap_remove((rng.next_float() * live.len() as Float) as usize);
+                    assert!(tlas.remove(key).is_some());
+                    assert!(tlas.remove(key).is_none());
+                    assert!(tlas.move_instance(key, Vec3::zero()).is_none());
+                }
+                _ => {
+                    let key = live[(rng.next_float() * live.len() as Float) as usize];
+                    let offset = tlas.tree.primitives[key].offset();
+                    let previous = tlas.move_instance(key, random_offset(&mut rng)).unwrap();
+                    assert_eq!((previous - offset).length(), 0.0);
+                }
+            }
+            let mut keys = tlas.instances().map(|(key, _)| key).collect::<Vec<_>>();
+            keys.sort();
+            let mut expected = live.clone();
+            expected.sort();
+            assert_eq!(keys, expected);
+            // every instance is found by a ray straight down onto it
+            for (_, instance) in tlas.instances() {
+                let above = instance.offset() + Vec3::new(0.0, 0.0, 10.0);
+                let ray = Ray::new(above, Vec3::new(0.0, 0.0, -1.0), 0.0);
+                let hit = tlas.hit(&ray, &Interval::new(0.001, Float::INFINITY));
+                assert!((hit.unwrap().t - 9.5).abs() < 1e-4);
+            }
+        }
+        assert!(tlas.unused_nodes <= tlas.tree.nodes.len());
+        // keys never handed out are refused too
+        assert!(tlas.remove(1000).is_none());
+        assert!(tlas.move_instance(1000, Vec3::zero()).is_none());
+    }
+}
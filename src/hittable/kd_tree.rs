@@ -0,0 +1,355 @@
+use std::sync::Arc;
+
+use crate::{
+    float::Float,
+    interval::Interval,
+    ray::Ray,
+    telemetry::{self, Counter},
+};
+
+use super::{aabb::AABB, containers::BvhBox, report::SceneReport, HitRecord, Hittable};
+
+// The costs of the surface area heuristic, of stepping through a node and of testing an object,
+// relative to each other.
+const TRAVERSAL_COST: Float = 1.0;
+const INTERSECTION_COST: Float = 80.0;
+// How much cheaper splits are made look when they cut off empty space, which rays then skip.
+const EMPTY_BONUS: Float = 0.5;
+// How many splits in a row may cost more than the leaf they replace, in the hope that the ones
+// below them pay off.
+const MAX_BAD_REFINES: usize = 3;
+// The deepest the tree gets, which bounds the stack of traversal.
+const MAX_DEPTH: usize = 64;
+
+// A kd-tree splitting space by axis aligned planes placed with the surface area heuristic, on the
+// faces of the boxes of the objects where it is cheapest to trace. Unlike the boxes of a BVH its
+// cells never overlap, so rays visit them strictly front to back and stop at the first one past
+// the closest hit, but objects crossing a plane are listed on both sides of it. It is there to
+// benchmark against the BVH.
+#[derive(Debug)]
+pub struct KdTree {
+    // nodes are laid out depth first, so the child below a split comes right after it
+    nodes: Vec<KdNode>,
+    // the objects of the leaves, by index into `objects`
+    leaf_objects: Vec<u32>,
+    objects: Vec<Box<dyn Hittable>>,
+    bounding_box: AABB,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum KdNode {
+    Split {
+        axis: usize,
+        position: Float,
+        above: u32,
+    },
+    Leaf {
+        first: u32,
+        count: u32,
+    },
+}
+
+impl KdTree {
+    pub fn new(objects: Vec<Box<dyn Hittable>>) -> Self {
+        let mut bounding_box = AABB::EMPTY;
+        for object in &objects {
+            bounding_box.grow(object.bounding_box());
+        }
+        let max_depth = (8.0 + 1.3 * (objects.len().max(1) as Float).log2()).round() as usize;
+        let mut tree = Self {
+            nodes: Vec::new(),
+            leaf_objects: Vec::new(),
+            objects,
+            bounding_box,
+        };
+        let indices = (0..tree.objects.len() as u32).collect();
+        tree.build(
+            tree.bounding_box.clone(),
+            indices,
+            max_depth.min(MAX_DEPTH - 1),
+            0,
+        );
+        tree
+    }
+
+    // Appends the subtree over `indices` within `bounds` to the nodes in depth first order.
+    fn build(&mut self, bounds: AABB, indices: Vec<u32>, depth: usize, bad_refines: usize) {
+        let leaf_cost = INTERSECTION_COST * indices.len() as Float;
+        let split = match self.best_split(&bounds, &indices) {
+            Some(split) if indices.len() > 1 && depth > 0 => split,
+            _ => return self.push_leaf(indices),
+        };
+        let (axis, position, cost) = split;
+        let bad_refines = bad_refines + (cost > leaf_cost) as usize;
+        if (cost > 4.0 * leaf_cost && indices.len() < 16) || bad_refines >= MAX_BAD_REFINES {
+            return self.push_leaf(indices);
+        }
+
+        let (mut below, mut above) = (Vec::new(), Vec::new());
+        for &index in &indices {
+            let extent = self.objects[index as usize].bounding_box().axis(axis);
+            // boxes flat on the plane go below it, so every object is on at least one side
+            if extent.min < position || extent.max <= position {
+                below.push(index);
+            }
+            if extent.max > position {
+                above.push(index);
+            }
+        }
+        let (mut below_bounds, mut above_bounds) = (bounds.clone(), bounds);
+        axis_mut(&mut below_bounds, axis).max = position;
+        axis_mut(&mut above_bounds, axis).min = position;
+
+        let index = self.nodes.len();
+        self.nodes.push(KdNode::Leaf { first: 0, count: 0 });
+        self.build(below_bounds, below, depth - 1, bad_refines);
+        let above_index = self.nodes.len() as u32;
+        self.build(above_bounds, above, depth - 1, bad_refines);
+        self.nodes[index] = KdNode::Split {
+            axis,
+            position,
+            above: above_index,
+        };
+    }
+
+    fn push_leaf(&mut self, indices: Vec<u32>) {
+        self.nodes.push(KdNode::Leaf {
+            first: self.leaf_objects.len() as u32,
+            count: indices.len() as u32,
+        });
+        self.leaf_objects.extend(indices);
+    }
+
+    // The cheapest plane to split `bounds` by and its cost, sweeping the faces of the boxes of
+    // the objects along every axis.
+    fn best_split(&self, bounds: &AABB, indices: &[u32]) -> Option<(usize, Float, Float)> {
+        let total_area = bounds.surface_area();
+        if total_area.is_nan() || total_area <= 0.0 {
+            return None;
+        }
+        let size = [0, 1, 2].map(|a| bounds.axis(a).size());
+        let mut best: Option<(usize, Float, Float)> = None;
+        let mut edges = Vec::with_capacity(2 * indices.len());
+        for axis in 0..3 {
+            edges.clear();
+            for &index in indices {
+                let extent = self.objects[index as usize].bounding_box().axis(axis);
+                // ends come before starts where they meet, so boxes touching a plane are only
+                // counted on the side they are on
+                edges.push((extent.min, true));
+                edges.push((extent.max, false));
+            }
+            edges.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+            let (u, v) = (size[(axis + 1) % 3], size[(axis + 2) % 3]);
+            let extent = bounds.axis(axis);
+            let (mut below, mut above) = (0, indices.len());
+            for &(position, starts) in &edges {
+                if !starts {
+                    above -= 1;
+                }
+                if extent.surrounds(position) {
+                    let below_area = 2.0 * (u * v + (position - extent.min) * (u + v));
+                    let above_area = 2.0 * (u * v + (extent.max - position) * (u + v));
+                    let bonus = if below == 0 || above == 0 {
+                        EMPTY_BONUS
+                    } else {
+                        0.0
+                    };
+                    let cost = TRAVERSAL_COST
+                        + INTERSECTION_COST
+                            * (1.0 - bonus)
+                            * (below_area * below as Float + above_area * above as Float)
+                            / total_area;
+                    if best.is_none_or(|(_, _, best)| cost < best) {
+                        best = Some((axis, position, cost));
+                    }
+                }
+                if starts {
+                    below += 1;
+                }
+            }
+        }
+        best
+    }
+
+    fn node_bvh_boxes(&self, index: usize, bounds: AABB, depth: usize, boxes: &mut Vec<BvhBox>) {
+        match self.nodes[index] {
+            KdNode::Split {
+                axis,
+                position,
+                above,
+            } => BvhBox::node(depth, boxes, |boxes| {
+                let (mut below_bounds, mut above_bounds) = (bounds.clone(), bounds);
+                axis_mut(&mut below_bounds, axis).max = position;
+                axis_mut(&mut above_bounds, axis).min = position;
+                self.node_bvh_boxes(index + 1, below_bounds, depth + 1, boxes);
+                self.node_bvh_boxes(above as usize, above_bounds, depth + 1, boxes);
+            }),
+            KdNode::Leaf { .. } => boxes.push(BvhBox {
+                bounding_box: bounds,
+                depth,
+                leaf: true,
+                end: boxes.len() + 1,
+            }),
+        }
+    }
+}
+
+impl Hittable for KdTree {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        let span = self.bounding_box.hit(ray)?;
+        let (mut t_min, mut t_max) = (span.min.max(ray_trange.min), span.max.min(ray_trange.max));
+        if self.nodes.is_empty() || t_min > t_max {
+            return None;
+        }
+        // the far sides of the splits passed through, with the part of the ray within them
+        let mut stack = [(0, 0.0, 0.0); MAX_DEPTH];
+        let mut stack_size = 0;
+        let mut index = 0;
+        let mut closest_so_far = ray_trange.max;
+        let mut result = None;
+        loop {
+            match self.nodes[index] {
+                KdNode::Split {
+                    axis,
+                    position,
+                    above,
+                } => {
+                    telemetry::count(Counter::NodeVisits);
+                    let (origin, direction) = (ray.origin[axis], ray.direction[axis]);
+                    // NaN for rays in the plane, which only pass through the side they start on
+                    let t_split = (position - origin) / direction;
+                    let below_first = origin < position || (origin == position && direction <= 0.0);
+                    let (near, far) = if below_first {
+                        (index + 1, above as usize)
+                    } else {
+                        (above as usize, index + 1)
+                    };
+                    if !(t_split <= t_max && t_split > 0.0) {
+                        index = near;
+                    } else if t_split < t_min {
+                        index = far;
+                    } else {
+                        stack[stack_size] = (far, t_split, t_max);
+                        stack_size += 1;
+                        index = near;
+                        t_max = t_split;
+                    }
+                }
+                KdNode::Leaf { first, count } => {
+                    let first = first as usize;
+                    for &object in &self.leaf_objects[first..first + count as usize] {
+                        telemetry::count(Counter::PrimitiveTests);
+                        let object = &self.objects[object as usize];
+                        if let Some(hit_record) =
+                            object.hit(ray, &ray_trange.with_max(closest_so_far))
+                        {
+                            closest_so_far = hit_record.t;
+                            result = Some(hit_record);
+                        }
+                    }
+                    // objects crossing into the cells behind can be hit there, so the walk goes
+                    // on until the next cell starts behind the closest hit
+                    if stack_size == 0 {
+                        return result;
+                    }
+                    stack_size -= 1;
+                    (index, t_min, t_max) = stack[stack_size];
+                    if closest_so_far < t_min {
+                        return result;
+                    }
+                }
+            }
+        }
+    }
+
+    fn bounding_box(&self) -> &AABB {
+        &self.bounding_box
+    }
+
+    fn bvh_boxes(&self, depth: usize, boxes: &mut Vec<BvhBox>) {
+        if !self.nodes.is_empty() {
+            self.node_bvh_boxes(0, self.bounding_box.clone(), depth, boxes);
+        }
+    }
+
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        for object in &self.objects {
+            object.lights(lights);
+        }
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.memory += std::mem::size_of_val(self)
+            + self.nodes.capacity() * std::mem::size_of::<KdNode>()
+            + self.leaf_objects.capacity() * std::mem::size_of::<u32>()
+            + self.objects.capacity() * std::mem::size_of::<Box<dyn Hittable>>();
+        for object in &self.objects {
+            object.report(report);
+        }
+    }
+}
+
+fn axis_mut(bounding_box: &mut AABB, axis: usize) -> &mut Interval {
+    match axis {
+        1 => &mut bounding_box.y,
+        2 => &mut bounding_box.z,
+        _ => &mut bounding_box.x,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        color::Color,
+        float::INFINITY,
+        hittable::{
+            containers::{tests::assert_hits_match_the_bvh, Accelerator, HittableList},
+            geometry::Sphere,
+            materials::Lambertian,
+        },
+        random::{RandomSource, Rng},
+        vec3::{Point3, Vec3},
+    };
+
+    fn field() -> HittableList {
+        let mut rng = Rng::from_seed([13, 17]);
+        let material = Arc::new(Lambertian::from(Color::gray(0.5)));
+        let mut world = HittableList::default();
+        for _ in 0..300 {
+            let center = 4.0 * Vec3::random_in_unit_sphere(&mut rng);
+            let radius = rng.next_float_range(0.05..0.6);
+            world.add(Box::new(Sphere::new(center, radius, material.clone())));
+        }
+        world
+    }
+
+    #[test]
+    fn hits_match_the_bvh() {
+        assert_hits_match_the_bvh(field, Accelerator::KdTree);
+        let kd_tree = KdTree::new(field().objects);
+        assert!(kd_tree.nodes.len() > 1);
+        let bvh = field().into_bvh();
+        let range = Interval::new(0.001, INFINITY);
+        // a ray in the plane of the first split
+        let KdNode::Split { axis, position, .. } = kd_tree.nodes[0] else {
+            panic!("the root should be split");
+        };
+        let (mut origin, mut direction) = ([0.0; 3], [0.0; 3]);
+        origin[axis] = position;
+        origin[(axis + 1) % 3] = -10.0;
+        direction[(axis + 1) % 3] = 1.0;
+        let ray = Ray::new(
+            Point3::new(origin[0], origin[1], origin[2]),
+            Vec3::new(direction[0], direction[1], direction[2]),
+            0.0,
+        );
+        assert_eq!(
+            bvh.hit(&ray, &range).map(|r| r.object_id),
+            kd_tree.hit(&ray, &range).map(|r| r.object_id)
+        );
+    }
+}
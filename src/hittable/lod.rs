@@ -0,0 +1,182 @@
+use std::sync::Arc;
+
+use crate::{
+    float::{Float, INFINITY, NEG_INFINITY},
+    interval::Interval,
+    random::RandomSource,
+    ray::Ray,
+    vec3::{Point3, Vec3},
+};
+
+use super::{
+    aabb::AABB, geometry::Sphere, materials::Material, report::SceneReport, HitRecord, Hittable,
+};
+
+// Versions of an object in decreasing detail, with the one a ray sees picked by how far its origin
+// is from the object. Distant objects cover few pixels, so a simplified version or just a sphere in
+// the same material looks the same there at a fraction of the cost, which keeps scenes with many
+// detailed objects tractable. Every bounce picks again, so reflections up close see the full
+// object too.
+#[derive(Debug)]
+pub struct LevelOfDetail {
+    // the levels from the most detailed, each used from its distance on
+    levels: Vec<(Float, Box<dyn Hittable>)>,
+    center: Point3,
+    // the radius of the sphere around the bounding box of the most detailed level
+    radius: Float,
+    bounding_box: AABB,
+}
+
+impl LevelOfDetail {
+    pub fn new(object: Box<dyn Hittable>) -> Self {
+        let bounding_box = object.bounding_box().clone();
+        let center = bounding_box.centroid();
+        let half_size = Vec3::new(
+            bounding_box.x.size(),
+            bounding_box.y.size(),
+            bounding_box.z.size(),
+        ) / 2.0;
+        Self {
+            levels: vec![(0.0, object)],
+            center,
+            radius: half_size.length(),
+            bounding_box,
+        }
+    }
+    // Adds a level used from `distance` on, measured from the center of the object.
+    pub fn level(mut self, distance: Float, object: Box<dyn Hittable>) -> Self {
+        self.bounding_box.grow(object.bounding_box());
+        let index = self.levels.partition_point(|(from, _)| *from <= distance);
+        self.levels.insert(index, (distance, object));
+        self
+    }
+    // Adds a level used where the object looks smaller than `coverage`, its angular radius in
+    // radians as seen from the ray's origin. A coverage of 0.005 is about twelve pixels across with
+    // a 60° field of view on a 1280 pixel wide image.
+    pub fn level_by_coverage(self, coverage: Float, object: Box<dyn Hittable>) -> Self {
+        let distance = self.radius / coverage.tan();
+        self.level(distance, object)
+    }
+    // Adds the sphere around the object as a level from `distance` on, in the object's material.
+    pub fn proxy_sphere(self, distance: Float, material: Arc<dyn Material>) -> Self {
+        let sphere = Sphere::new(self.center, self.radius, material);
+        self.level(distance, Box::new(sphere))
+    }
+    fn select(&self, origin: Point3) -> &dyn Hittable {
+        let distance = (self.center - origin).length();
+        let index = self.levels.partition_point(|(from, _)| *from <= distance);
+        self.levels[index.saturating_sub(1)].1.as_ref()
+    }
+}
+
+impl Hittable for LevelOfDetail {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        self.select(ray.origin).hit(ray, ray_trange)
+    }
+    fn bounding_box(&self) -> &AABB {
+        &self.bounding_box
+    }
+    // The lights of every level, each lighting only the points its level is picked from.
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        for (index, (from, level)) in self.levels.iter().enumerate() {
+            // the first level is also picked closer than its distance, see `select`
+            let from = if index == 0 { NEG_INFINITY } else { *from };
+            let to = self.levels.get(index + 1).map_or(INFINITY, |(to, _)| *to);
+            let mut found = Vec::new();
+            level.lights(&mut found);
+            for (id, light) in found {
+                let light = LevelLight {
+                    light,
+                    center: self.center,
+                    distances: Interval::new(from, to),
+                };
+                lights.push((id, Arc::new(light)));
+            }
+        }
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.objects("level of detail", 1, std::mem::size_of_val(self));
+        for (_, level) in &self.levels {
+            level.report(report);
+        }
+    }
+}
+
+// A light of one of the levels, which can only be seen from as far from the center of the object
+// as the level is picked. Points drawn on it from elsewhere bring no light and directions can't be
+// drawn towards it, so the densities of the lights that can be seen still add up right.
+#[derive(Debug)]
+struct LevelLight {
+    light: Arc<dyn Hittable>,
+    center: Point3,
+    distances: Interval,
+}
+
+impl LevelLight {
+    fn seen_from(&self, origin: &Point3) -> bool {
+        let distance = (self.center - *origin).length();
+        self.distances.min <= distance && distance < self.distances.max
+    }
+}
+
+impl Hittable for LevelLight {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        self.seen_from(&ray.origin)
+            .then(|| self.light.hit(ray, ray_trange))?
+    }
+    fn bounding_box(&self) -> &AABB {
+        self.light.bounding_box()
+    }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        match self.seen_from(origin) {
+            true => self.light.pdf_value(origin, direction),
+            false => 0.0,
+        }
+    }
+    fn random(&self, origin: &Point3, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        self.seen_from(origin)
+            .then(|| self.light.random(origin, rng))?
+    }
+    fn sample_point(&self, rng: &mut dyn RandomSource) -> Option<(Point3, Vec3, Float)> {
+        self.light.sample_point(rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color::Color,
+        hittable::{containers::HittableList, materials::Lambertian},
+    };
+
+    #[test]
+    fn far_rays_see_the_proxy() {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::from(Color::gray(0.5)));
+        // two small spheres with a gap between them
+        let mut cluster = HittableList::default();
+        for x in [-1.0, 1.0] {
+            cluster.add(Box::new(Sphere::new(
+                Point3::new(x, 0.0, 0.0),
+                0.5,
+                material.clone(),
+            )));
+        }
+        let lod = LevelOfDetail::new(cluster.into_bvh()).proxy_sphere(50.0, material);
+        let range = Interval::new(0.001, Float::INFINITY);
+        let through_gap =
+            |z: Float| Ray::new(Point3::new(0.0, 0.0, z), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        assert!(lod.hit(&through_gap(10.0), &range).is_none());
+        let far = lod.hit(&through_gap(100.0), &range).unwrap();
+        assert!((far.t - (100.0 - lod.radius)).abs() < 1e-3);
+
+        let coverage = LevelOfDetail::new(Box::new(Sphere::new(
+            Point3::zero(),
+            1.0,
+            Arc::new(Lambertian::from(Color::white())),
+        )))
+        .level_by_coverage(0.01, Box::new(HittableList::default()));
+        assert!(coverage.hit(&through_gap(10.0), &range).is_some());
+        assert!(coverage.hit(&through_gap(1000.0), &range).is_none());
+    }
+}
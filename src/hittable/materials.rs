@@ -1,18 +1,25 @@
-use std::{
-    fmt::Debug,
-    ops::{Neg, Range},
-    sync::Arc,
-};
+use std::{fmt::Debug, ops::Neg, sync::Arc};
 
 use super::{
+    compound_id,
+    pdf::{CosinePdf, Pdf},
+    report::SceneReport,
     texture::{SolidColor, Texture},
     HitRecord,
 };
-use crate::{color::Color, random::Rng, ray::Ray, vec3::Vec3};
+use crate::{
+    color::Color,
+    float::Float,
+    random::RandomSource,
+    ray::Ray,
+    units::Units,
+    vec3::{Onb, Vec3},
+};
 
 #[derive(Debug)]
 pub struct Lambertian {
     pub albedo: Arc<dyn Texture>,
+    id: u32,
 }
 
 impl Lambertian {
@@ -21,18 +28,61 @@ impl Lambertian {
     }
 }
 
+// the default methods ignore what they are given
+#[allow(unused_variables)]
 pub trait Material: Sync + Send + Debug {
-    fn scatter(&self, rng: &mut Rng, ray: &Ray, hit_record: &HitRecord) -> Option<(Color, Ray)>;
+    fn scatter(
+        &self,
+        rng: &mut dyn RandomSource,
+        ray: &Ray,
+        hit_record: &HitRecord,
+    ) -> Option<(Color, Ray)>;
+    // Like `scatter` but for following a path through nested media, `media` holds the media the
+    // ray is inside of. Materials that bound a medium update it as the ray enters and leaves them,
+    // the others scatter as usual.
+    fn scatter_through(
+        &self,
+        rng: &mut dyn RandomSource,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        media: &mut MediumStack,
+    ) -> Option<(Color, Ray)> {
+        self.scatter(rng, ray, hit_record)
+    }
+    // The density `scatter` draws `scattered` with, zero for materials that don't scatter at
+    // random, so directions drawn from another `Pdf` can be weighed against it.
+    fn scattering_pdf(&self, ray: &Ray, hit_record: &HitRecord, scattered: &Ray) -> Float {
+        0.0
+    }
+    // The surface color shown in the albedo AOV.
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        Color::white()
+    }
+    // The light given off where a path hits the surface. `lit_object` is the object the path
+    // bounced off before reaching it, or `None` for rays from the camera.
+    fn emitted(&self, hit_record: &HitRecord, lit_object: Option<u32>) -> Color {
+        Color::black()
+    }
+    // Whether `emitted` can give off any light, for finding the lights of a scene.
+    fn is_emissive(&self) -> bool {
+        false
+    }
+    // Adds the textures of the material to `report`, and says whether it gives off light.
+    fn report(&self, report: &mut SceneReport) {}
+    // The ID of the material in the material ID AOV, made from its settings when the material is
+    // made like the IDs of objects.
+    fn id(&self) -> u32;
 }
 
 impl Material for Lambertian {
-    fn scatter(&self, rng: &mut Rng, ray: &Ray, hit_record: &HitRecord) -> Option<(Color, Ray)> {
-        let scatter_direction = hit_record.normal + Vec3::random_on_unit_sphere(rng);
-        let scatter_direction = if scatter_direction.near_zero() {
-            hit_record.normal
-        } else {
-            scatter_direction
-        };
+    fn scatter(
+        &self,
+        rng: &mut dyn RandomSource,
+        ray: &Ray,
+        hit_record: &HitRecord,
+    ) -> Option<(Color, Ray)> {
+        let scatter_direction =
+            Onb::new(&hit_record.normal).transform(&Vec3::random_cosine_direction(rng));
         let scattered_ray = Ray::new(hit_record.point, scatter_direction, ray.time);
         return Some((
             self.albedo
@@ -40,30 +90,49 @@ impl Material for Lambertian {
             scattered_ray,
         ));
     }
+    fn scattering_pdf(&self, _ray: &Ray, hit_record: &HitRecord, scattered: &Ray) -> Float {
+        CosinePdf::new(&hit_record.normal).value(&scattered.direction)
+    }
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        self.albedo
+            .value(hit_record.u, hit_record.v, &hit_record.point)
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.texture(&self.albedo);
+    }
+    fn id(&self) -> u32 {
+        self.id
+    }
 }
 
 impl From<Color> for Lambertian {
     fn from(value: Color) -> Self {
-        Self {
-            albedo: Arc::new(SolidColor::from(value)),
-        }
+        Self::from(Arc::new(SolidColor::from(value)) as Arc<dyn Texture>)
     }
 }
 impl From<Arc<dyn Texture>> for Lambertian {
     fn from(value: Arc<dyn Texture>) -> Self {
-        Self { albedo: value }
+        Self {
+            id: compound_id("lambertian", &[], &[value.id()]),
+            albedo: value,
+        }
     }
 }
 
 #[derive(Debug)]
 pub struct Metal {
     pub(crate) albedo: Color,
-    pub(crate) fuzz: f64,
+    pub(crate) fuzz: Float,
+    id: u32,
 }
 
 impl Metal {
-    pub fn new(albedo: Color, fuzz: f64) -> Self {
-        Self { albedo, fuzz }
+    pub fn new(albedo: Color, fuzz: Float) -> Self {
+        Self {
+            albedo,
+            fuzz,
+            id: compound_id("metal", &[albedo.r, albedo.g, albedo.b, fuzz], &[]),
+        }
     }
     pub fn into_arc(self) -> Arc<Self> {
         Arc::new(self)
@@ -71,12 +140,23 @@ impl Metal {
 }
 
 impl Material for Metal {
-    fn scatter(&self, rng: &mut Rng, ray: &Ray, hit_record: &HitRecord) -> Option<(Color, Ray)> {
+    fn scatter(
+        &self,
+        rng: &mut dyn RandomSource,
+        ray: &Ray,
+        hit_record: &HitRecord,
+    ) -> Option<(Color, Ray)> {
         let reflected = ray.direction.reflect(&hit_record.normal);
         let scatter_direction = reflected + self.fuzz * Vec3::random_on_unit_sphere(rng);
         let scattered_ray = Ray::new(hit_record.point, scatter_direction, ray.time);
         return Some((self.albedo, scattered_ray));
     }
+    fn albedo(&self, _hit_record: &HitRecord) -> Color {
+        self.albedo
+    }
+    fn id(&self) -> u32 {
+        self.id
+    }
 }
 
 impl From<Color> for Metal {
@@ -85,28 +165,85 @@ impl From<Color> for Metal {
     }
 }
 
+// Glass and other clear media. Where dielectrics overlap, like liquid in a glass, the one with the
+// highest priority decides the medium and the surfaces of the others inside it are ignored. A
+// hollow object is one material used for both an outer sphere and an inner sphere with a negative
+// radius.
 #[derive(Debug)]
 pub struct Dielectric {
-    pub(crate) index_of_refraction: f64,
+    pub(crate) index_of_refraction: Float,
+    pub(crate) priority: u32,
+    id: u32,
 }
 
 impl Dielectric {
-    pub fn new(index_of_refraction: f64) -> Self {
+    pub fn new(index_of_refraction: Float) -> Self {
         Self {
             index_of_refraction,
+            priority: 0,
+            id: Self::id_for(index_of_refraction, 0),
+        }
+    }
+    pub fn with_priority(self, priority: u32) -> Self {
+        Self {
+            priority,
+            id: Self::id_for(self.index_of_refraction, priority),
+            ..self
         }
     }
+    fn id_for(index_of_refraction: Float, priority: u32) -> u32 {
+        compound_id("dielectric", &[index_of_refraction], &[priority])
+    }
     pub fn into_arc(self) -> Arc<Self> {
         Arc::new(self)
     }
 }
 
 impl Material for Dielectric {
-    fn scatter(&self, rng: &mut Rng, ray: &Ray, hit_record: &HitRecord) -> Option<(Color, Ray)> {
-        let refraction_ratio = if hit_record.front_face {
-            1.0 / self.index_of_refraction
+    fn scatter(
+        &self,
+        rng: &mut dyn RandomSource,
+        ray: &Ray,
+        hit_record: &HitRecord,
+    ) -> Option<(Color, Ray)> {
+        // on its own the dielectric is surrounded by air
+        self.scatter_through(rng, ray, hit_record, &mut MediumStack::default())
+    }
+
+    fn scatter_through(
+        &self,
+        rng: &mut dyn RandomSource,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        media: &mut MediumStack,
+    ) -> Option<(Color, Ray)> {
+        let medium = Medium {
+            id: self as *const Self as usize,
+            index_of_refraction: self.index_of_refraction,
+            priority: self.priority,
+        };
+        let current = media.current();
+        let outside = media.without(medium.id);
+        let (refraction_ratio, entering) = if hit_record.front_face {
+            match current {
+                // a surface inside a medium that takes priority isn't there
+                Some(current) if current.priority > medium.priority => {
+                    media.push(medium);
+                    return Some((Color::white(), pass_through(ray, hit_record)));
+                }
+                _ => (media.index_of_refraction() / self.index_of_refraction, true),
+            }
         } else {
-            self.index_of_refraction
+            match current {
+                Some(current) if current.id != medium.id && media.contains(medium.id) => {
+                    media.remove(medium.id);
+                    return Some((Color::white(), pass_through(ray, hit_record)));
+                }
+                _ => (
+                    self.index_of_refraction / outside.index_of_refraction(),
+                    false,
+                ),
+            }
         };
 
         let unit_direction = ray.direction.unit_vector();
@@ -116,9 +253,14 @@ impl Material for Dielectric {
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
 
         let direction =
-            if cannot_refract || reflectance(cos_theta, refraction_ratio) > rng.next_f64() {
+            if cannot_refract || reflectance(cos_theta, refraction_ratio) > rng.next_float() {
                 unit_direction.reflect(&hit_record.normal)
             } else {
+                if entering {
+                    media.push(medium);
+                } else {
+                    *media = outside;
+                }
                 refract(&unit_direction, &hit_record.normal, refraction_ratio)
             };
 
@@ -126,16 +268,358 @@ impl Material for Dielectric {
 
         return Some((Color::white(), scattered));
     }
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+// Makes parts of a surface see-through, for cutout cards like leaves and fences and for decals.
+// Where `opacity` is below one the ray goes through the surface unchanged with that probability,
+// otherwise `material` scatters it. The opacity is read from the red channel of the texture, use
+// `ImageTexture::alpha` for masks stored in the alpha channel of an image.
+#[derive(Debug)]
+pub struct Cutout {
+    material: Arc<dyn Material>,
+    opacity: Arc<dyn Texture>,
+    id: u32,
+}
+
+impl Cutout {
+    pub fn new(material: Arc<dyn Material>, opacity: Arc<dyn Texture>) -> Self {
+        Self {
+            id: compound_id("cutout", &[], &[material.id(), opacity.id()]),
+            material,
+            opacity,
+        }
+    }
+    pub fn into_arc(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+    fn transparent(&self, rng: &mut dyn RandomSource, hit_record: &HitRecord) -> bool {
+        let opacity = self
+            .opacity
+            .value(hit_record.u, hit_record.v, &hit_record.point)
+            .r;
+        opacity < 1.0 && rng.next_float() >= opacity
+    }
+}
+
+impl Material for Cutout {
+    fn scatter(
+        &self,
+        rng: &mut dyn RandomSource,
+        ray: &Ray,
+        hit_record: &HitRecord,
+    ) -> Option<(Color, Ray)> {
+        if self.transparent(rng, hit_record) {
+            return Some((Color::white(), pass_through(ray, hit_record)));
+        }
+        self.material.scatter(rng, ray, hit_record)
+    }
+    fn scatter_through(
+        &self,
+        rng: &mut dyn RandomSource,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        media: &mut MediumStack,
+    ) -> Option<(Color, Ray)> {
+        if self.transparent(rng, hit_record) {
+            return Some((Color::white(), pass_through(ray, hit_record)));
+        }
+        self.material.scatter_through(rng, ray, hit_record, media)
+    }
+    fn scattering_pdf(&self, ray: &Ray, hit_record: &HitRecord, scattered: &Ray) -> Float {
+        self.material.scattering_pdf(ray, hit_record, scattered)
+    }
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        self.material.albedo(hit_record)
+    }
+    fn emitted(&self, hit_record: &HitRecord, lit_object: Option<u32>) -> Color {
+        self.material.emitted(hit_record, lit_object)
+    }
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.material(&self.material);
+        report.texture(&self.opacity);
+    }
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+// Which objects a light shines on, by the object IDs of their hit records. The camera sees every
+// light no matter its links, they only decide which surfaces it lights up.
+#[derive(Debug, Clone, Default)]
+pub enum LightLinks {
+    #[default]
+    All,
+    Only(Vec<u32>),
+    Except(Vec<u32>),
+}
+
+impl LightLinks {
+    pub fn illuminates(&self, object_id: u32) -> bool {
+        match self {
+            LightLinks::All => true,
+            LightLinks::Only(ids) => ids.contains(&object_id),
+            LightLinks::Except(ids) => !ids.contains(&object_id),
+        }
+    }
+}
+
+// A surface that gives off light from its front side and absorbs whatever hits it.
+#[derive(Debug)]
+pub struct DiffuseLight {
+    emit: Arc<dyn Texture>,
+    links: LightLinks,
+    id: u32,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Arc<dyn Texture>) -> Self {
+        Self {
+            id: compound_id("diffuse_light", &[], &[emit.id()]),
+            emit,
+            links: LightLinks::All,
+        }
+    }
+    // A light of `area` square units of a scene in `units` that puts out `power` watts, so it lights
+    // the scene the same whatever units it's modelled in.
+    pub fn from_power(power: Color, area: Float, units: Units) -> Self {
+        Self::from(units.radiance(power, area))
+    }
+    pub fn with_links(self, links: LightLinks) -> Self {
+        Self { links, ..self }
+    }
+    pub fn into_arc(self) -> Arc<Self> {
+        Arc::new(self)
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(
+        &self,
+        _rng: &mut dyn RandomSource,
+        _ray: &Ray,
+        _hit_record: &HitRecord,
+    ) -> Option<(Color, Ray)> {
+        None
+    }
+    fn albedo(&self, _hit_record: &HitRecord) -> Color {
+        Color::black()
+    }
+    fn emitted(&self, hit_record: &HitRecord, lit_object: Option<u32>) -> Color {
+        if !hit_record.front_face || !lit_object.is_none_or(|id| self.links.illuminates(id)) {
+            return Color::black();
+        }
+        self.emit
+            .value(hit_record.u, hit_record.v, &hit_record.point)
+    }
+    fn is_emissive(&self) -> bool {
+        true
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.emissive();
+        report.texture(&self.emit);
+    }
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl From<Color> for DiffuseLight {
+    fn from(value: Color) -> Self {
+        Self::new(Arc::new(SolidColor::from(value)))
+    }
+}
+
+fn pass_through(ray: &Ray, hit_record: &HitRecord) -> Ray {
+    Ray::new(hit_record.point, ray.direction, ray.time)
+}
+
+// The dielectric media a path is inside of, innermost last. Paths are rarely inside more than a
+// couple at once, so they are kept inline and any beyond `MAX_MEDIA` are forgotten.
+const MAX_MEDIA: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MediumStack {
+    media: [Medium; MAX_MEDIA],
+    len: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Medium {
+    // the address of the material, shared by the surfaces of one object
+    id: usize,
+    index_of_refraction: Float,
+    priority: u32,
+}
+
+impl MediumStack {
+    // The medium with the highest priority, the innermost one on ties.
+    fn current(&self) -> Option<Medium> {
+        self.media[..self.len]
+            .iter()
+            .max_by_key(|medium| medium.priority)
+            .copied()
+    }
+    fn index_of_refraction(&self) -> Float {
+        self.current()
+            .map_or(1.0, |medium| medium.index_of_refraction)
+    }
+    fn contains(&self, id: usize) -> bool {
+        self.media[..self.len].iter().any(|medium| medium.id == id)
+    }
+    fn push(&mut self, medium: Medium) {
+        if self.len < MAX_MEDIA {
+            self.media[self.len] = medium;
+            self.len += 1;
+        }
+    }
+    // Removes the innermost entry of the medium.
+    fn remove(&mut self, id: usize) {
+        if let Some(index) = self.media[..self.len].iter().rposition(|m| m.id == id) {
+            self.media.copy_within(index + 1..self.len, index);
+            self.len -= 1;
+        }
+    }
+    fn without(&self, id: usize) -> Self {
+        let mut media = *self;
+        media.remove(id);
+        media
+    }
 }
 
-pub(crate) fn refract(uv: &Vec3, n: &Vec3, etai_over_etat: f64) -> Vec3 {
+pub(crate) fn refract(uv: &Vec3, n: &Vec3, etai_over_etat: Float) -> Vec3 {
     let cos_theta = (-(*uv)).dot(n).min(1.0);
     let r_out_perp = etai_over_etat * (*uv + cos_theta * *n);
     let r_out_parallel = (1.0 - r_out_perp.length_squared()).abs().sqrt().neg() * *n;
     return r_out_perp + r_out_parallel;
 }
 
-pub(crate) fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
+pub(crate) fn reflectance(cosine: Float, ref_idx: Float) -> Float {
     let r0 = ((1.0 - ref_idx) / (1.0 + ref_idx)).powi(2);
     return r0 + (1.0 - r0) * (1.0 - cosine).powi(5);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{random::Rng, vec3::Point3};
+
+    // Always draws the largest number, so dielectrics refract whenever they can.
+    struct Largest;
+
+    impl RandomSource for Largest {
+        fn next_u64(&mut self) -> u64 {
+            u64::MAX
+        }
+    }
+
+    fn hit(material: &Arc<dyn Material>, front_face: bool) -> HitRecord {
+        HitRecord {
+            point: Point3::zero(),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            material: material.clone(),
+            t: 1.0,
+            u: 0.0,
+            v: 0.0,
+            front_face,
+            object_id: 1,
+            motion: Vec3::zero(),
+            shading_offset: Vec3::zero(),
+            temperature: 0.0,
+        }
+    }
+
+    #[test]
+    fn liquid_in_glass_refracts_between_them() {
+        let glass: Arc<dyn Material> = Arc::new(Dielectric::new(1.5).with_priority(1));
+        let liquid: Arc<dyn Material> = Arc::new(Dielectric::new(1.33));
+        let ray = Ray::new(Point3::new(-1.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0), 0.0);
+        let unit_direction = ray.direction.unit_vector();
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        let mut media = MediumStack::default();
+        let mut scatter = |material: &Arc<dyn Material>, front_face: bool| {
+            let record = hit(material, front_face);
+            let (_, scattered) = material
+                .scatter_through(&mut Largest, &ray, &record, &mut media)
+                .unwrap();
+            scattered.direction.unit_vector()
+        };
+        let close = |a: Vec3, b: Vec3| (a - b).length() < 1e-4;
+
+        let into_glass = scatter(&glass, true);
+        assert!(close(
+            into_glass,
+            refract(&unit_direction, &normal, 1.0 / 1.5)
+        ));
+        // the surface of the liquid inside the glass wall is hidden by the glass
+        assert!(close(scatter(&liquid, true), unit_direction));
+        let into_liquid = scatter(&glass, false);
+        assert!(close(
+            into_liquid,
+            refract(&unit_direction, &normal, 1.5 / 1.33)
+        ));
+        let into_air = scatter(&liquid, false);
+        assert!(close(into_air, refract(&unit_direction, &normal, 1.33)));
+    }
+
+    #[test]
+    fn cutouts_let_rays_through_where_transparent() {
+        let surface: Arc<dyn Material> = Arc::new(Lambertian::from(Color::gray(0.5)));
+        let cutout = |opacity: Float| -> Arc<dyn Material> {
+            let opacity = Arc::new(SolidColor::from(Color::gray(opacity)));
+            Arc::new(Cutout::new(surface.clone(), opacity))
+        };
+        let ray = Ray::new(Point3::new(0.0, 1.0, 0.0), Vec3::new(1.0, -1.0, 0.0), 0.0);
+        let mut rng = Rng::from_seed([1, 2]);
+        for (opacity, passes) in [(0.0, true), (1.0, false)] {
+            let material = cutout(opacity);
+            let record = hit(&material, true);
+            let (attenuation, scattered) = material.scatter(&mut rng, &ray, &record).unwrap();
+            assert_eq!(
+                (scattered.direction - ray.direction).length() == 0.0,
+                passes
+            );
+            assert_eq!(attenuation.r, if passes { 1.0 } else { 0.5 });
+        }
+    }
+
+    #[test]
+    fn linked_lights_only_light_their_objects() {
+        let light = |links: LightLinks| -> Arc<dyn Material> {
+            Arc::new(DiffuseLight::from(Color::white()).with_links(links))
+        };
+        let lit = |material: &Arc<dyn Material>, lit_object: Option<u32>| {
+            material.emitted(&hit(material, true), lit_object).r
+        };
+        let fill = light(LightLinks::Only(vec![2]));
+        assert_eq!(lit(&fill, Some(2)), 1.0);
+        assert_eq!(lit(&fill, Some(3)), 0.0);
+        // the camera sees the light even though it lights nothing of the background
+        assert_eq!(lit(&fill, None), 1.0);
+        let rim = light(LightLinks::Except(vec![2]));
+        assert_eq!(lit(&rim, Some(2)), 0.0);
+        assert_eq!(lit(&rim, Some(3)), 1.0);
+        assert_eq!(fill.emitted(&hit(&fill, false), None).r, 0.0);
+    }
+
+    #[test]
+    fn materials_are_identified_by_their_settings() {
+        let gray = Lambertian::from(Color::gray(0.5)).id();
+        assert_eq!(gray, Lambertian::from(Color::gray(0.5)).id());
+        assert_ne!(gray, Lambertian::from(Color::gray(0.6)).id());
+        assert_ne!(gray, Metal::from(Color::gray(0.5)).id());
+        assert_ne!(gray, DiffuseLight::from(Color::gray(0.5)).id());
+        let glass = Dielectric::new(1.5);
+        assert_ne!(glass.id(), Dielectric::new(1.5).with_priority(1).id());
+        let surface: Arc<dyn Material> = Arc::new(glass);
+        let opacity: Arc<dyn Texture> = Arc::new(SolidColor::from(Color::gray(0.5)));
+        let cutout = Cutout::new(surface.clone(), opacity.clone()).id();
+        assert_eq!(cutout, Cutout::new(surface, opacity).id());
+        assert_ne!(cutout, Dielectric::new(1.5).id());
+    }
+}
@@ -0,0 +1,744 @@
+use std::sync::Arc;
+
+use crate::{
+    error::{Error, Result},
+    float::{Float, INFINITY},
+    interval::Interval,
+    random::RandomSource,
+    ray::Ray,
+    vec3::{Point3, Vec3},
+};
+
+use super::{
+    aabb::AABB,
+    containers::{BvhBox, QBVH},
+    materials::Material,
+    report::SceneReport,
+    stable_id, HitRecord, Hittable,
+};
+
+// Faces are smoothed with the faces around them that are at most this many degrees off by
+// `Mesh::load_obj`, see `MeshData::smooth_normals`.
+pub const SMOOTHING_ANGLE: Float = 30.0;
+
+// A triangle of a mesh, as indices into the vertex lists of its `MeshData`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Face {
+    pub positions: [usize; 3],
+    // normals for the corners, the face is shaded flat without them
+    pub normals: Option<[usize; 3]>,
+    // texture coordinates for the corners in the UV set of the material of the face
+    pub uvs: Option<[usize; 3]>,
+    // the index of the material of the face in `MeshData::materials`
+    pub material: usize,
+    // The OBJ smoothing group of the face, which `MeshData::smooth_normals` only smooths it with.
+    // `None` for faces that are shaded flat, faces before the first `s` line of a file are in
+    // group zero.
+    pub smoothing_group: Option<u32>,
+}
+
+impl Face {
+    pub fn new(positions: [usize; 3]) -> Self {
+        Self {
+            positions,
+            normals: None,
+            uvs: None,
+            material: 0,
+            smoothing_group: Some(0),
+        }
+    }
+}
+
+// A material of a mesh, named as in the file it came from, and the set of texture coordinates its
+// textures are mapped with.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MaterialSlot {
+    pub name: String,
+    pub uv_set: usize,
+}
+
+// The vertices and faces of a triangle mesh as loaded from an OBJ file or made in code, before it
+// is built into a `Mesh`.
+#[derive(Debug, Clone, Default)]
+pub struct MeshData {
+    pub positions: Vec<Point3>,
+    pub normals: Vec<Vec3>,
+    // Sets of texture coordinates that the `uvs` of the faces index alike. OBJ files have one,
+    // more can be added for materials that map their textures differently.
+    pub uv_sets: Vec<Vec<(Float, Float)>>,
+    pub faces: Vec<Face>,
+    // One for every `usemtl` of an OBJ file, and one named "" for faces before the first, so a
+    // mesh with several materials stays one object, see `Mesh::new`.
+    pub materials: Vec<MaterialSlot>,
+}
+
+impl MeshData {
+    pub fn load_obj(path: &str) -> Result<Self> {
+        Self::parse_obj(&std::fs::read_to_string(path)?)
+    }
+    // Reads the vertices, texture coordinates, normals, faces, smoothing groups and material names
+    // of an OBJ file. Faces with more than three corners are split into a fan of triangles, and
+    // everything else, like groups and material libraries, is skipped.
+    pub fn parse_obj(text: &str) -> Result<Self> {
+        let mut data = MeshData::default();
+        let mut smoothing_group = Some(0);
+        let mut material = None;
+        for (number, line) in text.lines().enumerate() {
+            let invalid = || Error::InvalidMesh(format!("line {}: {}", number + 1, line));
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => {
+                    let [x, y, z] = numbers(tokens).ok_or_else(invalid)?;
+                    data.positions.push(Point3::new(x, y, z));
+                }
+                Some("vn") => {
+                    let [x, y, z] = numbers(tokens).ok_or_else(invalid)?;
+                    data.normals.push(Vec3::new(x, y, z));
+                }
+                Some("vt") => {
+                    let [u] = numbers(&mut tokens).ok_or_else(invalid)?;
+                    // the second coordinate is optional, for one dimensional textures
+                    let v = match tokens.next() {
+                        Some(v) => v.parse().map_err(|_| invalid())?,
+                        None => 0.0,
+                    };
+                    if data.uv_sets.is_empty() {
+                        data.uv_sets.push(Vec::new());
+                    }
+                    data.uv_sets[0].push((u, v));
+                }
+                Some("f") => {
+                    let corners = tokens
+                        .map(|token| data.corner(token))
+                        .collect::<Option<Vec<_>>>()
+                        .filter(|corners| corners.len() >= 3)
+                        .ok_or_else(invalid)?;
+                    let material = *material.get_or_insert_with(|| data.slot(""));
+                    for i in 1..corners.len() - 1 {
+                        let triangle = [corners[0], corners[i], corners[i + 1]];
+                        data.faces.push(Face {
+                            positions: triangle.map(|(position, _, _)| position),
+                            normals: all(triangle.map(|(_, _, normal)| normal)),
+                            uvs: all(triangle.map(|(_, uv, _)| uv)),
+                            material,
+                            smoothing_group,
+                        });
+                    }
+                }
+                Some("s") => {
+                    smoothing_group = match tokens.next().ok_or_else(invalid)? {
+                        "off" | "0" => None,
+                        group => Some(group.parse().map_err(|_| invalid())?),
+                    };
+                }
+                Some("usemtl") => {
+                    material = Some(data.slot(tokens.next().ok_or_else(invalid)?));
+                }
+                _ => {}
+            }
+        }
+        Ok(data)
+    }
+    // The position, texture coordinate and normal indices of a corner of an OBJ face, from its
+    // `position`, `position/uv`, `position//normal` or `position/uv/normal`.
+    fn corner(&self, token: &str) -> Option<(usize, Option<usize>, Option<usize>)> {
+        let mut parts = token.split('/');
+        let position = obj_index(parts.next()?, self.positions.len())?;
+        let mut optional = |count| match parts.next() {
+            None | Some("") => Some(None),
+            Some(part) => obj_index(part, count).map(Some),
+        };
+        let uv = optional(self.uv_sets.first().map_or(0, Vec::len))?;
+        let normal = optional(self.normals.len())?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some((position, uv, normal))
+    }
+    // The index of the material slot named `name`, which is added if there is none yet.
+    fn slot(&mut self, name: &str) -> usize {
+        match self.materials.iter().position(|slot| slot.name == name) {
+            Some(index) => index,
+            None => {
+                self.materials.push(MaterialSlot {
+                    name: name.to_string(),
+                    uv_set: 0,
+                });
+                self.materials.len() - 1
+            }
+        }
+    }
+    // The texture coordinates the material of `face` is mapped with, the first set for faces
+    // without a slot.
+    fn uv_set(&self, face: &Face) -> &[(Float, Float)] {
+        let set = self
+            .materials
+            .get(face.material)
+            .map_or(0, |slot| slot.uv_set);
+        self.uv_sets.get(set).map_or(&[], Vec::as_slice)
+    }
+    // Gives the faces in a smoothing group that have no normals of their own normals at their
+    // corners, averaged from the faces around the corner in the same group and weighted by the
+    // angle of each face there. Faces more than `max_angle` degrees from the face being smoothed
+    // are left out, so the hard edges of a mesh stay hard. Faces with normals or without a group
+    // are left as they are.
+    pub fn smooth_normals(&mut self, max_angle: Float) {
+        let face_normals = self
+            .faces
+            .iter()
+            .map(|face| {
+                let [a, b, c] = face.positions.map(|index| self.positions[index]);
+                (b - a).cross(&(c - a)).unit_vector()
+            })
+            .collect::<Vec<_>>();
+        // the faces at each vertex and their angle there
+        let mut around = vec![Vec::new(); self.positions.len()];
+        for (index, face) in self.faces.iter().enumerate() {
+            for corner in 0..3 {
+                let at = self.positions[face.positions[corner]];
+                let to_next = self.positions[face.positions[(corner + 1) % 3]] - at;
+                let to_previous = self.positions[face.positions[(corner + 2) % 3]] - at;
+                let cosine = to_next.unit_vector().dot(&to_previous.unit_vector());
+                around[face.positions[corner]].push((index, cosine.clamp(-1.0, 1.0).acos()));
+            }
+        }
+        let min_cosine = max_angle.to_radians().cos();
+        for index in 0..self.faces.len() {
+            let face = self.faces[index];
+            let (None, Some(group)) = (face.normals, face.smoothing_group) else {
+                continue;
+            };
+            if !face_normals[index].is_finite() {
+                continue;
+            }
+            let corner_normals = face.positions.map(|vertex| {
+                let neighbours = around[vertex].iter().filter(|(other, _)| {
+                    self.faces[*other].smoothing_group == Some(group)
+                        && face_normals[*other].dot(&face_normals[index]) >= min_cosine
+                });
+                let sum = neighbours.fold(Vec3::zero(), |sum, (other, angle)| {
+                    sum + *angle * face_normals[*other]
+                });
+                self.normals.push(sum.unit_vector());
+                self.normals.len() - 1
+            });
+            self.faces[index].normals = Some(corner_normals);
+        }
+    }
+}
+
+// The index into a list of `count` items an OBJ index refers to. OBJ indices count from one, or
+// back from the last item so far when negative.
+fn obj_index(token: &str, count: usize) -> Option<usize> {
+    let index: isize = token.parse().ok()?;
+    let index = if index < 0 {
+        count as isize + index
+    } else {
+        index - 1
+    };
+    (index >= 0 && (index as usize) < count).then_some(index as usize)
+}
+
+// The first N numbers of an OBJ line.
+fn numbers<'a, const N: usize>(mut tokens: impl Iterator<Item = &'a str>) -> Option<[Float; N]> {
+    let mut numbers = [0.0; N];
+    for number in &mut numbers {
+        *number = tokens.next()?.parse().ok()?;
+    }
+    Some(numbers)
+}
+
+// The indices of all three corners, if they all have one.
+fn all(indices: [Option<usize>; 3]) -> Option<[usize; 3]> {
+    Some([indices[0]?, indices[1]?, indices[2]?])
+}
+
+// What the triangles of a mesh share.
+#[derive(Debug)]
+struct Surface {
+    data: MeshData,
+    materials: Vec<Arc<dyn Material>>,
+    id: u32,
+}
+
+impl Surface {
+    fn corners(&self, face: usize) -> [Point3; 3] {
+        self.data.faces[face]
+            .positions
+            .map(|index| self.data.positions[index])
+    }
+    fn area(&self, face: usize) -> Float {
+        let [a, b, c] = self.corners(face);
+        (b - a).cross(&(c - a)).length() / 2.0
+    }
+    // A point drawn evenly over `face` with its geometric normal, on the side hits find the front
+    // face of.
+    fn point_on(&self, face: usize, rng: &mut dyn RandomSource) -> (Point3, Vec3) {
+        let [a, b, c] = self.corners(face);
+        let (root, along) = (rng.next_float().sqrt(), rng.next_float());
+        let (u, v) = (root * (1.0 - along), root * along);
+        let point = a + u * (b - a) + v * (c - a);
+        (point, (b - a).cross(&(c - a)).unit_vector())
+    }
+}
+
+#[derive(Debug)]
+struct Triangle {
+    surface: Arc<Surface>,
+    face: usize,
+    bounding_box: AABB,
+}
+
+impl Hittable for Triangle {
+    // Möller and Trumbore's intersection, which finds the barycentric coordinates of the hit
+    // along with the distance.
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        let data = &self.surface.data;
+        let face = &data.faces[self.face];
+        let [a, b, c] = face.positions.map(|index| data.positions[index]);
+        let (edge1, edge2) = (b - a, c - a);
+        let p = ray.direction.cross(&edge2);
+        let determinant = edge1.dot(&p);
+        if determinant == 0.0 {
+            return None;
+        }
+        let inverse = 1.0 / determinant;
+        let from_a = ray.origin - a;
+        let u = from_a.dot(&p) * inverse;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = from_a.cross(&edge1);
+        let v = ray.direction.dot(&q) * inverse;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = edge2.dot(&q) * inverse;
+        if !ray_trange.surrounds(t) {
+            return None;
+        }
+        let w = 1.0 - u - v;
+        let geometric_normal = edge1.cross(&edge2);
+        let outward_normal = match face.normals {
+            Some(normals) => {
+                let [na, nb, nc] = normals.map(|index| data.normals[index]);
+                (w * na + u * nb + v * nc).unit_vector()
+            }
+            None => geometric_normal.unit_vector(),
+        };
+        let (tex_u, tex_v) = match face.uvs {
+            Some(uvs) => {
+                let uv_set = data.uv_set(face);
+                let [ta, tb, tc] = uvs.map(|index| uv_set[index]);
+                (
+                    w * ta.0 + u * tb.0 + v * tc.0,
+                    w * ta.1 + u * tb.1 + v * tc.1,
+                )
+            }
+            None => (u, v),
+        };
+        let front_face = ray.direction.dot(&geometric_normal) < 0.0;
+        let point = ray.at(t);
+        // Hanika's fix for the shadow terminator (Ray Tracing Gems II, ch. 4). The point is moved
+        // up onto the tangent plane of each corner it is below and the moves are blended like the
+        // normals, which lifts it onto the curved surface the normals describe. Rays leaving the
+        // flat triangle from there aren't shadowed by its neighbours where the normals say the
+        // surface is still lit, so low poly meshes don't get terminators in steps.
+        let shading_offset = match face.normals {
+            Some(normals) if front_face => {
+                let corners = [(a, normals[0], w), (b, normals[1], u), (c, normals[2], v)];
+                corners
+                    .into_iter()
+                    .fold(Vec3::zero(), |sum, (corner, normal, weight)| {
+                        let normal = data.normals[normal].unit_vector();
+                        let to_point = point - corner;
+                        let below = to_point.dot(&normal).min(0.0);
+                        sum + weight * (to_point - below * normal)
+                    })
+            }
+            _ => Vec3::zero(),
+        };
+        Some(HitRecord {
+            point,
+            normal: if front_face { 1. } else { -1. } * outward_normal,
+            material: self.surface.materials[face.material].clone(),
+            t,
+            u: tex_u,
+            v: tex_v,
+            front_face,
+            object_id: self.surface.id,
+            motion: Vec3::zero(),
+            shading_offset,
+            temperature: 0.0,
+        })
+    }
+    fn bounding_box(&self) -> &AABB {
+        &self.bounding_box
+    }
+    // The density of directions towards points drawn by `sample_point`, from either side.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        let ray = Ray::new(*origin, *direction, 0.0);
+        let Some(hit_record) = self.hit(&ray, &Interval::new(0.001, INFINITY)) else {
+            return 0.0;
+        };
+        let [a, b, c] = self.surface.corners(self.face);
+        let normal = (b - a).cross(&(c - a)).unit_vector();
+        let distance_squared = (hit_record.t * direction.length()).powi(2);
+        let cosine = normal.dot(&direction.unit_vector()).abs();
+        distance_squared / (cosine * self.surface.area(self.face))
+    }
+    fn random(&self, origin: &Point3, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        let (point, _, _) = self.sample_point(rng)?;
+        Some(point - *origin)
+    }
+    fn sample_point(&self, rng: &mut dyn RandomSource) -> Option<(Point3, Vec3, Float)> {
+        let area = self.surface.area(self.face);
+        if area <= 0.0 {
+            return None;
+        }
+        let (point, normal) = self.surface.point_on(self.face, rng);
+        Some((point, normal, 1.0 / area))
+    }
+}
+
+// A triangle mesh with a BVH of its own over its triangles, so it is one object in the BVH of the
+// scene. Faces with normals at their corners are shaded smooth, see `MeshData::smooth_normals`.
+#[derive(Debug)]
+pub struct Mesh {
+    tree: QBVH<Triangle>,
+    surface: Arc<Surface>,
+    // the area of the faces up to and including every face, for drawing points evenly over it
+    areas: Vec<Float>,
+}
+
+impl Mesh {
+    // Builds the mesh with the material of every slot of `data.materials` in `materials`, in the
+    // same order. Meshes made in code without slots can have faces of any material.
+    pub fn new(data: MeshData, materials: Vec<Arc<dyn Material>>) -> Result<Self> {
+        if data.faces.is_empty() {
+            return Err(Error::InvalidMesh(
+                "a mesh needs at least one face".to_string(),
+            ));
+        }
+        let in_range = |indices: Option<[usize; 3]>, count: usize| {
+            indices.is_none_or(|indices| indices.iter().all(|&index| index < count))
+        };
+        for face in &data.faces {
+            if !(in_range(Some(face.positions), data.positions.len())
+                && in_range(face.normals, data.normals.len())
+                && in_range(face.uvs, data.uv_set(face).len())
+                && face.material < materials.len())
+            {
+                return Err(Error::InvalidMesh(format!(
+                    "a face refers to vertices or a material the mesh doesn't have: {:?}",
+                    face
+                )));
+            }
+        }
+        // single precision so f32 builds give the same IDs, like spheres
+        let id = stable_id(
+            &data
+                .positions
+                .iter()
+                .flat_map(|position| [position.x, position.y, position.z])
+                .flat_map(|value| (value as f32).to_le_bytes())
+                .collect::<Vec<_>>(),
+        );
+        let surface = Arc::new(Surface {
+            data,
+            materials,
+            id,
+        });
+        let triangles = (0..surface.data.faces.len())
+            .map(|face| {
+                let [a, b, c] = surface.data.faces[face]
+                    .positions
+                    .map(|index| surface.data.positions[index]);
+                Triangle {
+                    surface: surface.clone(),
+                    face,
+                    bounding_box: AABB::from_boxes(&AABB::from_vecs(a, b), &AABB::from_vecs(c, c)),
+                }
+            })
+            .collect();
+        let (tree, _) = QBVH::new(triangles);
+        let areas = (0..surface.data.faces.len())
+            .scan(0.0, |total, face| {
+                *total += surface.area(face);
+                Some(*total)
+            })
+            .collect();
+        Ok(Self {
+            tree,
+            surface,
+            areas,
+        })
+    }
+    // Loads an OBJ file and smooths the faces that have no normals by `SMOOTHING_ANGLE`. The
+    // material of every slot is the one `materials` gives for its name.
+    pub fn load_obj(path: &str, materials: impl Fn(&str) -> Arc<dyn Material>) -> Result<Self> {
+        Self::parse_obj(&std::fs::read_to_string(path)?, materials)
+    }
+    // Like `load_obj` for the text of an OBJ file.
+    pub fn parse_obj(text: &str, materials: impl Fn(&str) -> Arc<dyn Material>) -> Result<Self> {
+        let mut data = MeshData::parse_obj(text)?;
+        data.smooth_normals(SMOOTHING_ANGLE);
+        let materials = data
+            .materials
+            .iter()
+            .map(|slot| materials(&slot.name))
+            .collect();
+        Self::new(data, materials)
+    }
+    // The object ID of the mesh in hit records, for linking lights to it.
+    pub fn id(&self) -> u32 {
+        self.surface.id
+    }
+}
+
+impl Hittable for Mesh {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        self.tree.hit(ray, ray_trange)
+    }
+    fn bounding_box(&self) -> &AABB {
+        self.tree.bounding_box()
+    }
+    fn hit_packet(&self, rays: &[Ray], ray_trange: &Interval, records: &mut [Option<HitRecord>]) {
+        self.tree.hit_packet(rays, ray_trange, records)
+    }
+    // The density of directions towards points drawn by `sample_point`, which adds up over every
+    // face the direction passes through.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        let Some(&total) = self.areas.last().filter(|&&total| total > 0.0) else {
+            return 0.0;
+        };
+        let ray = Ray::new(*origin, *direction, 0.0);
+        let mut pdf = 0.0;
+        self.tree
+            .visit_crossed(&ray, &Interval::new(0.001, INFINITY), &mut |triangle| {
+                let share = self.surface.area(triangle.face) / total;
+                pdf += share * triangle.pdf_value(origin, direction);
+            });
+        pdf
+    }
+    fn random(&self, origin: &Point3, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        let (point, _, _) = self.sample_point(rng)?;
+        Some(point - *origin)
+    }
+    // Points are drawn evenly over the whole mesh, the faces picked by their area.
+    fn sample_point(&self, rng: &mut dyn RandomSource) -> Option<(Point3, Vec3, Float)> {
+        let total = *self.areas.last()?;
+        if total <= 0.0 {
+            return None;
+        }
+        let target = rng.next_float() * total;
+        let face = self
+            .areas
+            .partition_point(|&area| area <= target)
+            .min(self.areas.len() - 1);
+        let (point, normal) = self.surface.point_on(face, rng);
+        Some((point, normal, 1.0 / total))
+    }
+    // The faces that give off light, as a mesh of their own with the same ID.
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        let materials = &self.surface.materials;
+        let data = &self.surface.data;
+        let faces = data
+            .faces
+            .iter()
+            .filter(|face| materials[face.material].is_emissive())
+            .copied()
+            .collect::<Vec<_>>();
+        if faces.is_empty() {
+            return;
+        }
+        // the positions are all kept, which the ID is made from
+        let data = MeshData {
+            faces,
+            ..data.clone()
+        };
+        if let Ok(light) = Mesh::new(data, materials.clone()) {
+            lights.push((light.id(), Arc::new(light)));
+        }
+    }
+    fn bvh_boxes(&self, depth: usize, boxes: &mut Vec<BvhBox>) {
+        self.tree.bvh_boxes(depth, boxes)
+    }
+    fn report(&self, report: &mut SceneReport) {
+        let data = &self.surface.data;
+        let memory = std::mem::size_of_val(self)
+            + std::mem::size_of_val(data.positions.as_slice())
+            + std::mem::size_of_val(data.normals.as_slice())
+            + data
+                .uv_sets
+                .iter()
+                .map(|set| std::mem::size_of_val(set.as_slice()))
+                .sum::<usize>()
+            + std::mem::size_of_val(data.faces.as_slice())
+            + self.tree.primitives.len() * std::mem::size_of::<Triangle>()
+            + std::mem::size_of_val(self.areas.as_slice());
+        report.objects("mesh", 1, memory);
+        for material in &self.surface.materials {
+            report.material(material);
+        }
+        if !data.positions.iter().all(Vec3::is_finite) {
+            report.warn(format!(
+                "mesh {} has a vertex that isn't finite",
+                self.surface.id
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{color::Color, hittable::materials::Lambertian};
+
+    // Two faces meeting at a right angle along the x axis, one lying flat and one standing up,
+    // with the standing one shaded flat.
+    const FOLD: &str = "
+        v 0 0 0
+        v 1 0 0
+        v 0 0 -1
+        v 0 1 0
+        vt 0 0
+        vt 1 0
+        vt 0 1
+        f 1/1 2/2 3/3
+        s off
+        f 1 2 4
+    ";
+
+    #[test]
+    fn obj_faces_are_read_and_split_into_triangles() {
+        let data = MeshData::parse_obj(FOLD).unwrap();
+        assert_eq!(data.positions.len(), 4);
+        assert_eq!(data.faces[0].uvs, Some([0, 1, 2]));
+        assert_eq!(data.faces[0].smoothing_group, Some(0));
+        assert_eq!(data.faces[1].positions, [0, 1, 3]);
+        assert_eq!(data.faces[1].smoothing_group, None);
+        // a quad with negative indices and normals makes two triangles
+        let quad = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nvn 0 0 1\ns 2\nf -4//1 -3//1 -2//1 -1//1";
+        let data = MeshData::parse_obj(quad).unwrap();
+        assert_eq!(data.faces.len(), 2);
+        assert_eq!(data.faces[1].positions, [0, 2, 3]);
+        assert_eq!(data.faces[1].normals, Some([0, 0, 0]));
+        assert_eq!(data.faces[1].smoothing_group, Some(2));
+        for broken in ["v 0 0", "v 0 0 0\nf 1 2 3", "vt 0 0\nf 1/1 1/1", "s on"] {
+            assert!(matches!(
+                MeshData::parse_obj(broken),
+                Err(Error::InvalidMesh(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn smoothing_keeps_hard_edges() {
+        let mut fold = MeshData::parse_obj(&FOLD.replace("s off", "")).unwrap();
+        let mut hard = fold.clone();
+        hard.smooth_normals(SMOOTHING_ANGLE);
+        // the faces are further apart than the angle, so each keeps its own normal
+        for (face, expected) in hard.faces.iter().zip([(0.0, 1.0, 0.0), (0.0, 0.0, 1.0)]) {
+            for index in face.normals.unwrap() {
+                let expected = Vec3::new(expected.0, expected.1, expected.2);
+                assert!((hard.normals[index] - expected).length() < 1e-6);
+            }
+        }
+        // but with a wider angle they meet halfway along the edge they share
+        fold.smooth_normals(100.0);
+        let shared = fold.normals[fold.faces[0].normals.unwrap()[0]];
+        let halfway = Vec3::new(0.0, 1.0, 1.0).unit_vector();
+        assert!((shared - halfway).length() < 1e-6);
+        // and faces without a group stay flat
+        let mut flat = MeshData::parse_obj(FOLD).unwrap();
+        flat.smooth_normals(100.0);
+        assert_eq!(flat.faces[1].normals, None);
+    }
+
+    #[test]
+    fn rays_hit_the_interpolated_surface() {
+        let mut data = MeshData::parse_obj(&FOLD.replace("s off", "")).unwrap();
+        data.smooth_normals(100.0);
+        let material = Arc::new(Lambertian::from(Color::white()));
+        let mesh = Mesh::new(data, vec![material.clone()]).unwrap();
+        let ray = Ray::new(
+            Point3::new(0.25, 1.0, -0.25),
+            Vec3::new(0.0, -1.0, 0.0),
+            0.0,
+        );
+        let hit = mesh
+            .hit(&ray, &Interval::new(0.001, Float::INFINITY))
+            .unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-6);
+        assert!(hit.front_face && hit.object_id == mesh.id());
+        // the normal leans towards the standing face it is smoothed with
+        assert!(hit.normal.z > 0.0 && hit.normal.y > hit.normal.z);
+        assert!((hit.u - 0.25).abs() < 1e-6 && (hit.v - 0.25).abs() < 1e-6);
+        // rays leave it from the curved surface the normals describe, above the flat one
+        let lifted = hit.shading_offset;
+        assert!(lifted.x.abs() < 1e-6);
+        assert!((lifted.y - 0.09375).abs() < 1e-6 && (lifted.z - 0.09375).abs() < 1e-6);
+        // the standing face seen from behind
+        let ray = Ray::new(Point3::new(0.25, 0.25, -1.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let hit = mesh
+            .hit(&ray, &Interval::new(0.001, Float::INFINITY))
+            .unwrap();
+        assert!(!hit.front_face && hit.normal.z < 0.0 && hit.normal.y < 0.0);
+        assert_eq!(hit.shading_offset.length(), 0.0);
+        let missing = MeshData {
+            faces: vec![Face::new([0, 1, 5])],
+            ..MeshData::parse_obj(FOLD).unwrap()
+        };
+        assert!(Mesh::new(missing, vec![material]).is_err());
+    }
+
+    #[test]
+    fn meshes_of_one_face_are_hit() {
+        let data = MeshData::parse_obj("v 0 0 0\nv 1 0 0\nv 0 0 1\nf 1 2 3\n").unwrap();
+        let mesh = Mesh::new(data, vec![Arc::new(Lambertian::from(Color::white()))]).unwrap();
+        let ray = Ray::new(Point3::new(0.25, 1.0, 0.25), Vec3::new(0.0, -1.0, 0.0), 0.0);
+        let range = Interval::new(0.001, Float::INFINITY);
+        let hit = mesh.hit(&ray, &range).unwrap();
+        assert!((hit.t - 1.0).abs() < 1e-6);
+        let mut records = [None, None];
+        mesh.hit_packet(&[ray.clone(), ray], &range, &mut records);
+        assert!(records.iter().all(|record| record.is_some()));
+    }
+
+    #[test]
+    fn faces_keep_their_materials_and_uv_sets() {
+        let obj = FOLD
+            .replace("f 1/1", "usemtl red\nf 1/1")
+            .replace("f 1 2 4", "usemtl blue\nf 1 2 4\nusemtl red\nf 2 3 4");
+        let mut data = MeshData::parse_obj(&obj).unwrap();
+        let names = data.materials.iter().map(|slot| slot.name.as_str());
+        assert_eq!(names.collect::<Vec<_>>(), ["red", "blue"]);
+        let materials = data.faces.iter().map(|face| face.material);
+        assert_eq!(materials.collect::<Vec<_>>(), [0, 1, 0]);
+        let unnamed = MeshData::parse_obj("v 0 0 0\nf 1 1 1\nusemtl a\nf 1 1 1").unwrap();
+        assert_eq!(unnamed.materials[0].name, "");
+        // the red faces read their textures mirrored
+        let mirrored = data.uv_sets[0].iter().map(|(u, v)| (1.0 - u, *v)).collect();
+        data.uv_sets.push(mirrored);
+        data.materials[0].uv_set = 1;
+        let red: Arc<dyn Material> = Arc::new(Lambertian::from(Color::new(1.0, 0.0, 0.0)));
+        let blue: Arc<dyn Material> = Arc::new(Lambertian::from(Color::new(0.0, 0.0, 1.0)));
+        assert!(Mesh::new(data.clone(), vec![red.clone()]).is_err());
+        let mesh = Mesh::new(data.clone(), vec![red.clone(), blue]).unwrap();
+        let hit = |origin: Point3, direction: Vec3| {
+            let ray = Ray::new(origin, direction, 0.0);
+            mesh.hit(&ray, &Interval::new(0.001, Float::INFINITY))
+                .unwrap()
+        };
+        // from below, as the last red face leans over the first
+        let flat = hit(Point3::new(0.25, -1.0, -0.25), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(flat.material.albedo(&flat).r, 1.0);
+        assert!((flat.u - 0.75).abs() < 1e-6);
+        let standing = hit(Point3::new(0.25, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0));
+        assert_eq!(standing.material.albedo(&standing).b, 1.0);
+        // a slot can't read a set the mesh doesn't have
+        data.materials[0].uv_set = 2;
+        assert!(Mesh::new(data, vec![red.clone(), red]).is_err());
+    }
+}
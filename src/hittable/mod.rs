@@ -1,44 +1,223 @@
-use std::{
-    cmp::Ordering,
-    f64::{},
-    fmt::Debug,
-    ops::{Neg, Range},
-    slice::IterMut,
-    sync::Arc,
-};
+use std::{fmt::Debug, sync::Arc};
 
 use crate::{
-    color::Color,
-    random::Rng,
-    range::{Membership, RangeExtensions},
+    float::Float,
+    interval::Interval,
+    random::RandomSource,
     ray::Ray,
     vec3::{Point3, Vec3},
 };
 
-use self::{aabb::AABB, materials::Material};
+use self::{
+    aabb::AABB, containers::BvhBox, geometry::Sphere, materials::Material, report::SceneReport,
+};
 
 pub mod aabb;
+pub mod animation;
+pub mod bvh_cache;
 pub mod containers;
-pub mod materials;
+pub mod descriptor;
+mod expression;
 pub mod geometry;
+pub mod grid;
+pub mod instance;
+pub mod kd_tree;
+pub mod lod;
+pub mod materials;
+pub mod mesh;
+pub mod nodes;
+pub mod pdf;
+pub mod report;
+pub mod sphere_list;
+pub mod streaming;
 pub mod texture;
+pub mod volume;
+
+// The largest number of rays traced together by `Hittable::hit_packet`.
+pub const PACKET_SIZE: usize = 64;
 
-pub trait Hittable: Sync + Debug {
-    fn hit(&self, ray: &Ray, ray_trange: &Range<f64>) -> Option<HitRecord>;
+// the default methods ignore what they are given
+#[allow(unused_variables)]
+pub trait Hittable: Send + Sync + Debug {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord>;
     fn bounding_box(&self) -> &AABB;
+    // Intersects a packet of at most PACKET_SIZE rays, `records` holds the closest hit found so
+    // far for each ray and is only overwritten by closer hits.
+    fn hit_packet(&self, rays: &[Ray], ray_trange: &Interval, records: &mut [Option<HitRecord>]) {
+        for (ray, record) in rays.iter().zip(records.iter_mut()) {
+            let closest_so_far = record.as_ref().map_or(ray_trange.max, |r| r.t);
+            if let Some(hit_record) = self.hit(ray, &ray_trange.with_max(closest_so_far)) {
+                *record = Some(hit_record);
+            }
+        }
+    }
+    // The density of `random` for `direction` from `origin`, zero where the object isn't.
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        0.0
+    }
+    // A random direction from `origin` towards the object, for sampling lights. `None` for
+    // objects that can't be sampled, whose `pdf_value` is zero everywhere.
+    fn random(&self, origin: &Point3, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        None
+    }
+    // A random point on the surface of the object with the normal there and the density it was
+    // drawn with over the area of the surface, for sampling lights from the points they are lit
+    // from, see `pdf::solid_angle_pdf`. `None` for objects that can't be sampled.
+    fn sample_point(&self, rng: &mut dyn RandomSource) -> Option<(Point3, Vec3, Float)> {
+        None
+    }
+    // Appends the objects that give off light to `lights` with the ID of their hits, for sampling
+    // them directly. Objects placed by others, like instances, are listed where they are placed.
+    // Lights that move aren't listed, paths only find them by hitting them.
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {}
+    // The sphere this object is and how far it moves over the shutter interval, so spheres can be
+    // batched into a `SphereList`.
+    fn as_sphere(&self) -> Option<(&Sphere, Vec3)> {
+        None
+    }
+    // Appends the boxes of the acceleration structure this object is, `depth` levels down the
+    // tree, for visualizing it. Objects that aren't one are a single leaf.
+    fn bvh_boxes(&self, depth: usize, boxes: &mut Vec<BvhBox>) {
+        boxes.push(BvhBox {
+            bounding_box: self.bounding_box().clone(),
+            depth,
+            leaf: true,
+            end: boxes.len() + 1,
+        });
+    }
+    // Adds what the object is made of to `report` and warns about what looks wrong with it.
+    // Containers report the objects they hold.
+    fn report(&self, report: &mut SceneReport) {
+        report.objects("other", 1, std::mem::size_of_val(self));
+    }
+}
+
+// Boxed objects are objects too, so structures generic over their primitives can hold any of them.
+impl Hittable for Box<dyn Hittable> {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        self.as_ref().hit(ray, ray_trange)
+    }
+    fn bounding_box(&self) -> &AABB {
+        self.as_ref().bounding_box()
+    }
+    fn hit_packet(&self, rays: &[Ray], ray_trange: &Interval, records: &mut [Option<HitRecord>]) {
+        self.as_ref().hit_packet(rays, ray_trange, records)
+    }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        self.as_ref().pdf_value(origin, direction)
+    }
+    fn random(&self, origin: &Point3, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        self.as_ref().random(origin, rng)
+    }
+    fn sample_point(&self, rng: &mut dyn RandomSource) -> Option<(Point3, Vec3, Float)> {
+        self.as_ref().sample_point(rng)
+    }
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        self.as_ref().lights(lights)
+    }
+    fn as_sphere(&self) -> Option<(&Sphere, Vec3)> {
+        self.as_ref().as_sphere()
+    }
+    fn bvh_boxes(&self, depth: usize, boxes: &mut Vec<BvhBox>) {
+        self.as_ref().bvh_boxes(depth, boxes)
+    }
+    fn report(&self, report: &mut SceneReport) {
+        self.as_ref().report(report)
+    }
+}
+
+// Shared objects are objects too, so the lights of a scene can be listed apart from it.
+impl Hittable for Arc<dyn Hittable> {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        self.as_ref().hit(ray, ray_trange)
+    }
+    fn bounding_box(&self) -> &AABB {
+        self.as_ref().bounding_box()
+    }
+    fn hit_packet(&self, rays: &[Ray], ray_trange: &Interval, records: &mut [Option<HitRecord>]) {
+        self.as_ref().hit_packet(rays, ray_trange, records)
+    }
+    fn pdf_value(&self, origin: &Point3, direction: &Vec3) -> Float {
+        self.as_ref().pdf_value(origin, direction)
+    }
+    fn random(&self, origin: &Point3, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        self.as_ref().random(origin, rng)
+    }
+    fn sample_point(&self, rng: &mut dyn RandomSource) -> Option<(Point3, Vec3, Float)> {
+        self.as_ref().sample_point(rng)
+    }
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        self.as_ref().lights(lights)
+    }
+    fn as_sphere(&self) -> Option<(&Sphere, Vec3)> {
+        self.as_ref().as_sphere()
+    }
+    fn bvh_boxes(&self, depth: usize, boxes: &mut Vec<BvhBox>) {
+        self.as_ref().bvh_boxes(depth, boxes)
+    }
+    fn report(&self, report: &mut SceneReport) {
+        self.as_ref().report(report)
+    }
 }
 
 pub struct HitRecord {
     pub point: Point3,
     pub normal: Vec3,
     pub material: Arc<dyn Material>,
-    pub t: f64,
-    pub u: f64,
-    pub v: f64,
+    pub t: Float,
+    pub u: Float,
+    pub v: Float,
     pub front_face: bool,
+    // The `stable_id` of the object that was hit.
+    pub object_id: u32,
+    // How far the hit point moves over the shutter interval, for the motion vector AOV.
+    pub motion: Vec3,
+    // Where rays leaving on the side of the normal start from, off the hit point. Meshes shaded
+    // smooth move it off their flat triangles towards the surface their normals describe, see
+    // `mesh`, everything else leaves it at zero.
+    pub shading_offset: Vec3,
+    // The temperature in kelvin of the particle hit in a glowing `Volume`, zero for surfaces.
+    pub temperature: Float,
+}
+
+// An ID for an object or material made from the values that define it with FNV-1a, so it stays the
+// same between runs, builds and render workers. IDs have 24 bits so they are exact in an f32, and
+// zero is left for where nothing was hit.
+pub fn stable_id(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in bytes {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    let id = (hash >> 24 ^ hash) & 0xffffff;
+    return id.max(1);
+}
+
+// The `stable_id` of a material or texture from its kind, the numbers it was made with and the IDs
+// of the materials and textures it is made of.
+pub(crate) fn compound_id(kind: &str, values: &[Float], parts: &[u32]) -> u32 {
+    let bytes = kind
+        .bytes()
+        .chain(
+            values
+                .iter()
+                .flat_map(|value| (*value as f32).to_le_bytes()),
+        )
+        .chain(parts.iter().flat_map(|part| part.to_le_bytes()))
+        .collect::<Vec<_>>();
+    stable_id(&bytes)
 }
 
 impl HitRecord {
+    // `ray` leaving the surface of the hit, which starts from the `shading_offset` of the hit if it
+    // leaves on the side of the normal.
+    pub fn leave_surface(&self, ray: Ray) -> Ray {
+        if ray.direction.dot(&self.normal) > 0.0 {
+            Ray::new(ray.origin + self.shading_offset, ray.direction, ray.time)
+        } else {
+            ray
+        }
+    }
     pub fn set_face_normal(&mut self, ray: &Ray, outward_normal: Vec3) {
         self.front_face = ray.direction.dot(&outward_normal) < 0.;
         self.normal = if self.front_face {
@@ -0,0 +1,487 @@
+use std::{collections::HashMap, fs, path::Path, sync::Arc};
+
+use crate::{
+    color::Color,
+    error::{Error, Result},
+    float::Float,
+    random::RandomSource,
+    ray::Ray,
+    vec3::{Onb, Vec3},
+};
+
+use super::{
+    compound_id, expression,
+    materials::{reflectance, Material},
+    report::SceneReport,
+    streaming::open_texture,
+    texture::{NoiseTexture, Texture},
+    HitRecord,
+};
+
+// A node of a `NodeMaterial`. Every node gives a color at the point being shaded, numbers are gray
+// colors. Nodes take their inputs from the nodes added before them by index.
+#[derive(Debug)]
+pub enum Node {
+    Constant(Color),
+    Texture(Arc<dyn Texture>),
+    // The `odd` input in every other cube of a 3D checkerboard with cubes `scale` across and
+    // `even` in the rest.
+    Checker {
+        scale: Float,
+        odd: usize,
+        even: usize,
+    },
+    Math(MathOp, usize, usize),
+    // The share of light a surface of this index of refraction reflects, more at grazing angles.
+    Fresnel(Float),
+    // The first input where the factor is zero, the second where it is one and a blend between.
+    Mix {
+        a: usize,
+        b: usize,
+        factor: usize,
+    },
+}
+
+// Math on the channels of two inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MathOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+    Minimum,
+    Maximum,
+}
+
+// What a node sees of the point being shaded. Emission is looked up without the ray, which nodes
+// see as a ray straight into the surface.
+struct Shading<'a> {
+    hit_record: &'a HitRecord,
+    direction: Option<Vec3>,
+}
+
+// A material put together from a graph of nodes, evaluated at every hit. The graph feeds the inputs
+// of one surface: a diffuse `base_color`, a `specular` chance of a mirror-like bounce tinted by
+// `specular_color` and blurred by `roughness`, and an `emission`. Only the base color has to be
+// set. Graphs can be written as text and loaded with `NodeMaterial::from_graph`, so materials like
+// a coat that reflects more at grazing angles over a checker pattern don't need Rust types of
+// their own.
+#[derive(Debug)]
+pub struct NodeMaterial {
+    nodes: Vec<Node>,
+    base_color: usize,
+    specular: Option<usize>,
+    specular_color: Option<usize>,
+    roughness: Option<usize>,
+    emission: Option<usize>,
+    id: u32,
+}
+
+impl NodeMaterial {
+    // A material with the base color from the last of `nodes` and the other outputs unset.
+    pub fn new(nodes: Vec<Node>) -> Result<Self> {
+        for (index, node) in nodes.iter().enumerate() {
+            let inputs = match *node {
+                Node::Checker { odd, even, .. } => vec![odd, even],
+                Node::Math(_, a, b) => vec![a, b],
+                Node::Mix { a, b, factor } => vec![a, b, factor],
+                _ => Vec::new(),
+            };
+            if inputs.iter().any(|&input| input >= index) {
+                return Err(Error::InvalidMaterial(format!(
+                    "node {} takes its input from a node after it",
+                    index
+                )));
+            }
+        }
+        let base_color = nodes
+            .len()
+            .checked_sub(1)
+            .ok_or_else(|| Error::InvalidMaterial("the graph has no nodes".to_string()))?;
+        Ok(Self {
+            nodes,
+            base_color,
+            specular: None,
+            specular_color: None,
+            roughness: None,
+            emission: None,
+            id: 0,
+        }
+        .identified())
+    }
+    // The outputs are set to nodes by their index, which fails for nodes the graph doesn't have.
+    pub fn with_base_color(self, node: usize) -> Result<Self> {
+        Ok(Self {
+            base_color: self.node("base_color", node)?,
+            ..self
+        }
+        .identified())
+    }
+    pub fn with_specular(self, specular: usize, specular_color: Option<usize>) -> Result<Self> {
+        Ok(Self {
+            specular: Some(self.node("specular", specular)?),
+            specular_color: specular_color
+                .map(|node| self.node("specular_color", node))
+                .transpose()?,
+            ..self
+        }
+        .identified())
+    }
+    pub fn with_roughness(self, node: usize) -> Result<Self> {
+        Ok(Self {
+            roughness: Some(self.node("roughness", node)?),
+            ..self
+        }
+        .identified())
+    }
+    pub fn with_emission(self, node: usize) -> Result<Self> {
+        Ok(Self {
+            emission: Some(self.node("emission", node)?),
+            ..self
+        }
+        .identified())
+    }
+    // The node for `output` if the graph has it.
+    fn node(&self, output: &str, node: usize) -> Result<usize> {
+        if node >= self.nodes.len() {
+            return Err(Error::InvalidMaterial(format!(
+                "{} is set to node {} of a graph of {}",
+                output,
+                node,
+                self.nodes.len()
+            )));
+        }
+        Ok(node)
+    }
+    // Sets the material ID from the nodes and the outputs they feed, whenever either changes.
+    fn identified(self) -> Self {
+        let nodes = self.nodes.iter().map(|node| match *node {
+            Node::Constant(color) => compound_id("constant", &[color.r, color.g, color.b], &[]),
+            Node::Texture(ref texture) => texture.id(),
+            Node::Checker { scale, odd, even } => {
+                compound_id("checker", &[scale], &[odd as u32, even as u32])
+            }
+            Node::Math(op, a, b) => compound_id(&format!("{:?}", op), &[], &[a as u32, b as u32]),
+            Node::Fresnel(index_of_refraction) => {
+                compound_id("fresnel", &[index_of_refraction], &[])
+            }
+            Node::Mix { a, b, factor } => {
+                compound_id("mix", &[], &[a as u32, b as u32, factor as u32])
+            }
+        });
+        // outputs are numbered from one so unset ones differ from the first node
+        let outputs = [
+            Some(self.base_color),
+            self.specular,
+            self.specular_color,
+            self.roughness,
+            self.emission,
+        ]
+        .map(|output| output.map_or(0, |node| node as u32 + 1));
+        let parts = nodes.chain(outputs).collect::<Vec<_>>();
+        Self {
+            id: compound_id("node", &[], &parts),
+            ..self
+        }
+    }
+    // Reads a graph from text, one `name = node inputs...` line per node like
+    //
+    //     tiles = checker 0.5 0.9 0.1
+    //     base_color = multiply tiles 0.8
+    //     specular = fresnel 1.5
+    //
+    // The nodes are `rgb r g b`, `value x`, `image path`, `noise scale`, `checker scale odd even`,
+    // `fresnel index_of_refraction`, `mix a b factor` and the math nodes `add`, `subtract`,
+    // `multiply`, `divide`, `power`, `min` and `max` taking two inputs. Inputs are the names of
+    // earlier nodes or numbers. A node can also be an expression like
+    // `base_color = mix(checker(2.0), noise(5.0), fresnel()) * 0.8`, see `expression`. The nodes
+    // named after the outputs of the material feed them, lines starting with `#` are comments.
+    pub fn from_graph(graph: &str) -> Result<Self> {
+        let mut nodes = Vec::new();
+        let mut names = HashMap::new();
+        for line in graph.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || Error::InvalidMaterial(format!("invalid node: {}", line));
+            let (name, node) = line.split_once('=').ok_or_else(invalid)?;
+            if node.contains('(') {
+                let value = expression::compile(node, &mut nodes, &names)?;
+                names.insert(name.trim().to_string(), value);
+                continue;
+            }
+            let mut words = node.split_whitespace();
+            let kind = words.next().ok_or_else(invalid)?;
+            let arguments = words.collect::<Vec<_>>();
+            let number = |word: &str| word.parse::<Float>().map_err(|_| invalid());
+            // inputs given as numbers become constant nodes of their own
+            let input = |nodes: &mut Vec<Node>, word: &str| -> Result<usize> {
+                match names.get(word) {
+                    Some(&index) => Ok(index),
+                    None => {
+                        nodes.push(Node::Constant(Color::gray(number(word)?)));
+                        Ok(nodes.len() - 1)
+                    }
+                }
+            };
+            let node = match (kind, &arguments[..]) {
+                ("rgb", [r, g, b]) => {
+                    Node::Constant(Color::new(number(r)?, number(g)?, number(b)?))
+                }
+                ("value", [value]) => Node::Constant(Color::gray(number(value)?)),
+                ("image", [path]) => Node::Texture(Arc::from(open_texture(path, false)?)),
+                ("noise", [scale]) => Node::Texture(Arc::new(NoiseTexture::new(number(scale)?))),
+                ("checker", [scale, odd, even]) => Node::Checker {
+                    scale: number(scale)?,
+                    odd: input(&mut nodes, odd)?,
+                    even: input(&mut nodes, even)?,
+                },
+                ("fresnel", [index_of_refraction]) => Node::Fresnel(number(index_of_refraction)?),
+                ("mix", [a, b, factor]) => Node::Mix {
+                    a: input(&mut nodes, a)?,
+                    b: input(&mut nodes, b)?,
+                    factor: input(&mut nodes, factor)?,
+                },
+                (op, [a, b]) => {
+                    let op = match op {
+                        "add" => MathOp::Add,
+                        "subtract" => MathOp::Subtract,
+                        "multiply" => MathOp::Multiply,
+                        "divide" => MathOp::Divide,
+                        "power" => MathOp::Power,
+                        "min" => MathOp::Minimum,
+                        "max" => MathOp::Maximum,
+                        _ => return Err(invalid()),
+                    };
+                    Node::Math(op, input(&mut nodes, a)?, input(&mut nodes, b)?)
+                }
+                _ => return Err(invalid()),
+            };
+            nodes.push(node);
+            names.insert(name.trim().to_string(), nodes.len() - 1);
+        }
+        let output = |name: &str| names.get(name).copied();
+        let base_color = output("base_color")
+            .ok_or_else(|| Error::InvalidMaterial("the graph has no base_color".to_string()))?;
+        let mut material = Self::new(nodes)?.with_base_color(base_color)?;
+        if let Some(specular) = output("specular") {
+            material = material.with_specular(specular, output("specular_color"))?;
+        }
+        if let Some(roughness) = output("roughness") {
+            material = material.with_roughness(roughness)?;
+        }
+        if let Some(emission) = output("emission") {
+            material = material.with_emission(emission)?;
+        }
+        Ok(material)
+    }
+    // A material with the expression as its base color.
+    pub fn from_expression(expression: &str) -> Result<Self> {
+        let mut nodes = Vec::new();
+        let base_color = expression::compile(expression, &mut nodes, &HashMap::new())?;
+        Self::new(nodes)?.with_base_color(base_color)
+    }
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_graph(&fs::read_to_string(path)?)
+    }
+    // The value of every node up to and including `output`.
+    fn evaluate(&self, shading: &Shading, output: usize) -> Vec<Color> {
+        let hit_record = shading.hit_record;
+        let mut values: Vec<Color> = Vec::with_capacity(output + 1);
+        for node in &self.nodes[..=output] {
+            let value = match *node {
+                Node::Constant(color) => color,
+                Node::Texture(ref texture) => {
+                    texture.value(hit_record.u, hit_record.v, &hit_record.point)
+                }
+                Node::Checker { scale, odd, even } => {
+                    let point = hit_record.point / scale;
+                    let sum = point.x.floor() + point.y.floor() + point.z.floor();
+                    if (sum as i64).rem_euclid(2) == 0 {
+                        values[odd]
+                    } else {
+                        values[even]
+                    }
+                }
+                Node::Math(op, a, b) => {
+                    let (a, b) = (values[a], values[b]);
+                    let apply = |f: fn(Float, Float) -> Float| {
+                        Color::new(f(a.r, b.r), f(a.g, b.g), f(a.b, b.b))
+                    };
+                    match op {
+                        MathOp::Add => a + b,
+                        MathOp::Subtract => a - b,
+                        MathOp::Multiply => a * b,
+                        MathOp::Divide => apply(|a, b| if b == 0.0 { 0.0 } else { a / b }),
+                        MathOp::Power => apply(Float::powf),
+                        MathOp::Minimum => apply(Float::min),
+                        MathOp::Maximum => apply(Float::max),
+                    }
+                }
+                Node::Fresnel(index_of_refraction) => {
+                    let cosine = shading
+                        .direction
+                        .map_or(1.0, |direction| {
+                            (-direction.unit_vector()).dot(&hit_record.normal)
+                        })
+                        .clamp(0.0, 1.0);
+                    // the surface is seen from outside of it either way
+                    Color::gray(reflectance(cosine, index_of_refraction))
+                }
+                Node::Mix { a, b, factor } => {
+                    let factor = values[factor];
+                    values[a] * (Color::white() - factor) + values[b] * factor
+                }
+            };
+            values.push(value);
+        }
+        values
+    }
+    fn output(&self, shading: &Shading, output: usize) -> Color {
+        self.evaluate(shading, output)[output]
+    }
+}
+
+impl Material for NodeMaterial {
+    fn scatter(
+        &self,
+        rng: &mut dyn RandomSource,
+        ray: &Ray,
+        hit_record: &HitRecord,
+    ) -> Option<(Color, Ray)> {
+        let shading = Shading {
+            hit_record,
+            direction: Some(ray.direction),
+        };
+        let last = [self.base_color]
+            .into_iter()
+            .chain(self.specular)
+            .chain(self.specular_color)
+            .chain(self.roughness)
+            .max()
+            .unwrap();
+        let values = self.evaluate(&shading, last);
+        let specular = self.specular.map_or(0.0, |node| values[node].luminance());
+        if rng.next_float() < specular {
+            let tint = self
+                .specular_color
+                .map_or(Color::white(), |node| values[node]);
+            let roughness = self.roughness.map_or(0.0, |node| values[node].luminance());
+            let reflected = ray.direction.unit_vector().reflect(&hit_record.normal);
+            let direction = reflected + roughness * Vec3::random_on_unit_sphere(rng);
+            if direction.dot(&hit_record.normal) <= 0.0 {
+                return None;
+            }
+            return Some((tint, Ray::new(hit_record.point, direction, ray.time)));
+        }
+        let direction = Onb::new(&hit_record.normal).transform(&Vec3::random_cosine_direction(rng));
+        Some((
+            values[self.base_color],
+            Ray::new(hit_record.point, direction, ray.time),
+        ))
+    }
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        let shading = Shading {
+            hit_record,
+            direction: None,
+        };
+        self.output(&shading, self.base_color)
+    }
+    fn emitted(&self, hit_record: &HitRecord, _lit_object: Option<u32>) -> Color {
+        let Some(emission) = self.emission else {
+            return Color::black();
+        };
+        if !hit_record.front_face {
+            return Color::black();
+        }
+        let shading = Shading {
+            hit_record,
+            direction: None,
+        };
+        self.output(&shading, emission)
+    }
+    fn is_emissive(&self) -> bool {
+        self.emission.is_some()
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.memory += self.nodes.capacity() * std::mem::size_of::<Node>();
+        for node in &self.nodes {
+            if let Node::Texture(texture) = node {
+                report.texture(texture);
+            }
+        }
+        if self.emission.is_some() {
+            report.emissive();
+        }
+    }
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{random::Rng, vec3::Point3};
+
+    fn hit(point: Point3, material: Arc<dyn Material>) -> HitRecord {
+        HitRecord {
+            point,
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            material,
+            t: 1.0,
+            u: 0.0,
+            v: 0.0,
+            front_face: true,
+            object_id: 0,
+            motion: Vec3::zero(),
+            shading_offset: Vec3::zero(),
+            temperature: 0.0,
+        }
+    }
+
+    #[test]
+    fn graphs_feed_the_surface() {
+        let material: Arc<dyn Material> = Arc::new(
+            NodeMaterial::from_graph(
+                "# a glossy coat over tiles\n\
+                 tiles = checker 1.0 0.9 0.1\n\
+                 base_color = multiply tiles 0.5\n\
+                 specular = fresnel 1.5\n",
+            )
+            .unwrap(),
+        );
+        let odd = hit(Point3::new(0.5, 0.0, 0.5), material.clone());
+        assert!((material.albedo(&odd).g - 0.45).abs() < 1e-6);
+        let even = hit(Point3::new(1.5, 0.0, 0.5), material.clone());
+        assert!((material.albedo(&even).g - 0.05).abs() < 1e-6);
+
+        // the coat reflects a few percent head on and most of the light at grazing angles
+        let mut rng = Rng::from_seed([5, 6]);
+        let reflections = |direction: Vec3, rng: &mut Rng| {
+            let ray = Ray::new(Point3::new(0.5, 1.0, 0.5), direction, 0.0);
+            (0..2000)
+                .filter(|_| {
+                    let (attenuation, _) = material.scatter(rng, &ray, &odd).unwrap();
+                    attenuation.g == 1.0
+                })
+                .count()
+        };
+        let head_on = reflections(Vec3::new(0.0, -1.0, 0.0), &mut rng);
+        let grazing = reflections(Vec3::new(1.0, -0.05, 0.0), &mut rng);
+        assert!(head_on < 200 && grazing > 800);
+
+        let tiles = NodeMaterial::from_graph("base_color = checker(1.0, 0.9, 0.1) * 0.5").unwrap();
+        assert!((tiles.albedo(&even).g - 0.05).abs() < 1e-6);
+        let gray = NodeMaterial::from_expression("min(rgb(0.2, 0.4, 0.6), 0.5)").unwrap();
+        assert!((gray.albedo(&even).b - 0.5).abs() < 1e-6);
+
+        assert!(NodeMaterial::from_graph("specular = fresnel 1.5").is_err());
+        assert!(NodeMaterial::from_graph("base_color = mix a b 0.5").is_err());
+        let plain = || NodeMaterial::new(vec![Node::Constant(Color::white())]).unwrap();
+        assert!(plain().with_emission(1).is_err());
+        assert!(plain().with_specular(0, Some(3)).is_err());
+        assert!(plain().with_roughness(0).is_ok());
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{
+    float::{consts::PI, Float},
+    random::RandomSource,
+    vec3::{Onb, Point3, Vec3},
+};
+
+use super::Hittable;
+
+// A probability density over directions, to draw directions from and to weigh the samples drawn
+// by their density. Blending the density of a material with one towards the lights in a
+// `MixturePdf` lets a path find small lights that the material alone would rarely hit.
+pub trait Pdf {
+    fn value(&self, direction: &Vec3) -> Float;
+    // `None` when there is nothing to draw a direction towards, like a list without lights.
+    fn generate(&self, rng: &mut dyn RandomSource) -> Option<Vec3>;
+}
+
+// Directions around a normal with density proportional to their cosine, the density of
+// `Lambertian` bounces.
+pub struct CosinePdf {
+    onb: Onb,
+}
+
+impl CosinePdf {
+    pub fn new(normal: &Vec3) -> Self {
+        Self {
+            onb: Onb::new(normal),
+        }
+    }
+}
+
+impl Pdf for CosinePdf {
+    fn value(&self, direction: &Vec3) -> Float {
+        let cosine = direction.unit_vector().dot(&self.onb.w);
+        (cosine / PI).max(0.0)
+    }
+    fn generate(&self, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        Some(self.onb.transform(&Vec3::random_cosine_direction(rng)))
+    }
+}
+
+// Directions evenly within a cone around `axis`, whose radius has the cosine `cos_radius`, like
+// towards the disk of the sun.
+pub struct ConePdf {
+    onb: Onb,
+    cos_radius: Float,
+}
+
+impl ConePdf {
+    pub fn new(axis: &Vec3, cos_radius: Float) -> Self {
+        Self {
+            onb: Onb::new(axis),
+            cos_radius,
+        }
+    }
+}
+
+impl Pdf for ConePdf {
+    fn value(&self, direction: &Vec3) -> Float {
+        if direction.unit_vector().dot(&self.onb.w) < self.cos_radius {
+            return 0.0;
+        }
+        1.0 / (2.0 * PI * (1.0 - self.cos_radius))
+    }
+    fn generate(&self, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        let z = 1.0 - rng.next_float() * (1.0 - self.cos_radius);
+        let phi = 2.0 * PI * rng.next_float();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        Some(
+            self.onb
+                .transform(&Vec3::new(r * phi.cos(), r * phi.sin(), z)),
+        )
+    }
+}
+
+// Directions from `origin` towards an object, usually the lights of a scene.
+pub struct HittablePdf<'a> {
+    objects: &'a dyn Hittable,
+    origin: Point3,
+}
+
+impl<'a> HittablePdf<'a> {
+    pub fn new(objects: &'a dyn Hittable, origin: Point3) -> Self {
+        Self { objects, origin }
+    }
+}
+
+impl Pdf for HittablePdf<'_> {
+    fn value(&self, direction: &Vec3) -> Float {
+        self.objects.pdf_value(&self.origin, direction)
+    }
+    fn generate(&self, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        self.objects.random(&self.origin, rng)
+    }
+}
+
+// The density over the directions from `origin` of a point drawn by `Hittable::sample_point`.
+// The same patch of surface covers less of the view the further it is and the more it is turned
+// away, so the density grows with the square of the distance over the cosine. Points on the side
+// of a surface facing away from `origin` can't be seen from it and have no density.
+pub fn solid_angle_pdf(origin: &Point3, (point, normal, pdf): (Point3, Vec3, Float)) -> Float {
+    let direction = *origin - point;
+    let distance_squared = direction.length_squared();
+    let cosine = normal.dot(&direction) / distance_squared.sqrt();
+    if cosine <= 0.0 {
+        return 0.0;
+    }
+    pdf * distance_squared / cosine
+}
+
+// An even blend of two densities.
+pub struct MixturePdf<'a> {
+    pdfs: [&'a dyn Pdf; 2],
+}
+
+impl<'a> MixturePdf<'a> {
+    pub fn new(a: &'a dyn Pdf, b: &'a dyn Pdf) -> Self {
+        Self { pdfs: [a, b] }
+    }
+}
+
+impl Pdf for MixturePdf<'_> {
+    fn value(&self, direction: &Vec3) -> Float {
+        0.5 * self.pdfs[0].value(direction) + 0.5 * self.pdfs[1].value(direction)
+    }
+    fn generate(&self, rng: &mut dyn RandomSource) -> Option<Vec3> {
+        if rng.next_float() < 0.5 {
+            self.pdfs[0].generate(rng)
+        } else {
+            self.pdfs[1].generate(rng)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        color::Color,
+        hittable::{
+            containers::HittableList,
+            geometry::Sphere,
+            materials::Lambertian,
+            mesh::{Mesh, MeshData},
+        },
+        random::Rng,
+    };
+
+    // Estimates the integral of the density over all directions, which should be one.
+    fn integral(pdf: &dyn Pdf, rng: &mut Rng) -> Float {
+        let n = 200_000;
+        let sum = (0..n)
+            .map(|_| pdf.value(&Vec3::random_on_unit_sphere(rng)))
+            .sum::<Float>();
+        sum * 4.0 * PI / n as Float
+    }
+
+    #[test]
+    fn densities_integrate_to_one() {
+        let mut rng = Rng::new();
+        let light = Sphere::new(
+            Point3::new(0.0, 3.0, 0.0),
+            1.0,
+            Arc::new(Lambertian::from(Color::white())),
+        );
+        let cosine = CosinePdf::new(&Vec3::new(0.0, 1.0, 0.0));
+        let towards_light = HittablePdf::new(&light, Point3::zero());
+        let mixture = MixturePdf::new(&cosine, &towards_light);
+        let cone = ConePdf::new(&Vec3::new(1.0, 1.0, 0.0), 0.9);
+        for pdf in [&cosine as &dyn Pdf, &towards_light, &mixture, &cone] {
+            assert!((integral(pdf, &mut rng) - 1.0).abs() < 0.05);
+        }
+        // the directions drawn in the cone stay within it
+        for _ in 0..1000 {
+            assert!(cone.value(&cone.generate(&mut rng).unwrap()) > 0.0);
+        }
+        // and the directions drawn towards the light all reach it
+        for _ in 0..1000 {
+            let direction = towards_light.generate(&mut rng).unwrap();
+            assert!(towards_light.value(&direction) > 0.0);
+        }
+        // and there is nothing to draw without lights
+        let no_lights = HittableList::default();
+        let towards_nothing = HittablePdf::new(&no_lights, Point3::zero());
+        assert!(towards_nothing.generate(&mut rng).is_none());
+        assert_eq!(towards_nothing.value(&Vec3::new(0.0, 1.0, 0.0)), 0.0);
+    }
+
+    // Adds up the points of `light` facing the origin, weighed by their density over directions,
+    // which comes to the solid angle of the light.
+    fn solid_angle(light: &dyn Hittable, rng: &mut Rng) -> Float {
+        let n = 100_000;
+        (0..n)
+            .map(|_| {
+                let pdf = solid_angle_pdf(&Point3::zero(), light.sample_point(rng).unwrap());
+                if pdf > 0.0 {
+                    1.0 / pdf
+                } else {
+                    0.0
+                }
+            })
+            .sum::<Float>()
+            / n as Float
+    }
+
+    #[test]
+    fn sampled_points_cover_the_solid_angle() {
+        let mut rng = Rng::new();
+        let light = Sphere::new(
+            Point3::new(0.0, 3.0, 0.0),
+            1.0,
+            Arc::new(Lambertian::from(Color::white())),
+        );
+        for _ in 0..1000 {
+            let (point, _, _) = light.sample_point(&mut rng).unwrap();
+            assert!(((point - light.center).length() - 1.0).abs() < 1e-4);
+        }
+        let expected = 2.0 * PI * (1.0 - (1.0 - 1.0 / 9.0 as Float).sqrt());
+        assert!((solid_angle(&light, &mut rng) - expected).abs() < 0.02 * expected);
+
+        // a square two wide facing down at the same height, cut into faces of different sizes
+        let square = "v -1 3 -1\nv 1 3 -1\nv 1 3 1\nv -1 3 1\nv 0.5 3 1\nf 1 5 4\nf 1 3 5\nf 1 2 3";
+        let mesh = Mesh::new(
+            MeshData::parse_obj(square).unwrap(),
+            vec![Arc::new(Lambertian::from(Color::white()))],
+        )
+        .unwrap();
+        let expected = 4.0 * (1.0 / 10.0 as Float).asin();
+        assert!((solid_angle(&mesh, &mut rng) - expected).abs() < 0.02 * expected);
+        // the points are spread evenly over the square whatever the faces
+        let n = 100_000;
+        let mut left = 0;
+        for _ in 0..n {
+            let (point, normal, pdf) = mesh.sample_point(&mut rng).unwrap();
+            assert!(point.x.abs() <= 1.0 && point.z.abs() <= 1.0 && point.y == 3.0);
+            assert!(normal.y == -1.0 && (pdf - 0.25).abs() < 1e-6);
+            left += (point.x < 0.0) as usize;
+        }
+        assert!((left as Float / n as Float - 0.5).abs() < 0.01);
+    }
+}
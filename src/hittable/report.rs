@@ -0,0 +1,244 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Display, Write};
+use std::sync::Arc;
+
+use tracing::{debug, info, warn};
+
+use super::{containers::BvhBox, materials::Material, texture::Texture, Hittable};
+use crate::{
+    float::{consts::PI, Float, INFINITY},
+    interval::Interval,
+    ray::Ray,
+    vec3::{Point3, Vec3},
+};
+
+// The directions around the camera the check for enclosed scenes looks in.
+const ENCLOSURE_RAYS: usize = 64;
+
+// What a scene is made of, gathered when it is built, and what looks wrong with it: zero radius
+// spheres, transforms gone NaN and scenes closed in around the camera with nothing giving off
+// light, which render black however long they are left to.
+#[derive(Debug, Default)]
+pub struct SceneReport {
+    // the objects by kind, without the containers and trees holding them
+    pub objects: BTreeMap<&'static str, usize>,
+    pub materials: usize,
+    pub emissive_materials: usize,
+    pub textures: usize,
+    // the leaves of the BVH by their depth in it
+    pub leaf_depths: BTreeMap<usize, usize>,
+    // the nodes of the BVH with leaves right under them, by how many
+    pub leaf_sizes: BTreeMap<usize, usize>,
+    // A rough estimate of the memory the scene takes in bytes, the objects, trees, materials and
+    // textures. Shared objects, materials and textures count once.
+    pub memory: usize,
+    pub warnings: Vec<String>,
+    // the addresses of the shared objects, materials and textures already counted
+    seen: HashSet<usize>,
+}
+
+impl SceneReport {
+    pub fn new(world: &dyn Hittable) -> Self {
+        let mut report = Self::default();
+        world.report(&mut report);
+        let mut boxes = Vec::new();
+        world.bvh_boxes(0, &mut boxes);
+        report.add_bvh(&boxes);
+        report
+    }
+
+    // Counts `count` objects of a kind taking `memory` bytes between them.
+    pub fn objects(&mut self, kind: &'static str, count: usize, memory: usize) {
+        *self.objects.entry(kind).or_default() += count;
+        self.memory += memory;
+    }
+
+    // Reports an object shared between others, like the structure of an instance, the first time
+    // it comes up.
+    pub fn shared(&mut self, object: &Arc<dyn Hittable>) {
+        if self.first_sight(Arc::as_ptr(object)) {
+            object.report(self);
+        }
+    }
+
+    pub fn material(&mut self, material: &Arc<dyn Material>) {
+        if self.first_sight(Arc::as_ptr(material)) {
+            self.materials += 1;
+            self.memory += std::mem::size_of_val(material.as_ref());
+            material.report(self);
+        }
+    }
+
+    // Called by the materials that give off light.
+    pub fn emissive(&mut self) {
+        self.emissive_materials += 1;
+    }
+
+    pub fn texture(&mut self, texture: &Arc<dyn Texture>) {
+        if self.first_sight(Arc::as_ptr(texture)) {
+            self.textures += 1;
+            self.memory += std::mem::size_of_val(texture.as_ref());
+            texture.report(self);
+        }
+    }
+
+    pub fn warn(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    fn first_sight<T: ?Sized>(&mut self, pointer: *const T) -> bool {
+        self.seen.insert(pointer as *const () as usize)
+    }
+
+    fn add_bvh(&mut self, boxes: &[BvhBox]) {
+        for (index, bvh_box) in boxes.iter().enumerate() {
+            if bvh_box.leaf {
+                *self.leaf_depths.entry(bvh_box.depth).or_default() += 1;
+                continue;
+            }
+            let mut leaves = 0;
+            let mut child = index + 1;
+            while child < bvh_box.end {
+                leaves += boxes[child].leaf as usize;
+                child = boxes[child].end;
+            }
+            if leaves > 0 {
+                *self.leaf_sizes.entry(leaves).or_default() += 1;
+            }
+        }
+    }
+
+    // Warns when every direction from `origin` runs into the world and nothing in it gives off
+    // light, as neither the sky nor the background can light a scene closed in around the camera.
+    pub fn check_enclosure(&mut self, world: &dyn Hittable, origin: Point3) {
+        if self.emissive_materials > 0 {
+            return;
+        }
+        // directions spread evenly over the sphere along a Fibonacci spiral
+        let golden_angle = PI * (3.0 - (5.0 as Float).sqrt());
+        let enclosed = (0..ENCLOSURE_RAYS).all(|i| {
+            let y = 1.0 - 2.0 * (i as Float + 0.5) / ENCLOSURE_RAYS as Float;
+            let radius = (1.0 - y * y).sqrt();
+            let (sin, cos) = (golden_angle * i as Float).sin_cos();
+            let ray = Ray::new(origin, Vec3::new(cos * radius, y, sin * radius), 0.0);
+            world
+                .hit(&ray, &Interval::new(0.000001, INFINITY))
+                .is_some()
+        });
+        if enclosed {
+            self.warn(
+                "the camera is enclosed and nothing in the scene gives off light, the image will \
+                 be black"
+                    .to_string(),
+            );
+        }
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.values().sum()
+    }
+
+    pub fn bvh_depth(&self) -> usize {
+        self.leaf_depths.keys().copied().max().unwrap_or(0)
+    }
+
+    // Logs the totals, the whole report at debug level and the warnings as warnings.
+    pub fn log(&self) {
+        info!(
+            objects = self.object_count(),
+            materials = self.materials,
+            textures = self.textures,
+            bvh_depth = self.bvh_depth(),
+            memory_bytes = self.memory,
+            "built the scene"
+        );
+        debug!("scene report\n{}", self);
+        for warning in &self.warnings {
+            warn!("{}", warning);
+        }
+    }
+}
+
+impl Display for SceneReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let histogram = |counts: &BTreeMap<usize, usize>| {
+            let mut histogram = String::new();
+            for (value, count) in counts {
+                write!(histogram, " {}: {}", value, count).unwrap();
+            }
+            histogram
+        };
+        for (kind, count) in &self.objects {
+            writeln!(f, "{:>16}: {}", kind, count)?;
+        }
+        writeln!(f, "{:>16}: {}", "materials", self.materials)?;
+        writeln!(f, "{:>16}: {}", "emissive", self.emissive_materials)?;
+        writeln!(f, "{:>16}: {}", "textures", self.textures)?;
+        writeln!(f, "{:>16}:{}", "leaf depths", histogram(&self.leaf_depths))?;
+        writeln!(f, "{:>16}:{}", "leaf sizes", histogram(&self.leaf_sizes))?;
+        writeln!(
+            f,
+            "{:>16}: {:.1} MiB",
+            "memory",
+            self.memory as f64 / (1 << 20) as f64
+        )?;
+        for warning in &self.warnings {
+            writeln!(f, "{:>16}: {}", "warning", warning)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        color::Color,
+        hittable::{
+            containers::HittableList,
+            geometry::Sphere,
+            materials::{DiffuseLight, Lambertian},
+        },
+    };
+
+    #[test]
+    fn reports_counts_and_problems() {
+        let gray: Arc<dyn Material> = Arc::new(Lambertian::from(Color::gray(0.5)));
+        let mut world = HittableList::default();
+        // a room around the origin, a sphere in it and one without a radius
+        world.add(Box::new(Sphere::new(Point3::zero(), -10.0, gray.clone())));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 0.0, -2.0),
+            1.0,
+            gray.clone(),
+        )));
+        world.add(Box::new(Sphere::new(
+            Point3::new(2.0, 0.0, -2.0),
+            0.0,
+            gray.clone(),
+        )));
+        let world = world.into_bvh();
+        let mut report = SceneReport::new(world.as_ref());
+        report.check_enclosure(world.as_ref(), Point3::zero());
+        assert_eq!(report.objects["sphere"], 3);
+        assert_eq!((report.materials, report.textures), (1, 1));
+        assert_eq!(report.leaf_depths.values().sum::<usize>(), 3);
+        assert!(report.bvh_depth() >= 1 && report.memory > 0);
+        assert_eq!(report.warnings.len(), 2, "{:?}", report.warnings);
+
+        // a light in the room is enough
+        let mut world = HittableList::default();
+        world.add(Box::new(Sphere::new(Point3::zero(), -10.0, gray)));
+        let light = Arc::new(DiffuseLight::from(Color::white()));
+        world.add(Box::new(Sphere::new(
+            Point3::new(0.0, 5.0, 0.0),
+            1.0,
+            light,
+        )));
+        let world = world.into_bvh();
+        let mut report = SceneReport::new(world.as_ref());
+        report.check_enclosure(world.as_ref(), Point3::zero());
+        assert_eq!(report.emissive_materials, 1);
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    }
+}
@@ -0,0 +1,358 @@
+use std::{
+    simd::{prelude::*, StdFloat},
+    sync::Arc,
+};
+
+use crate::{
+    float::{Float, Floatx4, INFINITY},
+    interval::Interval,
+    ray::Ray,
+    vec3::{Point3, Vec3},
+};
+
+use super::{
+    aabb::AABB, geometry::Sphere, materials::Material, report::SceneReport, HitRecord, Hittable,
+};
+
+// The most spheres in one `SphereList`, a multiple of the four lanes intersected at a time.
+const BATCH_SIZE: usize = 16;
+// Worlds with fewer spheres than this keep every sphere as a leaf of its own.
+const MIN_SPHERES: usize = 64;
+// Spheres this many times larger than the median, like ground spheres, are left out of the
+// batches so they don't blow up the bounds of their neighbours.
+const MAX_RADIUS_RATIO: Float = 8.0;
+
+// A batch of spheres stored as flat arrays, four lanes to a SIMD vector, so a ray is tested
+// against four of them at once instead of going through a boxed leaf for every sphere. Moving
+// spheres move linearly from their center to `center + velocity` over the shutter interval,
+// static ones have no velocity. The unused lanes of the last vector have NaN centers and are
+// never hit.
+#[derive(Debug)]
+pub struct SphereList {
+    center: [Vec<Floatx4>; 3],
+    velocity: [Vec<Floatx4>; 3],
+    radius: Vec<Floatx4>,
+    material_indices: Vec<u32>,
+    materials: Vec<Arc<dyn Material>>,
+    ids: Vec<u32>,
+    bounding_box: AABB,
+}
+
+impl SphereList {
+    pub fn new(spheres: &[(&Sphere, Vec3)]) -> Self {
+        let lanes = spheres.len().div_ceil(4) * 4;
+        let mut center = [(); 3].map(|_| vec![Float::NAN; lanes]);
+        let mut velocity = [(); 3].map(|_| vec![0.0; lanes]);
+        let mut radius = vec![0.0; lanes];
+        let mut material_indices = Vec::with_capacity(spheres.len());
+        let mut materials: Vec<Arc<dyn Material>> = Vec::new();
+        let mut ids = Vec::with_capacity(spheres.len());
+        let mut bounding_box = AABB::EMPTY;
+        for (lane, (sphere, motion)) in spheres.iter().enumerate() {
+            for a in 0..3 {
+                center[a][lane] = sphere.center[a];
+                velocity[a][lane] = motion[a];
+            }
+            radius[lane] = sphere.radius;
+            let index = match materials
+                .iter()
+                .position(|m| Arc::ptr_eq(m, &sphere.material))
+            {
+                Some(index) => index,
+                None => {
+                    materials.push(sphere.material.clone());
+                    materials.len() - 1
+                }
+            };
+            material_indices.push(index as u32);
+            ids.push(sphere.id);
+            bounding_box.grow(&sphere.bounding_box);
+            let radius = Vec3::new(sphere.radius, sphere.radius, sphere.radius);
+            let destination = sphere.center + *motion;
+            bounding_box.grow(&AABB::from_vecs(destination - radius, destination + radius));
+        }
+        let pack = |values: Vec<Float>| {
+            values
+                .chunks_exact(4)
+                .map(Floatx4::from_slice)
+                .collect::<Vec<_>>()
+        };
+        Self {
+            center: center.map(pack),
+            velocity: velocity.map(pack),
+            radius: pack(radius),
+            material_indices,
+            materials,
+            ids,
+            bounding_box,
+        }
+    }
+
+    // Replaces the spheres among `objects` with batches of nearby spheres when there are enough
+    // of them to be worth it, the other objects are passed through as they are.
+    pub fn batch(objects: Vec<Box<dyn Hittable>>) -> Vec<Box<dyn Hittable>> {
+        let sphere_count = objects.iter().filter(|o| o.as_sphere().is_some()).count();
+        if sphere_count < MIN_SPHERES {
+            return objects;
+        }
+        let mut radii = objects
+            .iter()
+            .filter_map(|o| o.as_sphere().map(|(sphere, _)| sphere.radius.abs()))
+            .collect::<Vec<_>>();
+        radii.sort_by(Float::total_cmp);
+        let max_radius = radii[radii.len() / 2] * MAX_RADIUS_RATIO;
+
+        let (spheres, mut rest): (Vec<_>, Vec<_>) = objects.into_iter().partition(|o| {
+            o.as_sphere()
+                .is_some_and(|(sphere, _)| sphere.radius.abs() <= max_radius)
+        });
+        let mut parts = spheres
+            .iter()
+            .map(|o| o.as_sphere().unwrap())
+            .collect::<Vec<_>>();
+        let mut batches = Vec::new();
+        split_into_batches(&mut parts, &mut batches);
+        rest.extend(batches);
+        return rest;
+    }
+}
+
+// Splits the spheres along the longest axis of their centers until every part fits in a batch.
+fn split_into_batches(spheres: &mut [(&Sphere, Vec3)], batches: &mut Vec<Box<dyn Hittable>>) {
+    if spheres.len() <= BATCH_SIZE {
+        batches.push(Box::new(SphereList::new(spheres)));
+        return;
+    }
+    let extent = |a: usize| {
+        let (min, max) = spheres
+            .iter()
+            .fold((INFINITY, -INFINITY), |(min, max), (s, _)| {
+                (min.min(s.center[a]), max.max(s.center[a]))
+            });
+        max - min
+    };
+    let axis = (0..3)
+        .max_by(|&a, &b| extent(a).total_cmp(&extent(b)))
+        .unwrap();
+    spheres.sort_by(|(a, _), (b, _)| a.center[axis].total_cmp(&b.center[axis]));
+    // keep the batches full by splitting on a multiple of the batch size
+    let middle = (spheres.len() / 2).div_ceil(BATCH_SIZE) * BATCH_SIZE;
+    let (left, right) = spheres.split_at_mut(middle);
+    split_into_batches(left, batches);
+    split_into_batches(right, batches);
+}
+
+impl Hittable for SphereList {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        let origin = [0, 1, 2].map(|axis| Floatx4::splat(ray.origin[axis]));
+        let direction = [0, 1, 2].map(|axis| Floatx4::splat(ray.direction[axis]));
+        let time = Floatx4::splat(ray.time);
+        let inverse_a = Floatx4::splat(1.0 / ray.direction.length_squared());
+        let start = Floatx4::splat(ray_trange.min);
+        let mut closest_so_far = ray_trange.max;
+        let mut closest = None;
+        for chunk in 0..self.radius.len() {
+            let mut half_b = Floatx4::splat(0.0);
+            let mut c = -self.radius[chunk] * self.radius[chunk];
+            for axis in 0..3 {
+                let center = self.center[axis][chunk] + time * self.velocity[axis][chunk];
+                let offset = origin[axis] - center;
+                half_b += offset * direction[axis];
+                c += offset * offset;
+            }
+            // the discriminant divided by a, which keeps the roots to a single multiplication
+            let discriminant = half_b * half_b * inverse_a - c;
+            let hits = discriminant.simd_ge(Floatx4::splat(0.0));
+            if !hits.any() {
+                continue;
+            }
+            let sqrtd = (discriminant * inverse_a).sqrt();
+            let end = Floatx4::splat(closest_so_far);
+            let near = -half_b * inverse_a - sqrtd;
+            let far = -half_b * inverse_a + sqrtd;
+            let near_hits = hits & near.simd_gt(start) & near.simd_lt(end);
+            let far_hits = hits & far.simd_gt(start) & far.simd_lt(end);
+            let roots = near_hits.select(near, far_hits.select(far, Floatx4::splat(INFINITY)));
+            let nearest = roots.reduce_min();
+            if nearest < closest_so_far {
+                let lane = roots.as_array().iter().position(|&t| t == nearest).unwrap();
+                closest_so_far = nearest;
+                closest = Some(chunk * 4 + lane);
+            }
+        }
+        closest.map(|index| self.hit_record(ray, index, closest_so_far))
+    }
+    fn bounding_box(&self) -> &AABB {
+        &self.bounding_box
+    }
+    fn lights(&self, lights: &mut Vec<(u32, Arc<dyn Hittable>)>) {
+        for (index, &id) in self.ids.iter().enumerate() {
+            let (chunk, lane) = (index / 4, index % 4);
+            let material = &self.materials[self.material_indices[index] as usize];
+            let moves = (0..3).any(|axis| self.velocity[axis][chunk][lane] != 0.0);
+            if !material.is_emissive() || moves {
+                continue;
+            }
+            let center = Point3::new(
+                self.center[0][chunk][lane],
+                self.center[1][chunk][lane],
+                self.center[2][chunk][lane],
+            );
+            let mut sphere = Sphere::new(center, self.radius[chunk][lane], material.clone());
+            sphere.id = id;
+            lights.push((id, Arc::new(sphere)));
+        }
+    }
+    fn report(&self, report: &mut SceneReport) {
+        let lanes = self.radius.len() * std::mem::size_of::<Floatx4>() * 7;
+        let memory = lanes + self.ids.len() * 8 + std::mem::size_of_val(self);
+        report.objects("sphere", self.ids.len(), memory);
+        for material in &self.materials {
+            report.material(material);
+        }
+        for (index, id) in self.ids.iter().enumerate() {
+            if self.radius[index / 4][index % 4] == 0.0 {
+                report.warn(format!("sphere {} has a radius of zero", id));
+            }
+        }
+    }
+}
+
+impl SphereList {
+    fn hit_record(&self, ray: &Ray, index: usize, t: Float) -> HitRecord {
+        let (chunk, lane) = (index / 4, index % 4);
+        let at = |values: &[Vec<Floatx4>; 3]| {
+            Vec3::new(
+                values[0][chunk][lane],
+                values[1][chunk][lane],
+                values[2][chunk][lane],
+            )
+        };
+        let center = at(&self.center) + ray.time * at(&self.velocity);
+        let point = ray.at(t);
+        let outward_normal = (point - center) / self.radius[chunk][lane];
+        let front_face = ray.direction.dot(&outward_normal) < 0.;
+        let (u, v) = Sphere::get_sphere_uv(&outward_normal);
+        HitRecord {
+            point,
+            normal: if front_face { 1. } else { -1. } * outward_normal,
+            material: self.materials[self.material_indices[index] as usize].clone(),
+            t,
+            u,
+            v,
+            front_face,
+            object_id: self.ids[index],
+            motion: at(&self.velocity),
+            shading_offset: Vec3::zero(),
+            temperature: 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hint::black_box;
+
+    use super::*;
+    use crate::{
+        color::Color,
+        hittable::{
+            animation::{Animated, Transform},
+            containers::HittableList,
+            materials::Lambertian,
+        },
+        random::{RandomSource, Rng},
+    };
+    use test::Bencher;
+
+    fn random_spheres(rng: &mut Rng, n: usize) -> Vec<Sphere> {
+        let material: Arc<dyn Material> = Arc::new(Lambertian::from(Color::gray(0.5)));
+        (0..n)
+            .map(|_| {
+                let center = 4.0 * Vec3::random_in_unit_sphere(rng);
+                Sphere::new(center, 0.2, material.clone())
+            })
+            .collect()
+    }
+
+    fn random_rays(rng: &mut Rng, n: usize) -> Vec<Ray> {
+        (0..n)
+            .map(|_| {
+                let origin = 10.0 * Vec3::random_in_unit_sphere(rng).unit_vector();
+                let target = Vec3::random_in_unit_sphere(rng);
+                Ray::new(origin, target - origin, rng.next_float())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hits_match_separate_spheres() {
+        let mut rng = Rng::from_seed([7, 11]);
+        let spheres = random_spheres(&mut rng, 37);
+        let moving = spheres
+            .iter()
+            .enumerate()
+            .map(|(i, sphere)| (sphere, Vec3::new(0.0, (i % 3) as Float * 0.1, 0.0)))
+            .collect::<Vec<_>>();
+        let list = SphereList::new(&moving);
+        let mut separate = HittableList::default();
+        for (sphere, motion) in &moving {
+            separate.add(Box::new(Animated::between(
+                Arc::new(Sphere::new(
+                    sphere.center,
+                    sphere.radius,
+                    sphere.material.clone(),
+                )),
+                Transform::default(),
+                Transform::new(*motion, 0.0, 1.0),
+            )));
+        }
+        for ray in random_rays(&mut rng, 1000) {
+            let expected = separate.hit(&ray, &Interval::new(0.001, INFINITY));
+            let actual = list.hit(&ray, &Interval::new(0.001, INFINITY));
+            match (expected, actual) {
+                (None, None) => {}
+                // loose enough for the `f32` feature, the normals divide by the small radius
+                (Some(expected), Some(actual)) => {
+                    assert!((expected.t - actual.t).abs() < 1e-3);
+                    assert!((expected.normal - actual.normal).length() < 1e-2);
+                    assert_eq!(expected.object_id, actual.object_id);
+                }
+                _ => panic!("the sphere list and the separate spheres disagree"),
+            }
+        }
+    }
+
+    #[bench]
+    fn bench_sphere_list(b: &mut Bencher) {
+        let mut rng = Rng::from_seed([7, 11]);
+        let spheres = random_spheres(&mut rng, BATCH_SIZE);
+        let list = SphereList::new(
+            &spheres
+                .iter()
+                .map(|s| (s, Vec3::zero()))
+                .collect::<Vec<_>>(),
+        );
+        let rays = random_rays(&mut rng, 256);
+        b.iter(|| {
+            for ray in &rays {
+                black_box(list.hit(ray, &Interval::new(0.001, INFINITY)));
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_separate_spheres(b: &mut Bencher) {
+        let mut rng = Rng::from_seed([7, 11]);
+        let mut separate = HittableList::default();
+        for sphere in random_spheres(&mut rng, BATCH_SIZE) {
+            separate.add(Box::new(sphere));
+        }
+        let rays = random_rays(&mut rng, 256);
+        b.iter(|| {
+            for ray in &rays {
+                black_box(separate.hit(ray, &Interval::new(0.001, INFINITY)));
+            }
+        });
+    }
+}
@@ -0,0 +1,448 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::UNIX_EPOCH,
+};
+
+use image::{codecs::png::PngDecoder, ColorType, ImageDecoder, ImageFormat, RgbaImage};
+use tracing::{debug, warn};
+
+use crate::{color::Color, error::Result, float::Float, vec3::Point3};
+
+use super::{
+    compound_id,
+    texture::{ImageTexture, Texture},
+};
+
+// Texels along each side of a tile, the unit textures are streamed in.
+const TILE: u32 = 64;
+const TILE_TEXELS: usize = (TILE * TILE) as usize;
+const TILE_BYTES: usize = TILE_TEXELS * 12;
+const DEFAULT_BUDGET: usize = 1 << 30;
+
+static GLOBAL_CACHE: OnceLock<TextureCache> = OnceLock::new();
+// whether a cache was installed, which `open_texture` streams textures through
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+type Tile = Arc<[[f32; 3]]>;
+
+thread_local! {
+    // the tile the thread looked at last, so runs of lookups in one tile skip the shared cache
+    static LAST_TILE: RefCell<Option<(u64, u32, Tile)>> = const { RefCell::new(None) };
+}
+
+// Keeps the tiles of `StreamedTexture`s in memory up to a budget in bytes, loading them from disk
+// when they are first looked at and dropping the ones used longest ago to make room. The textures
+// are stored on disk in `directory` as tiles of linear colors, so a tile is read without decoding
+// the whole image. Scenes with more texture than memory still render, only slower as tiles are
+// read over and over.
+#[derive(Debug)]
+pub struct TextureCache {
+    budget: usize,
+    directory: PathBuf,
+    resident: Mutex<Resident>,
+}
+
+#[derive(Debug, Default)]
+struct Resident {
+    // the tiles by texture key and tile index, with when they were last used
+    tiles: HashMap<(u64, u32), (Tile, u64)>,
+    // the same tiles by when they were last used, the one used longest ago first
+    by_use: BTreeMap<u64, (u64, u32)>,
+    bytes: usize,
+    clock: u64,
+}
+
+impl Resident {
+    // Marks the tile under `key` as used just now.
+    fn touch(&mut self, key: (u64, u32)) -> Option<Tile> {
+        self.clock += 1;
+        let clock = self.clock;
+        let (tile, last_used) = self.tiles.get_mut(&key)?;
+        self.by_use.remove(last_used);
+        self.by_use.insert(clock, key);
+        *last_used = clock;
+        Some(tile.clone())
+    }
+}
+
+impl TextureCache {
+    pub fn new<P: AsRef<Path>>(budget: usize, directory: P) -> Self {
+        Self {
+            budget,
+            directory: directory.as_ref().to_path_buf(),
+            resident: Mutex::default(),
+        }
+    }
+    // The cache used by `StreamedTexture::open`, one with a budget of a gigabyte in the temporary
+    // directory unless another has been installed.
+    pub fn global() -> &'static TextureCache {
+        GLOBAL_CACHE.get_or_init(|| {
+            TextureCache::new(DEFAULT_BUDGET, std::env::temp_dir().join("raytracer-tiles"))
+        })
+    }
+    // Makes this the global cache, which also streams the image textures of scenes, see
+    // `open_texture`.
+    pub fn install(self) {
+        if GLOBAL_CACHE.set(self).is_err() {
+            warn!("a texture cache is already installed");
+        }
+        INSTALLED.store(true, Ordering::Relaxed);
+    }
+    // The bytes of texture in memory.
+    pub fn resident_bytes(&self) -> usize {
+        self.resident.lock().unwrap().bytes
+    }
+    fn tile(&self, texture: &StreamedTexture, index: u32) -> Tile {
+        let key = (texture.key, index);
+        if let Some(tile) = self.resident.lock().unwrap().touch(key) {
+            return tile;
+        }
+        // read outside the lock so other threads keep going, two threads may both read a tile
+        let tile = texture.read_tile(index).unwrap_or_else(|e| {
+            warn!("failed to read a tile of {}: {}", texture.path.display(), e);
+            Arc::from(vec![[1.0, 0.0, 1.0]; TILE_TEXELS])
+        });
+        let mut resident = self.resident.lock().unwrap();
+        resident.clock += 1;
+        let clock = resident.clock;
+        match resident.tiles.insert(key, (tile.clone(), clock)) {
+            Some((_, last_used)) => {
+                resident.by_use.remove(&last_used);
+            }
+            None => resident.bytes += TILE_BYTES,
+        }
+        resident.by_use.insert(clock, key);
+        // the tile just read is the newest, so it stays
+        while resident.bytes > self.budget && resident.tiles.len() > 1 {
+            let (_, oldest) = resident.by_use.pop_first().unwrap();
+            resident.tiles.remove(&oldest);
+            resident.bytes -= TILE_BYTES;
+        }
+        tile
+    }
+}
+
+// Opens an image as a texture, streamed through the global cache when a memory budget was set
+// with `TextureCache::install` and held in memory whole otherwise. `linear` is for images of data
+// rather than colors, see `ImageTexture::linear`.
+pub fn open_texture<P: AsRef<Path>>(path: P, linear: bool) -> Result<Box<dyn Texture>> {
+    Ok(match (INSTALLED.load(Ordering::Relaxed), linear) {
+        (true, false) => Box::new(StreamedTexture::open(path)?),
+        (true, true) => Box::new(StreamedTexture::open_linear(path)?),
+        (false, false) => Box::new(ImageTexture::new(image::open(path)?.to_rgba8())),
+        (false, true) => Box::new(ImageTexture::linear(image::open(path)?.to_rgba8())),
+    })
+}
+
+// A 64 bit FNV-1a hash, for keys that tell apart far more textures than `stable_id`.
+fn hash64(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// Reads an image into `row`, one row of RGBA texels at a time from the top.
+type RowReader = Box<dyn FnMut(&mut [u8]) -> Result<()>>;
+
+// The rows of an image at `path`. PNGs with 8 bit channels are decoded as they are read, so only a
+// row of them is ever in memory, other images are decoded whole first.
+fn image_rows(path: &Path) -> Result<RowReader> {
+    if ImageFormat::from_path(path).ok() == Some(ImageFormat::Png) {
+        let decoder = PngDecoder::new(BufReader::new(File::open(path)?))?;
+        let channels = match decoder.color_type() {
+            ColorType::L8 => 1,
+            ColorType::La8 => 2,
+            ColorType::Rgb8 => 3,
+            ColorType::Rgba8 => 4,
+            _ => 0,
+        };
+        if channels > 0 {
+            let width = decoder.dimensions().0 as usize;
+            let mut reader = decoder.into_reader()?;
+            let mut decoded = vec![0; width * channels];
+            return Ok(Box::new(move |row: &mut [u8]| {
+                reader.read_exact(&mut decoded)?;
+                for (texel, pixel) in row.chunks_exact_mut(4).zip(decoded.chunks_exact(channels)) {
+                    let rgba = match *pixel {
+                        [l] => [l, l, l, 255],
+                        [l, a] => [l, l, l, a],
+                        [r, g, b] => [r, g, b, 255],
+                        [r, g, b, a] => [r, g, b, a],
+                        _ => unreachable!(),
+                    };
+                    texel.copy_from_slice(&rgba);
+                }
+                Ok(())
+            }));
+        }
+    }
+    let image = image::open(path)?.to_rgba8();
+    Ok(whole_image_rows(image))
+}
+
+fn whole_image_rows(image: RgbaImage) -> RowReader {
+    let mut rows = image.into_raw().into_iter();
+    Box::new(move |row: &mut [u8]| {
+        for (byte, value) in row.iter_mut().zip(rows.by_ref()) {
+            *byte = value;
+        }
+        Ok(())
+    })
+}
+
+// An image texture that stays on disk, with only the tiles being looked at kept in memory by a
+// `TextureCache`. The texture looks the same as an `ImageTexture` of the image.
+#[derive(Debug)]
+pub struct StreamedTexture {
+    width: u32,
+    height: u32,
+    columns: u32,
+    // the tiles on disk, named after `key` so textures of the same image share them
+    path: PathBuf,
+    // a hash of where the image came from and how it was decoded, which tells the textures apart
+    // in the cache
+    key: u64,
+    id: u32,
+    cache: &'static TextureCache,
+}
+
+impl StreamedTexture {
+    // Opens an sRGB encoded image for streaming through the global cache. The image is only
+    // decoded the first time, later renders read the tiles written then, until the file changes.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let decoded: [f32; 256] =
+            std::array::from_fn(|value| Color::srgb_to_linear(value as Float / 255.0) as f32);
+        Self::open_with(path.as_ref(), |value| decoded[value as usize])
+    }
+    // Like `open` for images that are linear already, see `ImageTexture::linear`.
+    pub fn open_linear<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with(path.as_ref(), |value| value as f32 / 255.0)
+    }
+    fn open_with(path: &Path, decode: impl Fn(u8) -> f32) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        let source = fs::canonicalize(path)?
+            .as_os_str()
+            .as_encoded_bytes()
+            .iter()
+            .copied()
+            .chain(metadata.len().to_le_bytes())
+            .chain(modified.to_le_bytes())
+            .collect::<Vec<_>>();
+        let (width, height) = image::image_dimensions(path)?;
+        let texture = Self::new(width, height, &source, &decode, TextureCache::global());
+        if !texture.path.exists() {
+            texture.write_tiles(image_rows(path)?, decode)?;
+        }
+        Ok(texture)
+    }
+    // Writes the tiles of the image to the cache's directory, unless they are there already.
+    pub fn from_image(
+        image: RgbaImage,
+        decode: impl Fn(u8) -> f32,
+        cache: &'static TextureCache,
+    ) -> Result<Self> {
+        let (width, height) = image.dimensions();
+        let texture = Self::new(width, height, image.as_raw(), &decode, cache);
+        if !texture.path.exists() {
+            texture.write_tiles(whole_image_rows(image), decode)?;
+        }
+        Ok(texture)
+    }
+    // A texture of an image of the size, told apart from others by the bytes of `source`.
+    fn new(
+        width: u32,
+        height: u32,
+        source: &[u8],
+        decode: &impl Fn(u8) -> f32,
+        cache: &'static TextureCache,
+    ) -> Self {
+        // the same image decoded differently makes different tiles
+        let decoded = (0..=255).flat_map(|value| decode(value).to_le_bytes());
+        let key = hash64(source.iter().copied().chain(decoded));
+        let path = cache
+            .directory
+            .join(format!("{:016x}-{}x{}.tiles", key, width, height));
+        if path.exists() {
+            debug!("reusing the tiles in {}", path.display());
+        }
+        Self {
+            width,
+            height,
+            columns: width.div_ceil(TILE),
+            path,
+            key,
+            id: compound_id("streamed", &[], &[key as u32, (key >> 32) as u32]),
+            cache,
+        }
+    }
+    // Writes the tiles a band of them at a time from the rows of the image.
+    fn write_tiles(&self, mut rows: RowReader, decode: impl Fn(u8) -> f32) -> Result<()> {
+        fs::create_dir_all(&self.cache.directory)?;
+        // written next to the final file first so other renders never see a partial one
+        let partial = self.path.with_extension("partial");
+        let mut writer = BufWriter::new(File::create(&partial)?);
+        let row_bytes = self.width as usize * 4;
+        let mut band = vec![0; row_bytes * TILE as usize];
+        for top in (0..self.height).step_by(TILE as usize) {
+            let band_height = TILE.min(self.height - top);
+            for row in band.chunks_exact_mut(row_bytes).take(band_height as usize) {
+                rows(row)?;
+            }
+            for left in (0..self.width).step_by(TILE as usize) {
+                for y in 0..TILE {
+                    for x in left..left + TILE {
+                        let texel = if y < band_height && x < self.width {
+                            let start = y as usize * row_bytes + x as usize * 4;
+                            [0, 1, 2].map(|channel| decode(band[start + channel]))
+                        } else {
+                            [0.0; 3]
+                        };
+                        for channel in texel {
+                            writer.write_all(&channel.to_le_bytes())?;
+                        }
+                    }
+                }
+            }
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&partial, &self.path)?;
+        Ok(())
+    }
+    fn read_tile(&self, index: u32) -> std::io::Result<Tile> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(index as u64 * TILE_BYTES as u64))?;
+        let mut bytes = vec![0; TILE_BYTES];
+        file.read_exact(&mut bytes)?;
+        let texels = bytes
+            .chunks_exact(12)
+            .map(|texel| {
+                let channel = |i: usize| f32::from_le_bytes(texel[i..i + 4].try_into().unwrap());
+                [channel(0), channel(4), channel(8)]
+            })
+            .collect::<Vec<_>>();
+        Ok(Arc::from(texels))
+    }
+}
+
+impl Texture for StreamedTexture {
+    fn value(&self, u: Float, v: Float, _point: &Point3) -> Color {
+        if self.width == 0 || self.height == 0 {
+            return Color::cyan();
+        }
+        let u = u.clamp(0.0, 1.0);
+        let v = 1.0 - v.clamp(0.0, 1.0);
+        let i = ((u * self.width as Float) as u32).min(self.width - 1);
+        let j = ((v * self.height as Float) as u32).min(self.height - 1);
+        let index = (j / TILE) * self.columns + i / TILE;
+        let texel = ((j % TILE) * TILE + i % TILE) as usize;
+        let [r, g, b] = LAST_TILE.with(|last| {
+            let mut last = last.borrow_mut();
+            match &*last {
+                Some((key, last_index, tile)) if *key == self.key && *last_index == index => {
+                    tile[texel]
+                }
+                _ => {
+                    let tile = self.cache.tile(self, index);
+                    let value = tile[texel];
+                    *last = Some((self.key, index, tile));
+                    value
+                }
+            }
+        });
+        Color::new(r as Float, g as Float, b as Float)
+    }
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+    use crate::{
+        hittable::texture::ImageTexture,
+        random::{RandomSource, Rng},
+    };
+
+    #[test]
+    fn tiles_stay_within_the_budget() {
+        let directory =
+            std::env::temp_dir().join(format!("raytracer-tiles-{}", std::process::id()));
+        // room for two tiles of a texture that has six
+        let cache = Box::leak(Box::new(TextureCache::new(2 * TILE_BYTES, &directory)));
+        let image = RgbaImage::from_fn(3 * TILE, 2 * TILE - 10, |x, y| {
+            Rgba([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8, 255])
+        });
+        let reference = ImageTexture::linear(image.clone());
+        let streamed =
+            StreamedTexture::from_image(image, |value| value as f32 / 255.0, cache).unwrap();
+        let mut rng = Rng::from_seed([1, 2]);
+        for _ in 0..2000 {
+            let (u, v) = (rng.next_float(), rng.next_float());
+            let expected = reference.value(u, v, &Point3::zero());
+            let actual = streamed.value(u, v, &Point3::zero());
+            assert!((expected - actual).length() < 1e-6);
+            assert!(cache.resident_bytes() <= 2 * TILE_BYTES);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn image_files_are_keyed_by_their_path() {
+        let directory =
+            std::env::temp_dir().join(format!("raytracer-streamed-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let image = RgbaImage::from_fn(TILE + 20, TILE + 5, |x, y| {
+            Rgba([(x * 3 % 256) as u8, (y * 5 % 256) as u8, 7, 255])
+        });
+        let reference = ImageTexture::new(image.clone());
+        // a PNG read a row at a time, in color and in gray, and a BMP decoded whole
+        let gray = ::image::DynamicImage::ImageRgba8(image.clone()).to_luma8();
+        let gray_reference =
+            ImageTexture::new(::image::DynamicImage::ImageLuma8(gray.clone()).to_rgba8());
+        let [color_png, gray_png, bmp] =
+            ["color.png", "gray.png", "color.bmp"].map(|name| directory.join(name));
+        ::image::DynamicImage::ImageRgba8(image.clone())
+            .to_rgb8()
+            .save(&color_png)
+            .unwrap();
+        gray.save(&gray_png).unwrap();
+        ::image::DynamicImage::ImageRgba8(image)
+            .to_rgb8()
+            .save(&bmp)
+            .unwrap();
+        let textures =
+            [&color_png, &gray_png, &bmp].map(|path| StreamedTexture::open(path).unwrap());
+        let mut rng = Rng::from_seed([5, 6]);
+        for _ in 0..500 {
+            let (u, v) = (rng.next_float(), rng.next_float());
+            let expected = reference.value(u, v, &Point3::zero());
+            for texture in [&textures[0], &textures[2]] {
+                assert!((expected - texture.value(u, v, &Point3::zero())).length() < 1e-5);
+            }
+            let gray = gray_reference.value(u, v, &Point3::zero());
+            assert!((gray - textures[1].value(u, v, &Point3::zero())).length() < 1e-5);
+        }
+        // the same texels in another file are another texture
+        assert_ne!(textures[0].key, textures[2].key);
+        for texture in &textures {
+            fs::remove_file(&texture.path).unwrap();
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,51 +1,66 @@
-use std::{
-    fmt::Debug,
-    ops::{Div, Rem, Shl},
-    result,
-};
+use std::fmt::Debug;
 
-use image::{ImageBuffer, RgbaImage};
+use image::RgbaImage;
 
 use crate::{
     color::Color,
-    random::Rng,
+    float::Float,
+    random::{RandomSource, Rng},
     vec3::{Point3, Vec3},
 };
 
+use super::{compound_id, report::SceneReport, stable_id};
+
+// the default methods ignore what they are given
+#[allow(unused_variables)]
 pub trait Texture: Send + Sync + Debug {
-    fn value(&self, u: f64, v: f64, point: &Point3) -> Color;
+    fn value(&self, u: Float, v: Float, point: &Point3) -> Color;
+    // The ID of the texture, made from its settings when the texture is made, that the IDs of the
+    // materials using it are made from.
+    fn id(&self) -> u32;
+    // Adds the memory the texture holds on to, like the texels of images, to `report`.
+    fn report(&self, report: &mut SceneReport) {}
 }
 
 #[derive(Debug)]
 pub struct SolidColor {
     color: Color,
+    id: u32,
 }
 
 impl SolidColor {}
 
 impl From<Color> for SolidColor {
     fn from(color: Color) -> Self {
-        Self { color }
+        Self {
+            color,
+            id: compound_id("solid", &[color.r, color.g, color.b], &[]),
+        }
     }
 }
 
 impl Texture for SolidColor {
-    fn value(&self, _u: f64, _v: f64, _point: &Point3) -> Color {
+    fn value(&self, _u: Float, _v: Float, _point: &Point3) -> Color {
         self.color
     }
+    fn id(&self) -> u32 {
+        self.id
+    }
 }
 
 #[derive(Debug)]
 pub struct CheckerTexture {
-    inv_scale: f64,
+    inv_scale: Float,
     odd: Box<dyn Texture>,
     even: Box<dyn Texture>,
+    id: u32,
 }
 
 impl CheckerTexture {
-    pub fn new(scale: f64, odd: Box<dyn Texture>, even: Box<dyn Texture>) -> Self {
+    pub fn new(scale: Float, odd: Box<dyn Texture>, even: Box<dyn Texture>) -> Self {
         Self {
             inv_scale: 1.0 / scale,
+            id: compound_id("checker", &[scale], &[odd.id(), even.id()]),
             odd,
             even,
         }
@@ -53,60 +68,116 @@ impl CheckerTexture {
 }
 
 impl Texture for CheckerTexture {
-    fn value(&self, u: f64, v: f64, point: &Point3) -> Color {
+    fn value(&self, u: Float, v: Float, point: &Point3) -> Color {
         let x = (point.x * self.inv_scale).floor() as i32;
         let y = (point.y * self.inv_scale).floor() as i32;
         let z = (point.z * self.inv_scale).floor() as i32;
-        if ((x + y + z).rem_euclid(2) == 0) {
+        if (x + y + z).rem_euclid(2) == 0 {
             self.odd.value(u, v, point)
         } else {
             self.even.value(u, v, point)
         }
     }
+    fn id(&self) -> u32 {
+        self.id
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.memory += std::mem::size_of_val(self.odd.as_ref());
+        report.memory += std::mem::size_of_val(self.even.as_ref());
+        self.odd.report(report);
+        self.even.report(report);
+    }
 }
 
+// Image files store colors sRGB encoded, so they are converted to linear colors when the texture
+// is made. Images that hold data rather than colors, like roughness or normal maps, are stored
+// linearly already and are loaded with `ImageTexture::linear` instead.
 #[derive(Debug)]
 pub struct ImageTexture {
-    image: RgbaImage,
+    width: u32,
+    height: u32,
+    texels: Vec<Color>,
+    id: u32,
 }
 
 impl ImageTexture {
     pub fn new(image: RgbaImage) -> Self {
-        Self { image }
+        let decoded: [Float; 256] =
+            std::array::from_fn(|value| Color::srgb_to_linear(value as Float / 255.0));
+        Self::from_image("image", image, |value| decoded[value as usize])
+    }
+    pub fn linear(image: RgbaImage) -> Self {
+        Self::from_image("linear", image, |value| value as Float / 255.0)
+    }
+    // The alpha channel of the image as a gray texture, for the opacity of a `Cutout`.
+    pub fn alpha(image: RgbaImage) -> Self {
+        let texels = image
+            .pixels()
+            .map(|pixel| Color::gray(pixel[3] as Float / 255.0))
+            .collect();
+        Self {
+            width: image.width(),
+            height: image.height(),
+            texels,
+            id: Self::image_id("alpha", &image),
+        }
+    }
+    fn from_image(kind: &str, image: RgbaImage, decode: impl Fn(u8) -> Float) -> Self {
+        let texels = image
+            .pixels()
+            .map(|pixel| Color::new(decode(pixel[0]), decode(pixel[1]), decode(pixel[2])))
+            .collect();
+        Self {
+            width: image.width(),
+            height: image.height(),
+            texels,
+            id: Self::image_id(kind, &image),
+        }
+    }
+    fn image_id(kind: &str, image: &RgbaImage) -> u32 {
+        let size = [image.width() as Float, image.height() as Float];
+        compound_id(kind, &size, &[stable_id(image.as_raw())])
     }
 }
 
 impl Texture for ImageTexture {
-    fn value(&self, u: f64, v: f64, point: &Point3) -> Color {
-        if self.image.width() == 0 || self.image.height() == 0 {
+    fn value(&self, u: Float, v: Float, _point: &Point3) -> Color {
+        if self.width == 0 || self.height == 0 {
             return Color::cyan();
         }
 
         let u = u.clamp(0.0, 1.0);
         let v = 1.0 - v.clamp(0.0, 1.0);
 
-        let i = (u * self.image.width() as f64) as u32;
-        let j = (v * self.image.height() as f64) as u32;
-        let pixel = self.image.get_pixel(i, j);
-        Color::new(pixel[0] as f64, pixel[1] as f64, pixel[2] as f64) / 255.0
+        let i = ((u * self.width as Float) as u32).min(self.width - 1);
+        let j = ((v * self.height as Float) as u32).min(self.height - 1);
+        self.texels[(j * self.width + i) as usize]
+    }
+    fn id(&self) -> u32 {
+        self.id
+    }
+    fn report(&self, report: &mut SceneReport) {
+        report.memory += self.texels.len() * std::mem::size_of::<Color>();
     }
 }
 
 #[derive(Debug)]
 pub struct NoiseTexture {
-    inv_scale: f64,
+    inv_scale: Float,
+    id: u32,
 }
 
 impl NoiseTexture {
-    pub fn new(scale: f64) -> Self {
+    pub fn new(scale: Float) -> Self {
         Self {
             inv_scale: 1.0 / scale,
+            id: compound_id("noise", &[scale], &[]),
         }
     }
 }
 
 impl Texture for NoiseTexture {
-    fn value(&self, _u: f64, _v: f64, point: &Point3) -> Color {
+    fn value(&self, _u: Float, _v: Float, point: &Point3) -> Color {
         let x = point.x * self.inv_scale;
         let y = point.y * self.inv_scale;
         let z = point.z * self.inv_scale;
@@ -114,14 +185,15 @@ impl Texture for NoiseTexture {
         let iy = y.floor() as i32;
         let iz = z.floor() as i32;
 
-        let linear_to_piecewise_quadratic = |x: f64| {
+        #[allow(unused)]
+        let linear_to_piecewise_quadratic = |x: Float| {
             if x < 0.5 {
                 2. * x.powi(2)
             } else {
                 1.0 - 2.0 * (x - 1.0).powi(2)
             }
         };
-        let linear_to_hermite_cubic = |x: f64| x.powi(2) * (3.0 - 2.0 * x);
+        let linear_to_hermite_cubic = |x: Float| x.powi(2) * (3.0 - 2.0 * x);
 
         let x_blend = linear_to_hermite_cubic(x.rem_euclid(1.0));
         let y_blend = linear_to_hermite_cubic(y.rem_euclid(1.0));
@@ -141,16 +213,20 @@ impl Texture for NoiseTexture {
 
         let result = o0.blend(&o1, z_blend);
 
-        result
+        Color::from(result)
+    }
+    fn id(&self) -> u32 {
+        self.id
     }
 }
 
+#[allow(unused)]
 fn noise_at(x: i32, y: i32, z: i32) -> Color {
     let a = x as u64;
     let b = (y as u64).wrapping_add((z as u64).wrapping_shl(32));
     let mut rng = Rng::from_seed([a, b]);
     rng.short_jump();
-    Color::gray(rng.next_f64())
+    Color::gray(rng.next_float())
 }
 
 fn random_vec_at(x: i32, y: i32, z: i32) -> Vec3 {
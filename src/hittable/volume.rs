@@ -0,0 +1,329 @@
+use std::sync::Arc;
+
+use crate::{
+    color::Color,
+    error::{Error, Result},
+    float::Float,
+    interval::Interval,
+    random::{RandomSource, Rng},
+    ray::Ray,
+    vec3::{Point3, Vec3},
+};
+
+use super::{
+    aabb::AABB, compound_id, materials::Material, report::SceneReport, stable_id, HitRecord,
+    Hittable,
+};
+
+// Values on a regular grid of points spanning the unit cube, interpolated trilinearly between
+// them. Grids are indexed by x first, then y and then z.
+#[derive(Debug, Clone)]
+pub struct VoxelGrid {
+    resolution: [usize; 3],
+    values: Vec<Float>,
+}
+
+impl VoxelGrid {
+    pub fn new(resolution: [usize; 3], values: Vec<Float>) -> Result<Self> {
+        if resolution.iter().any(|&n| n < 2) {
+            return Err(Error::InvalidVolume(format!(
+                "voxel grids need at least two points along every axis, current value: {:?}",
+                resolution
+            )));
+        }
+        let points = resolution.iter().product::<usize>();
+        if values.len() != points {
+            return Err(Error::InvalidVolume(format!(
+                "a grid of {:?} points needs {} values, current value: {}",
+                resolution,
+                points,
+                values.len()
+            )));
+        }
+        Ok(Self { resolution, values })
+    }
+    // A grid of the values of `f` at the grid points, given their position in the unit cube.
+    pub fn from_fn(resolution: [usize; 3], f: impl Fn(Point3) -> Float) -> Result<Self> {
+        let [nx, ny, nz] = resolution;
+        let at = |i: usize, n: usize| i as Float / (n - 1) as Float;
+        let mut values = Vec::with_capacity(nx * ny * nz);
+        for z in 0..nz {
+            for y in 0..ny {
+                for x in 0..nx {
+                    values.push(f(Point3::new(at(x, nx), at(y, ny), at(z, nz))));
+                }
+            }
+        }
+        Self::new(resolution, values)
+    }
+    fn max(&self) -> Float {
+        self.values.iter().copied().fold(0.0, Float::max)
+    }
+    // The value at a point in the unit cube.
+    fn sample(&self, point: Point3) -> Float {
+        let mut base = [0; 3];
+        let mut fraction = [0.0; 3];
+        for axis in 0..3 {
+            let n = self.resolution[axis];
+            let position = point[axis].clamp(0.0, 1.0) * (n - 1) as Float;
+            base[axis] = (position as usize).min(n - 2);
+            fraction[axis] = position - base[axis] as Float;
+        }
+        let [nx, ny, _] = self.resolution;
+        let mut value = 0.0;
+        for corner in 0..8 {
+            let offset = [corner & 1, corner >> 1 & 1, corner >> 2];
+            let mut weight = 1.0;
+            for axis in 0..3 {
+                weight *= if offset[axis] == 1 {
+                    fraction[axis]
+                } else {
+                    1.0 - fraction[axis]
+                };
+            }
+            let [x, y, z] = [0, 1, 2].map(|axis| base[axis] + offset[axis]);
+            value += weight * self.values[(z * ny + y) * nx + x];
+        }
+        value
+    }
+}
+
+// A participating medium filling a box, like smoke, fire or a nebula, with its density given by a
+// grid as the chance per unit of distance of light hitting a particle. Particles scatter light
+// evenly in every direction, keeping `albedo` of it, and with a temperature grid they also glow
+// with the color of a black body as hot as the grid says. Paths through the volume are traced with
+// delta tracking, so hits are points in the volume where the path meets a particle and rays that
+// make it through without meeting one miss the volume altogether.
+#[derive(Debug)]
+pub struct Volume {
+    bounds: AABB,
+    density: VoxelGrid,
+    // the largest density, which collisions are proposed with before being accepted in proportion
+    // to the density where they land
+    majorant: Float,
+    material: Arc<Glow>,
+    id: u32,
+}
+
+// The light of a volume where a path meets a particle, glowing with the `temperature` of the hit.
+#[derive(Debug)]
+struct Glow {
+    albedo: Color,
+    temperature: Option<VoxelGrid>,
+    // the radiance of particles at 1000 K, which grows with the fourth power of the temperature
+    strength: Float,
+    id: u32,
+}
+
+impl Glow {
+    // The glow of the volume with the object ID `volume`, which the material ID is made from as
+    // the temperature grid is too large to hash.
+    fn new(albedo: Color, temperature: Option<VoxelGrid>, strength: Float, volume: u32) -> Self {
+        Self {
+            albedo,
+            temperature,
+            strength,
+            id: compound_id("glow", &[albedo.r, albedo.g, albedo.b, strength], &[volume]),
+        }
+    }
+}
+
+impl Volume {
+    pub fn new(bounds: AABB, density: VoxelGrid, albedo: Color) -> Self {
+        let id = stable_id(
+            &[
+                bounds.x.min,
+                bounds.x.max,
+                bounds.y.min,
+                bounds.y.max,
+                bounds.z.min,
+                bounds.z.max,
+            ]
+            .map(|value| (value as f32).to_le_bytes())
+            .concat(),
+        );
+        Self {
+            bounds,
+            majorant: density.max(),
+            density,
+            material: Arc::new(Glow::new(albedo, None, 0.0, id)),
+            id,
+        }
+    }
+    // Makes the volume glow with the temperature in kelvin from the grid. Glowing particles absorb
+    // the light they don't scatter and give off their own in its place, so with an albedo of one
+    // they don't glow at all.
+    pub fn with_emission(self, temperature: VoxelGrid, strength: Float) -> Self {
+        Self {
+            material: Arc::new(Glow::new(
+                self.material.albedo,
+                Some(temperature),
+                strength,
+                self.id,
+            )),
+            ..self
+        }
+    }
+    // The object ID of the volume in hit records.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+    fn local(&self, point: Point3) -> Point3 {
+        let axis = |value: Float, interval: &Interval| (value - interval.min) / interval.size();
+        Point3::new(
+            axis(point.x, &self.bounds.x),
+            axis(point.y, &self.bounds.y),
+            axis(point.z, &self.bounds.z),
+        )
+    }
+}
+
+impl Hittable for Volume {
+    fn hit(&self, ray: &Ray, ray_trange: &Interval) -> Option<HitRecord> {
+        let inside = self.bounds.hit(ray)?;
+        let mut t = inside.min.max(ray_trange.min);
+        let end = inside.max.min(ray_trange.max);
+        if t >= end || self.majorant <= 0.0 {
+            return None;
+        }
+        // hits take no random source, the ray itself seeds the distances so the same ray always
+        // meets the same particles
+        let mut rng = ray_rng(ray);
+        let speed = ray.direction.length();
+        loop {
+            t -= (1.0 - rng.next_float()).ln() / (self.majorant * speed);
+            if t >= end {
+                return None;
+            }
+            let point = ray.at(t);
+            let local = self.local(point);
+            if rng.next_float() * self.majorant < self.density.sample(local) {
+                let temperature = self.material.temperature.as_ref();
+                return Some(HitRecord {
+                    point,
+                    normal: Vec3::new(1.0, 0.0, 0.0),
+                    material: self.material.clone(),
+                    t,
+                    u: 0.0,
+                    v: 0.0,
+                    front_face: true,
+                    object_id: self.id,
+                    motion: Vec3::zero(),
+                    shading_offset: Vec3::zero(),
+                    temperature: temperature.map_or(0.0, |grid| grid.sample(local)),
+                });
+            }
+        }
+    }
+    fn bounding_box(&self) -> &AABB {
+        &self.bounds
+    }
+    fn report(&self, report: &mut SceneReport) {
+        let grid = self.density.values.len() * std::mem::size_of::<Float>();
+        report.objects("volume", 1, std::mem::size_of_val(self) + grid);
+        report.material(&(self.material.clone() as Arc<dyn Material>));
+    }
+}
+
+impl Material for Glow {
+    fn scatter(
+        &self,
+        rng: &mut dyn RandomSource,
+        ray: &Ray,
+        hit_record: &HitRecord,
+    ) -> Option<(Color, Ray)> {
+        let direction = Vec3::random_on_unit_sphere(rng);
+        Some((self.albedo, Ray::new(hit_record.point, direction, ray.time)))
+    }
+    fn albedo(&self, _hit_record: &HitRecord) -> Color {
+        self.albedo
+    }
+    fn emitted(&self, hit_record: &HitRecord, _lit_object: Option<u32>) -> Color {
+        let temperature = hit_record.temperature;
+        if self.temperature.is_none() || temperature <= 0.0 {
+            return Color::black();
+        }
+        let absorbed = Color::white() - self.albedo;
+        let radiance = self.strength * (temperature / 1000.0).powi(4);
+        absorbed * Color::blackbody(temperature) * radiance
+    }
+    fn report(&self, report: &mut SceneReport) {
+        if let Some(temperature) = &self.temperature {
+            report.memory += temperature.values.len() * std::mem::size_of::<Float>();
+            if self.strength > 0.0 {
+                report.emissive();
+            }
+        }
+    }
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+// A random source seeded by the bits of a ray, mixed with the splitmix64 finalizer.
+fn ray_rng(ray: &Ray) -> Rng {
+    let mix = |values: [Float; 4]| {
+        let bytes = values.iter().flat_map(|value| value.to_le_bytes());
+        bytes.fold(0x9e3779b97f4a7c15_u64, |hash, byte| {
+            let mut z = hash ^ byte as u64;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+            z ^ (z >> 31)
+        })
+    };
+    let (origin, direction) = (ray.origin, ray.direction);
+    Rng::from_seed([
+        mix([origin.x, origin.y, origin.z, ray.time]),
+        mix([direction.x, direction.y, direction.z, ray.time]) | 1,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rays_thin_out_through_the_volume() {
+        let bounds = AABB::from_vecs(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0));
+        // twice as dense on the far side, the optical depth across is 1.5
+        let density = VoxelGrid::from_fn([2, 2, 2], |point| 1.0 + point.x).unwrap();
+        assert_eq!(density.sample(Point3::new(0.25, 0.5, 0.5)), 1.25);
+        let volume = Volume::new(bounds.clone(), density, Color::gray(0.5));
+        let through = (0..4000)
+            .filter(|&i| {
+                let y = (i as Float + 0.5) / 4000.0;
+                let ray = Ray::new(Point3::new(-1.0, y, 0.5), Vec3::new(2.0, 0.0, 0.0), 0.0);
+                volume
+                    .hit(&ray, &Interval::new(0.001, Float::INFINITY))
+                    .is_none()
+            })
+            .count();
+        let expected = (-1.5 as Float).exp();
+        assert!((through as Float / 4000.0 - expected).abs() < 0.02);
+
+        let hot = VoxelGrid::from_fn([2, 2, 2], |_| 1000.0).unwrap();
+        let fire = Volume::new(
+            bounds,
+            VoxelGrid::from_fn([2, 2, 2], |_| 50.0).unwrap(),
+            Color::black(),
+        )
+        .with_emission(hot, 2.0);
+        let ray = Ray::new(Point3::new(0.5, 0.5, -1.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        let hit = fire
+            .hit(&ray, &Interval::new(0.001, Float::INFINITY))
+            .unwrap();
+        assert!(hit.point.z < 0.2);
+        let glow = hit.material.emitted(&hit, None);
+        assert!((glow.luminance() - 2.0).abs() < 0.01);
+        assert!(glow.r > glow.g && glow.g > glow.b);
+        assert_eq!(hit.u, 0.0);
+    }
+
+    #[test]
+    fn grids_must_fit_their_values() {
+        assert!(VoxelGrid::new([2, 2, 2], vec![1.0; 8]).is_ok());
+        assert!(VoxelGrid::new([2, 2, 2], vec![1.0; 7]).is_err());
+        assert!(VoxelGrid::new([1, 2, 2], vec![1.0; 4]).is_err());
+        assert!(VoxelGrid::from_fn([2, 0, 2], |_| 1.0).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, Scope};
+use std::time::Duration;
+
+use ::image::{codecs::jpeg::JpegEncoder, Rgb, RgbImage};
+use tracing::{debug, info, warn};
+
+use crate::{
+    camera::{
+        aov::{Layer, TileUpdate},
+        color_space::ColorSpace,
+        RenderControl,
+    },
+    color::Color,
+    float::Float,
+};
+
+// How often the stream sends a new frame.
+const FRAME_INTERVAL: Duration = Duration::from_millis(500);
+// How long a browser may take to send its request or to take a frame before it's hung up on.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<title>raytracer</title>
+<img src="/stream" style="max-width: 100%"><br>
+<button onclick="fetch('/pause', {method: 'POST'})">pause</button>
+<button onclick="fetch('/cancel', {method: 'POST'})">cancel</button>
+exposure <input type="range" min="-4" max="4" step="0.5" value="0"
+    onchange="fetch('/exposure?stops=' + this.value, {method: 'POST'})">
+<pre id="status"></pre>
+<script>
+setInterval(async () => {
+    document.getElementById('status').textContent = await (await fetch('/status')).text();
+}, 1000);
+</script>
+"#;
+
+// A live preview of a headless render over HTTP, to keep an eye on long renders on remote machines
+// from a browser. The page at `/` shows the render with buttons to pause and cancel it and a
+// slider for the exposure of the preview, which leaves the output as it is. The calls behind them
+// are there for scripts as well:
+//
+//     GET /            the page
+//     GET /stream      the render as an MJPEG stream, a new frame every half second
+//     GET /frame.jpg   the render as it is right now
+//     GET /status      the progress as JSON
+//     POST /pause      pauses or resumes the render
+//     POST /cancel     cancels the render, keeping the finished tiles
+//     POST /exposure?stops=1.5
+pub struct PreviewServer {
+    listener: TcpListener,
+    width: usize,
+    height: usize,
+    image: Mutex<Vec<Color>>,
+    rendered: AtomicUsize,
+    exposure: Mutex<Float>,
+    done: AtomicBool,
+}
+
+impl PreviewServer {
+    pub fn bind<A: ToSocketAddrs>(address: A, width: usize, height: usize) -> io::Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        info!("serving the preview on http://{}", listener.local_addr()?);
+        Ok(Self {
+            listener,
+            width,
+            height,
+            image: Mutex::new(vec![Color::black(); width * height]),
+            rendered: AtomicUsize::new(0),
+            exposure: Mutex::new(0.0),
+            done: AtomicBool::new(false),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Stands in for the preview window, taking the tiles of the render into the preview image until
+    // the render hangs up. The server stops then too.
+    pub fn receive(&self, receiver: Receiver<TileUpdate>) {
+        for (layer, (top, left), (height, width), colors) in receiver {
+            if layer != Layer::Beauty {
+                continue;
+            }
+            let mut image = self.image.lock().unwrap();
+            for y in 0..height {
+                image[(top + y) * self.width + left..][..width]
+                    .copy_from_slice(&colors[y * width..][..width]);
+            }
+            // post processed images replace the whole image rather than adding to it
+            let rendered = self.rendered.load(Ordering::Relaxed) + height * width;
+            self.rendered
+                .store(rendered.min(self.width * self.height), Ordering::Relaxed);
+        }
+        self.done.store(true, Ordering::Relaxed);
+    }
+
+    // Keeps accepting browsers until the render is done, each one served on a thread of its own.
+    // The browsers still connected then are hung up on once the streams have sent their last
+    // frame, so they don't keep the scope open after the render.
+    pub fn serve<'s>(&'s self, s: &'s Scope<'s, '_>, control: &'s RenderControl) {
+        s.spawn(move || {
+            let mut clients: Vec<Arc<TcpStream>> = Vec::new();
+            while !self.done.load(Ordering::Relaxed) {
+                match self.listener.accept() {
+                    Ok((stream, _)) => {
+                        // the thread serving a browser holds the other reference to its stream
+                        clients.retain(|client| Arc::strong_count(client) > 1);
+                        let stream = Arc::new(stream);
+                        clients.push(stream.clone());
+                        s.spawn(move || {
+                            if let Err(e) = self.handle(&stream, control) {
+                                debug!("preview connection closed: {}", e);
+                            }
+                            // the list of clients holds on to the stream, so dropping it won't do
+                            let _ = stream.shutdown(Shutdown::Both);
+                        });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                    Err(e) => {
+                        warn!("failed to accept a preview connection: {}", e);
+                        break;
+                    }
+                }
+            }
+            thread::sleep(FRAME_INTERVAL);
+            for client in clients {
+                // the browser may have gone away already
+                let _ = client.shutdown(Shutdown::Both);
+            }
+        });
+    }
+
+    fn handle(&self, stream: &TcpStream, control: &RenderControl) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut reader = BufReader::new(stream);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        // the headers are of no interest, but they have to be read past
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+        let (method, target) = match request.split_whitespace().collect::<Vec<_>>()[..] {
+            [method, target, _] => (method, target),
+            _ => return respond(stream, "400 Bad Request", "text/plain", b"bad request"),
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        debug!(method, target, "preview request");
+        match (method, path) {
+            ("GET", "/") => respond(stream, "200 OK", "text/html", PAGE.as_bytes()),
+            ("GET", "/frame.jpg") => respond(stream, "200 OK", "image/jpeg", &self.frame()?),
+            ("GET", "/stream") => self.stream(stream),
+            ("GET", "/status") => {
+                let status = self.status(control);
+                respond(stream, "200 OK", "application/json", status.as_bytes())
+            }
+            ("POST", "/pause") => {
+                let paused = control.toggle_pause();
+                info!(paused, "toggled pause from the preview server");
+                respond(stream, "204 No Content", "text/plain", b"")
+            }
+            ("POST", "/cancel") => {
+                info!("cancelled from the preview server");
+                control.cancel();
+                respond(stream, "204 No Content", "text/plain", b"")
+            }
+            ("POST", "/exposure") => {
+                let stops = query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("stops="))
+                    .and_then(|stops| stops.parse().ok());
+                match stops {
+                    Some(stops) => {
+                        *self.exposure.lock().unwrap() = stops;
+                        respond(stream, "204 No Content", "text/plain", b"")
+                    }
+                    None => respond(
+                        stream,
+                        "400 Bad Request",
+                        "text/plain",
+                        b"the exposure needs a number of stops",
+                    ),
+                }
+            }
+            _ => respond(stream, "404 Not Found", "text/plain", b"not found"),
+        }
+    }
+
+    fn status(&self, control: &RenderControl) -> String {
+        format!(
+            "{{\"width\": {}, \"height\": {}, \"rendered_pixels\": {}, \"paused\": {}, \"cancelled\": {}, \"done\": {}, \"exposure\": {}}}",
+            self.width,
+            self.height,
+            self.rendered.load(Ordering::Relaxed),
+            control.is_paused(),
+            control.is_cancelled(),
+            self.done.load(Ordering::Relaxed),
+            *self.exposure.lock().unwrap()
+        )
+    }
+
+    // The image so far as a JPEG, at the exposure of the preview.
+    fn frame(&self) -> io::Result<Vec<u8>> {
+        let gain = (2.0 as Float).powf(*self.exposure.lock().unwrap());
+        let image = self.image.lock().unwrap();
+        let color_space = ColorSpace::default();
+        let image = RgbImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let color = image[y as usize * self.width + x as usize] * gain;
+            let (r, g, b) = color_space.encode(color).into_u8();
+            Rgb([r, g, b])
+        });
+        let mut bytes = Vec::new();
+        JpegEncoder::new_with_quality(&mut bytes, 85)
+            .encode_image(&image)
+            .map_err(io::Error::other)?;
+        Ok(bytes)
+    }
+
+    // Sends a frame every `FRAME_INTERVAL` until the render is done or the browser goes away.
+    fn stream(&self, mut stream: &TcpStream) -> io::Result<()> {
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Type: multipart/x-mixed-replace; boundary=frame\r\n\
+              Cache-Control: no-cache\r\n\r\n",
+        )?;
+        loop {
+            // the last frame goes out after the render is done, so it shows the whole image
+            let done = self.done.load(Ordering::Relaxed);
+            let frame = self.frame()?;
+            write!(
+                stream,
+                "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                frame.len()
+            )?;
+            stream.write_all(&frame)?;
+            stream.write_all(b"\r\n")?;
+            if done {
+                return Ok(());
+            }
+            thread::sleep(FRAME_INTERVAL);
+        }
+    }
+}
+
+fn respond(
+    mut stream: &TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::sync::mpsc::sync_channel;
+
+    use super::*;
+
+    fn request(server: &PreviewServer, request: &str) -> Vec<u8> {
+        let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        write!(stream, "{}\r\nHost: localhost\r\n\r\n", request).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_the_render_and_takes_commands() {
+        let server = PreviewServer::bind("127.0.0.1:0", 4, 2).unwrap();
+        let control = RenderControl::default();
+        let (sender, receiver) = sync_channel(4);
+        thread::scope(|s| {
+            server.serve(s, &control);
+            let tile = vec![Color::gray(0.5); 4];
+            sender.send((Layer::Beauty, (0, 0), (1, 4), tile)).unwrap();
+            drop(sender);
+            // the server stops accepting once the render is done, so ask before then
+            let status = request(&server, "GET /status HTTP/1.1");
+            assert!(String::from_utf8_lossy(&status).contains("\"paused\": false"));
+            request(&server, "POST /pause HTTP/1.1");
+            assert!(control.is_paused());
+            request(&server, "POST /exposure?stops=-1 HTTP/1.1");
+            assert_eq!(*server.exposure.lock().unwrap(), -1.0);
+            let not_found = request(&server, "GET /nothing HTTP/1.1");
+            assert!(not_found.starts_with(b"HTTP/1.1 404"));
+            server.receive(receiver);
+        });
+        assert_eq!(server.rendered.load(Ordering::Relaxed), 4);
+        let frame = server.frame().unwrap();
+        assert_eq!(frame[..2], [0xff, 0xd8]);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::float::{Float, INFINITY, NEG_INFINITY};
+
+// A closed interval of floats, for the extents of bounding boxes and the range of distances a hit
+// is looked for in. Unlike `Range` it is `Copy` and says whether the ends are included where it
+// is used. The two ends are plain fields next to each other so they can be loaded into SIMD lanes.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub min: Float,
+    pub max: Float,
+}
+
+impl Interval {
+    pub const EMPTY: Self = Self::new(INFINITY, NEG_INFINITY);
+    pub const UNIVERSE: Self = Self::new(NEG_INFINITY, INFINITY);
+
+    pub const fn new(min: Float, max: Float) -> Self {
+        Self { min, max }
+    }
+    pub fn size(&self) -> Float {
+        self.max - self.min
+    }
+    // Whether `value` is in the interval, ends included.
+    pub fn contains(&self, value: Float) -> bool {
+        self.min <= value && value <= self.max
+    }
+    // Whether `value` is in the interval, ends excluded.
+    pub fn surrounds(&self, value: Float) -> bool {
+        self.min < value && value < self.max
+    }
+    pub fn clamp(&self, value: Float) -> Float {
+        value.max(self.min).min(self.max)
+    }
+    // The interval grown by `delta` in total, half on each end.
+    pub fn expand(&self, delta: Float) -> Self {
+        let padding = delta / 2.;
+        Self::new(self.min - padding, self.max + padding)
+    }
+    pub fn union(&self, other: &Self) -> Self {
+        Self::new(self.min.min(other.min), self.max.max(other.max))
+    }
+    pub fn middle(&self) -> Float {
+        (self.min + self.max) / 2.
+    }
+    // The same interval ending at `max`, for narrowing the search as closer hits are found.
+    pub fn with_max(&self, max: Float) -> Self {
+        Self::new(self.min, max)
+    }
+}
+
+impl std::ops::Add<Float> for Interval {
+    type Output = Self;
+    fn add(self, offset: Float) -> Self {
+        Self::new(self.min + offset, self.max + offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_and_universe() {
+        assert!(!Interval::EMPTY.contains(0.0));
+        assert!(Interval::UNIVERSE.surrounds(1e30));
+        assert_eq!(
+            Interval::EMPTY.union(&Interval::new(1.0, 2.0)),
+            Interval::new(1.0, 2.0)
+        );
+        assert_eq!(Interval::new(1.0, 2.0).clamp(3.0), 2.0);
+        assert!(!Interval::new(1.0, 2.0).surrounds(2.0));
+    }
+}
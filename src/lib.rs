@@ -0,0 +1,36 @@
+#![cfg_attr(test, feature(test))]
+#![feature(portable_simd)]
+
+// The renderer as a library, for driving it from other programs. The binary in `main.rs` is a thin
+// command line front end on top of it.
+
+pub mod batch;
+pub mod bench;
+pub mod camera;
+pub mod color;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod float;
+#[cfg(any(feature = "preview", test))]
+mod font;
+pub mod hittable;
+pub mod http;
+pub mod interval;
+pub mod network;
+pub mod prelude;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod random;
+pub mod ray;
+pub mod scene;
+pub mod telemetry;
+#[cfg(feature = "preview")]
+pub mod ui;
+pub mod units;
+pub mod vec3;
+#[cfg(feature = "web")]
+pub mod web;
+
+#[cfg(test)]
+extern crate test;
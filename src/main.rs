@@ -1,69 +1,490 @@
 #![allow(unused)]
 #![feature(test)]
 
-use std::sync::mpsc::SyncSender;
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, SyncSender};
 use std::time::Instant;
 
-use camera::{builder::CameraBuilder, image::ImageSpecBuilder};
-use color::Color;
-use hittable::Hittable;
-use random::Rng;
-use scene::{book_cover, composition, Scene};
-use vec3::{Point3, Vec3};
+use raytracer::batch;
+use raytracer::bench;
+use raytracer::camera::{
+    aov::{Layer, TileUpdate},
+    buffer::RenderBuffer,
+    builder::CameraBuilder,
+    color_space::{ColorSpace, Primaries},
+    image::ImageSpecBuilder,
+    post,
+    probe::ReflectionProbe,
+    tiff::{write_float_tiff, TiffLayout},
+    RenderControl,
+};
+use raytracer::color::Color;
+use raytracer::error::{Error, Result};
+use raytracer::float::Float;
+use raytracer::hittable::{bvh_cache::BVHCache, streaming::TextureCache, Hittable};
+use raytracer::http::PreviewServer;
+use raytracer::network::{self, Coordinator, RenderJob};
+use raytracer::scene::{self, FrameSequence, Scene, SceneDescriptor, SceneSource};
+use raytracer::telemetry;
+#[cfg(feature = "preview")]
+use raytracer::ui;
+use tracing::{info, Level};
 
-use crate::scene::{two_spheres, earth, something_blocky};
-
-mod camera;
-mod color;
-mod hittable;
-mod random;
-mod range;
-mod ray;
-mod scene;
-mod ui;
-mod vec3;
-
-fn main() {
-    let image_spec = ImageSpecBuilder::default()
-        .width(3840 / 3)
-        //.width(3840 / 2)
-        .aspect_ratio((16.0 / 3.) / (9.0 / 2.))
-        .aspect_ratio(16.0 / 9.0)
-        .build();
+fn main() -> Result<()> {
+    let mut worker_address = None;
+    let mut bench = false;
+    let mut merge_paths = None;
+    let mut queue = None;
+    let mut bvh_cache = None;
+    let mut memory_budget = None;
+    let mut coordinator_address = None;
+    let mut scene_file = None;
+    let mut http_address = None;
+    let mut verbosity = 0;
+    let mut explore = false;
+    let mut aovs = false;
+    let mut clay = false;
+    let mut tile_heatmap = false;
+    let mut sample_heatmap = false;
+    let mut bvh_view = None;
+    let mut accelerator = None;
+    let mut vignetting = false;
+    let mut path_guiding = false;
+    let mut light_candidates = None;
+    let mut light_mixture = false;
+    let mut seed = 0;
+    let mut save_buffer = false;
+    let mut hdr_output = false;
+    let mut tiff_layout = None;
+    let mut padded_sampler = false;
+    let mut sobol_sampler = false;
+    let mut post_process = Vec::new();
+    let mut color_space = ColorSpace::default();
+    let mut float_primaries = Primaries::default();
+    let mut white_balance = None;
+    let mut exposure_bracket = Vec::new();
+    let mut aperture_mask = None;
+    let mut cameras = Vec::new();
+    let mut frames = None;
+    let mut fps = 24.0;
+    let mut omnidirectional_stereo = None;
+    let mut reflection_probe = None;
+    let mut headless = !cfg!(feature = "preview");
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "worker" => {
+                let address = flag_value(&mut args, "worker needs the address of a coordinator")?;
+                worker_address = Some(address);
+            }
+            "bench" => bench = true,
+            "batch" => {
+                queue = Some(flag_value(&mut args, "batch needs a queue file")?);
+            }
+            "merge" => {
+                // the merged output followed by the buffers merged into it
+                let paths = args.by_ref().collect::<Vec<_>>();
+                if paths.len() < 2 {
+                    return Err(Error::InvalidArgument(
+                        "merge needs an output and the buffers to merge".to_string(),
+                    ));
+                }
+                merge_paths = Some(paths);
+            }
+            "--memory-budget" => {
+                let megabytes = flag_value(&mut args, "--memory-budget needs a size in megabytes")?;
+                let megabytes: usize =
+                    parse_number(&megabytes, "the memory budget must be a number")?;
+                memory_budget = Some(megabytes << 20);
+            }
+            "--bvh-cache" => {
+                bvh_cache = Some(flag_value(&mut args, "--bvh-cache needs a directory")?);
+            }
+            "-v" | "--verbose" => verbosity += 1,
+            "-q" | "--quiet" => verbosity -= 1,
+            "--explore" => explore = true,
+            "--headless" => headless = true,
+            "--http" => {
+                // the browser stands in for the preview window
+                http_address = Some(flag_value(&mut args, "--http needs an address")?);
+                headless = true;
+            }
+            "--aovs" => aovs = true,
+            "--clay" => clay = true,
+            "--tile-heatmap" => tile_heatmap = true,
+            "--sample-heatmap" => sample_heatmap = true,
+            "--bvh-view" => {
+                let view = flag_value(&mut args, "--bvh-view needs boxes, overlay or heat")?;
+                bvh_view = Some(view.parse()?);
+            }
+            "--accelerator" => {
+                let name = flag_value(&mut args, "--accelerator needs bvh, grid or kd-tree")?;
+                accelerator = Some(name.parse()?);
+            }
+            "--vignetting" => vignetting = true,
+            "--guiding" => path_guiding = true,
+            "--light-mixture" => light_mixture = true,
+            "--light-candidates" => {
+                let count = flag_value(&mut args, "--light-candidates needs a number")?;
+                light_candidates = Some(parse_number(&count, "the count must be a number")?);
+            }
+            "--seed" => {
+                let value = flag_value(&mut args, "--seed needs a number")?;
+                seed = parse_number(&value, "the seed must be a number")?;
+            }
+            "--frames" => {
+                let mut frame = || -> Result<usize> {
+                    let value = flag_value(&mut args, "--frames needs the first and last frame")?;
+                    parse_number(&value, "frames must be numbers")
+                };
+                frames = Some((frame()?, frame()?));
+            }
+            "--fps" => {
+                let value = flag_value(&mut args, "--fps needs a number")?;
+                fps = parse_number(&value, "the frame rate must be a number")?;
+            }
+            "--save-buffer" => save_buffer = true,
+            "--hdr" => hdr_output = true,
+            "--tiff" => {
+                let layout = flag_value(&mut args, "--tiff needs strips:<rows> or tiles:<w>x<h>")?;
+                tiff_layout = Some(layout.parse()?);
+            }
+            "--padded-sampler" => padded_sampler = true,
+            "--sobol-sampler" => sobol_sampler = true,
+            "--post" => {
+                let pipeline = flag_value(&mut args, "--post needs a list of stages")?;
+                post_process = post::parse_pipeline(&pipeline)?;
+            }
+            "--color-space" => {
+                let name = flag_value(&mut args, "--color-space needs a color space")?;
+                color_space = name.parse()?;
+            }
+            "--float-primaries" => {
+                let name = flag_value(&mut args, "--float-primaries needs primaries")?;
+                float_primaries = name.parse()?;
+            }
+            "--white-balance" => {
+                let kelvin = flag_value(&mut args, "--white-balance needs a temperature")?;
+                white_balance = Some(parse_number(&kelvin, "the white balance must be a number")?);
+            }
+            "--bracket" => exposure_bracket = vec![-2.0, 0.0, 2.0],
+            "--aperture-mask" => {
+                aperture_mask = Some(flag_value(&mut args, "--aperture-mask needs an image")?);
+            }
+            "--ods" => {
+                let distance = flag_value(&mut args, "--ods needs the distance between the eyes")?;
+                let distance = parse_number(&distance, "the distance must be a number")?;
+                omnidirectional_stereo = Some(distance);
+            }
+            "--probe" => {
+                let probe = flag_value(&mut args, "--probe needs equirect or cube")?;
+                reflection_probe = Some(probe.parse::<ReflectionProbe>()?);
+            }
+            "--camera" => {
+                let name = flag_value(&mut args, "--camera needs a name and a preset")?;
+                let preset = flag_value(&mut args, "--camera needs a name and a preset")?;
+                cameras.push((name, preset));
+            }
+            "--scene-file" => {
+                scene_file = Some(flag_value(&mut args, "--scene-file needs a JSON scene")?);
+            }
+            "--coordinator" => {
+                coordinator_address =
+                    Some(flag_value(&mut args, "--coordinator needs an address")?);
+            }
+            _ => return Err(Error::InvalidArgument(format!("unknown argument: {}", arg))),
+        }
+    }
+    let max_level = match verbosity {
+        ..=-1 => Level::WARN,
+        0 => Level::INFO,
+        1 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(max_level)
+        .with_target(false)
+        .init();
+    if let Some(directory) = bvh_cache {
+        BVHCache::new(directory).install();
+    }
+    if let Some(budget) = memory_budget {
+        let directory = std::env::temp_dir().join("raytracer-tiles");
+        TextureCache::new(budget, directory).install();
+    }
+    if let Some(address) = worker_address {
+        return network::run_worker(address);
+    }
+    if let Some(paths) = merge_paths {
+        return merge(&paths[0], &paths[1..]);
+    }
+    if bench {
+        // one line of JSON per scene on stdout, the log goes to stderr
+        for result in bench::run()? {
+            println!("{}", result.to_json());
+        }
+        return Ok(());
+    }
 
-    let camera = CameraBuilder::default()
-        .image_spec(image_spec.clone())
-        .uniform_sampler(10_usize.pow(2))
-        .uniform_sampler(6_usize.pow(2))
-        .max_ray_depth(16)
-        //.random_sampler(9_usize.pow(2))
-        .defocus_angle(0.2)
-        //.focus_distance(10.0)
-     ;
+    let job = RenderJob {
+        scene: "something_blocky".to_string(),
+        width: 3840 / 3,
+        //width: 3840 / 2,
+        //aspect_ratio: (16.0 / 3.) / (9.0 / 2.),
+        aspect_ratio: 16.0 / 9.0,
+        //samples_per_pixel: 10_usize.pow(2),
+        samples_per_pixel: 6_usize.pow(2),
+        max_ray_depth: 16,
+        defocus_angle: 0.2,
+    };
+    if let Some(queue) = queue {
+        // jobs without a preset render with the default settings, not the ones given here
+        let jobs = batch::parse_queue(&std::fs::read_to_string(queue)?)?;
+        let results = batch::run(&jobs, || job.camera_builder());
+        print!("{}", batch::summary(&results));
+        return Ok(());
+    }
+    let camera = job
+        .camera_builder()
+        .aovs(aovs)
+        .clay(clay)
+        .tile_heatmap(tile_heatmap)
+        .sample_heatmap(sample_heatmap)
+        .vignetting(vignetting)
+        .path_guiding(path_guiding)
+        .light_mixture(light_mixture)
+        .seed(seed)
+        .save_buffer(save_buffer)
+        .hdr_output(hdr_output)
+        .post_process(post_process)
+        .color_space(color_space)
+        .float_primaries(float_primaries)
+        .exposure_bracket(exposure_bracket);
+    let camera = match white_balance {
+        Some(kelvin) => camera.white_balance(kelvin),
+        None => camera,
+    };
+    let camera = match aperture_mask {
+        Some(path) => camera.aperture_mask(path),
+        None => camera,
+    };
+    let camera = match bvh_view {
+        Some(view) => camera.bvh_view(view),
+        None => camera,
+    };
+    let camera = match accelerator {
+        Some(accelerator) => camera.accelerator(accelerator),
+        None => camera,
+    };
+    let camera = match light_candidates {
+        Some(count) => camera.light_candidates(count),
+        None => camera,
+    };
+    let camera = match tiff_layout {
+        Some(layout) => camera.tiff_layout(layout),
+        None => camera,
+    };
+    let camera = if sobol_sampler {
+        camera.sobol_sampler(job.samples_per_pixel)
+    } else if padded_sampler {
+        camera.padded_sampler(job.samples_per_pixel)
+    } else {
+        camera
+    };
+    // both eyes of the panorama are as wide as the image and half as tall
+    let camera = match omnidirectional_stereo {
+        Some(distance) => camera
+            .image_spec(
+                ImageSpecBuilder::default()
+                    .width(job.width)
+                    .aspect_ratio(1.0)
+                    .build(),
+            )
+            .omnidirectional_stereo(distance),
+        None => camera,
+    };
+    // as wide as the faces of the probe allow
+    let camera = match reflection_probe {
+        Some(probe) => camera
+            .image_spec(
+                ImageSpecBuilder::default()
+                    .width(job.width / probe.aspect_ratio() * probe.aspect_ratio())
+                    .aspect_ratio(probe.aspect_ratio() as Float)
+                    .build(),
+            )
+            .reflection_probe(probe),
+        None => camera,
+    };
+    if let Some((first, last)) = frames {
+        let sequence = FrameSequence::new(first, last, fps);
+        let frames = sequence.render(&job.scene, &camera, &RenderControl::default())?;
+        for (frame, stats) in (first..).zip(frames) {
+            info!(
+                "frame {} done in {:.3} seconds",
+                frame,
+                stats.elapsed.as_secs_f64()
+            );
+        }
+        return Ok(());
+    }
+    let source = match scene_file {
+        Some(path) => {
+            SceneSource::Described(SceneDescriptor::from_json(&std::fs::read_to_string(path)?)?)
+        }
+        None => SceneSource::Named(job.scene.clone()),
+    };
+    let coordinator = coordinator_address
+        .map(|address| Coordinator::bind(address, &source, &camera))
+        .transpose()?;
+    let image_spec = camera.image_spec.clone().unwrap();
+    let http = http_address
+        .map(|address| PreviewServer::bind(address, image_spec.width, image_spec.height))
+        .transpose()?;
 
+    if explore && headless {
+        return Err(Error::InvalidArgument(
+            "exploring needs the preview window".to_string(),
+        ));
+    }
+    if sample_heatmap && headless {
+        return Err(Error::InvalidArgument(
+            "the sample heatmap needs the preview window to refine the image".to_string(),
+        ));
+    }
+    let start_time = Instant::now();
+    let mut scene = telemetry::time_stage("scene build", || source.build(camera))?;
+    for (name, preset) in cameras {
+        let preset = std::fs::read_to_string(preset)?;
+        scene.add_camera(&name, CameraBuilder::from_config(&preset)?)?;
+    }
+    let settings = scene.camera.settings();
+    let control = RenderControl::default();
     std::thread::scope(|s| {
         let (sender, receiver) = std::sync::mpsc::sync_channel(64);
-        s.spawn(move || {
-            ui::sdl_thread(image_spec.width, image_spec.height, receiver);
+        let (control_sender, control_receiver) = std::sync::mpsc::channel();
+        let (request_sender, request_receiver) = std::sync::mpsc::channel();
+        let control = &control;
+        let scene = &scene;
+        let http = &http;
+        if let Some(server) = http {
+            server.serve(s, control);
+        }
+        let preview = s.spawn(move || -> Result<()> {
+            if headless {
+                // nobody asks for anything more, let the render thread finish
+                drop(request_sender);
+                match http {
+                    Some(server) => server.receive(receiver),
+                    None => print_progress(image_spec.width * image_spec.height, receiver),
+                }
+                return Ok(());
+            }
+            #[cfg(feature = "preview")]
+            ui::sdl_thread(
+                image_spec.width,
+                image_spec.height,
+                receiver,
+                explore.then_some(control_sender),
+                request_sender,
+                settings,
+                control,
+            )?;
+            Ok(())
         });
-        s.spawn(move || {
-            let start_time = Instant::now();
-
-            // slet scene = two_spheres(camera);
-            let scene = something_blocky(camera);
-            render_thread(scene, sender);
+        let render = s.spawn(move || -> Result<()> {
+            if explore {
+                return scene.explore(sender, control_receiver);
+            }
+            let image_buffer = render_thread(scene, sender.clone(), coordinator.as_ref(), control)?;
             let elapsed = start_time.elapsed().as_secs_f64();
-            println!("Done in {:.3} seconds", elapsed);
+            info!("done in {:.3} seconds", elapsed);
+            print!("{}", telemetry::report());
+            for (name, stats) in scene.render_cameras(control)? {
+                info!(
+                    "{} done in {:.3} seconds",
+                    name,
+                    stats.elapsed.as_secs_f64()
+                );
+            }
+            scene.serve_requests(image_buffer, sender, request_receiver, control)
         });
-    });
+        // a failing preview hangs up on the render thread, which then stops on its own
+        preview.join().unwrap()?;
+        render.join().unwrap()
+    })
+}
+
+// The value after a flag, or an error saying what the flag needs when there is none.
+fn flag_value(args: &mut impl Iterator<Item = String>, needs: &str) -> Result<String> {
+    args.next()
+        .ok_or_else(|| Error::InvalidArgument(needs.to_string()))
+}
+
+// A number on the command line, or an error with `message` and what was given instead.
+fn parse_number<T: FromStr>(value: &str, message: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidArgument(format!("{}, not {}", message, value)))
 }
 
 fn render_thread(
-    scene: Scene<Box<dyn Hittable>>,
-    sender: SyncSender<((usize, usize), (usize, usize), Vec<Color>)>,
-) {
-    scene.render(sender);
+    scene: &Scene<Box<dyn Hittable>>,
+    sender: SyncSender<TileUpdate>,
+    coordinator: Option<&Coordinator>,
+    control: &RenderControl,
+) -> Result<Vec<Color>> {
+    scene.render_distributed(sender, coordinator, control)
+}
+
+// Averages the buffers of renders with different seeds into `output`, another buffer when it ends
+// in `.buffer`, a Radiance file when it ends in `.hdr`, a float TIFF in tiles when it ends in `.tif`
+// and otherwise an image in linear color like an EXR.
+fn merge(output: &str, inputs: &[String]) -> Result<()> {
+    let buffers = inputs
+        .iter()
+        .map(RenderBuffer::read)
+        .collect::<Result<Vec<_>>>()?;
+    let merged = RenderBuffer::merge(&buffers)?;
+    if output.ends_with(".buffer") {
+        merged.write(output)?;
+    } else if output.ends_with(".hdr") {
+        merged.write_hdr(output)?;
+    } else if output.ends_with(".tif") || output.ends_with(".tiff") {
+        write_float_tiff(
+            output,
+            merged.width,
+            merged.height,
+            &merged.colors,
+            TiffLayout::default(),
+        )?;
+    } else {
+        merged.to_float_image().save(output)?;
+    }
+    info!(
+        "merged {} buffers into {} with {} samples per pixel",
+        buffers.len(),
+        output,
+        merged.samples
+    );
+    Ok(())
+}
+
+// Stands in for the preview window when running headless.
+fn print_progress(pixel_count: usize, receiver: Receiver<TileUpdate>) {
+    let mut rendered = 0;
+    let mut reported = 0;
+    for (layer, _, rect, _) in receiver {
+        if layer != Layer::Beauty {
+            continue;
+        }
+        rendered += rect.0 * rect.1;
+        let percent = rendered * 100 / pixel_count;
+        if percent >= reported + 10 {
+            reported = percent - percent % 10;
+            info!("{}% rendered", reported);
+        }
+    }
 }
 
 extern crate test;
@@ -72,8 +493,8 @@ extern crate test;
 mod tests {
     use std::hint::black_box;
 
-    use super::*;
-    use crate::random::Rng;
+    use raytracer::random::Rng;
+    use raytracer::vec3::Vec3;
     use test::Bencher;
 
     #[bench]
@@ -0,0 +1,436 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread::{self, Scope};
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    camera::{builder::CameraBuilder, image::ImageSpecBuilder, tiles::TileWorker},
+    color::Color,
+    error::{Error, Result},
+    float::{self, Float},
+    hittable::Hittable,
+    scene::{self, Scene, SceneDescriptor, SceneSource},
+    telemetry,
+};
+
+// How long a worker may take to answer before its tile is rendered here instead. Far longer than
+// a tile takes on any worker that is still there, see `Coordinator::worker_timeout`.
+const WORKER_TIMEOUT: Duration = Duration::from_secs(120);
+
+// The scene and the main settings of a render, what the command line and the benchmarks start
+// from.
+#[derive(Debug, Clone)]
+pub struct RenderJob {
+    pub scene: String,
+    pub width: usize,
+    pub aspect_ratio: Float,
+    pub samples_per_pixel: usize,
+    pub max_ray_depth: usize,
+    pub defocus_angle: Float,
+}
+
+impl RenderJob {
+    pub fn camera_builder(&self) -> CameraBuilder {
+        let image_spec = ImageSpecBuilder::default()
+            .width(self.width)
+            .aspect_ratio(self.aspect_ratio)
+            .build();
+        CameraBuilder::default()
+            .image_spec(image_spec)
+            .uniform_sampler(self.samples_per_pixel)
+            .max_ray_depth(self.max_ray_depth)
+            .defocus_angle(self.defocus_angle)
+    }
+    pub fn build_scene(&self) -> Result<Scene<Box<dyn Hittable>>> {
+        scene::from_name(&self.scene, self.camera_builder())
+    }
+}
+
+// Accepts remote workers and feeds them tiles from the queue of a running render. The workers are
+// sent the whole camera config, see `CameraBuilder::to_config`, and the scene, by name for the
+// scenes built from code and serialized for described ones, and build the same scene locally.
+// Their tiles come out the same as the ones rendered here. Meshes are sent along with their
+// descriptions but image textures are still loaded from their paths on the workers.
+pub struct Coordinator {
+    listener: TcpListener,
+    // The line the scene is sent as, see `Coordinator::job`.
+    scene: String,
+    config: String,
+    timeout: Duration,
+}
+
+impl Coordinator {
+    // Fails for scenes `scene::from_name` doesn't know, which the workers couldn't build, and for
+    // described scenes whose files can't be read.
+    pub fn bind<A: ToSocketAddrs>(
+        address: A,
+        scene: &SceneSource,
+        camera_builder: &CameraBuilder,
+    ) -> Result<Self> {
+        let scene = match scene {
+            SceneSource::Named(name) if scene::is_named(name) => format!("NAMED {}", name),
+            SceneSource::Named(name) => return Err(Error::UnknownScene(name.to_string())),
+            SceneSource::Described(descriptor) => {
+                // the workers don't have the files of the coordinator
+                let mut descriptor = descriptor.clone();
+                descriptor.embed()?;
+                format!("DESCRIBED {}", descriptor.to_json()?)
+            }
+        };
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        info!("waiting for workers on {}", listener.local_addr()?);
+        Ok(Self {
+            listener,
+            scene,
+            config: camera_builder.to_config(),
+            timeout: WORKER_TIMEOUT,
+        })
+    }
+    // How long to wait for a worker to send back a tile before giving up on it.
+    pub fn worker_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // The job sent to every worker, the number of config lines that follow and then the scene on
+    // a line of its own.
+    fn job(&self) -> String {
+        let lines = self.config.lines().collect::<Vec<_>>();
+        let config = lines
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect::<String>();
+        format!("JOB {}\n{}{}\n", lines.len(), config, self.scene)
+    }
+
+    // Keeps accepting workers until every tile has been handed out or the render is cancelled.
+    pub(crate) fn serve<'s>(&'s self, s: &'s Scope<'s, '_>, worker: &'s TileWorker) {
+        s.spawn(move || {
+            while !worker.queue.lock().unwrap().is_empty() && !worker.control.is_cancelled() {
+                match self.listener.accept() {
+                    Ok((stream, address)) => {
+                        info!("worker {} connected", address);
+                        s.spawn(move || self.handle(stream, worker));
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(Duration::from_millis(50));
+                    }
+                    Err(e) => {
+                        warn!("failed to accept worker: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+
+    fn handle(&self, stream: TcpStream, worker: &TileWorker) {
+        let mut connection = match Connection::new(stream, self.timeout) {
+            Ok(connection) => connection,
+            Err(_) => return,
+        };
+        if connection.send(&self.job()).is_err() {
+            return;
+        }
+        // building the scene can take longer than any tile, so rather than timing out the worker
+        // is waited on for as long as there are tiles it could take
+        loop {
+            match connection.receive() {
+                Ok(line) if line == "READY\n" => break,
+                Ok(line) => {
+                    warn!("worker failed: {}", protocol_error(&line));
+                    return;
+                }
+                Err(e) if timed_out(&e) => {
+                    if worker.queue.lock().unwrap().is_empty() || worker.control.is_cancelled() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    warn!("worker failed: {}", e);
+                    return;
+                }
+            }
+        }
+        while let Some((top_left, rect)) = worker.claim() {
+            worker.control.start_tile((top_left, rect));
+            debug!(?top_left, ?rect, "sending a tile to a worker");
+            match connection.render_remotely(top_left, rect) {
+                Ok(result) => worker.complete(top_left, rect, result, Vec::new()),
+                Err(e) => {
+                    // the worker is gone or hung, render its last tile here so it isn't lost
+                    warn!("worker failed: {}", e);
+                    let result =
+                        worker
+                            .camera
+                            .render_tile(top_left, rect, worker.world, worker.control);
+                    telemetry::flush();
+                    if worker.control.is_cancelled() {
+                        worker.control.finish_tile((top_left, rect));
+                    } else {
+                        worker.complete(top_left, rect, result, Vec::new());
+                    }
+                    return;
+                }
+            }
+        }
+        let _ = connection.send("DONE\n");
+    }
+}
+
+// Connects to a coordinator and renders the tiles it assigns until it is done.
+pub fn run_worker<A: ToSocketAddrs>(address: A) -> Result<()> {
+    let mut connection = Connection::new(TcpStream::connect(address)?, WORKER_TIMEOUT)?;
+    let (source, camera_builder) = receive_job(&mut connection)?;
+    let scene = source.build(camera_builder)?;
+    match &source {
+        SceneSource::Named(name) => info!("rendering {}", name),
+        SceneSource::Described(_) => info!("rendering the scene of the coordinator"),
+    }
+    connection.send("READY\n")?;
+    loop {
+        let line = connection.receive()?;
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        match fields[..] {
+            ["TILE", y, x, height, width] => {
+                let top_left = (parse_field(y)?, parse_field(x)?);
+                let rect = (parse_field(height)?, parse_field(width)?);
+                let result = scene.render_tile(top_left, rect);
+                connection.send_colors(&result)?;
+            }
+            ["DONE"] => return Ok(()),
+            _ => return Err(protocol_error(&line).into()),
+        }
+    }
+}
+
+// Reads the job a coordinator sends first, see `Coordinator::job`.
+fn receive_job(connection: &mut Connection) -> Result<(SceneSource, CameraBuilder)> {
+    let line = connection.receive()?;
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    let ["JOB", lines] = fields[..] else {
+        return Err(protocol_error(&line).into());
+    };
+    let mut config = String::new();
+    for _ in 0..parse_field::<usize>(lines)? {
+        config += &connection.receive()?;
+    }
+    let line = connection.receive()?;
+    let source = match line.trim_end().split_once(' ') {
+        Some(("NAMED", name)) => SceneSource::Named(name.to_string()),
+        Some(("DESCRIBED", json)) => SceneSource::Described(SceneDescriptor::from_json(json)?),
+        _ => return Err(protocol_error(&line).into()),
+    };
+    Ok((source, CameraBuilder::from_config(&config)?))
+}
+
+// A line based protocol, colors are sent as little endian f64 triples. Reads and writes fail
+// after `timeout` so a peer that is gone without hanging up can't block the other forever.
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    // What a read that timed out got of a line, kept for the next one.
+    line: String,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, timeout: Duration) -> io::Result<Self> {
+        stream.set_nonblocking(false)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            line: String::new(),
+        })
+    }
+    fn send(&mut self, line: &str) -> io::Result<()> {
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.flush()
+    }
+    fn receive(&mut self) -> io::Result<String> {
+        if self.reader.read_line(&mut self.line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(std::mem::take(&mut self.line))
+    }
+    fn render_remotely(
+        &mut self,
+        top_left: (usize, usize),
+        rect: (usize, usize),
+    ) -> io::Result<Vec<Color>> {
+        self.send(&format!(
+            "TILE {} {} {} {}\n",
+            top_left.0, top_left.1, rect.0, rect.1
+        ))?;
+        self.receive_colors(rect.0 * rect.1)
+    }
+    fn send_colors(&mut self, colors: &[Color]) -> io::Result<()> {
+        for color in colors {
+            for channel in [color.r, color.g, color.b] {
+                self.writer
+                    .write_all(&float::to_f64(channel).to_le_bytes())?;
+            }
+        }
+        self.writer.flush()
+    }
+    fn receive_colors(&mut self, count: usize) -> io::Result<Vec<Color>> {
+        let mut bytes = vec![0; count * 3 * 8];
+        self.reader.read_exact(&mut bytes)?;
+        let channels = bytes
+            .chunks_exact(8)
+            .map(|b| f64::from_le_bytes(b.try_into().unwrap()) as Float)
+            .collect::<Vec<_>>();
+        Ok(channels
+            .chunks_exact(3)
+            .map(|c| Color::new(c[0], c[1], c[2]))
+            .collect())
+    }
+}
+
+// Timeouts are reported as either kind depending on the platform.
+fn timed_out(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+fn parse_field<T: std::str::FromStr>(field: &str) -> io::Result<T> {
+    field.parse().map_err(|_| protocol_error(field))
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected message: {}", message.trim()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::mpsc;
+
+    use super::*;
+    use crate::camera::{test_camera, RenderControl};
+
+    // Renders `source` on one local thread with `worker` connected to the coordinator, and checks
+    // that the image comes out the same as without it.
+    fn assert_distributed_render_matches(
+        name: &str,
+        source: &SceneSource,
+        coordinator: impl FnOnce(Coordinator) -> Coordinator,
+        worker: impl FnOnce(SocketAddr) + Send,
+    ) {
+        let output = std::env::temp_dir()
+            .join(format!("raytracer-{}-{}", name, std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        // enough samples that the worker connects long before the tiles run out
+        let camera_builder = test_camera(256, 1.0)
+            .random_sampler(16)
+            .output(output.clone());
+        let coordinator =
+            coordinator(Coordinator::bind("127.0.0.1:0", source, &camera_builder).unwrap());
+        let address = coordinator.listener.local_addr().unwrap();
+        let scene = source.build(camera_builder).unwrap();
+        let expected = scene
+            .render_with(&RenderControl::default(), |_| {})
+            .unwrap();
+        // a single local thread leaves tiles for the worker
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let actual = thread::scope(|s| {
+            s.spawn(|| worker(address));
+            let (sender, receiver) = mpsc::sync_channel(64);
+            s.spawn(move || receiver.into_iter().count());
+            pool.install(|| {
+                scene.render_distributed(sender, Some(&coordinator), &RenderControl::default())
+            })
+            .unwrap()
+        });
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(&expected) {
+            assert_eq!(
+                (actual.r, actual.g, actual.b),
+                (expected.r, expected.g, expected.b)
+            );
+        }
+        std::fs::remove_file(format!("{}.ppm", output)).unwrap();
+    }
+
+    #[test]
+    fn tiles_of_hung_workers_are_rendered_here() {
+        let (tile_sender, tile_receiver) = mpsc::channel();
+        let (hang_up, hung_up) = mpsc::channel::<()>();
+        assert_distributed_render_matches(
+            "hung-worker",
+            &SceneSource::Named("two_spheres".to_string()),
+            |coordinator| coordinator.worker_timeout(Duration::from_millis(200)),
+            move |address| {
+                // takes a tile and then never answers, nor hangs up
+                let stream = TcpStream::connect(address).unwrap();
+                let mut connection = Connection::new(stream, Duration::from_secs(10)).unwrap();
+                receive_job(&mut connection).unwrap();
+                connection.send("READY\n").unwrap();
+                let tile = connection.receive().unwrap();
+                tile_sender.send(tile).unwrap();
+                thread::spawn(move || {
+                    let _ = hung_up.recv();
+                    drop(connection);
+                });
+            },
+        );
+        assert!(tile_receiver.recv().unwrap().starts_with("TILE "));
+        drop(hang_up);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn described_scenes_are_sent_to_workers() {
+        use crate::{
+            color::Color,
+            hittable::descriptor::{MaterialDescriptor, ObjectDescriptor},
+            vec3::Point3,
+        };
+
+        let path = std::env::temp_dir().join(format!("raytracer-quad-{}.obj", std::process::id()));
+        std::fs::write(&path, "v -1 0 -1\nv 1 0 -1\nv 1 0 1\nv -1 0 1\nf 1 2 3 4\n").unwrap();
+        let gray = || MaterialDescriptor::Lambertian {
+            albedo: Color::gray(0.5).into(),
+        };
+        let mut descriptor = SceneDescriptor {
+            lookfrom: Some(Point3::new(0.0, 2.0, 4.0)),
+            lookat: Some(Point3::new(0.0, 0.0, 0.0)),
+            field_of_view: Some(40.0),
+            objects: vec![
+                ObjectDescriptor::Sphere {
+                    center: Point3::new(0.0, 0.5, 0.0),
+                    radius: 0.5,
+                    material: gray(),
+                },
+                ObjectDescriptor::Mesh {
+                    path: path.to_string_lossy().into_owned(),
+                    obj: None,
+                    material: gray(),
+                },
+            ],
+        };
+        // with the file gone the mesh can only be built from the text the descriptor carries
+        descriptor.embed().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_distributed_render_matches(
+            "described",
+            &SceneSource::Described(descriptor),
+            |coordinator| coordinator,
+            |address| run_worker(address).unwrap(),
+        );
+    }
+}
@@ -0,0 +1,39 @@
+// The types needed to set up and render a scene, for `use raytracer::prelude::*`.
+
+pub use crate::camera::{
+    bloom::Bloom,
+    buffer::RenderBuffer,
+    builder::CameraBuilder,
+    color_space::{ColorSpace, Primaries},
+    events::{RenderStats, TileEvent},
+    fog::Fog,
+    image::{ImageSpec, ImageSpecBuilder},
+    post::{PostProcess, PostStage, Tonemap},
+    render_layers::RenderLayer,
+    shutter::ShutterCurve,
+    sky::Sky,
+    Camera, RenderControl,
+};
+pub use crate::color::Color;
+pub use crate::error::Error;
+pub use crate::float::Float;
+pub use crate::hittable::{
+    animation::{Animated, Animation, Transform},
+    containers::HittableList,
+    descriptor::{MaterialDescriptor, TextureDescriptor},
+    geometry::Sphere,
+    instance::{Instance, TopLevelBVH},
+    lod::LevelOfDetail,
+    materials::{Cutout, Dielectric, DiffuseLight, Lambertian, LightLinks, Material, Metal},
+    mesh::{Face, MaterialSlot, Mesh, MeshData},
+    nodes::NodeMaterial,
+    streaming::{StreamedTexture, TextureCache},
+    texture::{CheckerTexture, ImageTexture, NoiseTexture, SolidColor, Texture},
+    volume::{Volume, VoxelGrid},
+    Hittable,
+};
+pub use crate::random::RandomSource;
+pub use crate::ray::Ray;
+pub use crate::scene::{FrameSequence, Scene, SceneHandle};
+pub use crate::units::Units;
+pub use crate::vec3::{Point3, Vec3};
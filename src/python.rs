@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use pyo3::{
+    exceptions::{PyKeyError, PyOSError, PyValueError},
+    prelude::*,
+    types::{PyBytes, PyDict},
+};
+
+use crate::{
+    camera::builder::CameraBuilder,
+    color::Color,
+    error::Error,
+    float::Float,
+    hittable::{
+        containers::HittableList,
+        geometry::Sphere,
+        materials::{Dielectric, Lambertian, Material, Metal},
+    },
+    scene::Scene,
+    vec3::Point3,
+};
+
+// A Python module for building scenes and rendering them from notebooks and other tools, built
+// with `maturin build`, see `pyproject.toml`. Camera settings are given as a dict with the names
+// used in camera config files, materials as dicts with a `type` and its parameters, and rendered
+// images come back as numpy arrays of linear colors:
+//
+//     scene = raytracer.Scene({"width": 320, "aspect_ratio": 1.5, "random_sampler": 16,
+//                              "max_ray_depth": 8, "lookfrom": [0, 1, 2]})
+//     scene.add_spheres(centers, radii, {"type": "metal", "color": [0.8, 0.8, 0.8], "fuzz": 0.1})
+//     image = scene.render()
+#[pyclass(name = "Scene")]
+pub struct PyScene {
+    camera: String,
+    spheres: Vec<(Point3, Float, Arc<dyn Material>)>,
+}
+
+#[pymethods]
+impl PyScene {
+    #[new]
+    fn new(camera: &Bound<'_, PyDict>) -> PyResult<Self> {
+        let mut config = String::new();
+        for (name, value) in camera {
+            let value = if let Ok(value) = value.extract::<bool>() {
+                value.to_string()
+            } else if let Ok([x, y, z]) = value.extract::<[Float; 3]>() {
+                format!("{} {} {}", x, y, z)
+            } else {
+                value.str()?.to_string()
+            };
+            config.push_str(&format!("{} = {}\n", name, value));
+        }
+        // parsed here so mistakes show up when the scene is made rather than when rendering
+        CameraBuilder::from_config(&config)?;
+        Ok(Self {
+            camera: config,
+            spheres: Vec::new(),
+        })
+    }
+
+    fn add_sphere(
+        &mut self,
+        center: [Float; 3],
+        radius: Float,
+        material: &Bound<'_, PyDict>,
+    ) -> PyResult<()> {
+        let [x, y, z] = center;
+        self.spheres
+            .push((Point3::new(x, y, z), radius, to_material(material)?));
+        Ok(())
+    }
+
+    // Adds spheres sharing one material, `centers` is anything shaped like an n×3 array.
+    fn add_spheres(
+        &mut self,
+        centers: Vec<[Float; 3]>,
+        radii: Vec<Float>,
+        material: &Bound<'_, PyDict>,
+    ) -> PyResult<()> {
+        if centers.len() != radii.len() {
+            return Err(PyValueError::new_err(
+                "centers and radii must have the same length",
+            ));
+        }
+        let material = to_material(material)?;
+        for ([x, y, z], radius) in centers.into_iter().zip(radii) {
+            self.spheres
+                .push((Point3::new(x, y, z), radius, material.clone()));
+        }
+        Ok(())
+    }
+
+    // Renders the scene into a height×width×3 float32 numpy array.
+    fn render(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let camera = CameraBuilder::from_config(&self.camera)?.build()?;
+        let mut world = HittableList::default();
+        for (center, radius, material) in &self.spheres {
+            world.add(Box::new(Sphere::new(*center, *radius, material.clone())));
+        }
+        let scene = Scene::new(camera, world.into_bvh());
+        let image = py.allow_threads(|| scene.render_to_float_image());
+        let (width, height) = image.dimensions();
+        let bytes = image
+            .into_raw()
+            .into_iter()
+            .flat_map(f32::to_ne_bytes)
+            .collect::<Vec<_>>();
+        let array = py
+            .import_bound("numpy")?
+            .call_method1("frombuffer", (PyBytes::new_bound(py, &bytes), "float32"))?
+            .call_method1("reshape", ((height, width, 3),))?;
+        Ok(array.unbind())
+    }
+}
+
+fn to_material(material: &Bound<'_, PyDict>) -> PyResult<Arc<dyn Material>> {
+    let get = |key: &str| {
+        material
+            .get_item(key)?
+            .ok_or_else(|| PyKeyError::new_err(format!("the material needs a {}", key)))
+    };
+    let color = || -> PyResult<Color> {
+        let [r, g, b] = get("color")?.extract::<[Float; 3]>()?;
+        Ok(Color::new(r, g, b))
+    };
+    let kind = get("type")?.extract::<String>()?;
+    Ok(match kind.as_str() {
+        "lambertian" => Arc::new(Lambertian::from(color()?)),
+        "metal" => Arc::new(Metal::new(color()?, get("fuzz")?.extract()?)),
+        "dielectric" => Arc::new(Dielectric::new(get("refraction_index")?.extract()?)),
+        _ => return Err(PyValueError::new_err(format!("unknown material: {}", kind))),
+    })
+}
+
+impl From<Error> for PyErr {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => PyOSError::new_err(e.to_string()),
+            e => PyValueError::new_err(e.to_string()),
+        }
+    }
+}
+
+#[pymodule]
+fn raytracer(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyScene>()
+}
@@ -1,5 +1,36 @@
 use std::ops::BitXor;
+use std::sync::OnceLock;
 
+use crate::float::Float;
+
+// A source of uniformly distributed random numbers. Materials and the `Vec3` helpers only draw
+// from this trait, so other generators and samplers can be used in place of `Rng` by
+// implementing `next_u64`.
+pub trait RandomSource {
+    fn next_u64(&mut self) -> u64;
+    #[inline]
+    fn next_f64(&mut self) -> f64 {
+        self.next_u64() as f64 / u64::MAX as f64
+    }
+    #[inline]
+    fn next_f64_range(&mut self, range: std::ops::Range<f64>) -> f64 {
+        self.next_f64() * (range.end - range.start) + range.start
+    }
+    #[inline]
+    fn next_float(&mut self) -> Float {
+        self.next_f64() as Float
+    }
+    #[inline]
+    fn next_float_range(&mut self, range: std::ops::Range<Float>) -> Float {
+        self.next_float() * (range.end - range.start) + range.start
+    }
+    // Called by the camera as a path reaches its `depth`th bounce, for samplers that give every
+    // bounce sample dimensions of its own. Plain generators go on with their one stream.
+    #[inline]
+    fn start_bounce(&mut self, _depth: usize) {}
+}
+
+// xoroshiro128+
 #[derive(Clone)]
 pub struct Rng {
     state: [u64; 2],
@@ -14,26 +45,6 @@ impl Rng {
     pub fn from_seed(seed: [u64; 2]) -> Self {
         Self { state: seed }
     }
-    #[inline]
-    pub fn next_u64(&mut self) -> u64 {
-        let a = self.state[0];
-        let b = self.state[1];
-        let result = a.wrapping_add(b);
-
-        let c = b.bitxor(a);
-        self.state[0] = a.rotate_left(24).bitxor(c).bitxor(c << 16);
-        self.state[1] = c.rotate_left(37);
-
-        return result;
-    }
-    #[inline]
-    pub fn next_f64(&mut self) -> f64 {
-        self.next_u64() as f64 / u64::MAX as f64
-    }
-    #[inline]
-    pub fn next_f64_range(&mut self, range: std::ops::Range<f64>) -> f64 {
-        self.next_f64() * (range.end - range.start) + range.start
-    }
     fn jump_impl(&mut self, jumper: [u64; 2]) -> &mut Self {
         let mut s0 = 0;
         let mut s1 = 0;
@@ -59,4 +70,91 @@ impl Rng {
         const JUMPER: [u64; 2] = [0xd2a98b26625eee7b, 0xdddf9b1090aa7ac1];
         return self.jump_impl(JUMPER);
     }
+    // Jumps `count` short jumps ahead at once, in a time that only grows with the number of bits
+    // in `count`. A long jump is 2^32 short jumps.
+    pub fn jump_ahead(&mut self, count: u128) -> &mut Self {
+        let tables = jump_tables();
+        let mut state = self.state_bits();
+        for (bit, table) in tables.iter().enumerate() {
+            if count >> bit & 1 == 1 {
+                state = apply(table, state);
+            }
+        }
+        *self = Self::from_state_bits(state);
+        return self;
+    }
+    // The `minor`th stream of the `major`th group of streams split off this generator, which is
+    // `major` long jumps and `minor` short jumps ahead of it. Every stream is 2^64 numbers long and
+    // every group holds 2^32 of them, so no two streams overlap and the same indices always give
+    // the same stream, whatever order they are split off in.
+    pub fn stream(&self, major: u64, minor: u32) -> Rng {
+        let mut stream = self.clone();
+        stream.jump_ahead((major as u128) << 32 | minor as u128);
+        return stream;
+    }
+    fn state_bits(&self) -> u128 {
+        self.state[0] as u128 | (self.state[1] as u128) << 64
+    }
+    fn from_state_bits(bits: u128) -> Self {
+        Self::from_seed([bits as u64, (bits >> 64) as u64])
+    }
+}
+
+// Every step of the generator, and so every jump, is a linear map of the bits of its state. These
+// are the matrices of 2^k short jumps, for every `k`, as the images of the bits of the state.
+type JumpTable = [u128; 128];
+
+fn jump_tables() -> &'static [JumpTable] {
+    static TABLES: OnceLock<Vec<JumpTable>> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let mut table = [0; 128];
+        for (bit, image) in table.iter_mut().enumerate() {
+            *image = Rng::from_state_bits(1 << bit).short_jump().state_bits();
+        }
+        let mut tables = vec![table];
+        for _ in 1..128 {
+            // jumping twice as far is applying the last table twice
+            let last = tables.last().unwrap();
+            tables.push(last.map(|image| apply(last, image)));
+        }
+        tables
+    })
+}
+
+fn apply(table: &JumpTable, state: u128) -> u128 {
+    (0..128)
+        .filter(|bit| state >> bit & 1 == 1)
+        .fold(0, |result, bit| result ^ table[bit])
+}
+
+impl RandomSource for Rng {
+    #[inline]
+    fn next_u64(&mut self) -> u64 {
+        let a = self.state[0];
+        let b = self.state[1];
+        let result = a.wrapping_add(b);
+
+        let c = b.bitxor(a);
+        self.state[0] = a.rotate_left(24).bitxor(c).bitxor(c << 16);
+        self.state[1] = c.rotate_left(37);
+
+        return result;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_are_jumps_ahead() {
+        let rng = Rng::from_seed([0x0123456789abcdef, 0xfedcba9876543210]);
+        let mut jumped = rng.clone();
+        jumped.short_jump().short_jump().short_jump();
+        assert_eq!(rng.stream(0, 3).state, jumped.state);
+        let mut jumped = rng.clone();
+        jumped.long_jump().long_jump().short_jump();
+        assert_eq!(rng.stream(2, 1).state, jumped.state);
+        assert_ne!(rng.stream(1, 0).state, rng.stream(0, 1).state);
+    }
 }
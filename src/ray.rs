@@ -1,5 +1,6 @@
 use crate::{
     color::Color,
+    float::Float,
     vec3::{Point3, Vec3},
 };
 
@@ -7,14 +8,18 @@ use crate::{
 pub struct Ray {
     pub origin: Point3,
     pub direction: Vec3,
-    pub time: f64,
+    pub time: Float,
 }
 
 impl Ray {
-    pub fn new(origin: Point3, direction: Vec3, time: f64) -> Self {
-        Self { origin, direction, time }
+    pub fn new(origin: Point3, direction: Vec3, time: Float) -> Self {
+        Self {
+            origin,
+            direction,
+            time,
+        }
     }
-    pub fn at(&self, t: f64) -> Point3 {
+    pub fn at(&self, t: Float) -> Point3 {
         self.origin + t * self.direction
     }
     pub fn color(&self) -> Color {
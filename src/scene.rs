@@ -1,45 +1,399 @@
-use std::sync::{mpsc::SyncSender, Arc};
+use std::sync::{
+    mpsc::{Receiver, SyncSender},
+    Arc,
+};
+
+use image::{Rgb32FImage, RgbImage};
+use tracing::info_span;
 
 use crate::{
-    camera::{builder::CameraBuilder, Camera},
+    camera::{
+        aov::TileUpdate,
+        builder::CameraBuilder,
+        environment::Environment,
+        events::{RenderStats, TileEvent},
+        explore::CameraControl,
+        sky::Sky,
+        Camera, RenderControl, RenderRequest,
+    },
     color::Color,
+    error::{Error, Result},
+    float::{consts, Float},
     hittable::{
+        aabb::AABB,
+        animation::{Animated, Animation, Transform},
         containers::HittableList,
-        geometry::MovingSphere,
+        descriptor::ObjectDescriptor,
         geometry::Sphere,
+        instance::{Instance, TopLevelBVH},
         materials::Dielectric,
+        materials::DiffuseLight,
         materials::Lambertian,
+        materials::LightLinks,
         materials::Material,
         materials::Metal,
-        texture::{CheckerTexture, SolidColor, Texture, ImageTexture, NoiseTexture},
+        report::SceneReport,
+        streaming::open_texture,
+        texture::{CheckerTexture, NoiseTexture, SolidColor, Texture},
+        volume::{Volume, VoxelGrid},
         Hittable,
     },
-    random::Rng,
+    network::Coordinator,
+    random::{RandomSource, Rng},
     vec3::{Point3, Vec3},
 };
 
 pub struct Scene<W> {
     pub camera: Camera,
     world: W,
+    // More cameras looking at the same world by name, rendered one after another by
+    // `render_cameras`.
+    cameras: Vec<(String, Camera)>,
+}
+
+impl<W: Hittable> Scene<W> {
+    // What the world is made of and what looks wrong with it, as seen from the main camera.
+    pub fn report(&self) -> SceneReport {
+        let mut report = SceneReport::new(&self.world);
+        report.check_enclosure(&self.world, self.camera.center);
+        report
+    }
 }
 
 impl Scene<Box<dyn Hittable>> {
     pub fn new(camera: Camera, world: Box<dyn Hittable>) -> Self {
-        Self { camera, world }
+        let scene = Self {
+            camera,
+            world,
+            cameras: Vec::new(),
+        };
+        scene.report().log();
+        scene
+    }
+    // Gathers the objects of `world` into the accelerator the camera asks for.
+    pub fn accelerated(camera: Camera, world: HittableList) -> Self {
+        let world = world.into_accelerator(camera.accelerator());
+        Self::new(camera, world)
+    }
+    // Adds a camera by name, written to the output of the main camera followed by the name, like
+    // `image-closeup.ppm`, unless the builder sets an output of its own.
+    pub fn add_camera(&mut self, name: &str, camera_builder: CameraBuilder) -> Result<()> {
+        let camera_builder = match camera_builder.output {
+            Some(_) => camera_builder,
+            None => camera_builder.output(self.camera.output_path(&format!("-{}", name))),
+        };
+        self.cameras
+            .push((name.to_string(), camera_builder.build()?));
+        Ok(())
+    }
+    pub fn cameras(&self) -> impl Iterator<Item = (&str, &Camera)> {
+        self.cameras
+            .iter()
+            .map(|(name, camera)| (name.as_str(), camera))
+    }
+    // Renders the image of every camera added by `add_camera` to its output in turn. The world is
+    // built once and shared by them all.
+    pub fn render_cameras(&self, control: &RenderControl) -> Result<Vec<(String, RenderStats)>> {
+        let mut results = Vec::new();
+        for (name, camera) in &self.cameras {
+            if control.is_cancelled() {
+                break;
+            }
+            let _span = info_span!("camera", name = name.as_str()).entered();
+            let stats = camera.render_to_outputs(&self.world, control)?;
+            results.push((name.clone(), stats));
+        }
+        Ok(results)
+    }
+    pub fn render(
+        &self,
+        sender: SyncSender<TileUpdate>,
+        control: &RenderControl,
+    ) -> Result<Vec<Color>> {
+        self.camera.render(&self.world, sender, control)
+    }
+    pub fn render_with(
+        &self,
+        control: &RenderControl,
+        on_event: impl FnMut(TileEvent),
+    ) -> Result<Vec<Color>> {
+        self.camera.render_with(&self.world, control, on_event)
+    }
+    // Renders the image to the outputs of the camera, like `render` without a preview.
+    pub fn render_to_outputs(&self, control: &RenderControl) -> Result<RenderStats> {
+        self.camera.render_to_outputs(&self.world, control)
+    }
+    // Renders the image without touching the file system or the preview.
+    pub fn render_to_image(&self) -> RgbImage {
+        let image_buffer = self.camera.render_to_buffer(&self.world);
+        self.camera.to_rgb_image(&image_buffer)
+    }
+    // Like `render_to_image` but keeps the linear colors.
+    pub fn render_to_float_image(&self) -> Rgb32FImage {
+        let image_buffer = self.camera.render_to_buffer(&self.world);
+        self.camera.to_float_image(&image_buffer)
+    }
+    pub fn render_distributed(
+        &self,
+        sender: SyncSender<TileUpdate>,
+        coordinator: Option<&Coordinator>,
+        control: &RenderControl,
+    ) -> Result<Vec<Color>> {
+        self.camera
+            .render_distributed(&self.world, sender, coordinator, control)
+    }
+    pub fn serve_requests(
+        &self,
+        image_buffer: Vec<Color>,
+        sender: SyncSender<TileUpdate>,
+        requests: Receiver<RenderRequest>,
+        control: &RenderControl,
+    ) -> Result<()> {
+        self.camera
+            .serve_requests(&self.world, image_buffer, sender, requests, control)
+    }
+    pub fn explore(
+        &self,
+        sender: SyncSender<TileUpdate>,
+        controls: Receiver<CameraControl>,
+    ) -> Result<()> {
+        self.camera.explore(&self.world, sender, controls)
+    }
+    pub(crate) fn render_tile(&self, top_left: (usize, usize), rect: (usize, usize)) -> Vec<Color> {
+        self.camera
+            .render_tile(top_left, rect, &self.world, &RenderControl::default())
     }
-    pub fn render(&self, sender: SyncSender<((usize, usize), (usize, usize), Vec<Color>)>) {
-        self.camera.render(&self.world, sender);
+}
+
+// A scene that instances can be added to, removed from and moved around in between renders, for
+// editors. Edits update the top level tree in place rather than building it again, see
+// `TopLevelBVH`.
+pub type SceneHandle = Scene<TopLevelBVH>;
+
+impl SceneHandle {
+    pub fn with_instances(camera: Camera, world: TopLevelBVH) -> Self {
+        let scene = Self {
+            camera,
+            world,
+            cameras: Vec::new(),
+        };
+        scene.report().log();
+        scene
+    }
+    // Adds an instance and returns the key to edit it with. Every edit has the camera find the
+    // lights and train the path guide again on the next render.
+    pub fn add(&mut self, instance: Instance) -> usize {
+        self.camera.forget_world();
+        self.world.add(instance)
+    }
+    // See `TopLevelBVH::remove`.
+    pub fn remove(&mut self, key: usize) -> Option<Instance> {
+        self.camera.forget_world();
+        self.world.remove(key)
+    }
+    // See `TopLevelBVH::move_instance`.
+    pub fn move_object(&mut self, key: usize, offset: Vec3) -> Option<Vec3> {
+        self.camera.forget_world();
+        self.world.move_instance(key, offset)
+    }
+    pub fn world(&self) -> &TopLevelBVH {
+        &self.world
+    }
+    pub fn render_with(
+        &self,
+        control: &RenderControl,
+        on_event: impl FnMut(TileEvent),
+    ) -> Result<Vec<Color>> {
+        self.camera.render_with(&self.snapshot(), control, on_event)
+    }
+    pub fn render_to_image(&self) -> RgbImage {
+        let image_buffer = self.camera.render_to_buffer(&self.snapshot());
+        self.camera.to_rgb_image(&image_buffer)
+    }
+    pub fn render_to_float_image(&self) -> Rgb32FImage {
+        let image_buffer = self.camera.render_to_buffer(&self.snapshot());
+        self.camera.to_float_image(&image_buffer)
+    }
+    // The world as it is now for a render. The instances share their geometry with the handle, so
+    // only the top level tree is copied.
+    fn snapshot(&self) -> Box<dyn Hittable> {
+        Box::new(self.world.clone())
+    }
+}
+
+// The frames of an animation from `first` to `last`, at `fps` frames a second. The shutter of every
+// frame is open for the first half of it, like the 180° shutter of a film camera.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameSequence {
+    pub first: usize,
+    pub last: usize,
+    pub fps: Float,
+}
+
+impl FrameSequence {
+    pub fn new(first: usize, last: usize, fps: Float) -> Self {
+        Self { first, last, fps }
+    }
+    // The scene time the shutter of `frame` is open over.
+    pub fn shutter_interval(&self, frame: usize) -> (Float, Float) {
+        let open = frame as Float / self.fps;
+        (open, open + 0.5 / self.fps)
+    }
+    // Renders every frame of the scene called `name` to the output of the camera followed by the
+    // frame number, like `image-0001.ppm`. The scene is built again for every frame, so its
+    // animated objects are placed in the shutter interval of the frame.
+    pub fn render(
+        &self,
+        name: &str,
+        camera_builder: &CameraBuilder,
+        control: &RenderControl,
+    ) -> Result<Vec<RenderStats>> {
+        if !(self.fps > 0.0 && self.fps.is_finite()) {
+            return Err(Error::InvalidCamera(format!(
+                "frames need a frame rate above zero, current value: {}",
+                self.fps
+            )));
+        }
+        let output = camera_builder.output.clone();
+        let output = output.unwrap_or_else(|| "image".to_string());
+        let mut results = Vec::new();
+        for frame in self.first..=self.last {
+            if control.is_cancelled() {
+                break;
+            }
+            let _span = info_span!("frame", frame).entered();
+            let camera_builder = camera_builder
+                .clone()
+                .shutter_interval(self.shutter_interval(frame))
+                .output(format!("{}-{:04}", output, frame));
+            results.push(from_name(name, camera_builder)?.render_to_outputs(control)?);
+        }
+        Ok(results)
     }
 }
 
-pub fn composition(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
+type SceneFn = fn(CameraBuilder) -> Result<Scene<Box<dyn Hittable>>>;
+
+// The scenes below by their function names, for picking them from the command line, batch queues
+// and remote workers.
+const SCENES: [(&str, SceneFn); 10] = [
+    ("composition", composition),
+    ("book_cover", book_cover),
+    ("two_spheres", two_spheres),
+    ("earth", earth),
+    ("something_blocky", something_blocky),
+    ("furnace", furnace),
+    ("material_preview", |camera_builder| {
+        material_preview(camera_builder, Arc::new(Lambertian::from(Color::gray(0.5))))
+    }),
+    ("linked_lights", linked_lights),
+    ("reykjavik_evening", reykjavik_evening),
+    ("campfire", campfire),
+];
+
+// Whether `from_name` knows a scene by `name`.
+pub fn is_named(name: &str) -> bool {
+    SCENES.iter().any(|&(scene, _)| scene == name)
+}
+
+// Looks up one of the scenes below by its function name.
+pub fn from_name(name: &str, camera_builder: CameraBuilder) -> Result<Scene<Box<dyn Hittable>>> {
+    match SCENES.iter().find(|&&(scene, _)| scene == name) {
+        Some((_, build)) => build(camera_builder),
+        None => Err(Error::UnknownScene(name.to_string())),
+    }
+}
+
+// A scene described by its objects rather than built by code, for loading from a file with
+// `--scene-file` and for sending to remote workers. The view is the one of the camera builder
+// unless the descriptor sets it.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SceneDescriptor {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub lookfrom: Option<Point3>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub lookat: Option<Point3>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub field_of_view: Option<Float>,
+    pub objects: Vec<ObjectDescriptor>,
+}
+
+impl SceneDescriptor {
+    pub fn build(&self, camera_builder: CameraBuilder) -> Result<Scene<Box<dyn Hittable>>> {
+        let camera_builder = match self.lookfrom {
+            Some(lookfrom) => camera_builder.lookfrom(lookfrom),
+            None => camera_builder,
+        };
+        let camera_builder = match self.lookat {
+            Some(lookat) => camera_builder.lookat(lookat),
+            None => camera_builder,
+        };
+        let camera_builder = match self.field_of_view {
+            Some(field_of_view) => camera_builder.field_of_view(field_of_view),
+            None => camera_builder,
+        };
+        let mut world = HittableList::default();
+        for object in &self.objects {
+            world.add(object.build()?);
+        }
+        Ok(Scene::accelerated(camera_builder.build()?, world))
+    }
+    // Reads the files of the objects into the descriptor, see `ObjectDescriptor::embed`.
+    pub fn embed(&mut self) -> Result<()> {
+        self.objects
+            .iter_mut()
+            .try_for_each(ObjectDescriptor::embed)
+    }
+    // Reads a description written as JSON, on one line or many.
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(|e| Error::InvalidScene(e.to_string()))
+    }
+    // Writes the description as JSON on a single line.
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|e| Error::InvalidScene(e.to_string()))
+    }
+    #[cfg(not(feature = "serde"))]
+    pub fn from_json(_: &str) -> Result<Self> {
+        Err(serde_disabled())
+    }
+    #[cfg(not(feature = "serde"))]
+    pub fn to_json(&self) -> Result<String> {
+        Err(serde_disabled())
+    }
+}
+
+#[cfg(not(feature = "serde"))]
+fn serde_disabled() -> Error {
+    Error::InvalidScene("scene descriptions are read and written with the serde feature".into())
+}
+
+// Where the scene of a render comes from, one of the scenes of `from_name` or a description.
+#[derive(Debug, Clone)]
+pub enum SceneSource {
+    Named(String),
+    Described(SceneDescriptor),
+}
+
+impl SceneSource {
+    pub fn build(&self, camera_builder: CameraBuilder) -> Result<Scene<Box<dyn Hittable>>> {
+        match self {
+            SceneSource::Named(name) => from_name(name, camera_builder),
+            SceneSource::Described(descriptor) => descriptor.build(camera_builder),
+        }
+    }
+}
+
+pub fn composition(camera_builder: CameraBuilder) -> Result<Scene<Box<dyn Hittable>>> {
     let camera = camera_builder
         .field_of_view(55.0)
         .lookfrom(Point3::new(0.0, 0.5, 1.0) * 1.5)
         .lookat(Point3::new(0.0, 0.3, 0.0))
         .up_vector(Vec3::new(0.0, 1.0, 0.0))
         .defocus_angle(0.0)
-        .build();
+        .build()?;
     let mut world = Box::new(HittableList::default());
 
     // Ground
@@ -63,15 +417,17 @@ pub fn composition(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
         0.5,
         blue_lamb,
     )));
+    // a hollow glass sphere, both surfaces share the material to bound the same medium
+    let hollow_glass: Arc<dyn Material> = Arc::new(Dielectric::new(1.5));
     world.add(Box::new(Sphere::new(
         Point3::new(-0.25 - 0.125, -0.25, -0.5),
         0.25,
-        Arc::new(Dielectric::new(1.5)),
+        hollow_glass.clone(),
     )));
     world.add(Box::new(Sphere::new(
         Point3::new(-0.25 - 0.125, -0.25, -0.5),
         -0.20,
-        Arc::new(Dielectric::new(1.5)),
+        hollow_glass,
     )));
     world.add(Box::new(Sphere::new(
         Point3::new(0.6, 0.1, -0.4),
@@ -83,10 +439,10 @@ pub fn composition(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
         0.5,
         Arc::new(Metal::new(Color::gray(0.7), 0.0)),
     )));
-    return Scene { camera, world };
+    return Ok(Scene::new(camera, world));
 }
 
-pub fn book_cover(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
+pub fn book_cover(camera_builder: CameraBuilder) -> Result<Scene<Box<dyn Hittable>>> {
     let camera = camera_builder
         .field_of_view(20.0)
         .lookfrom(Point3::new(13.0, 2.0, 3.0))
@@ -94,7 +450,7 @@ pub fn book_cover(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
         .up_vector(Vec3::new(0.0, 1.0, 0.0))
         .defocus_angle(0.3)
         .focus_distance(10.0)
-        .build();
+        .build()?;
 
     let mut rng = Rng::from_seed([42, 1337]);
     let mut world = Box::new(HittableList::default());
@@ -113,11 +469,11 @@ pub fn book_cover(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
 
     for a in -11..11 {
         for b in -11..11 {
-            let choose_mat = rng.next_f64();
+            let choose_mat = rng.next_float();
             let center = Point3::new(
-                a as f64 + 0.9 * rng.next_f64(),
+                a as Float + 0.9 * rng.next_float(),
                 0.2,
-                b as f64 + 0.9 * rng.next_f64(),
+                b as Float + 0.9 * rng.next_float(),
             );
             if (center - Point3::new(4.0, 0.2, 0.0)).length() > 0.9 {
                 let sphere_material: Arc<dyn Material> = if choose_mat < 0.7 {
@@ -127,16 +483,22 @@ pub fn book_cover(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
                 } else if choose_mat < 0.9 {
                     // metal
                     let albedo = Color::random(&mut rng) / 2.0 + 0.5;
-                    let fuzz = rng.next_f64_range(0.0..0.5);
+                    let fuzz = rng.next_float_range(0.0..0.5);
                     Arc::new(Metal::new(albedo, fuzz))
                 } else {
                     // glass
                     Arc::new(Dielectric::new(1.5))
                 };
-                // world.add(Box::new(Sphere::new(center, 0.2, sphere_material)));
-                world.add(Box::new(MovingSphere::new(
-                    Sphere::new(center, 0.2, sphere_material),
-                    center + Point3::new(0.0, 0.5 * (1. - choose_mat), 0.0),
+                // up over the first second and back down over the next
+                let bounce = Vec3::new(0.0, 0.5 * (1. - choose_mat), 0.0);
+                let animation = Animation::new()
+                    .keyframe(0.0, Transform::default())
+                    .keyframe(1.0, Transform::new(bounce, 0.0, 1.0))
+                    .keyframe(2.0, Transform::default());
+                world.add(Box::new(Animated::new(
+                    Arc::new(Sphere::new(center, 0.2, sphere_material)),
+                    animation,
+                    camera.shutter_interval(),
                 )));
             }
         }
@@ -157,12 +519,10 @@ pub fn book_cover(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
         1.0,
         Arc::new(Metal::new(Color::new(0.8, 0.8, 0.8), 0.0)),
     )));
-    return Scene {
-        camera,
-        world: world.into_bvh(),
-    };
+    return Ok(Scene::accelerated(camera, *world));
 }
 
+#[allow(unused)]
 fn ordered() -> Box<HittableList> {
     let mut world = Box::new(HittableList::default());
     let mat_ground = Arc::new(Lambertian::from(Color::new(0.8, 0.8, 0.0)));
@@ -197,9 +557,10 @@ fn ordered() -> Box<HittableList> {
     )));
     return world;
 }
+#[allow(unused)]
 fn fov_test() -> Box<HittableList> {
     let mut world = Box::new(HittableList::default());
-    let r = (std::f64::consts::PI / 4.0).cos();
+    let r = (consts::PI / 4.0).cos();
     world.add(Box::new(Sphere::new(
         Point3::new(r, 0., -1.),
         r,
@@ -213,12 +574,12 @@ fn fov_test() -> Box<HittableList> {
     return world;
 }
 
-pub fn two_spheres(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
+pub fn two_spheres(camera_builder: CameraBuilder) -> Result<Scene<Box<dyn Hittable>>> {
     let camera = camera_builder
         .field_of_view(20.0)
         .lookfrom(Point3::new(13.0, 2.0, 3.0))
         .lookat(Point3::new(0.0, 0.0, 0.0))
-        .build();
+        .build()?;
     let mut world = Box::new(HittableList::default());
     let checker_texture: Arc<dyn Texture> = Arc::new(CheckerTexture::new(
         0.3,
@@ -237,18 +598,17 @@ pub fn two_spheres(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
         material.clone(),
     )));
 
-    return Scene::new(camera, world.into_bvh());
+    return Ok(Scene::accelerated(camera, *world));
 }
 
-pub fn earth(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
+pub fn earth(camera_builder: CameraBuilder) -> Result<Scene<Box<dyn Hittable>>> {
     let camera = camera_builder
         .field_of_view(20.0)
         .lookfrom(Point3::new(12.0, 0.0, 0.0))
         .lookat(Point3::new(0.0, 0.0, 0.0))
-        .build();
+        .build()?;
     let mut world = Box::new(HittableList::default());
-    let earth_image = image::open("images/earthmap.jpg").unwrap().to_rgba8();
-    let earth_texture: Arc<dyn Texture> = Arc::new(ImageTexture::new(earth_image));
+    let earth_texture: Arc<dyn Texture> = Arc::from(open_texture("images/earthmap.jpg", false)?);
     let material = Arc::new(Lambertian::from(earth_texture.clone()));
     world.add(Box::new(Sphere::new(
         Point3::new(0.0, 0.0, 0.0),
@@ -256,19 +616,200 @@ pub fn earth(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
         material.clone(),
     )));
 
-    return Scene::new(camera, world.into_bvh());
+    return Ok(Scene::accelerated(camera, *world));
+}
+
+// The furnace test, a 50% gray sphere filling the view in a uniform white environment. A material
+// that neither gains nor loses energy comes out uniformly as bright as its albedo, whatever the
+// lighting, so any shading on the sphere is a bug in the material.
+pub fn furnace(camera_builder: CameraBuilder) -> Result<Scene<Box<dyn Hittable>>> {
+    furnace_with(camera_builder, Arc::new(Lambertian::from(Color::gray(0.5))))
 }
 
-pub fn something_blocky(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable>> {
+pub fn furnace_with(
+    camera_builder: CameraBuilder,
+    material: Arc<dyn Material>,
+) -> Result<Scene<Box<dyn Hittable>>> {
     let camera = camera_builder
+        .background(Color::white())
         .field_of_view(20.0)
-        .lookfrom(Point3::new(13.0, 2.0, 3.0))
+        .lookfrom(Point3::new(0.0, 0.0, 3.0))
         .lookat(Point3::new(0.0, 0.0, 0.0))
-        .build();
+        .build()?;
     let mut world = Box::new(HittableList::default());
-    let noise_texture: Arc<dyn Texture> = Arc::new(NoiseTexture::new(
-        0.2,
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, 0.0, 0.0),
+        1.0,
+        material,
+    )));
+
+    return Ok(Scene::accelerated(camera, *world));
+}
+
+// The seed the material previews are rendered with, so a preview of a material is the same image
+// every time.
+pub const MATERIAL_PREVIEW_SEED: u64 = 7;
+
+// A sphere of `material` on a checkered floor in the studio environment, the standard setup for
+// showing off a material. It needs no files and renders at a fixed seed, so it is what the
+// documentation images of materials are rendered with and what the golden tests of new materials
+// compare against.
+pub fn material_preview(
+    camera_builder: CameraBuilder,
+    material: Arc<dyn Material>,
+) -> Result<Scene<Box<dyn Hittable>>> {
+    let camera = camera_builder
+        .environment(Environment::Studio)
+        .seed(MATERIAL_PREVIEW_SEED)
+        .field_of_view(30.0)
+        .lookfrom(Point3::new(0.0, 1.6, 4.5))
+        .lookat(Point3::new(0.0, 0.9, 0.0))
+        .build()?;
+    let mut world = Box::new(HittableList::default());
+    let checker_texture: Arc<dyn Texture> = Arc::new(CheckerTexture::new(
+        0.5,
+        Box::new(SolidColor::from(Color::gray(0.2))),
+        Box::new(SolidColor::from(Color::gray(0.6))),
+    ));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        Arc::new(Lambertian::from(checker_texture)),
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, 1.0, 0.0),
+        1.0,
+        material,
+    )));
+
+    return Ok(Scene::accelerated(camera, *world));
+}
+
+// A subject in front of a backdrop, lit from above by a key light and from the side by a fill
+// light that is linked to the subject alone, so the backdrop and ground stay dark on that side. The
+// lights are given by the watts they put out, in the units the camera declares.
+pub fn linked_lights(camera_builder: CameraBuilder) -> Result<Scene<Box<dyn Hittable>>> {
+    let camera = camera_builder
+        .background(Color::black())
+        .field_of_view(30.0)
+        .lookfrom(Point3::new(0.0, 1.0, 6.0))
+        .lookat(Point3::new(0.0, 0.5, 0.0))
+        .build()?;
+    let mut world = Box::new(HittableList::default());
+    let gray = Arc::new(Lambertian::from(Color::gray(0.5)));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        gray.clone(),
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, 0.0, -1005.0),
+        1000.0,
+        gray,
+    )));
+    let subject = Sphere::new(
+        Point3::new(0.0, 0.8, 0.0),
+        0.8,
+        Arc::new(Lambertian::from(Color::new(0.8, 0.3, 0.2))),
+    );
+    let units = camera.units();
+    let area = |radius: Float| 4.0 * consts::PI * radius * radius;
+    let fill = DiffuseLight::from_power(Color::gray(40.0), area(0.5), units)
+        .with_links(LightLinks::Only(vec![subject.id()]));
+    world.add(Box::new(subject));
+    world.add(Box::new(Sphere::new(
+        Point3::new(3.0, 1.0, 1.0),
+        0.5,
+        Arc::new(fill),
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(-1.0, 6.0, 2.0),
+        1.5,
+        Arc::new(DiffuseLight::from_power(
+            Color::gray(530.0),
+            area(1.5),
+            units,
+        )),
+    )));
+
+    return Ok(Scene::accelerated(camera, *world));
+}
+
+// A few spheres in the low evening sun of midsummer in Reykjavík, looking west.
+pub fn reykjavik_evening(camera_builder: CameraBuilder) -> Result<Scene<Box<dyn Hittable>>> {
+    let sky = Sky::new(64.1466, -21.9426).time(21, 30);
+    let camera = camera_builder
+        .sky(sky)
+        .field_of_view(40.0)
+        .lookfrom(Point3::new(6.0, 1.5, 0.0))
+        .lookat(Point3::new(0.0, 1.0, 0.0))
+        .build()?;
+    let mut world = Box::new(HittableList::default());
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        Arc::new(Lambertian::from(Color::new(0.3, 0.35, 0.25))),
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, 1.0, 0.0),
+        1.0,
+        Arc::new(Lambertian::from(Color::gray(0.7))),
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(-1.0, 0.5, 2.0),
+        0.5,
+        Arc::new(Metal::new(Color::gray(0.8), 0.1)),
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(1.0, 0.6, -2.0),
+        0.6,
+        Arc::new(Dielectric::new(1.5)),
+    )));
+
+    return Ok(Scene::accelerated(camera, *world));
+}
+
+// A flame over the ground at night, a volume that is hottest and densest at its base and cools as
+// it rises.
+pub fn campfire(camera_builder: CameraBuilder) -> Result<Scene<Box<dyn Hittable>>> {
+    let camera = camera_builder
+        .background(Color::black())
+        .field_of_view(30.0)
+        .lookfrom(Point3::new(0.0, 1.5, 6.0))
+        .lookat(Point3::new(0.0, 0.8, 0.0))
+        .build()?;
+    let mut world = Box::new(HittableList::default());
+    world.add(Box::new(Sphere::new(
+        Point3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        Arc::new(Lambertian::from(Color::new(0.4, 0.35, 0.3))),
+    )));
+    let resolution = [24, 48, 24];
+    // the flame narrows towards the top
+    let width = |point: Point3| {
+        let offset = Vec3::new(point.x - 0.5, 0.0, point.z - 0.5).length();
+        (1.0 - offset / (0.5 - 0.4 * point.y)).max(0.0)
+    };
+    let density = VoxelGrid::from_fn(resolution, |point| 8.0 * width(point))?;
+    let temperature = VoxelGrid::from_fn(resolution, |point| {
+        1000.0 + 900.0 * (1.0 - point.y) * width(point)
+    })?;
+    let bounds = AABB::from_vecs(Point3::new(-0.5, 0.0, -0.5), Point3::new(0.5, 2.0, 0.5));
+    world.add(Box::new(
+        Volume::new(bounds, density, Color::gray(0.2)).with_emission(temperature, 1.5),
     ));
+
+    return Ok(Scene::accelerated(camera, *world));
+}
+
+pub fn something_blocky(camera_builder: CameraBuilder) -> Result<Scene<Box<dyn Hittable>>> {
+    let camera = camera_builder
+        .field_of_view(20.0)
+        .lookfrom(Point3::new(13.0, 2.0, 3.0))
+        .lookat(Point3::new(0.0, 0.0, 0.0))
+        .build()?;
+    let mut world = Box::new(HittableList::default());
+    let noise_texture: Arc<dyn Texture> = Arc::new(NoiseTexture::new(0.2));
     let material = Arc::new(Lambertian::from(noise_texture.clone()));
     world.add(Box::new(Sphere::new(
         Point3::new(0.0, -1000.0, 0.0),
@@ -281,5 +822,162 @@ pub fn something_blocky(camera_builder: CameraBuilder) -> Scene<Box<dyn Hittable
         material.clone(),
     )));
 
-    return Scene::new(camera, world.into_bvh());
+    return Ok(Scene::accelerated(camera, *world));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    use crate::camera::image::ImageSpecBuilder;
+    use crate::hittable::containers::Accelerator;
+
+    #[test]
+    fn render_to_image_has_the_image_size() {
+        let image_spec = ImageSpecBuilder::default()
+            .width(32)
+            .aspect_ratio(2.0)
+            .build();
+        let camera_builder = CameraBuilder::default()
+            .image_spec(image_spec)
+            .uniform_sampler(1)
+            .max_ray_depth(4);
+        let image = two_spheres(camera_builder).unwrap().render_to_image();
+        assert_eq!(image.dimensions(), (32, 16));
+        assert!(image.pixels().any(|pixel| pixel.0 != [0, 0, 0]));
+    }
+
+    #[test]
+    fn every_accelerator_renders_the_same_scene() {
+        let render = |accelerator| {
+            let image_spec = ImageSpecBuilder::default()
+                .width(32)
+                .aspect_ratio(1.0)
+                .build();
+            let camera_builder = CameraBuilder::default()
+                .image_spec(image_spec)
+                .uniform_sampler(1)
+                .max_ray_depth(4)
+                .accelerator(accelerator);
+            from_name("book_cover", camera_builder)
+                .unwrap()
+                .render_to_image()
+        };
+        let mean = |image: RgbImage| {
+            let sum: u64 = image
+                .pixels()
+                .flat_map(|pixel| pixel.0)
+                .map(u64::from)
+                .sum();
+            sum as Float / (3 * image.width() * image.height()) as Float
+        };
+        let bvh = mean(render(Accelerator::Bvh));
+        for accelerator in [Accelerator::Grid, Accelerator::KdTree] {
+            // the spheres the BVH batches are intersected with SIMD, which rounds differently, so
+            // the odd path takes another turn
+            let actual = mean(render(accelerator));
+            assert!(
+                (actual - bvh).abs() < 0.02 * bvh,
+                "the {} renders {} where the BVH renders {}",
+                accelerator,
+                actual,
+                bvh
+            );
+        }
+    }
+
+    #[test]
+    fn every_camera_writes_its_own_output() {
+        let directory =
+            std::env::temp_dir().join(format!("raytracer-cameras-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let output = directory.join("spheres").to_string_lossy().into_owned();
+        let camera_builder = || {
+            let image_spec = ImageSpecBuilder::default()
+                .width(16)
+                .aspect_ratio(1.0)
+                .build();
+            CameraBuilder::default()
+                .image_spec(image_spec)
+                .uniform_sampler(1)
+                .max_ray_depth(4)
+        };
+        let mut scene = two_spheres(camera_builder().output(output.clone())).unwrap();
+        let above = camera_builder()
+            .lookfrom(Point3::new(0.0, 20.0, 0.1))
+            .lookat(Point3::zero());
+        scene.add_camera("above", above).unwrap();
+        let rendered = scene.render_cameras(&RenderControl::default()).unwrap();
+        assert_eq!(rendered.len(), 1);
+        assert!(Path::new(&format!("{}-above.ppm", output)).exists());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn every_frame_writes_its_own_output() {
+        let directory =
+            std::env::temp_dir().join(format!("raytracer-frames-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let output = directory.join("cover").to_string_lossy().into_owned();
+        let image_spec = ImageSpecBuilder::default()
+            .width(8)
+            .aspect_ratio(1.0)
+            .build();
+        let camera_builder = CameraBuilder::default()
+            .image_spec(image_spec)
+            .uniform_sampler(1)
+            .max_ray_depth(2)
+            .output(output.clone());
+        let sequence = FrameSequence::new(24, 25, 24.0);
+        assert_eq!(sequence.shutter_interval(24), (1.0, 1.0 + 1.0 / 48.0));
+        let rendered = sequence
+            .render("book_cover", &camera_builder, &RenderControl::default())
+            .unwrap();
+        assert_eq!(rendered.len(), 2);
+        for frame in [24, 25] {
+            assert!(Path::new(&format!("{}-{:04}.ppm", output, frame)).exists());
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+        let slow = FrameSequence::new(0, 1, 0.0);
+        assert!(slow
+            .render("book_cover", &camera_builder, &RenderControl::default())
+            .is_err());
+    }
+
+    #[test]
+    fn edits_are_lit_by_the_lights_left_in_the_world() {
+        let camera = crate::camera::test_camera(16, 1.0)
+            .random_sampler(16)
+            .max_ray_depth(2)
+            .background(Color::black())
+            .light_candidates(4)
+            .path_guiding(true)
+            .lookfrom(Point3::new(0.0, 3.0, 4.0))
+            .lookat(Point3::new(0.0, 0.0, -1.0))
+            .build()
+            .unwrap();
+        let ground: Arc<dyn Hittable> = Arc::new(Sphere::new(
+            Point3::new(0.0, -10.0, 0.0),
+            10.0,
+            Arc::new(Lambertian::from(Color::gray(0.5))),
+        ));
+        let light: Arc<dyn Hittable> = Arc::new(Sphere::new(
+            Point3::zero(),
+            0.4,
+            Arc::new(DiffuseLight::from(Color::gray(4.0))),
+        ));
+        let world = TopLevelBVH::new(vec![Instance::new(ground, Vec3::zero())]);
+        let mut scene = SceneHandle::with_instances(camera, world);
+        let key = scene.add(Instance::new(light.clone(), Vec3::new(0.0, 4.0, 3.0)));
+        let lit = |scene: &SceneHandle| {
+            let image = scene.render_to_float_image();
+            image.pixels().any(|pixel| pixel.0 != [0.0; 3])
+        };
+        assert!(lit(&scene));
+        scene.remove(key);
+        assert!(!lit(&scene));
+        scene.add(Instance::new(light, Vec3::new(0.0, 4.0, 3.0)));
+        assert!(lit(&scene));
+    }
 }
@@ -0,0 +1,127 @@
+use std::cell::Cell;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tracing::{debug, info_span};
+// std's `Instant` panics in the browser, this one is std's everywhere else
+use web_time::Instant;
+
+// Counters for the hot paths of the renderer. Every thread counts into its own thread local
+// counters which are flushed into the global totals after each tile, so counting is only a
+// thread local increment. Without the `telemetry` feature counting compiles to nothing.
+#[derive(Debug, Clone, Copy)]
+pub enum Counter {
+    CameraRays,
+    ScatteredRays,
+    ShadowRays,
+    NodeVisits,
+    PrimitiveTests,
+}
+
+const COUNTERS: usize = 5;
+const NAMES: [&str; COUNTERS] = [
+    "camera rays",
+    "scattered rays",
+    "shadow rays",
+    "BVH node visits",
+    "primitive tests",
+];
+
+thread_local! {
+    static LOCAL: [Cell<u64>; COUNTERS] = Default::default();
+}
+
+static TOTALS: [AtomicU64; COUNTERS] = [const { AtomicU64::new(0) }; COUNTERS];
+static STAGES: Mutex<Vec<(&'static str, Duration)>> = Mutex::new(Vec::new());
+static TILES: Mutex<Vec<(Tile, Duration)>> = Mutex::new(Vec::new());
+
+// A tile of the image as its top left corner and size.
+pub type Tile = ((usize, usize), (usize, usize));
+
+#[inline(always)]
+#[cfg_attr(not(feature = "telemetry"), allow(unused_variables))]
+pub fn count(counter: Counter) {
+    #[cfg(feature = "telemetry")]
+    LOCAL.with(|local| {
+        let cell = &local[counter as usize];
+        cell.set(cell.get() + 1);
+    });
+}
+
+// Moves the counts of the calling thread into the global totals.
+pub fn flush() {
+    #[cfg(feature = "telemetry")]
+    LOCAL.with(|local| {
+        for (cell, total) in local.iter().zip(TOTALS.iter()) {
+            total.fetch_add(cell.replace(0), Ordering::Relaxed);
+        }
+    });
+}
+
+pub fn total(counter: Counter) -> u64 {
+    TOTALS[counter as usize].load(Ordering::Relaxed)
+}
+
+pub fn record_stage(stage: &'static str, elapsed: Duration) {
+    debug!(stage, seconds = elapsed.as_secs_f64(), "stage finished");
+    STAGES.lock().unwrap().push((stage, elapsed));
+}
+
+// Records how long a tile took to render locally, for finding the expensive parts of a scene.
+#[cfg_attr(not(feature = "telemetry"), allow(unused_variables))]
+pub fn record_tile(tile: Tile, elapsed: Duration) {
+    #[cfg(feature = "telemetry")]
+    TILES.lock().unwrap().push((tile, elapsed));
+}
+
+// The tiles recorded since the last call, empty without the `telemetry` feature.
+pub fn take_tiles() -> Vec<(Tile, Duration)> {
+    std::mem::take(&mut *TILES.lock().unwrap())
+}
+
+// Runs `f` in a span named after `stage` and records how long it took.
+pub fn time_stage<T>(stage: &'static str, f: impl FnOnce() -> T) -> T {
+    let _span = info_span!("stage", name = stage).entered();
+    let start_time = Instant::now();
+    let result = f();
+    record_stage(stage, start_time.elapsed());
+    return result;
+}
+
+pub fn stage(stage: &str) -> Option<Duration> {
+    STAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(name, _)| *name == stage)
+        .map(|(_, elapsed)| *elapsed)
+        .reduce(|a, b| a + b)
+}
+
+pub fn reset() {
+    for total in TOTALS.iter() {
+        total.store(0, Ordering::Relaxed);
+    }
+    STAGES.lock().unwrap().clear();
+    TILES.lock().unwrap().clear();
+}
+
+pub fn report() -> String {
+    let mut report = String::new();
+    for (stage, elapsed) in STAGES.lock().unwrap().iter() {
+        writeln!(report, "{:>16}: {:.3} s", stage, elapsed.as_secs_f64()).unwrap();
+    }
+    if cfg!(feature = "telemetry") {
+        for (name, total) in NAMES.iter().zip(TOTALS.iter()) {
+            writeln!(report, "{:>16}: {}", name, total.load(Ordering::Relaxed)).unwrap();
+        }
+        let rays = total(Counter::CameraRays) + total(Counter::ScatteredRays);
+        if let Some(render_time) = stage("render") {
+            let mrays = rays as f64 / render_time.as_secs_f64() / 1e6;
+            writeln!(report, "{:>16}: {:.3} Mrays/s", "throughput", mrays).unwrap();
+        }
+    }
+    return report;
+}
@@ -1,43 +1,381 @@
-use std::sync::mpsc::Receiver;
-use std::time::Duration;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::PixelFormatEnum;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod, Scancode};
+use sdl2::mouse::MouseButton;
+use sdl2::pixels::{self, PixelFormatEnum};
 use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas};
+use sdl2::video::Window;
+use tracing::{info, warn};
 
+use crate::camera::{
+    aov::{Layer, TileUpdate},
+    explore::CameraControl,
+    settings::RenderSettings,
+    RenderControl, RenderRequest,
+};
 use crate::color::Color;
+use crate::error::{sdl_error, Result};
+use crate::float::Float;
+use crate::font;
+use crate::vec3::Vec3;
 
-pub(crate) fn sdl_thread(
+// Focus distances per second.
+const MOVE_SPEED: Float = 0.5;
+const FAST_MOVE_SPEED: Float = 2.0;
+// Degrees per pixel of mouse motion.
+const LOOK_SENSITIVITY: Float = 0.2;
+// Stops per press of + or -, and the gamma change with Ctrl held.
+const EXPOSURE_STEP: Float = 0.5;
+const GAMMA_STEP: Float = 0.1;
+const DEFAULT_GAMMA: Float = 2.2;
+
+// The linear colors of every layer of the rendered image and the 8 bit colors of the layer shown.
+// The exposure and gamma only apply to the preview, the rendered image is left alone. Pixels the
+// current render hasn't reached yet are dimmed.
+struct DisplayBuffer {
+    width: usize,
+    layers: [Vec<Color>; Layer::ALL.len()],
+    received: [bool; Layer::ALL.len()],
+    shown: Layer,
+    pixels: Vec<u8>,
+    exposure: Float,
+    gamma: Float,
+    // the depth and sample count layers are shown relative to their largest values
+    max_depth: Float,
+    max_samples: Float,
+    rendered: Vec<bool>,
+    rendered_count: usize,
+}
+
+impl DisplayBuffer {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            layers: Layer::ALL.map(|_| vec![Color::black(); width * height]),
+            received: [false; Layer::ALL.len()],
+            shown: Layer::Beauty,
+            pixels: vec![0; width * height * 3],
+            exposure: 0.0,
+            gamma: DEFAULT_GAMMA,
+            max_depth: 0.0,
+            max_samples: 0.0,
+            rendered: vec![false; width * height],
+            rendered_count: 0,
+        }
+    }
+    fn tonemap(&self, color: Color) -> (u8, u8, u8) {
+        match self.shown {
+            Layer::Beauty => (color * (2.0 as Float).powf(self.exposure))
+                .gamma_corrected(self.gamma)
+                .into_u8(),
+            Layer::Normal => color.into_u8(),
+            Layer::Albedo => color.gamma_corrected(self.gamma).into_u8(),
+            Layer::Depth if color.r.is_finite() => {
+                Color::gray(1.0 - color.r / self.max_depth).into_u8()
+            }
+            Layer::Depth => (0, 0, 0),
+            Layer::SampleCount => Color::heatmap(color.r / self.max_samples).into_u8(),
+            Layer::ObjectId | Layer::MaterialId => id_color(color.r as u32).into_u8(),
+            // still is gray, a motion of 64 pixels saturates
+            Layer::Motion => (Color::gray(0.5) + color / 128.0).into_u8(),
+        }
+    }
+    fn show_pixel(&mut self, index: usize) {
+        let (mut ir, mut ig, mut ib) = self.tonemap(self.layers[self.shown as usize][index]);
+        if !self.rendered[index] {
+            (ir, ig, ib) = (ir / 3, ig / 3, ib / 3);
+        }
+        self.pixels[index * 3..index * 3 + 3].copy_from_slice(&[ir, ig, ib]);
+    }
+    fn refresh(&mut self) {
+        for index in 0..self.pixels.len() / 3 {
+            self.show_pixel(index);
+        }
+    }
+    // Returns whether the whole layer shown was redrawn, which happens when the tile raises the
+    // largest depth or sample count.
+    fn store_tile(
+        &mut self,
+        layer: Layer,
+        top_left: (usize, usize),
+        size: (usize, usize),
+        colors: &[Color],
+    ) -> bool {
+        self.received[layer as usize] = true;
+        let largest = colors
+            .iter()
+            .map(|color| color.r)
+            .filter(|value| value.is_finite())
+            .fold(0.0, Float::max);
+        let rescaled = match layer {
+            Layer::Depth if largest > self.max_depth => {
+                self.max_depth = largest;
+                true
+            }
+            Layer::SampleCount if largest > self.max_samples => {
+                self.max_samples = largest;
+                true
+            }
+            _ => false,
+        };
+        for dy in 0..size.0 {
+            for dx in 0..size.1 {
+                let index = ((top_left.0 + dy) * self.width) + (top_left.1 + dx);
+                self.layers[layer as usize][index] = colors[(dy * size.1) + dx];
+                if layer == Layer::Beauty && !self.rendered[index] {
+                    self.rendered[index] = true;
+                    self.rendered_count += 1;
+                }
+                if layer == self.shown {
+                    self.show_pixel(index);
+                }
+            }
+        }
+        if rescaled && layer == self.shown {
+            self.refresh();
+        }
+        rescaled && layer == self.shown
+    }
+    // Dims a region that is about to be rendered again until its tiles come in.
+    fn mark_pending(&mut self, top_left: (usize, usize), size: (usize, usize)) {
+        for y in top_left.0..top_left.0 + size.0 {
+            for x in top_left.1..top_left.1 + size.1 {
+                let index = y * self.width + x;
+                if self.rendered[index] {
+                    self.rendered[index] = false;
+                    self.rendered_count -= 1;
+                }
+                self.show_pixel(index);
+            }
+        }
+    }
+    // The fraction of the current render that has come in.
+    fn progress(&self) -> Float {
+        self.rendered_count as Float / self.rendered.len() as Float
+    }
+    // Switches to the next layer that has been rendered.
+    fn next_layer(&mut self) {
+        let current = self.shown as usize;
+        let next = (1..Layer::ALL.len())
+            .map(|offset| (current + offset) % Layer::ALL.len())
+            .find(|&index| self.received[index]);
+        if let Some(next) = next {
+            self.shown = Layer::ALL[next];
+            self.refresh();
+            info!("showing {}", self.shown.name());
+        }
+    }
+    fn set_tonemap(&mut self, exposure: Float, gamma: Float) {
+        self.exposure = exposure;
+        self.gamma = gamma.max(GAMMA_STEP);
+        self.refresh();
+        info!(
+            "exposure {:+.1} stops, gamma {:.1}",
+            self.exposure, self.gamma
+        );
+    }
+    // The rows of the pixels starting at `top_left`, for updating the texture with.
+    fn rows_from(&self, top_left: (usize, usize)) -> &[u8] {
+        &self.pixels[((top_left.0 * self.width) + top_left.1) * 3..]
+    }
+}
+
+// The rows of the settings panel, the tonemapping rows change the preview while the rest are sent
+// to render the image again.
+const SETTINGS_ROWS: [&str; 7] = [
+    "field of view",
+    "defocus angle",
+    "focus distance",
+    "samples per pixel",
+    "max ray depth",
+    "exposure",
+    "gamma",
+];
+
+// A panel of the render settings, drawn over the top left corner of the preview while it's open.
+struct SettingsPanel {
+    settings: RenderSettings,
+    selected: usize,
+    open: bool,
+}
+
+impl SettingsPanel {
+    fn new(settings: RenderSettings) -> Self {
+        Self {
+            settings,
+            selected: 0,
+            open: false,
+        }
+    }
+    fn select(&mut self, step: isize) {
+        self.selected = self
+            .selected
+            .saturating_add_signed(step)
+            .min(SETTINGS_ROWS.len() - 1);
+    }
+    // Changes the selected row by `step` increments.
+    fn adjust(&mut self, step: isize, display: &mut DisplayBuffer) {
+        let settings = &mut self.settings;
+        let step_float = step as Float;
+        match self.selected {
+            0 => {
+                settings.field_of_view =
+                    (settings.field_of_view + 5.0 * step_float).clamp(1.0, 179.0)
+            }
+            1 => settings.defocus_angle = (settings.defocus_angle + 0.1 * step_float).max(0.0),
+            2 => settings.focus_distance *= (1.1 as Float).powf(step_float),
+            3 => {
+                // uniform sampling needs a square number of samples
+                let samples_sqrt = (settings.samples_per_pixel as Float).sqrt().round() as usize;
+                settings.samples_per_pixel = samples_sqrt.saturating_add_signed(step).max(1).pow(2);
+            }
+            4 => settings.max_ray_depth = settings.max_ray_depth.saturating_add_signed(step).max(1),
+            5 => display.set_tonemap(display.exposure + step_float * EXPOSURE_STEP, display.gamma),
+            _ => display.set_tonemap(display.exposure, display.gamma + step_float * GAMMA_STEP),
+        }
+    }
+    fn value(&self, row: usize, display: &DisplayBuffer) -> String {
+        let settings = &self.settings;
+        match row {
+            0 => format!("{:.0}°", settings.field_of_view),
+            1 => format!("{:.1}°", settings.defocus_angle),
+            2 => format!("{:.2}", settings.focus_distance),
+            3 => format!("{}", settings.samples_per_pixel),
+            4 => format!("{}", settings.max_ray_depth),
+            5 => format!("{:+.1} stops", display.exposure),
+            _ => format!("{:.1}", display.gamma),
+        }
+    }
+    fn draw(
+        &self,
+        canvas: &mut Canvas<Window>,
+        display: &DisplayBuffer,
+        viewport: Rect,
+    ) -> Result<()> {
+        let mut lines = vec![("render settings".to_string(), false)];
+        for (row, name) in SETTINGS_ROWS.iter().enumerate() {
+            let marker = if row == self.selected { '>' } else { ' ' };
+            let line = format!("{} {:<17} {}", marker, name, self.value(row, display));
+            lines.push((line, row == self.selected));
+        }
+        lines.push(("enter renders again".to_string(), false));
+
+        // the font is scaled up with the window, a line is a glyph high with a gap below it
+        let scale = (viewport.height() / 120).clamp(1, 4) as i32;
+        let line_height = (font::GLYPH_HEIGHT as i32 + 2) * scale;
+        let margin = 2 * scale;
+        let width = lines
+            .iter()
+            .map(|(line, _)| font::width(line) as i32 * scale)
+            .max()
+            .unwrap_or(0);
+        let background = Rect::new(
+            viewport.x(),
+            viewport.y(),
+            (width + 2 * margin) as u32,
+            (lines.len() as i32 * line_height + 2 * margin - 2 * scale) as u32,
+        );
+        canvas.set_blend_mode(BlendMode::Blend);
+        canvas.set_draw_color(pixels::Color::RGBA(0, 0, 0, 192));
+        canvas.fill_rect(background).map_err(sdl_error)?;
+        canvas.set_blend_mode(BlendMode::None);
+        for (index, (line, selected)) in lines.iter().enumerate() {
+            let (left, top) = (
+                background.x() + margin,
+                background.y() + margin + index as i32 * line_height,
+            );
+            let glyph_pixels = font::pixels(line)
+                .map(|(x, y)| {
+                    Rect::new(
+                        left + x as i32 * scale,
+                        top + y as i32 * scale,
+                        scale as u32,
+                        scale as u32,
+                    )
+                })
+                .collect::<Vec<_>>();
+            let color = if *selected {
+                pixels::Color::RGB(255, 200, 0)
+            } else {
+                pixels::Color::RGB(255, 255, 255)
+            };
+            canvas.set_draw_color(color);
+            canvas.fill_rects(&glyph_pixels).map_err(sdl_error)?;
+        }
+        Ok(())
+    }
+}
+
+// Space pauses and resumes the render, Escape cancels it and saves the finished tiles, S saves a
+// screenshot of the preview and Q closes the window. + and - change the exposure of the preview, or
+// the gamma with Ctrl held, and 0 resets them. Tab flips between the rendered image and its AOV
+// layers when they are rendered. The tiles being rendered are outlined, the parts of the image that
+// the render hasn't reached yet are dimmed and a bar along the bottom shows its progress. Dragging
+// out a rectangle with the left mouse button sends it to be rendered again with more samples once
+// the render is done. F1 opens the settings panel, where Up and Down pick a setting, Left and Right
+// change it and Enter renders the image again with the new settings once the current render is
+// done, or right away after cancelling it with Escape. With camera `controls` WASD moves the
+// camera, R and F move it up and down, Shift moves faster and dragging with the left mouse button
+// looks around. S is taken by the movement then, so screenshots are saved with Ctrl+S instead.
+pub fn sdl_thread(
     image_width: usize,
     image_height: usize,
-    receiver: Receiver<((usize, usize), (usize, usize), Vec<Color>)>,
-) {
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
+    receiver: Receiver<TileUpdate>,
+    controls: Option<Sender<CameraControl>>,
+    requests: Sender<RenderRequest>,
+    settings: RenderSettings,
+    render_control: &RenderControl,
+) -> Result<()> {
+    let sdl_context = sdl2::init().map_err(sdl_error)?;
+    let video_subsystem = sdl_context.video().map_err(sdl_error)?;
 
+    // shrink the window to fit the screen, the image is scaled to whatever size the window has
+    let (window_width, window_height) = match video_subsystem.desktop_display_mode(0) {
+        Ok(mode) => {
+            let screen = (mode.w as u32 * 9 / 10, mode.h as u32 * 9 / 10);
+            let fitted = letterbox(image_width, image_height, screen);
+            (
+                fitted.width().min(image_width as u32),
+                fitted.height().min(image_height as u32),
+            )
+        }
+        Err(_) => (image_width as u32, image_height as u32),
+    };
     let window = video_subsystem
-        .window("raytracer", image_width as u32, image_height as u32)
+        .window("raytracer", window_width, window_height)
         //.position_centered()
+        .resizable()
         .build()
-        .unwrap();
+        .map_err(sdl_error)?;
 
-    let mut canvas = window.into_canvas().build().unwrap();
+    let mut canvas = window.into_canvas().build().map_err(sdl_error)?;
     let texture_creator = canvas.texture_creator();
 
     let mut texture = texture_creator
         .create_texture_streaming(
             PixelFormatEnum::RGB24,
-            canvas.output_size().unwrap().0,
-            canvas.output_size().unwrap().1,
+            image_width as u32,
+            image_height as u32,
         )
-        .map_err(|e| e.to_string())
-        .unwrap();
+        .map_err(sdl_error)?;
 
-    canvas.copy(&texture, None, None).unwrap();
+    let mut viewport = letterbox(
+        image_width,
+        image_height,
+        canvas.output_size().map_err(sdl_error)?,
+    );
+    canvas.copy(&texture, None, viewport).map_err(sdl_error)?;
 
     canvas.present();
-    let mut event_pump = sdl_context.event_pump().unwrap();
+    let mut display = DisplayBuffer::new(image_width, image_height);
+    // the corners of the region being dragged out, in image coordinates
+    let mut selection = None;
+    let mut panel = SettingsPanel::new(settings);
+    let mut event_pump = sdl_context.event_pump().map_err(sdl_error)?;
+    let mut last_frame = Instant::now();
     'running: loop {
         for event in event_pump.poll_iter() {
             match event {
@@ -45,40 +383,330 @@ pub(crate) fn sdl_thread(
                 | Event::KeyDown {
                     keycode: Some(Keycode::Q),
                     ..
+                } => {
+                    render_control.cancel();
+                    break 'running;
                 }
-                | Event::KeyDown {
+                Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => break 'running,
+                } => {
+                    render_control.cancel();
+                    canvas.window_mut().set_title("raytracer (cancelled)").ok();
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Space),
+                    repeat: false,
+                    ..
+                } if !render_control.is_cancelled() => {
+                    let title = if render_control.toggle_pause() {
+                        "raytracer (paused)"
+                    } else {
+                        "raytracer"
+                    };
+                    canvas.window_mut().set_title(title).ok();
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::S),
+                    keymod,
+                    repeat: false,
+                    ..
+                } if controls.is_none() || keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                    save_screenshot(&display.pixels, image_width, image_height);
+                }
+                Event::KeyDown {
+                    keycode:
+                        Some(
+                            keycode @ (Keycode::Equals
+                            | Keycode::Plus
+                            | Keycode::KpPlus
+                            | Keycode::Minus
+                            | Keycode::KpMinus
+                            | Keycode::Num0),
+                        ),
+                    keymod,
+                    ..
+                } => {
+                    let step = match keycode {
+                        Keycode::Minus | Keycode::KpMinus => -1.0,
+                        _ => 1.0,
+                    };
+                    let (exposure, gamma) = match keycode {
+                        Keycode::Num0 => (0.0, DEFAULT_GAMMA),
+                        _ if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                            (display.exposure, display.gamma + step * GAMMA_STEP)
+                        }
+                        _ => (display.exposure + step * EXPOSURE_STEP, display.gamma),
+                    };
+                    display.set_tonemap(exposure, gamma);
+                    texture
+                        .update(None, &display.pixels, image_width * 3)
+                        .map_err(sdl_error)?;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => {
+                    display.next_layer();
+                    texture
+                        .update(None, &display.pixels, image_width * 3)
+                        .map_err(sdl_error)?;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F1),
+                    repeat: false,
+                    ..
+                } => {
+                    panel.open = !panel.open;
+                }
+                Event::KeyDown {
+                    keycode:
+                        Some(
+                            keycode
+                            @ (Keycode::Up | Keycode::Down | Keycode::Left | Keycode::Right),
+                        ),
+                    ..
+                } if panel.open => {
+                    match keycode {
+                        Keycode::Up => panel.select(-1),
+                        Keycode::Down => panel.select(1),
+                        Keycode::Left => panel.adjust(-1, &mut display),
+                        _ => panel.adjust(1, &mut display),
+                    }
+                    texture
+                        .update(None, &display.pixels, image_width * 3)
+                        .map_err(sdl_error)?;
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Return | Keycode::KpEnter),
+                    repeat: false,
+                    ..
+                } if panel.open && controls.is_none() => {
+                    requests.send(RenderRequest::Rerender(panel.settings)).ok();
+                    display.mark_pending((0, 0), (image_height, image_width));
+                    texture
+                        .update(None, &display.pixels, image_width * 3)
+                        .map_err(sdl_error)?;
+                    info!("rendering again with the new settings");
+                }
+                Event::MouseButtonDown {
+                    mouse_btn: MouseButton::Left,
+                    x,
+                    y,
+                    ..
+                } if controls.is_none() => {
+                    let start = window_to_image(viewport, image_width, (x, y));
+                    selection = Some((start, start));
+                }
+                Event::MouseMotion { x, y, .. } if selection.is_some() => {
+                    let end = window_to_image(viewport, image_width, (x, y));
+                    selection = selection.map(|(start, _)| (start, end));
+                }
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..),
+                    ..
+                } => {
+                    viewport = letterbox(
+                        image_width,
+                        image_height,
+                        canvas.output_size().map_err(sdl_error)?,
+                    );
+                }
+                Event::MouseButtonUp {
+                    mouse_btn: MouseButton::Left,
+                    ..
+                } => {
+                    let region = selection.take().and_then(|(start, end)| {
+                        selected_region(start, end, image_width, image_height)
+                    });
+                    if let Some(region) = region {
+                        requests.send(RenderRequest::Refine(region)).ok();
+                        display.mark_pending(region.0, region.1);
+                        texture
+                            .update(None, &display.pixels, image_width * 3)
+                            .map_err(sdl_error)?;
+                    }
+                }
+                Event::MouseMotion {
+                    mousestate,
+                    xrel,
+                    yrel,
+                    ..
+                } if mousestate.left() => {
+                    if let Some(controls) = &controls {
+                        let right = xrel as Float * LOOK_SENSITIVITY;
+                        let up = -yrel as Float * LOOK_SENSITIVITY;
+                        controls.send(CameraControl::Turn { right, up }).ok();
+                    }
+                }
                 _ => {}
             }
         }
-        while let Ok((top_left, size, result)) = receiver.try_recv() {
-            texture
-                .with_lock(None, |buffer: &mut [u8], _pitch: usize| {
-                    for dy in 0..size.0 {
-                        for dx in 0..size.1 {
-                            let index = (((top_left.0 + dy) * image_width) + (top_left.1 + dx)) * 3;
-                            let (ir, ig, ib) = result[(dy * size.1) + dx].into_u8();
-                            buffer[index + 0] = ir;
-                            buffer[index + 1] = ig;
-                            buffer[index + 2] = ib;
-                        }
-                    }
-                })
-                .unwrap();
+        let frame_time = last_frame.elapsed().as_secs_f64() as Float;
+        last_frame = Instant::now();
+        let keys = event_pump.keyboard_state();
+        let axis = |positive, negative| {
+            keys.is_scancode_pressed(positive) as i32 as Float
+                - keys.is_scancode_pressed(negative) as i32 as Float
+        };
+        let movement = Vec3::new(
+            axis(Scancode::D, Scancode::A),
+            axis(Scancode::R, Scancode::F),
+            axis(Scancode::W, Scancode::S),
+        );
+        // Ctrl+S saves a screenshot rather than moving back
+        let holding_ctrl =
+            keys.is_scancode_pressed(Scancode::LCtrl) || keys.is_scancode_pressed(Scancode::RCtrl);
+        let moving = movement.length_squared() > 0.0 && !holding_ctrl;
+        if let Some(controls) = controls.as_ref().filter(|_| moving) {
+            let speed = if keys.is_scancode_pressed(Scancode::LShift) {
+                FAST_MOVE_SPEED
+            } else {
+                MOVE_SPEED
+            };
+            let movement = movement * speed * frame_time;
+            controls.send(CameraControl::Move(movement)).ok();
+        }
+        while let Ok((layer, top_left, size, result)) = receiver.try_recv() {
+            if display.store_tile(layer, top_left, size, &result) {
+                texture
+                    .update(None, &display.pixels, image_width * 3)
+                    .map_err(sdl_error)?;
+                continue;
+            }
+            if layer != display.shown {
+                continue;
+            }
             let rect = Rect::new(
                 top_left.1 as i32,
                 top_left.0 as i32,
                 size.1 as u32,
                 size.0 as u32,
             );
-            canvas.copy(&texture, Some(rect), Some(rect)).unwrap();
+            texture
+                .update(rect, display.rows_from(top_left), image_width * 3)
+                .map_err(sdl_error)?;
+        }
+        canvas.set_draw_color(pixels::Color::RGB(0, 0, 0));
+        canvas.clear();
+        canvas.copy(&texture, None, viewport).map_err(sdl_error)?;
+        canvas.set_draw_color(pixels::Color::RGB(255, 200, 0));
+        for (top_left, size) in render_control.tiles_in_flight() {
+            let start = (top_left.1 as i32, top_left.0 as i32);
+            let end = (start.0 + size.1 as i32, start.1 + size.0 as i32);
+            let start = image_to_window(viewport, image_width, start);
+            let end = image_to_window(viewport, image_width, end);
+            canvas
+                .draw_rect(selection_rect(start, end))
+                .map_err(sdl_error)?;
+        }
+        if display.progress() < 1.0 {
+            let width = (viewport.width() as Float * display.progress()) as u32;
+            let bar = Rect::new(viewport.x(), viewport.bottom() - 4, width.max(1), 4);
+            canvas.fill_rect(bar).map_err(sdl_error)?;
+        }
+        if let Some((start, end)) = selection {
+            let start = image_to_window(viewport, image_width, start);
+            let end = image_to_window(viewport, image_width, end);
+            canvas.set_draw_color(pixels::Color::RGB(255, 255, 255));
+            canvas
+                .draw_rect(selection_rect(start, end))
+                .map_err(sdl_error)?;
+        }
+        if panel.open {
+            panel.draw(&mut canvas, &display, viewport)?;
         }
-        //canvas.copy(&texture, None, None).unwrap();
 
         canvas.present();
         ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
     }
     drop(receiver);
+    Ok(())
+}
+
+fn save_screenshot(pixels: &[u8], image_width: usize, image_height: usize) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let path = format!("screenshot-{}.png", timestamp);
+    match image::save_buffer(
+        &path,
+        pixels,
+        image_width as u32,
+        image_height as u32,
+        image::ColorType::Rgb8,
+    ) {
+        Ok(()) => info!("saved screenshot to {}", path),
+        Err(e) => warn!("failed to save screenshot: {}", e),
+    }
+}
+
+fn selection_rect(start: (i32, i32), end: (i32, i32)) -> Rect {
+    let (left, top) = (start.0.min(end.0), start.1.min(end.1));
+    let (width, height) = (start.0.abs_diff(end.0), start.1.abs_diff(end.1));
+    Rect::new(left, top, width, height)
+}
+
+// The top left corner and size of the part of the image inside a selection, if it isn't empty. The
+// corners of the selection may lie outside of the image.
+fn selected_region(
+    start: (i32, i32),
+    end: (i32, i32),
+    image_width: usize,
+    image_height: usize,
+) -> Option<((usize, usize), (usize, usize))> {
+    let clamp = |(x, y): (i32, i32)| {
+        (
+            (y.max(0) as usize).min(image_height),
+            (x.max(0) as usize).min(image_width),
+        )
+    };
+    let (start, end) = (clamp(start), clamp(end));
+    let top_left = (start.0.min(end.0), start.1.min(end.1));
+    let size = (start.0.abs_diff(end.0), start.1.abs_diff(end.1));
+    if size.0 == 0 || size.1 == 0 {
+        return None;
+    }
+    Some((top_left, size))
+}
+
+// The largest rectangle with the aspect ratio of the image that fits centered in the window, with
+// black bars filling the rest.
+fn letterbox(image_width: usize, image_height: usize, window: (u32, u32)) -> Rect {
+    let scale =
+        (window.0 as Float / image_width as Float).min(window.1 as Float / image_height as Float);
+    let width = ((image_width as Float * scale) as u32).max(1);
+    let height = ((image_height as Float * scale) as u32).max(1);
+    Rect::new(
+        (window.0.saturating_sub(width) / 2) as i32,
+        (window.1.saturating_sub(height) / 2) as i32,
+        width,
+        height,
+    )
+}
+
+fn window_to_image(viewport: Rect, image_width: usize, (x, y): (i32, i32)) -> (i32, i32) {
+    let scale = image_width as Float / viewport.width() as Float;
+    (
+        ((x - viewport.x()) as Float * scale) as i32,
+        ((y - viewport.y()) as Float * scale) as i32,
+    )
+}
+
+fn image_to_window(viewport: Rect, image_width: usize, (x, y): (i32, i32)) -> (i32, i32) {
+    let scale = viewport.width() as Float / image_width as Float;
+    (
+        viewport.x() + (x as Float * scale) as i32,
+        viewport.y() + (y as Float * scale) as i32,
+    )
+}
+
+// A color for every ID so neighbouring objects stand apart, black where nothing was hit.
+fn id_color(id: u32) -> Color {
+    if id == 0 {
+        return Color::black();
+    }
+    let [_, r, g, b] = id.wrapping_mul(0x9e3779b1).to_be_bytes();
+    Color::new(r as Float, g as Float, b as Float) / 255.0
 }
@@ -0,0 +1,102 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::{
+    color::Color,
+    error::{Error, Result},
+    float::{consts::PI, Float},
+};
+
+// The length one unit of a scene stands for. Geometry is placed in scene units whatever they are,
+// but the physical settings of the camera, like the focus distance, the distance between the eyes
+// of stereo panoramas and the density of fog, are given in meters once a scene declares its units
+// and scaled into them, and lights can be given by the power they put out, so assets modelled in
+// centimeters look through the same lens and are lit the same as ones modelled in meters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Units {
+    #[default]
+    Meters,
+    Centimeters,
+    Millimeters,
+    Inches,
+    Feet,
+}
+
+impl Units {
+    pub const ALL: [Units; 5] = [
+        Units::Meters,
+        Units::Centimeters,
+        Units::Millimeters,
+        Units::Inches,
+        Units::Feet,
+    ];
+
+    // The length of one unit in meters.
+    pub fn meters(self) -> Float {
+        match self {
+            Units::Meters => 1.0,
+            Units::Centimeters => 0.01,
+            Units::Millimeters => 0.001,
+            Units::Inches => 0.0254,
+            Units::Feet => 0.3048,
+        }
+    }
+    // A length of `meters` in these units.
+    pub fn from_meters(self, meters: Float) -> Float {
+        meters / self.meters()
+    }
+    // A length of `length` in these units in meters.
+    pub fn to_meters(self, length: Float) -> Float {
+        length * self.meters()
+    }
+    // The radiance of a diffuse light of `area` square units that puts out `power` watts, see
+    // `DiffuseLight::from_power`. A diffuse surface sends out π times its radiance per square
+    // meter.
+    pub fn radiance(self, power: Color, area: Float) -> Color {
+        power / (PI * area * self.meters() * self.meters())
+    }
+}
+
+impl Display for Units {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Units::Meters => "meters",
+            Units::Centimeters => "centimeters",
+            Units::Millimeters => "millimeters",
+            Units::Inches => "inches",
+            Units::Feet => "feet",
+        })
+    }
+}
+
+impl FromStr for Units {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|units| units.to_string() == s)
+            .ok_or_else(|| Error::InvalidCamera(format!("unknown units: {}", s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lights_of_the_same_power_look_the_same_in_any_units() {
+        for units in Units::ALL {
+            assert_eq!(units.to_string().parse::<Units>().unwrap(), units);
+            assert!((units.to_meters(units.from_meters(2.5)) - 2.5).abs() < 1e-6);
+        }
+        assert_eq!(Units::Centimeters.from_meters(2.0), 200.0);
+        // a square meter light putting out π watts, modelled in meters and in centimeters
+        let meters = Units::Meters.radiance(Color::gray(PI), 1.0);
+        let centimeters = Units::Centimeters.radiance(Color::gray(PI), 10_000.0);
+        assert!((meters.g - 1.0).abs() < 1e-6);
+        assert!((centimeters.g - meters.g).abs() < 1e-6);
+        assert!("parsecs".parse::<Units>().is_err());
+    }
+}
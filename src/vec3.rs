@@ -1,55 +1,86 @@
 use std::{
-    f64,
     fmt::Debug,
-    ops::{Add, Div, Mul, Neg, Sub, Index},
+    ops::{Add, Div, Index, Mul, Neg, Sub},
 };
 
-use crate::{color::Color, random::Rng};
+#[cfg(feature = "simd")]
+use std::simd::{num::SimdFloat, simd_swizzle};
+
+#[cfg(feature = "simd")]
+use crate::float::Floatx4;
+use crate::{
+    color::Color,
+    float::{consts, Float},
+    random::RandomSource,
+};
 
 pub type Point3 = Vec3;
 
-type Value = f64;
+type Value = Float;
 
+// With the `simd` feature the vector is padded to a full, aligned SIMD register so the arithmetic
+// below compiles to single vector instructions. The padding lane is always zero.
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "simd", repr(C))]
+#[cfg_attr(all(feature = "simd", not(feature = "f32")), repr(align(32)))]
+#[cfg_attr(all(feature = "simd", feature = "f32"), repr(align(16)))]
 pub struct Vec3 {
     pub x: Value,
     pub y: Value,
     pub z: Value,
+    #[cfg(feature = "simd")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    w: Value,
 }
 
 impl Vec3 {
+    #[cfg(not(feature = "simd"))]
     pub fn new(x: Value, y: Value, z: Value) -> Self {
         Self { x, y, z }
     }
+    #[cfg(feature = "simd")]
+    pub fn new(x: Value, y: Value, z: Value) -> Self {
+        Self { x, y, z, w: 0.0 }
+    }
+    #[cfg(feature = "simd")]
+    fn simd(self) -> Floatx4 {
+        Floatx4::from_array([self.x, self.y, self.z, self.w])
+    }
+    // Drops whatever ended up in the padding lane, 0/0 for instance.
+    #[cfg(feature = "simd")]
+    fn from_simd(value: Floatx4) -> Self {
+        Self::new(value[0], value[1], value[2])
+    }
     pub fn zero() -> Self {
         Self::new(0., 0., 0.)
     }
-    pub fn random_in_unit_sphere_reject(rng: &mut Rng) -> Self {
+    pub fn random_in_unit_sphere_reject(rng: &mut (impl RandomSource + ?Sized)) -> Self {
         loop {
             let candidate = Self::new(
-                rng.next_f64_range(-1.0..1.0),
-                rng.next_f64_range(-1.0..1.0),
-                rng.next_f64_range(-1.0..1.0),
+                rng.next_float_range(-1.0..1.0),
+                rng.next_float_range(-1.0..1.0),
+                rng.next_float_range(-1.0..1.0),
             );
             if candidate.length_squared() < 1.0 {
                 return candidate;
             }
         }
     }
-    pub fn random_in_unit_sphere(rng: &mut Rng) -> Self {
-        let theta = rng.next_f64_range(0.0..2.0 * f64::consts::PI);
-        let z = rng.next_f64_range(-1.0..1.0);
+    pub fn random_in_unit_sphere(rng: &mut (impl RandomSource + ?Sized)) -> Self {
+        let theta = rng.next_float_range(0.0..2.0 * consts::PI);
+        let z = rng.next_float_range(-1.0..1.0);
         let r = (1.0 - z.powi(2)).sqrt();
         Self::new(r * theta.cos(), r * theta.sin(), z)
     }
-    pub fn random_on_unit_sphere(rng: &mut Rng) -> Self {
+    pub fn random_on_unit_sphere(rng: &mut (impl RandomSource + ?Sized)) -> Self {
         Self::random_in_unit_sphere(rng).normalized()
     }
-    pub fn random_in_unit_circle(rng: &mut Rng) -> Self {
+    pub fn random_in_unit_circle(rng: &mut (impl RandomSource + ?Sized)) -> Self {
         loop {
             let candidate = Self::new(
-                rng.next_f64_range(-1.0..1.0),
-                rng.next_f64_range(-1.0..1.0),
+                rng.next_float_range(-1.0..1.0),
+                rng.next_float_range(-1.0..1.0),
                 0.0,
             );
             if candidate.length_squared() < 1.0 {
@@ -57,16 +88,30 @@ impl Vec3 {
             }
         }
     }
-    pub fn random_on_hemisphere(rng: &mut Rng, normal: &Vec3) -> Self {
+    pub fn random_on_hemisphere(rng: &mut (impl RandomSource + ?Sized), normal: &Vec3) -> Self {
         let random = Self::random_on_unit_sphere(rng);
         random.dot(normal).signum() * random
     }
+    // A direction on the hemisphere around +z, drawn with a density proportional to the cosine of
+    // its angle to the z axis.
+    pub fn random_cosine_direction(rng: &mut (impl RandomSource + ?Sized)) -> Self {
+        let phi = 2.0 * consts::PI * rng.next_float();
+        let r2 = rng.next_float();
+        let r = r2.sqrt();
+        Self::new(phi.cos() * r, phi.sin() * r, (1.0 - r2).sqrt())
+    }
     pub fn reflect(&self, normal: &Vec3) -> Vec3 {
         *self - 2.0 * (*self).dot(normal) * *normal
     }
+    #[cfg(not(feature = "simd"))]
     pub fn dot(&self, rhs: &Self) -> Value {
         self.x * rhs.x + self.y * rhs.y + self.z * rhs.z
     }
+    #[cfg(feature = "simd")]
+    pub fn dot(&self, rhs: &Self) -> Value {
+        (self.simd() * rhs.simd()).reduce_sum()
+    }
+    #[cfg(not(feature = "simd"))]
     pub fn cross(&self, rhs: &Self) -> Self {
         Self {
             x: self.y * rhs.z - self.z * rhs.y,
@@ -74,9 +119,22 @@ impl Vec3 {
             z: self.x * rhs.y - self.y * rhs.x,
         }
     }
+    #[cfg(feature = "simd")]
+    pub fn cross(&self, rhs: &Self) -> Self {
+        let (a, b) = (self.simd(), rhs.simd());
+        let a_yzx = simd_swizzle!(a, [1, 2, 0, 3]);
+        let b_yzx = simd_swizzle!(b, [1, 2, 0, 3]);
+        let c = a * b_yzx - a_yzx * b;
+        Self::from_simd(simd_swizzle!(c, [1, 2, 0, 3]))
+    }
+    #[cfg(not(feature = "simd"))]
     pub fn length_squared(&self) -> Value {
         self.x.powi(2) + self.y.powi(2) + self.z.powi(2)
     }
+    #[cfg(feature = "simd")]
+    pub fn length_squared(&self) -> Value {
+        self.dot(self)
+    }
     pub fn length(&self) -> Value {
         self.length_squared().sqrt()
     }
@@ -90,11 +148,41 @@ impl Vec3 {
         let threshold = 1e-9;
         self.x.abs() < threshold && self.y.abs() < threshold && self.z.abs() < threshold
     }
-    pub fn distance(&self, other: &Self) -> f64 {
+    pub fn is_finite(&self) -> bool {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
+    pub fn distance(&self, other: &Self) -> Float {
         (*self - *other).length()
     }
 }
 
+// An orthonormal basis with `w` along a given direction, for moving directions sampled around the
+// z axis to be around a surface normal.
+#[derive(Clone, Copy, Debug)]
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub w: Vec3,
+}
+
+impl Onb {
+    pub fn new(direction: &Vec3) -> Self {
+        let w = direction.unit_vector();
+        let a = if w.x.abs() > 0.9 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let v = w.cross(&a).unit_vector();
+        let u = w.cross(&v);
+        Self { u, v, w }
+    }
+    // The direction with coordinates `local` in this basis.
+    pub fn transform(&self, local: &Vec3) -> Vec3 {
+        local.x * self.u + local.y * self.v + local.z * self.w
+    }
+}
+
 impl Index<usize> for Vec3 {
     type Output = Value;
     fn index(&self, index: usize) -> &Self::Output {
@@ -115,11 +203,17 @@ impl From<Color> for Vec3 {
 
 impl Neg for Vec3 {
     type Output = Self;
+    #[cfg(not(feature = "simd"))]
     fn neg(self) -> Self::Output {
         Self::new(-self.x, -self.y, -self.z)
     }
+    #[cfg(feature = "simd")]
+    fn neg(self) -> Self::Output {
+        Self::from_simd(-self.simd())
+    }
 }
 
+#[cfg(not(feature = "simd"))]
 macro_rules! impl_vec3_ops {
     ($trait:ident, $op:ident, $type:ty) => {
         impl $trait for $type {
@@ -145,6 +239,32 @@ macro_rules! impl_vec3_ops {
     };
 }
 
+#[cfg(feature = "simd")]
+macro_rules! impl_vec3_ops {
+    ($trait:ident, $op:ident, $type:ty) => {
+        impl $trait for $type {
+            type Output = Self;
+            fn $op(self, rhs: Self) -> Self::Output {
+                Self::from_simd(self.simd().$op(rhs.simd()))
+            }
+        }
+
+        impl $trait<Value> for $type {
+            type Output = Self;
+            fn $op(self, rhs: Value) -> Self::Output {
+                Self::from_simd(self.simd().$op(Floatx4::splat(rhs)))
+            }
+        }
+
+        impl $trait<$type> for Value {
+            type Output = $type;
+            fn $op(self, rhs: $type) -> Self::Output {
+                Self::Output::from_simd(Floatx4::splat(self).$op(rhs.simd()))
+            }
+        }
+    };
+}
+
 impl_vec3_ops!(Add, add, Vec3);
 impl_vec3_ops!(Sub, sub, Vec3);
 impl_vec3_ops!(Mul, mul, Vec3);
@@ -168,6 +288,7 @@ impl Debug for Vec3 {
     }
 }
 
+#[cfg(test)]
 extern crate test;
 
 #[cfg(test)]
@@ -193,4 +314,82 @@ mod tests {
             black_box(Vec3::random_in_unit_sphere_reject(&mut rng));
         });
     }
+
+    #[test]
+    fn cosine_directions_are_cosine_weighted() {
+        let mut rng = Rng::new();
+        let onb = Onb::new(&Vec3::new(1.0, 2.0, -0.5));
+        let n = 100_000;
+        let mut cosine_sum = 0.0;
+        for _ in 0..n {
+            let direction = onb.transform(&Vec3::random_cosine_direction(&mut rng));
+            assert!((direction.length() - 1.0).abs() < 1e-4);
+            let cosine = direction.dot(&onb.w);
+            assert!(cosine >= 0.0);
+            cosine_sum += cosine;
+        }
+        // the mean cosine over a cosine weighted hemisphere is 2/3
+        assert!((cosine_sum / n as Float - 2.0 / 3.0).abs() < 0.01);
+    }
+
+    // Run these with and without `--features simd` to compare the two backends.
+    fn random_vectors(n: usize) -> Vec<Vec3> {
+        let mut rng = Rng::new();
+        (0..n)
+            .map(|_| Vec3::random_in_unit_sphere(&mut rng))
+            .collect()
+    }
+
+    #[bench]
+    fn bench_dot(b: &mut Bencher) {
+        let vectors = random_vectors(1024);
+        b.iter(|| {
+            for pair in vectors.windows(2) {
+                black_box(black_box(pair[0]).dot(&pair[1]));
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_cross(b: &mut Bencher) {
+        let vectors = random_vectors(1024);
+        b.iter(|| {
+            for pair in vectors.windows(2) {
+                black_box(black_box(pair[0]).cross(&pair[1]));
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_normalized(b: &mut Bencher) {
+        let vectors = random_vectors(1024);
+        b.iter(|| {
+            for vector in vectors.iter() {
+                black_box(black_box(*vector).normalized());
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_reflect(b: &mut Bencher) {
+        let vectors = random_vectors(1024);
+        b.iter(|| {
+            for pair in vectors.windows(2) {
+                black_box(black_box(pair[0]).reflect(&pair[1]));
+            }
+        });
+    }
+
+    #[test]
+    fn cross_is_orthogonal() {
+        let x = Vec3::new(1.0, 0.0, 0.0);
+        let y = Vec3::new(0.0, 1.0, 0.0);
+        let z = x.cross(&y);
+        assert_eq!((z.x, z.y, z.z), (0.0, 0.0, 1.0));
+        let a = Vec3::new(1.0, 2.0, 3.0);
+        let b = Vec3::new(-4.0, 0.5, 2.0);
+        let c = a.cross(&b);
+        assert!(c.dot(&a).abs() < 1e-5 && c.dot(&b).abs() < 1e-5);
+        assert_eq!(a.dot(&b), 3.0);
+    }
 }
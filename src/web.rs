@@ -0,0 +1,90 @@
+use wasm_bindgen::{prelude::*, Clamped};
+use web_sys::{CanvasRenderingContext2d, ImageData};
+
+use crate::{
+    camera::{builder::CameraBuilder, image::ImageSpecBuilder, Camera},
+    float::Float,
+    hittable::Hittable,
+    scene::{self, Scene},
+};
+
+// The renderer as a WebAssembly module for running in a browser, see `web/index.html` for how to
+// build it and a page that uses it. There are no threads to render on in the browser, so the page
+// asks for a few rows at a time from `requestAnimationFrame` and they are drawn into a canvas as
+// they finish. The image is rendered in passes of growing sample counts, like when exploring, so
+// it sharpens over time.
+#[wasm_bindgen]
+pub struct WebRenderer {
+    scene: Scene<Box<dyn Hittable>>,
+    passes: std::vec::IntoIter<Camera>,
+    row: usize,
+}
+
+#[wasm_bindgen]
+impl WebRenderer {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        scene: &str,
+        width: usize,
+        aspect_ratio: Float,
+        samples_per_pixel: usize,
+    ) -> Result<WebRenderer, JsError> {
+        let image_spec = ImageSpecBuilder::default()
+            .width(width)
+            .aspect_ratio(aspect_ratio)
+            .build();
+        let camera = CameraBuilder::default()
+            .image_spec(image_spec)
+            .random_sampler(samples_per_pixel)
+            .max_ray_depth(16);
+        let mut scene = scene::from_name(scene, camera)?;
+        let mut passes = scene.camera.progressive_passes()?.into_iter();
+        scene.camera = passes.next().unwrap();
+        Ok(Self {
+            scene,
+            passes,
+            row: 0,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.scene.camera.image_width
+    }
+
+    pub fn height(&self) -> usize {
+        self.scene.camera.image_height
+    }
+
+    // Renders the next `rows` rows and draws them into `context`, returns false once the last
+    // pass is done.
+    pub fn render_rows(
+        &mut self,
+        context: &CanvasRenderingContext2d,
+        rows: usize,
+    ) -> Result<bool, JsValue> {
+        if self.row >= self.height() {
+            match self.passes.next() {
+                Some(pass) => self.scene.camera = pass,
+                None => return Ok(false),
+            }
+            self.row = 0;
+        }
+        let rect = (rows.min(self.height() - self.row), self.width());
+        let colors = self.scene.render_tile((self.row, 0), rect);
+        let pixels = colors
+            .iter()
+            .flat_map(|color| {
+                let (r, g, b) = color.gamma_corrected(2.2).into_u8();
+                [r, g, b, 255]
+            })
+            .collect::<Vec<_>>();
+        let image_data = ImageData::new_with_u8_clamped_array_and_sh(
+            Clamped(&pixels),
+            rect.1 as u32,
+            rect.0 as u32,
+        )?;
+        context.put_image_data(&image_data, 0.0, self.row as f64)?;
+        self.row += rect.0;
+        Ok(true)
+    }
+}
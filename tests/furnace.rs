@@ -0,0 +1,86 @@
+// Furnace tests, every material on a sphere in a uniform white environment, see `scene::furnace`.
+// The light leaving the sphere must match the albedo of the material everywhere, a material that
+// gains or loses energy shows up as a brighter or darker image.
+
+use std::sync::Arc;
+
+use raytracer::camera::{builder::CameraBuilder, image::ImageSpecBuilder};
+use raytracer::color::Color;
+use raytracer::hittable::materials::{Dielectric, Lambertian, Material, Metal};
+use raytracer::scene;
+
+// Enough bounces that the light lost to the depth limit inside glass is below the tolerance.
+const MAX_RAY_DEPTH: usize = 64;
+const TOLERANCE: f32 = 0.01;
+
+// The average color of the sphere and how far the darkest and brightest pixels are from it.
+fn render(material: Arc<dyn Material>) -> ([f32; 3], f32) {
+    let image_spec = ImageSpecBuilder::default()
+        .width(32)
+        .aspect_ratio(1.0)
+        .build();
+    let camera = CameraBuilder::default()
+        .image_spec(image_spec)
+        .random_sampler(256)
+        .max_ray_depth(MAX_RAY_DEPTH);
+    let image = scene::furnace_with(camera, material)
+        .unwrap()
+        .render_to_float_image();
+    let pixel_count = (image.width() * image.height()) as f32;
+    let mut mean = [0.0; 3];
+    for pixel in image.pixels() {
+        for c in 0..3 {
+            mean[c] += pixel[c] / pixel_count;
+        }
+    }
+    let spread = image
+        .pixels()
+        .flat_map(|pixel| (0..3).map(move |c| (pixel[c] - mean[c]).abs()))
+        .fold(0.0, f32::max);
+    (mean, spread)
+}
+
+fn assert_conserves_energy(material: Arc<dyn Material>, albedo: Color) {
+    let description = format!("{:?}", material);
+    let (mean, spread) = render(material);
+    let expected = [albedo.r as f32, albedo.g as f32, albedo.b as f32];
+    for c in 0..3 {
+        assert!(
+            (mean[c] - expected[c]).abs() < TOLERANCE,
+            "{} reflects {:?} of the light, expected {:?}",
+            description,
+            mean,
+            expected
+        );
+    }
+    // the spread is noise, so it only catches shading well beyond it
+    assert!(
+        spread < 0.25,
+        "{} is not uniformly lit, pixels are up to {} from the mean",
+        description,
+        spread
+    );
+}
+
+#[test]
+fn lambertian() {
+    let albedo = Color::gray(0.5);
+    assert_conserves_energy(Arc::new(Lambertian::from(albedo)), albedo);
+}
+
+#[test]
+fn metal() {
+    let albedo = Color::gray(0.5);
+    assert_conserves_energy(Arc::new(Metal::from(albedo)), albedo);
+}
+
+#[test]
+fn fuzzy_metal() {
+    let albedo = Color::gray(0.5);
+    assert_conserves_energy(Arc::new(Metal::new(albedo, 0.3)), albedo);
+}
+
+#[test]
+fn dielectric() {
+    assert_conserves_energy(Arc::new(Dielectric::new(1.5)), Color::white());
+}
@@ -0,0 +1,150 @@
+// Renders small versions of the built-in scenes and compares them against the reference images in
+// `tests/golden`, so changes to the BVH or the materials can't change the output unnoticed. The
+// renders are noisy and the noise differs between runs and float precisions, so the images are
+// compared as averages over blocks of pixels rather than pixel by pixel. Single precision builds
+// shade visibly differently, so they have references of their own. After an intended change
+// to the output, write new references with `UPDATE_GOLDEN=1 cargo test --test golden`.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use image::RgbImage;
+use raytracer::camera::{builder::CameraBuilder, image::ImageSpecBuilder};
+use raytracer::color::Color;
+use raytracer::hittable::materials::{Dielectric, Material, Metal};
+use raytracer::scene;
+
+const WIDTH: usize = 96;
+const SAMPLES_PER_PIXEL: usize = 64;
+// The blocks are this many pixels on a side.
+const BLOCK: u32 = 6;
+// The largest difference allowed in the average of any block and over the whole image, in 8 bit
+// sRGB steps.
+const MAX_BLOCK_DIFFERENCE: f64 = 10.0;
+const MAX_MEAN_DIFFERENCE: f64 = 2.5;
+
+fn camera() -> CameraBuilder {
+    let image_spec = ImageSpecBuilder::default()
+        .width(WIDTH)
+        .aspect_ratio(16.0 / 9.0)
+        .build();
+    CameraBuilder::default()
+        .image_spec(image_spec)
+        .random_sampler(SAMPLES_PER_PIXEL)
+        .max_ray_depth(8)
+}
+
+// The average color of every block of the image.
+fn blocks(image: &RgbImage) -> Vec<[f64; 3]> {
+    let mut blocks = Vec::new();
+    for block_y in 0..image.height().div_ceil(BLOCK) {
+        for block_x in 0..image.width().div_ceil(BLOCK) {
+            let mut sum = [0.0; 3];
+            let mut count = 0.0;
+            for y in block_y * BLOCK..((block_y + 1) * BLOCK).min(image.height()) {
+                for x in block_x * BLOCK..((block_x + 1) * BLOCK).min(image.width()) {
+                    let pixel = image.get_pixel(x, y);
+                    for c in 0..3 {
+                        sum[c] += pixel[c] as f64;
+                    }
+                    count += 1.0;
+                }
+            }
+            blocks.push(sum.map(|channel| channel / count));
+        }
+    }
+    return blocks;
+}
+
+fn check(name: &str) {
+    let actual = scene::from_name(name, camera()).unwrap().render_to_image();
+    check_image(name, actual);
+}
+
+// Checks `material` on the material preview, against the reference `name`.
+fn check_material(name: &str, material: Arc<dyn Material>) {
+    let actual = scene::material_preview(camera(), material)
+        .unwrap()
+        .render_to_image();
+    check_image(name, actual);
+}
+
+fn check_image(name: &str, actual: RgbImage) {
+    let reference_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(reference_name(name));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        actual.save(&reference_path).unwrap();
+        return;
+    }
+    let reference = image::open(&reference_path)
+        .unwrap_or_else(|e| panic!("no reference image for {}: {}", name, e))
+        .to_rgb8();
+    assert_eq!(actual.dimensions(), reference.dimensions());
+
+    let differences = blocks(&actual)
+        .iter()
+        .zip(blocks(&reference))
+        .map(|(a, b)| (0..3).map(|c| (a[c] - b[c]).abs()).fold(0.0, f64::max))
+        .collect::<Vec<_>>();
+    let max = differences.iter().copied().fold(0.0, f64::max);
+    let mean = differences.iter().sum::<f64>() / differences.len() as f64;
+    if max > MAX_BLOCK_DIFFERENCE || mean > MAX_MEAN_DIFFERENCE {
+        let failure_path = failure_directory().join(reference_name(name));
+        actual.save(&failure_path).unwrap();
+        panic!(
+            "{} differs from its reference, largest block difference {:.1}, mean {:.2}, the render is at {}",
+            name,
+            max,
+            mean,
+            failure_path.display()
+        );
+    }
+}
+
+fn reference_name(name: &str) -> String {
+    if cfg!(feature = "f32") {
+        format!("{}-f32.png", name)
+    } else {
+        format!("{}.png", name)
+    }
+}
+
+fn failure_directory() -> PathBuf {
+    let directory = Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden");
+    std::fs::create_dir_all(&directory).unwrap();
+    return directory;
+}
+
+#[test]
+fn two_spheres() {
+    check("two_spheres");
+}
+
+#[test]
+fn composition() {
+    check("composition");
+}
+
+#[test]
+fn book_cover() {
+    check("book_cover");
+}
+
+#[test]
+fn earth() {
+    check("earth");
+}
+
+#[test]
+fn material_preview_metal() {
+    check_material(
+        "material_preview_metal",
+        Arc::new(Metal::new(Color::new(0.9, 0.6, 0.3), 0.2)),
+    );
+}
+
+#[test]
+fn material_preview_glass() {
+    check_material("material_preview_glass", Arc::new(Dielectric::new(1.5)));
+}